@@ -0,0 +1,420 @@
+//! Alerting on anomalies in the network, as seen from the data published by
+//! the nodes.
+//!
+//! Alert rules are evaluated periodically against the current [NodeInfo] of
+//! all nodes. A rule fires once its condition has held for the configured
+//! duration, and resolves once the condition no longer holds or the node it
+//! concerns is removed. Both transitions are delivered as an [Alert] to a
+//! webhook, and alerts that could not be delivered are raised again on the next
+//! evaluation.
+
+use crate::{average_without_outer_values, NodeInfo};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    time::Duration,
+};
+
+/// The subject of alerts concerning the network as a whole.
+const NETWORK_SUBJECT: &str = "network";
+
+/// A rule for raising alerts, as read from the rules file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRule {
+    /// Name of the rule, included in the alerts raised by it.
+    pub name:       String,
+    /// Time in milliseconds the condition must hold before the alert fires.
+    #[serde(default)]
+    pub for_millis: u64,
+    #[serde(flatten)]
+    pub condition:  AlertCondition,
+}
+
+/// The condition under which an [AlertRule] fires.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AlertCondition {
+    /// The finalized block height of a node is more than `max_lag` below the
+    /// average finalized block height of the nodes. Evaluated for each node.
+    #[serde(rename_all = "camelCase")]
+    FinalizationLag {
+        max_lag: u64,
+    },
+    /// The percentage of nodes running a version starting with `version` is
+    /// below `min_percentage`.
+    #[serde(rename_all = "camelCase")]
+    VersionShare {
+        version:        String,
+        min_percentage: f64,
+    },
+}
+
+/// Read the alert rules from a JSON file containing a list of rules.
+pub fn read_rules(path: &Path) -> anyhow::Result<Vec<AlertRule>> {
+    let rules = std::fs::read(path)
+        .with_context(|| format!("Could not read the alert rules file {}", path.display()))?;
+    serde_json::from_slice(&rules)
+        .with_context(|| format!("Invalid alert rules in {}", path.display()))
+}
+
+/// Whether an alert is raised or resolved.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// An alert delivered to the webhook.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    /// The name of the rule raising the alert.
+    pub rule:      String,
+    pub status:    AlertStatus,
+    /// The id of the node the alert concerns, or `network` if it concerns the
+    /// network as a whole.
+    pub subject:   String,
+    /// The name of the node the alert concerns, if any.
+    pub node_name: Option<String>,
+    /// Description of the condition when the alert fires.
+    pub message:   String,
+    /// Time of the evaluation raising the alert (Unix time in milliseconds).
+    pub timestamp: u64,
+    /// The index of the rule raising the alert.
+    #[serde(skip)]
+    rule_index:    usize,
+}
+
+/// The state of the condition of a rule for a subject.
+struct ConditionState {
+    /// Time the condition started to hold (Unix time in milliseconds).
+    since:     u64,
+    /// Whether an alert is fired for the condition and delivered.
+    firing:    bool,
+    /// The name of the node the condition concerns, if any.
+    node_name: Option<String>,
+}
+
+/// Evaluates the alert rules and keeps track of the state of the alerts.
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    /// The percentage of nodes used for computing averages, see
+    /// [average_without_outer_values].
+    percentage_used_for_averages: usize,
+    /// The state of each rule (by index) and subject for which the condition
+    /// holds.
+    conditions: HashMap<(usize, String), ConditionState>,
+    /// The resolved alerts that are not delivered yet, by rule index and
+    /// subject.
+    undelivered: HashMap<(usize, String), Alert>,
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>, percentage_used_for_averages: usize) -> Self {
+        Self {
+            rules,
+            percentage_used_for_averages,
+            conditions: HashMap::new(),
+            undelivered: HashMap::new(),
+        }
+    }
+
+    /// Evaluate the rules against the nodes at time `now`, and return the
+    /// alerts that fired or resolved since the last evaluation, together with
+    /// the alerts that are not delivered yet. An alert must be marked as
+    /// delivered with [AlertEngine::delivered], otherwise it is returned again
+    /// by the next evaluation.
+    pub fn evaluate<'a>(
+        &mut self,
+        nodes: impl Iterator<Item = &'a NodeInfo>,
+        now: u64,
+    ) -> Vec<Alert> {
+        let nodes: Vec<&NodeInfo> = nodes.collect();
+        let node_ids: HashSet<&str> = nodes.iter().map(|n| n.nodeId.as_str()).collect();
+        let mut alerts = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            // The subjects for which the condition holds, with a description.
+            let mut holding: HashMap<String, (Option<String>, String)> = HashMap::new();
+            match &rule.condition {
+                AlertCondition::FinalizationLag {
+                    max_lag,
+                } => {
+                    if !nodes.is_empty() {
+                        let number_to_include =
+                            std::cmp::max(1, nodes.len() * self.percentage_used_for_averages / 100);
+                        let average = average_without_outer_values(
+                            nodes.iter().map(|n| n.finalizedBlockHeight).collect(),
+                            number_to_include,
+                        );
+                        for node in &nodes {
+                            let lag = average.saturating_sub(node.finalizedBlockHeight);
+                            if lag > *max_lag {
+                                holding.insert(
+                                    node.nodeId.clone(),
+                                    (
+                                        Some(node.nodeName.clone()),
+                                        format!(
+                                            "Finalized block height {} is {} below the network \
+                                             average {}",
+                                            node.finalizedBlockHeight, lag, average
+                                        ),
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+                AlertCondition::VersionShare {
+                    version,
+                    min_percentage,
+                } => {
+                    if !nodes.is_empty() {
+                        let count = nodes.iter().filter(|n| n.client.starts_with(version)).count();
+                        let percentage = 100.0 * count as f64 / nodes.len() as f64;
+                        if percentage < *min_percentage {
+                            holding.insert(
+                                NETWORK_SUBJECT.to_owned(),
+                                (
+                                    None,
+                                    format!(
+                                        "{:.1}% of the nodes run version {}, which is below {}%",
+                                        percentage, version, min_percentage
+                                    ),
+                                ),
+                            );
+                        }
+                    }
+                }
+            }
+
+            // Resolve the alerts for subjects where the condition no longer holds,
+            // including nodes that were removed for being stale.
+            let resolved: Vec<String> = self
+                .conditions
+                .keys()
+                .filter(|(i, subject)| *i == index && !holding.contains_key(subject))
+                .map(|(_, subject)| subject.clone())
+                .collect();
+            for subject in resolved {
+                if let Some(state) = self.conditions.remove(&(index, subject.clone())) {
+                    let removed = state.node_name.is_some() && !node_ids.contains(subject.as_str());
+                    if state.firing {
+                        let message = if removed {
+                            "The node was removed for being stale"
+                        } else {
+                            "The condition no longer holds"
+                        };
+                        self.undelivered.insert((index, subject.clone()), Alert {
+                            rule: rule.name.clone(),
+                            status: AlertStatus::Resolved,
+                            subject,
+                            node_name: state.node_name,
+                            message: message.to_owned(),
+                            timestamp: now,
+                            rule_index: index,
+                        });
+                    }
+                }
+            }
+
+            // Fire the alerts for subjects where the condition held long enough.
+            for (subject, (node_name, message)) in holding {
+                // If the resolution of a fired alert is not delivered yet, the alert is
+                // still firing as far as the webhook knows.
+                let key = (index, subject.clone());
+                if self.undelivered.remove(&key).is_some() {
+                    self.conditions.insert(key, ConditionState {
+                        since:     now,
+                        firing:    true,
+                        node_name: node_name.clone(),
+                    });
+                    continue;
+                }
+                let state =
+                    self.conditions.entry((index, subject.clone())).or_insert(ConditionState {
+                        since:     now,
+                        firing:    false,
+                        node_name: node_name.clone(),
+                    });
+                if !state.firing && now >= state.since + rule.for_millis {
+                    alerts.push(Alert {
+                        rule: rule.name.clone(),
+                        status: AlertStatus::Firing,
+                        subject,
+                        node_name,
+                        message,
+                        timestamp: now,
+                        rule_index: index,
+                    });
+                }
+            }
+        }
+        alerts.extend(self.undelivered.values().cloned());
+        alerts
+    }
+
+    /// Record that the alert was delivered, so it is not raised again.
+    pub fn delivered(&mut self, alert: &Alert) {
+        let key = (alert.rule_index, alert.subject.clone());
+        match alert.status {
+            AlertStatus::Firing => {
+                if let Some(state) = self.conditions.get_mut(&key) {
+                    state.firing = true;
+                }
+            }
+            AlertStatus::Resolved => {
+                self.undelivered.remove(&key);
+            }
+        }
+    }
+}
+
+/// Delivers alerts by posting them as JSON to a URL.
+pub struct Webhook {
+    url:    String,
+    client: reqwest::blocking::Client,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> anyhow::Result<Self> {
+        let client =
+            reqwest::blocking::Client::builder().timeout(Duration::from_secs(10)).build()?;
+        Ok(Self {
+            url,
+            client,
+        })
+    }
+
+    /// Post the alert to the webhook.
+    pub fn deliver(&self, alert: &Alert) -> anyhow::Result<()> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(alert)?)
+            .send()?;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "Webhook responded with status {}",
+            response.status()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_node_info as node;
+    use std::net::TcpListener;
+
+    /// Evaluate the rules and mark the alerts as delivered.
+    fn evaluate(engine: &mut AlertEngine, nodes: &[NodeInfo], now: u64) -> Vec<Alert> {
+        let alerts = engine.evaluate(nodes.iter(), now);
+        for alert in &alerts {
+            engine.delivered(alert);
+        }
+        alerts
+    }
+
+    #[test]
+    fn alerts_fire_after_duration_and_resolve() -> anyhow::Result<()> {
+        let rules: Vec<AlertRule> = serde_json::from_str(
+            r#"[{"name": "lagging", "type": "finalizationLag", "maxLag": 10, "forMillis": 100},
+                {"name": "upgrade", "type": "versionShare", "version": "6.3", "minPercentage": 50}]"#,
+        )?;
+        let mut engine = AlertEngine::new(rules, 100);
+        let mut nodes = [node("a", "6.2.3", 100), node("b", "6.2.3", 100), node("c", "6.3.0", 70)];
+
+        // The version share fires immediately, the lag only after 100ms.
+        let alerts = evaluate(&mut engine, &nodes, 1000);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].rule.as_str(), alerts[0].subject.as_str()), ("upgrade", "network"));
+        assert!(evaluate(&mut engine, &nodes, 1050).is_empty());
+        let alerts = evaluate(&mut engine, &nodes, 1100);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].status, AlertStatus::Firing);
+        assert_eq!(alerts[0].subject, "c");
+        assert_eq!(alerts[0].node_name.as_deref(), Some("node c"));
+        assert!(evaluate(&mut engine, &nodes, 1200).is_empty());
+
+        nodes[2] = node("c", "6.3.0", 100);
+        let mut alerts = evaluate(&mut engine, &nodes, 1300);
+        alerts.sort_by(|a, b| a.rule.cmp(&b.rule));
+        let resolved: Vec<_> = alerts.iter().map(|a| (a.rule.as_str(), a.status)).collect();
+        assert_eq!(resolved, vec![("lagging", AlertStatus::Resolved)]);
+
+        // The alerts of removed nodes are resolved.
+        nodes[2] = node("c", "6.3.0", 70);
+        evaluate(&mut engine, &nodes, 1400);
+        assert_eq!(evaluate(&mut engine, &nodes, 1500).len(), 1);
+        let alerts = evaluate(&mut engine, &nodes[..2], 1600);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!((alerts[0].subject.as_str(), alerts[0].status), ("c", AlertStatus::Resolved));
+        assert!(engine.conditions.keys().all(|(_, subject)| subject != "c"));
+        Ok(())
+    }
+
+    #[test]
+    fn undelivered_alerts_are_raised_again() -> anyhow::Result<()> {
+        let rules: Vec<AlertRule> = serde_json::from_str(
+            r#"[{"name": "upgrade", "type": "versionShare", "version": "6.3", "minPercentage": 50}]"#,
+        )?;
+        let mut engine = AlertEngine::new(rules, 100);
+        let old = [node("a", "6.2.3", 100)];
+        let new = [node("a", "6.3.0", 100)];
+
+        assert_eq!(engine.evaluate(old.iter(), 1000).len(), 1);
+        let alerts = engine.evaluate(old.iter(), 1100);
+        assert_eq!(alerts.len(), 1, "The undelivered alert fires again");
+        engine.delivered(&alerts[0]);
+        assert!(engine.evaluate(old.iter(), 1200).is_empty());
+
+        let alerts = engine.evaluate(new.iter(), 1300);
+        assert_eq!(alerts[0].status, AlertStatus::Resolved);
+        let alerts = engine.evaluate(new.iter(), 1400);
+        assert_eq!(alerts.len(), 1, "The undelivered resolution is raised again");
+        assert_eq!(alerts[0].status, AlertStatus::Resolved);
+        // The condition holds again before the resolution is delivered, so the
+        // alert is still firing.
+        assert!(engine.evaluate(old.iter(), 1500).is_empty());
+        assert_eq!(engine.evaluate(new.iter(), 1600)[0].status, AlertStatus::Resolved);
+        Ok(())
+    }
+
+    #[test]
+    fn webhook_posts_alerts() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}/alerts", listener.local_addr()?);
+        let sink = std::thread::spawn(move || crate::http_stand_in::accept_request(listener));
+
+        let alert = Alert {
+            rule:       "upgrade".to_owned(),
+            status:     AlertStatus::Firing,
+            subject:    NETWORK_SUBJECT.to_owned(),
+            node_name:  None,
+            message:    "message".to_owned(),
+            timestamp:  1,
+            rule_index: 0,
+        };
+        Webhook::new(url)?.deliver(&alert)?;
+        let body: serde_json::Value =
+            serde_json::from_slice(&sink.join().expect("Sink panicked")?)?;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "rule": "upgrade",
+                "status": "firing",
+                "subject": "network",
+                "nodeName": null,
+                "message": "message",
+                "timestamp": 1,
+            })
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,16 @@
 use anyhow::anyhow;
-use collector_backend::{setup_logger, NodeInfo, NodeInfoChainViz, NodeInfoDashboard};
+use collector_backend::{
+    alerts::{read_rules, AlertEngine, Webhook},
+    average_without_outer_values,
+    history::{HistoryLimits, HistoryStore, NodeSnapshot},
+    live::{LiveFeed, LiveFeedQuery, LiveUpdate},
+    metrics::{self, MetricsOptions, NodeLabel},
+    setup_logger,
+    signatures::{parse_public_key, KeyBindings, PublicKey, ReplayGuard, SignaturePolicy},
+    stream::{DeltaDecoder, NodeUpdate, StreamAck, StreamFrame},
+    NodeInfo, NodeInfoChainViz, NodeInfoDashboard, PUBLIC_KEY_HEADER,
+};
+use futures::{SinkExt, StreamExt};
 use gotham::{
     anyhow::*,
     handler::{HandlerError, IntoResponse},
@@ -10,19 +21,34 @@ use gotham::{
     state::{FromState, State},
 };
 use gotham_derive::*;
-use hyper::{body::HttpBody, Body, Response, StatusCode};
-use log::{info, trace, warn};
+use hyper::{
+    body::HttpBody,
+    header,
+    upgrade::{OnUpgrade, Upgraded},
+    Body, HeaderMap, Response, StatusCode,
+};
+use log::{debug, info, trace, warn};
+use serde::Deserialize;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
     hash::BuildHasherDefault,
     io::Cursor,
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
     thread,
     time::Duration,
 };
 use structopt::StructOpt;
+use tokio::sync::broadcast;
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{Role, WebSocketConfig},
+        Message,
+    },
+    WebSocketStream,
+};
 use twox_hash::XxHash64;
 
 // Force the system allocator on every platform
@@ -102,6 +128,22 @@ struct ConfigCli {
     pub banned_node_names_file: Option<PathBuf>,
     #[structopt(flatten)]
     validation_config:          ValidationConfig,
+    #[structopt(flatten)]
+    signature_config:           SignatureConfig,
+    #[structopt(flatten)]
+    history_config:             HistoryConfig,
+    #[structopt(flatten)]
+    alert_config:               AlertConfig,
+    #[structopt(flatten)]
+    metrics_config:             MetricsConfig,
+    #[structopt(
+        long = "live-feed-capacity",
+        help = "Number of updates buffered for each subscriber of the live feed. Subscribers that \
+                fall further behind receive the current data of all nodes again.",
+        default_value = "1024",
+        env = "COLLECTOR_BACKEND_LIVE_FEED_CAPACITY"
+    )]
+    pub live_feed_capacity:     usize,
 }
 
 #[derive(Debug, Clone, StateData, StructOpt)]
@@ -168,6 +210,166 @@ pub struct ValidationConfig {
     pub valid_additional_finalized_block_height: u64,
 }
 
+#[derive(Debug, StructOpt)]
+pub struct SignatureConfig {
+    #[structopt(
+        long = "require-signatures",
+        help = "Reject submissions that are not signed by the collector. Signed submissions are \
+                always verified.",
+        env = "COLLECTOR_BACKEND_REQUIRE_SIGNATURES"
+    )]
+    pub require_signatures:       bool,
+    #[structopt(
+        long = "allowed-public-keys-file",
+        help = "Path to file containing the hex encoded public keys of the collectors that are \
+                allowed to publish to the collector backend, separated by line breaks. A key can \
+                be followed by the node ids it may publish data for, separated by whitespace. \
+                Implies --require-signatures.",
+        env = "COLLECTOR_BACKEND_ALLOWED_PUBLIC_KEYS_FILE"
+    )]
+    pub allowed_public_keys_file: Option<PathBuf>,
+    #[structopt(
+        long = "key-bindings-file",
+        help = "Path to the file storing the public key each node id is bound to. If not given, \
+                the bindings are lost when the collector backend restarts.",
+        env = "COLLECTOR_BACKEND_KEY_BINDINGS_FILE"
+    )]
+    pub key_bindings_file:        Option<PathBuf>,
+    #[structopt(
+        long = "max-signature-age",
+        help = "Maximum difference in ms between the time a submission was signed and the time it \
+                is received",
+        default_value = "60000",
+        env = "COLLECTOR_BACKEND_MAX_SIGNATURE_AGE"
+    )]
+    pub max_signature_age:        u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct HistoryConfig {
+    #[structopt(
+        long = "history-file",
+        help = "Path to the file storing the history of the nodes. If not given, no history is \
+                kept.",
+        env = "COLLECTOR_BACKEND_HISTORY_FILE"
+    )]
+    pub history_file:                   Option<PathBuf>,
+    #[structopt(
+        long = "history-snapshot-interval",
+        help = "Minimum time in ms between two snapshots of the same node in the history",
+        default_value = "60000",
+        env = "COLLECTOR_BACKEND_HISTORY_SNAPSHOT_INTERVAL"
+    )]
+    pub history_snapshot_interval:      u64,
+    #[structopt(
+        long = "history-retention",
+        help = "Time in ms snapshots are kept in the history",
+        default_value = "86400000",
+        env = "COLLECTOR_BACKEND_HISTORY_RETENTION"
+    )]
+    pub history_retention:              u64,
+    #[structopt(
+        long = "history-max-snapshots-per-node",
+        help = "Maximum number of snapshots kept in the history for each node",
+        default_value = "1440",
+        env = "COLLECTOR_BACKEND_HISTORY_MAX_SNAPSHOTS_PER_NODE"
+    )]
+    pub history_max_snapshots_per_node: usize,
+    #[structopt(
+        long = "history-max-nodes",
+        help = "Maximum number of nodes kept in the history. When exceeded, the history of the \
+                node that was updated least recently is dropped.",
+        default_value = "5000",
+        env = "COLLECTOR_BACKEND_HISTORY_MAX_NODES"
+    )]
+    pub history_max_nodes:              usize,
+    #[structopt(
+        long = "history-compaction-threshold",
+        help = "Number of expired or dropped snapshots in the history file before it is rewritten \
+                without them",
+        default_value = "100000",
+        env = "COLLECTOR_BACKEND_HISTORY_COMPACTION_THRESHOLD"
+    )]
+    pub history_compaction_threshold:   usize,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AlertConfig {
+    #[structopt(
+        long = "alert-rules-file",
+        help = "Path to a JSON file with the rules for raising alerts, evaluated on every \
+                cleanup. If not given, no alerts are raised.",
+        env = "COLLECTOR_BACKEND_ALERT_RULES_FILE",
+        requires = "alert-webhook-url"
+    )]
+    pub alert_rules_file:  Option<PathBuf>,
+    #[structopt(
+        long = "alert-webhook-url",
+        help = "URL alerts are posted to as JSON",
+        env = "COLLECTOR_BACKEND_ALERT_WEBHOOK_URL"
+    )]
+    pub alert_webhook_url: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MetricsConfig {
+    #[structopt(
+        long = "metrics-node-labels",
+        help = "Labels identifying the nodes in the per-node metrics served on /metrics. Possible \
+                values are nodeId, nodeName and client, and nodeId must be included.",
+        default_value = "nodeId,nodeName",
+        env = "COLLECTOR_BACKEND_METRICS_NODE_LABELS",
+        use_delimiter = true
+    )]
+    pub metrics_node_labels:         Vec<NodeLabel>,
+    #[structopt(
+        long = "no-per-node-metrics",
+        help = "Only serve aggregated metrics on /metrics, and no metrics for each node",
+        env = "COLLECTOR_BACKEND_NO_PER_NODE_METRICS"
+    )]
+    pub no_per_node_metrics:         bool,
+    #[structopt(
+        long = "metrics-max-client-versions",
+        help = "Maximum number of versions the node count is served for on /metrics. Nodes \
+                running other versions are counted as 'other'.",
+        default_value = "20",
+        env = "COLLECTOR_BACKEND_METRICS_MAX_CLIENT_VERSIONS"
+    )]
+    pub metrics_max_client_versions: usize,
+}
+
+impl SignatureConfig {
+    /// The rules for the signatures of submissions.
+    fn policy(&self) -> anyhow::Result<SignaturePolicy> {
+        let conf = self;
+        let allowed_keys = if let Some(file) = &conf.allowed_public_keys_file {
+            let mut keys = HashMap::new();
+            for line in fs::read_to_string(file)?.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.split_whitespace();
+                let key = fields.next().unwrap_or_default();
+                let public_key = parse_public_key(key).with_context(|| {
+                    format!("Invalid public key '{}' in {}", key, file.display())
+                })?;
+                keys.entry(public_key)
+                    .or_insert_with(HashSet::new)
+                    .extend(fields.map(String::from));
+            }
+            info!("Accepting submissions signed by {} registered keys", keys.len());
+            Some(keys)
+        } else {
+            None
+        };
+        Ok(SignaturePolicy {
+            required: conf.require_signatures || allowed_keys.is_some(),
+            allowed_keys,
+            max_signature_age: conf.max_signature_age,
+        })
+    }
+}
+
 pub struct HTMLStringResponse(pub String);
 
 impl IntoResponse for HTMLStringResponse {
@@ -188,6 +390,16 @@ impl IntoResponse for JSONStringResponse {
 
 type NodeId = String;
 
+/// The history of the nodes, if enabled.
+type History = Option<Arc<Mutex<HistoryStore>>>;
+
+/// The time of signing of the latest accepted submission for each public key
+/// and NodeId, used to reject replays.
+type LastSubmissionMap = Arc<Mutex<ReplayGuard>>;
+
+/// The public key each NodeId is bound to.
+type KeyBindingMap = Arc<Mutex<KeyBindings>>;
+
 /// Map from a NodeId to the most recent valid NodeInfo.
 type NodeInfoMap = Arc<RwLock<HashMap<NodeId, NodeInfo, BuildHasherDefault<XxHash64>>>>;
 
@@ -196,18 +408,37 @@ struct CollectorStateData {
     pub nodes:             NodeInfoMap,
     pub banned_versions:   Vec<String>,
     pub banned_node_names: Vec<String>,
+    signature_policy:      Arc<SignaturePolicy>,
+    last_submissions:      LastSubmissionMap,
+    key_bindings:          KeyBindingMap,
+    history:               History,
+    metrics_options:       Arc<MetricsOptions>,
+    live_feed:             LiveFeed,
 }
 
 impl CollectorStateData {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         nodes: NodeInfoMap,
         banned_versions: Vec<String>,
         banned_node_names: Vec<String>,
+        signature_policy: SignaturePolicy,
+        last_submissions: LastSubmissionMap,
+        key_bindings: KeyBindings,
+        history: History,
+        metrics_options: MetricsOptions,
+        live_feed: LiveFeed,
     ) -> Self {
         Self {
             nodes,
             banned_versions,
             banned_node_names,
+            signature_policy: Arc::new(signature_policy),
+            last_submissions,
+            key_bindings: Arc::new(Mutex::new(key_bindings)),
+            history,
+            metrics_options: Arc::new(metrics_options),
+            live_feed,
         }
     }
 }
@@ -229,12 +460,55 @@ pub fn main() -> anyhow::Result<()> {
         vec![]
     };
 
+    let signature_policy = conf.signature_config.policy()?;
+
     let node_info_map: NodeInfoMap =
         Arc::new(RwLock::new(HashMap::with_capacity_and_hasher(1500, Default::default())));
+    let last_submissions: LastSubmissionMap = Default::default();
+    let key_bindings = if let Some(file) = &conf.signature_config.key_bindings_file {
+        let key_bindings = KeyBindings::open(file)?;
+        info!("Loaded {} key bindings from {}", key_bindings.len(), file.display());
+        key_bindings
+    } else {
+        KeyBindings::default()
+    };
+
+    let history: History = if let Some(file) = &conf.history_config.history_file {
+        let limits = HistoryLimits {
+            snapshot_interval:      conf.history_config.history_snapshot_interval,
+            retention:              conf.history_config.history_retention,
+            max_snapshots_per_node: conf.history_config.history_max_snapshots_per_node,
+            max_nodes:              conf.history_config.history_max_nodes,
+            compaction_threshold:   conf.history_config.history_compaction_threshold,
+        };
+        let now = chrono::Utc::now().timestamp_millis() as u64;
+        info!("Keeping the history of the nodes in {}", file.display());
+        Some(Arc::new(Mutex::new(HistoryStore::open(file, limits, now)?)))
+    } else {
+        None
+    };
+
+    let alerting = match (&conf.alert_config.alert_rules_file, conf.alert_config.alert_webhook_url)
+    {
+        (Some(file), Some(url)) => {
+            let rules = read_rules(file)?;
+            info!("Evaluating {} alert rules, delivering alerts to {}", rules.len(), url);
+            let engine =
+                AlertEngine::new(rules, conf.validation_config.percentage_used_for_averages);
+            Some((engine, Webhook::new(url)?))
+        }
+        _ => None,
+    };
 
     let _allowed_stale_time = conf.stale_time_allowed;
     let _node_info_map_clone = Arc::clone(&node_info_map);
     let _cleanup_interval = conf.cleanup_interval;
+    let _last_submissions_clone = Arc::clone(&last_submissions);
+    let _max_signature_age = signature_policy.max_signature_age;
+    let _history_clone = history.clone();
+    let live_feed = LiveFeed::new(conf.live_feed_capacity);
+    let _live_feed_clone = live_feed.clone();
+    let mut _alerting = alerting;
     #[allow(unreachable_code)] // the loop never breaks on its own
     let _ = std::thread::Builder::new()
         .name("collector backend cleanup".into())
@@ -242,19 +516,77 @@ pub fn main() -> anyhow::Result<()> {
             thread::sleep(Duration::from_millis(_cleanup_interval));
             info!("Running cleanup");
             let current_stamp = chrono::Utc::now().timestamp_millis() as u64;
-            _node_info_map_clone
-                .write()
-                .expect("RWLock poisoned")
-                .retain(|_, element| current_stamp < element.last_updated + _allowed_stale_time);
+            let mut removed = Vec::new();
+            _node_info_map_clone.write().expect("RWLock poisoned").retain(|_, element| {
+                let keep = current_stamp < element.last_updated + _allowed_stale_time;
+                if !keep && _live_feed_clone.has_subscribers() {
+                    removed.push(LiveUpdate::removed(element));
+                }
+                keep
+            });
+            for update in removed {
+                _live_feed_clone.publish(update);
+            }
+            // Submissions signed before this are rejected anyway, so there is no need to
+            // remember them for detecting replays.
+            _last_submissions_clone
+                .lock()
+                .expect("Mutex poisoned")
+                .prune(current_stamp, _max_signature_age);
+            if let Some(history) = &_history_clone {
+                if let Err(e) = HistoryStore::compact(history, current_stamp) {
+                    warn!("Could not prune the history: {}", e);
+                }
+            }
+            if let Some((engine, webhook)) = &mut _alerting {
+                // Only hold the lock while evaluating, not while delivering the alerts.
+                let alerts = engine.evaluate(
+                    _node_info_map_clone.read().expect("RWLock poisoned").values(),
+                    current_stamp,
+                );
+                for alert in alerts {
+                    info!("Alert {} is {:?} for {}", alert.rule, alert.status, alert.subject);
+                    if let Err(e) = webhook.deliver(&alert) {
+                        warn!(
+                            "Could not deliver alert {}, retrying on the next cleanup: {}",
+                            alert.rule, e
+                        );
+                    } else {
+                        engine.delivered(&alert);
+                    }
+                }
+            }
         })
         .expect("The OS refused to create a new thread");
 
+    let metrics_options = MetricsOptions::new(
+        if conf.metrics_config.no_per_node_metrics {
+            Vec::new()
+        } else {
+            conf.metrics_config.metrics_node_labels
+        },
+        conf.metrics_config.metrics_max_client_versions,
+    )?;
+
     let addr = format!("{}:{}", conf.host, conf.port);
     info!("Listening for requests at http://{}", addr);
 
     gotham::start(
         addr,
-        router(node_info_map, conf.banned_versions, banned_node_names, conf.validation_config),
+        router(
+            CollectorStateData::new(
+                node_info_map,
+                conf.banned_versions,
+                banned_node_names,
+                signature_policy,
+                last_submissions,
+                key_bindings,
+                history,
+                metrics_options,
+                live_feed,
+            ),
+            conf.validation_config,
+        ),
     )?;
     Ok(())
 }
@@ -305,6 +637,68 @@ fn nodes_block_info(state: State) -> (State, JSONStringResponse) {
     (state, JSONStringResponse(String::from_utf8(response).unwrap()))
 }
 
+fn nodes_metrics(state: State) -> (State, Response<Body>) {
+    trace!("Processing a metrics request");
+    let state_data = CollectorStateData::borrow_from(&state);
+    let response = metrics::render(
+        state_data.nodes.read().expect("RWLock poisoned").values(),
+        &state_data.metrics_options,
+    )
+    .map(|body| {
+        let content_type: mime::Mime =
+            "text/plain; version=0.0.4".parse().expect("Valid mime type");
+        create_response(&state, StatusCode::OK, content_type, body)
+    })
+    .unwrap_or_else(|e| {
+        warn!("Could not render the metrics: {}", e);
+        create_empty_response(&state, StatusCode::INTERNAL_SERVER_ERROR)
+    });
+    (state, response)
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct NodeIdPath {
+    id: NodeId,
+}
+
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+struct HistoryQuery {
+    /// Start of the time range (Unix time in milliseconds). Defaults to a day
+    /// before `to`.
+    from: Option<u64>,
+    /// End of the time range (Unix time in milliseconds). Defaults to now.
+    to:   Option<u64>,
+}
+
+fn node_history(mut state: State) -> (State, Response<Body>) {
+    trace!("Processing a node history request");
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+    let NodeIdPath {
+        id,
+    } = NodeIdPath::take_from(&mut state);
+    let HistoryQuery {
+        from,
+        to,
+    } = HistoryQuery::take_from(&mut state);
+    let to = to.unwrap_or_else(|| chrono::Utc::now().timestamp_millis() as u64);
+    let from = from.unwrap_or_else(|| to.saturating_sub(DAY));
+    let state_data = CollectorStateData::borrow_from(&state);
+    let response = match &state_data.history {
+        Some(history) => {
+            let history = history.lock().expect("Mutex poisoned");
+            match history.range(&id, from, to) {
+                Some(snapshots) => JSONStringResponse(
+                    serde_json::to_string(&snapshots).expect("Snapshots are serializable"),
+                )
+                .into_response(&state),
+                None => create_empty_response(&state, StatusCode::NOT_FOUND),
+            }
+        }
+        None => create_empty_response(&state, StatusCode::NOT_FOUND),
+    };
+    (state, response)
+}
+
 async fn nodes_post_handler_wrapper(state: &mut State) -> Result<Response<Body>, HandlerError> {
     nodes_post_handler(state).await.map_err(|e| {
         warn!("Bad request: {}", e);
@@ -336,9 +730,28 @@ async fn nodes_post_handler(state: &mut State) -> gotham::anyhow::Result<Respons
         content
     };
 
-    let mut nodes_info: NodeInfo = rmp_serde::decode::from_read(Cursor::new(&body_content))
+    let state_data = CollectorStateData::borrow_from(state);
+    let signed = state_data.signature_policy.verify_headers(
+        HeaderMap::borrow_from(state),
+        &body_content,
+        chrono::Utc::now().timestamp_millis() as u64,
+    )?;
+
+    let nodes_info: NodeInfo = rmp_serde::decode::from_read(Cursor::new(&body_content))
         .context("Can't parse client data")?;
+    accept_node_info(state_data, &validation_conf, nodes_info, signed)?;
+
+    Ok(create_empty_response(state, StatusCode::OK))
+}
 
+/// Validate the data of a node and store it if it is valid. `signed` is the
+/// public key of the signer and the time of signing if the data is signed.
+fn accept_node_info(
+    state_data: &CollectorStateData,
+    validation_conf: &ValidationConfig,
+    mut nodes_info: NodeInfo,
+    signed: Option<(PublicKey, u64)>,
+) -> anyhow::Result<()> {
     ensure!(!nodes_info.nodeName.is_empty(), "nodeName cannot be empty");
     ensure!(
         nodes_info.nodeName.lines().count() == 1,
@@ -359,7 +772,6 @@ async fn nodes_post_handler(state: &mut State) -> gotham::anyhow::Result<Respons
         nodes_info.peersCount <= validation_conf.valid_node_peers_count,
         "peersCount is too high to be considered valid"
     );
-    let state_data = CollectorStateData::borrow_from(state);
     ensure!(!state_data.banned_versions.contains(&nodes_info.client), "node version is banned");
     ensure!(
         !state_data.banned_node_names.contains(&nodes_info.nodeName.trim().to_string()),
@@ -400,22 +812,277 @@ async fn nodes_post_handler(state: &mut State) -> gotham::anyhow::Result<Respons
     }
 
     nodes_info.last_updated = chrono::Utc::now().timestamp_millis() as u64;
+    nodes_info.public_key = signed.map(|(public_key, _)| public_key);
+    let mut nodes = state_data.nodes.write().expect("RWLock poisoned");
+    if let Some(public_key) = nodes_info.public_key {
+        state_data.signature_policy.check_node_id(public_key, &nodes_info.nodeId)?;
+    }
+    // A node that has published signed data can only be updated with data signed by
+    // the same key.
     state_data
-        .nodes
-        .write()
-        .expect("RWLock poisoned")
-        .insert(nodes_info.nodeId.clone(), nodes_info);
+        .key_bindings
+        .lock()
+        .expect("Mutex poisoned")
+        .check(&nodes_info.nodeId, nodes_info.public_key)?;
+    if let Some((public_key, timestamp)) = signed {
+        state_data.last_submissions.lock().expect("Mutex poisoned").check(
+            public_key,
+            &nodes_info.nodeId,
+            timestamp,
+        )?;
+    }
+    let snapshot = state_data.history.as_ref().map(|_| {
+        let network_finalized_height = nodes
+            .values()
+            .map(|n| n.finalizedBlockHeight)
+            .chain(std::iter::once(nodes_info.finalizedBlockHeight))
+            .max()
+            .unwrap_or_default();
+        NodeSnapshot::new(&nodes_info, nodes_info.last_updated, network_finalized_height)
+    });
+    // Only encode the update if someone is subscribed to the live feed.
+    let live_update =
+        state_data.live_feed.has_subscribers().then(|| LiveUpdate::updated(&nodes_info));
+    nodes.insert(nodes_info.nodeId.clone(), nodes_info);
+    drop(nodes);
 
-    Ok(create_empty_response(state, StatusCode::OK))
+    if let Some(update) = live_update {
+        state_data.live_feed.publish(update);
+    }
+
+    if let (Some(history), Some(snapshot)) = (&state_data.history, snapshot) {
+        if let Err(e) = history.lock().expect("Mutex poisoned").record(snapshot) {
+            warn!("Could not record the history of a node: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Handle a WebSocket handshake for streaming the data of nodes from a
+/// collector, see [collector_backend::stream].
+fn nodes_stream_handler(mut state: State) -> (State, Response<Body>) {
+    trace!("Processing a stream request from a node-collector");
+    let response = start_node_stream(&mut state).unwrap_or_else(|e| {
+        warn!("Bad request: {}", e);
+        create_empty_response(&state, StatusCode::BAD_REQUEST)
+    });
+    (state, response)
 }
 
-pub fn router(
-    node_info_map: NodeInfoMap,
-    banned_versions: Vec<String>,
-    banned_node_names: Vec<String>,
-    validation_config: ValidationConfig,
-) -> Router {
-    let state_data = CollectorStateData::new(node_info_map, banned_versions, banned_node_names);
+/// Accept the WebSocket handshake and spawn a task handling the stream.
+fn start_node_stream(state: &mut State) -> anyhow::Result<Response<Body>> {
+    let headers = HeaderMap::take_from(state);
+    let (response, on_upgrade) = accept_websocket(&headers, OnUpgrade::try_take_from(state))?;
+    let public_key = headers
+        .get(PUBLIC_KEY_HEADER)
+        .map(|value| parse_public_key(value.to_str()?).context("Invalid public key"))
+        .transpose()?;
+    let state_data = CollectorStateData::borrow_from(state).clone();
+    let validation_conf = ValidationConfig::borrow_from(state).clone();
+    let config = WebSocketConfig {
+        max_message_size: Some(validation_conf.valid_content_length as usize),
+        ..Default::default()
+    };
+    tokio::spawn(async move {
+        let result = async {
+            let upgraded = on_upgrade.await?;
+            let socket =
+                WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(config)).await;
+            handle_node_stream(socket, &state_data, &validation_conf, public_key).await
+        };
+        if let Err(e) = result.await {
+            warn!("Stream from a node-collector failed: {}", e);
+        }
+    });
+    Ok(response)
+}
+
+/// Handle a WebSocket handshake for the live feed of node updates.
+fn nodes_live_handler(mut state: State) -> (State, Response<Body>) {
+    trace!("Processing a live feed request");
+    let response = start_live_feed(&mut state).unwrap_or_else(|e| {
+        warn!("Bad request: {}", e);
+        create_empty_response(&state, StatusCode::BAD_REQUEST)
+    });
+    (state, response)
+}
+
+/// Accept the WebSocket handshake and spawn a task sending the live feed.
+fn start_live_feed(state: &mut State) -> anyhow::Result<Response<Body>> {
+    let query = LiveFeedQuery::take_from(state);
+    let headers = HeaderMap::take_from(state);
+    let (response, on_upgrade) = accept_websocket(&headers, OnUpgrade::try_take_from(state))?;
+    let state_data = CollectorStateData::borrow_from(state).clone();
+    // Subscribe before the handshake completes, so no updates are missed between
+    // sending the current data and the updates.
+    let receiver = state_data.live_feed.subscribe();
+    tokio::spawn(async move {
+        let result = async {
+            let upgraded = on_upgrade.await?;
+            let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+            send_live_feed(socket, &state_data.nodes, receiver, &query).await
+        };
+        if let Err(e) = result.await {
+            debug!("Live feed subscriber left: {}", e);
+        }
+    });
+    Ok(response)
+}
+
+/// Send the current data of the nodes matching the query, followed by their
+/// updates as they are accepted and their removals, see
+/// [collector_backend::live].
+async fn send_live_feed(
+    mut socket: WebSocketStream<Upgraded>,
+    nodes: &NodeInfoMap,
+    mut receiver: broadcast::Receiver<Arc<LiveUpdate>>,
+    query: &LiveFeedQuery,
+) -> anyhow::Result<()> {
+    let current_nodes = |nodes: &NodeInfoMap| -> Vec<String> {
+        nodes
+            .read()
+            .expect("RWLock poisoned")
+            .values()
+            .filter(|node| query.matches(&node.nodeName, &node.client))
+            .map(|node| LiveUpdate::updated(node).json)
+            .collect()
+    };
+    for json in current_nodes(nodes) {
+        socket.send(Message::Text(json)).await?;
+    }
+    loop {
+        tokio::select! {
+            update = receiver.recv() => match update {
+                Result::Ok(update) => {
+                    if query.matches_update(&update) {
+                        socket.send(Message::Text(update.json.clone())).await?;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!("Live feed subscriber skipped {} updates", skipped);
+                    for json in current_nodes(nodes) {
+                        socket.send(Message::Text(json)).await?;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = socket.next() => match message {
+                Some(Result::Ok(Message::Close(_))) | None => break,
+                // Pings are answered by the WebSocket implementation.
+                Some(Result::Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Check the headers of a WebSocket handshake. Returns the response accepting
+/// the handshake and the upgraded connection.
+fn accept_websocket(
+    headers: &HeaderMap,
+    on_upgrade: Option<OnUpgrade>,
+) -> anyhow::Result<(Response<Body>, OnUpgrade)> {
+    let header_contains = |name: header::HeaderName, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|part| part.trim().eq_ignore_ascii_case(token))
+    };
+    ensure!(
+        header_contains(header::CONNECTION, "upgrade")
+            && header_contains(header::UPGRADE, "websocket"),
+        "Expected a WebSocket handshake"
+    );
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .ok_or_else(|| anyhow!("Header 'Sec-WebSocket-Key' is required"))?;
+    let on_upgrade = on_upgrade.ok_or_else(|| anyhow!("Connection cannot be upgraded"))?;
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()))
+        .body(Body::empty())?;
+    Ok((response, on_upgrade))
+}
+
+/// Receive the updates of nodes streamed by a collector, and answer each of
+/// them with a [StreamAck] once it is processed.
+async fn handle_node_stream(
+    mut socket: WebSocketStream<Upgraded>,
+    state_data: &CollectorStateData,
+    validation_conf: &ValidationConfig,
+    public_key: Option<PublicKey>,
+) -> anyhow::Result<()> {
+    let mut decoder = DeltaDecoder::default();
+    while let Some(message) = socket.next().await {
+        let frame = match message? {
+            Message::Binary(frame) => frame,
+            Message::Close(_) => break,
+            // Pings are answered by the WebSocket implementation.
+            _ => continue,
+        };
+        let ack = if let Err(e) =
+            process_stream_frame(&frame, &mut decoder, state_data, validation_conf, public_key)
+        {
+            warn!("Bad stream update: {}", e);
+            StreamAck::Rejected(e.to_string())
+        } else {
+            StreamAck::Accepted
+        };
+        socket.send(Message::Binary(rmp_serde::to_vec(&ack)?)).await?;
+    }
+    Ok(())
+}
+
+/// Verify, decode and store an update streamed by a collector.
+fn process_stream_frame(
+    frame: &[u8],
+    decoder: &mut DeltaDecoder,
+    state_data: &CollectorStateData,
+    validation_conf: &ValidationConfig,
+    public_key: Option<PublicKey>,
+) -> anyhow::Result<()> {
+    let frame: StreamFrame = rmp_serde::from_slice(frame).context("Can't parse stream frame")?;
+    let signed = match (public_key, frame.timestamp, frame.signature) {
+        (None, None, None) => {
+            ensure!(!state_data.signature_policy.required, "Submission must be signed");
+            None
+        }
+        (Some(public_key), Some(timestamp), Some(signature)) => {
+            Some(state_data.signature_policy.verify(
+                public_key,
+                timestamp,
+                &signature,
+                &frame.update,
+                chrono::Utc::now().timestamp_millis() as u64,
+            )?)
+        }
+        _ => bail!("Signed updates require a public key, a timestamp and a signature"),
+    };
+    let update: NodeUpdate =
+        rmp_serde::from_slice(&frame.update).context("Can't parse client data")?;
+    let node_id = match &update {
+        NodeUpdate::Full(node_info) => node_info.nodeId.clone(),
+        NodeUpdate::Delta {
+            node_id,
+            ..
+        } => node_id.clone(),
+    };
+    let result = decoder
+        .decode(update)
+        .and_then(|node_info| accept_node_info(state_data, validation_conf, node_info, signed));
+    if result.is_err() {
+        // The collector sends the complete data of the node after a rejected update.
+        decoder.reset(&node_id);
+    }
+    result
+}
+
+fn router(state_data: CollectorStateData, validation_config: ValidationConfig) -> Router {
     let validation_config_middleware = StateMiddleware::new(validation_config);
     let collector_state_middleware = StateMiddleware::new(state_data);
     let (chain, pipelines) = single_pipeline(
@@ -427,20 +1094,27 @@ pub fn router(
         route.get("/data/nodesSummary").to(nodes_summary);
         route.get("/nodesBlocksInfo").to(nodes_block_info);
         route.get("/data/nodesBlocksInfo").to(nodes_block_info);
+        route.get("/metrics").to(nodes_metrics);
+        route
+            .get("/nodes/:id/history")
+            .with_path_extractor::<NodeIdPath>()
+            .with_query_string_extractor::<HistoryQuery>()
+            .to(node_history);
+        route
+            .get("/data/nodes/:id/history")
+            .with_path_extractor::<NodeIdPath>()
+            .with_query_string_extractor::<HistoryQuery>()
+            .to(node_history);
         route.post("/nodes/post").to_async_borrowing(nodes_post_handler_wrapper);
         route.post("/post/nodes").to_async_borrowing(nodes_post_handler_wrapper);
+        route.get("/stream/nodes").to(nodes_stream_handler);
+        route
+            .get("/nodes/live")
+            .with_query_string_extractor::<LiveFeedQuery>()
+            .to(nodes_live_handler);
+        route
+            .get("/data/nodes/live")
+            .with_query_string_extractor::<LiveFeedQuery>()
+            .to(nodes_live_handler);
     })
 }
-
-/// Calculates the average, but only from a part of the values. The omitted are
-/// taken from the highest and lowest values.
-fn average_without_outer_values(mut values: Vec<u64>, number_to_include: usize) -> u64 {
-    // the unstable sort is faster and stability makes no difference for sorting
-    // sequences of u64.
-    values.sort_unstable();
-    let omitting = values.len() - number_to_include;
-    let start = omitting / 2;
-    let end = start + number_to_include;
-
-    values[start..end].iter().sum::<u64>() / number_to_include as u64
-}
@@ -0,0 +1,474 @@
+//! Storage of the history of the nodes publishing to the collector backend.
+//!
+//! Periodic snapshots of each node are appended to a file with one JSON
+//! encoded [NodeSnapshot] per line, so the history survives restarts of the
+//! collector backend. A bounded number of recent snapshots of a bounded number
+//! of nodes is kept in memory for answering queries. Once enough snapshots in
+//! the file are expired or dropped from memory, they are removed from the file
+//! by [HistoryStore::compact], which rewrites the file without blocking the
+//! recording of new snapshots.
+
+use crate::{IsInBakingCommittee, NodeInfo};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// A snapshot of the state of a node at a point in time.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NodeSnapshot {
+    /// The time the snapshot was taken (Unix time in milliseconds).
+    pub timestamp: u64,
+    pub nodeId: String,
+    pub nodeName: String,
+    pub client: String,
+    pub uptime: u64,
+    pub peersCount: u64,
+    pub averagePing: Option<f64>,
+    pub bestBlockHeight: u64,
+    pub finalizedBlockHeight: u64,
+    /// The number of blocks the last finalized block of the node is behind the
+    /// highest last finalized block reported by the nodes at the time of the
+    /// snapshot.
+    pub finalizationLag: u64,
+    pub averageBytesPerSecondIn: u64,
+    pub averageBytesPerSecondOut: u64,
+    pub consensusRunning: bool,
+    pub bakingCommitteeMember: IsInBakingCommittee,
+    pub finalizationCommitteeMember: bool,
+}
+
+impl NodeSnapshot {
+    /// Take a snapshot of the node at time `timestamp`, given the highest last
+    /// finalized block height of the network.
+    pub fn new(node: &NodeInfo, timestamp: u64, network_finalized_height: u64) -> Self {
+        Self {
+            timestamp,
+            nodeId: node.nodeId.clone(),
+            nodeName: node.nodeName.clone(),
+            client: node.client.clone(),
+            uptime: node.uptime,
+            peersCount: node.peersCount,
+            averagePing: node.averagePing,
+            bestBlockHeight: node.bestBlockHeight,
+            finalizedBlockHeight: node.finalizedBlockHeight,
+            finalizationLag: network_finalized_height.saturating_sub(node.finalizedBlockHeight),
+            averageBytesPerSecondIn: node.averageBytesPerSecondIn,
+            averageBytesPerSecondOut: node.averageBytesPerSecondOut,
+            consensusRunning: node.consensusRunning,
+            bakingCommitteeMember: node.bakingCommitteeMember,
+            finalizationCommitteeMember: node.finalizationCommitteeMember,
+        }
+    }
+}
+
+/// The limits on the snapshots kept by a [HistoryStore].
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryLimits {
+    /// Minimum time in milliseconds between two snapshots of the same node.
+    pub snapshot_interval:      u64,
+    /// Time in milliseconds snapshots are kept for.
+    pub retention:              u64,
+    /// Maximum number of snapshots kept per node.
+    pub max_snapshots_per_node: usize,
+    /// Maximum number of nodes snapshots are kept for. Node ids are chosen by
+    /// the collectors, so this bounds the size of the history. If a snapshot
+    /// of a new node is recorded when the maximum is reached, the snapshots of
+    /// the node that was updated least recently are dropped.
+    pub max_nodes:              usize,
+    /// Minimum number of snapshots in the file that are expired or dropped
+    /// from memory before the file is compacted.
+    pub compaction_threshold:   usize,
+}
+
+/// Call `f` on each valid snapshot in the first `len` bytes of the history
+/// file at `path`. Invalid lines are logged and skipped.
+fn read_snapshots(
+    path: &Path,
+    len: u64,
+    mut f: impl FnMut(NodeSnapshot) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let reader = BufReader::new(File::open(path)?.take(len));
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<NodeSnapshot>(&line) {
+            Ok(snapshot) => f(snapshot)?,
+            // A partially written last line is expected if the backend was
+            // stopped while appending.
+            Err(e) => log::warn!(
+                "Skipping invalid snapshot on line {} of {}: {}",
+                number + 1,
+                path.display(),
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// The snapshots of all nodes, backed by an append-only file.
+pub struct HistoryStore {
+    /// Path of the file the snapshots are stored in.
+    path:           PathBuf,
+    /// The file opened for appending.
+    file:           BufWriter<File>,
+    limits:         HistoryLimits,
+    /// The most recent snapshots of each node, ordered by time. At most
+    /// `limits.max_snapshots_per_node` snapshots are kept for each of at most
+    /// `limits.max_nodes` nodes.
+    nodes:          HashMap<String, VecDeque<NodeSnapshot>>,
+    /// The number of snapshots kept in memory.
+    snapshots:      usize,
+    /// The number of snapshots in the file, including the ones that are not
+    /// kept in memory anymore.
+    file_snapshots: usize,
+}
+
+/// A compaction of the history file in progress, see [HistoryStore::compact].
+struct Compaction {
+    path:           PathBuf,
+    tmp_path:       PathBuf,
+    /// The length of the file when the compaction started. Snapshots appended
+    /// after this are copied as they are when the compaction finishes.
+    len:            u64,
+    /// The number of snapshots in the file when the compaction started.
+    file_snapshots: usize,
+    /// The time of the oldest snapshot kept in memory for each node when the
+    /// compaction started. Older snapshots and the snapshots of other nodes
+    /// are removed from the file.
+    kept_from:      HashMap<String, u64>,
+}
+
+impl Compaction {
+    /// Write the snapshots that are kept in memory to the temporary file. This
+    /// only reads the part of the history file that is not appended to
+    /// anymore, so it does not need access to the store. Returns the number of
+    /// snapshots written.
+    fn run(&self) -> anyhow::Result<usize> {
+        let mut writer = BufWriter::new(File::create(&self.tmp_path)?);
+        let mut written = 0;
+        read_snapshots(&self.path, self.len, |snapshot| {
+            if matches!(self.kept_from.get(&snapshot.nodeId), Some(&from) if snapshot.timestamp >= from)
+            {
+                serde_json::to_writer(&mut writer, &snapshot)?;
+                writer.write_all(b"\n")?;
+                written += 1;
+            }
+            Ok(())
+        })?;
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+impl HistoryStore {
+    /// Open the store at `path`, loading the snapshots that are not expired at
+    /// time `now`. The file is created if it does not exist, and expired
+    /// snapshots are removed from it.
+    pub fn open(path: &Path, limits: HistoryLimits, now: u64) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open history file {}", path.display()))?;
+        let mut store = Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            limits,
+            nodes: HashMap::new(),
+            snapshots: 0,
+            file_snapshots: 0,
+        };
+        let oldest = now.saturating_sub(limits.retention);
+        read_snapshots(path, u64::MAX, |snapshot| {
+            store.file_snapshots += 1;
+            if snapshot.timestamp >= oldest {
+                store.push(snapshot);
+            }
+            Ok(())
+        })?;
+        let compaction = store.start_compaction()?;
+        let written = compaction.run()?;
+        store.finish_compaction(compaction, written)?;
+        Ok(store)
+    }
+
+    /// Add a snapshot to the snapshots kept in memory, dropping the oldest
+    /// snapshot of the node if there are too many, and the snapshots of the
+    /// least recently updated node if there are too many nodes.
+    fn push(&mut self, snapshot: NodeSnapshot) {
+        if !self.nodes.contains_key(&snapshot.nodeId) && self.nodes.len() >= self.limits.max_nodes {
+            let least_recent = self
+                .nodes
+                .iter()
+                .min_by_key(|(_, snapshots)| snapshots.back().map(|s| s.timestamp))
+                .map(|(node_id, _)| node_id.clone());
+            if let Some(dropped) = least_recent.and_then(|node_id| self.nodes.remove(&node_id)) {
+                self.snapshots -= dropped.len();
+            }
+        }
+        let snapshots = self.nodes.entry(snapshot.nodeId.clone()).or_default();
+        snapshots.push_back(snapshot);
+        self.snapshots += 1;
+        if snapshots.len() > self.limits.max_snapshots_per_node {
+            snapshots.pop_front();
+            self.snapshots -= 1;
+        }
+    }
+
+    /// Record a snapshot, unless the latest snapshot of the node is more
+    /// recent than the snapshot interval.
+    pub fn record(&mut self, snapshot: NodeSnapshot) -> anyhow::Result<()> {
+        if let Some(last) = self.nodes.get(&snapshot.nodeId).and_then(VecDeque::back) {
+            if snapshot.timestamp < last.timestamp + self.limits.snapshot_interval {
+                return Ok(());
+            }
+        }
+        serde_json::to_writer(&mut self.file, &snapshot)?;
+        self.file.write_all(b"\n")?;
+        self.file.flush()?;
+        self.file_snapshots += 1;
+        self.push(snapshot);
+        Ok(())
+    }
+
+    /// The snapshots of a node taken in the time range `[from, to]`, or `None`
+    /// if there is no history of the node.
+    pub fn range(&self, node_id: &str, from: u64, to: u64) -> Option<Vec<&NodeSnapshot>> {
+        let snapshots = self.nodes.get(node_id)?;
+        Some(snapshots.iter().filter(|s| from <= s.timestamp && s.timestamp <= to).collect())
+    }
+
+    /// Remove the snapshots that are expired at time `now` from memory.
+    pub fn prune(&mut self, now: u64) {
+        let oldest = now.saturating_sub(self.limits.retention);
+        self.nodes.retain(|_, snapshots| {
+            while let Some(first) = snapshots.front() {
+                if first.timestamp >= oldest {
+                    break;
+                }
+                snapshots.pop_front();
+            }
+            !snapshots.is_empty()
+        });
+        self.snapshots = self.nodes.values().map(VecDeque::len).sum();
+    }
+
+    /// Remove the snapshots that are expired at time `now` from memory, and
+    /// remove the snapshots that are not kept in memory from the file if there
+    /// are at least `limits.compaction_threshold` of them. The file is
+    /// rewritten while the lock on the store is released, so snapshots can be
+    /// recorded in the meantime. Only one compaction must run at a time.
+    pub fn compact(store: &Mutex<Self>, now: u64) -> anyhow::Result<()> {
+        let compaction = {
+            let mut store = store.lock().expect("Mutex poisoned");
+            store.prune(now);
+            if store.file_snapshots - store.snapshots < store.limits.compaction_threshold {
+                return Ok(());
+            }
+            store.start_compaction()?
+        };
+        let written = compaction.run()?;
+        store.lock().expect("Mutex poisoned").finish_compaction(compaction, written)
+    }
+
+    fn start_compaction(&mut self) -> anyhow::Result<Compaction> {
+        self.file.flush()?;
+        Ok(Compaction {
+            path:           self.path.clone(),
+            tmp_path:       self.path.with_extension("tmp"),
+            len:            self.file.get_ref().metadata()?.len(),
+            file_snapshots: self.file_snapshots,
+            kept_from:      self
+                .nodes
+                .iter()
+                .filter_map(|(node_id, snapshots)| {
+                    Some((node_id.clone(), snapshots.front()?.timestamp))
+                })
+                .collect(),
+        })
+    }
+
+    /// Append the snapshots recorded since the compaction started to the
+    /// compacted file, and replace the history file by it. `written` is the
+    /// number of snapshots written to the compacted file.
+    fn finish_compaction(&mut self, compaction: Compaction, written: usize) -> anyhow::Result<()> {
+        self.file.flush()?;
+        {
+            let mut appended = File::open(&compaction.path)?;
+            appended.seek(SeekFrom::Start(compaction.len))?;
+            let mut tmp = OpenOptions::new().append(true).open(&compaction.tmp_path)?;
+            io::copy(&mut appended, &mut tmp)?;
+        }
+        fs::rename(&compaction.tmp_path, &self.path)?;
+        self.file = BufWriter::new(OpenOptions::new().append(true).open(&self.path)?);
+        self.file_snapshots = written + self.file_snapshots - compaction.file_snapshots;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(node_id: &str, timestamp: u64) -> NodeSnapshot {
+        NodeSnapshot {
+            timestamp,
+            nodeId: node_id.to_owned(),
+            nodeName: "node".to_owned(),
+            client: "6.3.0".to_owned(),
+            uptime: timestamp,
+            peersCount: 8,
+            averagePing: Some(20.0),
+            bestBlockHeight: 100,
+            finalizedBlockHeight: 98,
+            finalizationLag: 0,
+            averageBytesPerSecondIn: 1000,
+            averageBytesPerSecondOut: 1000,
+            consensusRunning: true,
+            bakingCommitteeMember: IsInBakingCommittee::NotInCommittee,
+            finalizationCommitteeMember: false,
+        }
+    }
+
+    #[test]
+    fn history_survives_reopening_and_expires() -> anyhow::Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("collector-backend-history-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let limits = HistoryLimits {
+            snapshot_interval:      10,
+            retention:              100,
+            max_snapshots_per_node: 3,
+            max_nodes:              10,
+            compaction_threshold:   0,
+        };
+
+        let mut store = HistoryStore::open(&path, limits, 0)?;
+        for timestamp in [0, 5, 10, 20, 30] {
+            store.record(snapshot("a", timestamp))?;
+        }
+        store.record(snapshot("b", 50))?;
+        // The snapshot at 5 is within the snapshot interval, and the one at 0 exceeds
+        // the maximum number of snapshots.
+        let timestamps = |store: &HistoryStore, node| {
+            store
+                .range(node, 0, u64::MAX)
+                .map(|s| s.iter().map(|s| s.timestamp).collect::<Vec<_>>())
+        };
+        assert_eq!(timestamps(&store, "a"), Some(vec![10, 20, 30]));
+        assert_eq!(store.range("a", 15, 25).map(|s| s.len()), Some(1));
+        drop(store);
+
+        let store = HistoryStore::open(&path, limits, 125)?;
+        assert_eq!(timestamps(&store, "a"), Some(vec![30]));
+        assert_eq!(timestamps(&store, "b"), Some(vec![50]));
+        drop(store);
+
+        let store = HistoryStore::open(&path, limits, 1000)?;
+        assert_eq!(timestamps(&store, "a"), None);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_keeps_snapshots_recorded_meanwhile() -> anyhow::Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("collector-backend-compaction-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let limits = HistoryLimits {
+            snapshot_interval:      10,
+            retention:              100,
+            max_snapshots_per_node: 2,
+            max_nodes:              10,
+            compaction_threshold:   0,
+        };
+        let file_timestamps = || -> anyhow::Result<Vec<u64>> {
+            let mut timestamps = Vec::new();
+            read_snapshots(&path, u64::MAX, |snapshot| {
+                timestamps.push(snapshot.timestamp);
+                Ok(())
+            })?;
+            Ok(timestamps)
+        };
+
+        let store = Mutex::new(HistoryStore::open(&path, limits, 0)?);
+        for timestamp in [0, 10, 20] {
+            store.lock().unwrap().record(snapshot("a", timestamp))?;
+        }
+        // Only the most recent snapshots are kept in memory, but all are in the file.
+        assert_eq!(store.lock().unwrap().range("a", 0, u64::MAX).map(|s| s.len()), Some(2));
+        assert_eq!(file_timestamps()?, vec![0, 10, 20]);
+
+        // A snapshot is recorded while the file is rewritten.
+        let compaction = {
+            let mut store = store.lock().unwrap();
+            store.prune(115);
+            store.start_compaction()?
+        };
+        store.lock().unwrap().record(snapshot("b", 115))?;
+        let written = compaction.run()?;
+        store.lock().unwrap().finish_compaction(compaction, written)?;
+        store.lock().unwrap().record(snapshot("b", 125))?;
+        // The snapshot of a at 10 is not expired, but no longer kept in memory.
+        assert_eq!(file_timestamps()?, vec![20, 115, 125]);
+        assert_eq!(store.lock().unwrap().range("a", 0, u64::MAX).map(|s| s.len()), Some(1));
+
+        HistoryStore::compact(&store, 1000)?;
+        assert_eq!(file_timestamps()?, Vec::<u64>::new());
+        assert!(store.lock().unwrap().range("b", 0, u64::MAX).is_none());
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn history_is_bounded_and_compacted_at_threshold() -> anyhow::Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("collector-backend-bounded-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let limits = HistoryLimits {
+            snapshot_interval:      10,
+            retention:              100,
+            max_snapshots_per_node: 2,
+            max_nodes:              2,
+            compaction_threshold:   3,
+        };
+        let count_file = || -> anyhow::Result<usize> {
+            let mut count = 0;
+            read_snapshots(&path, u64::MAX, |_| {
+                count += 1;
+                Ok(())
+            })?;
+            Ok(count)
+        };
+
+        let store = Mutex::new(HistoryStore::open(&path, limits, 0)?);
+        for (node_id, timestamp) in [("a", 0), ("b", 5), ("a", 10), ("c", 15)] {
+            store.lock().unwrap().record(snapshot(node_id, timestamp))?;
+        }
+        // b was updated least recently, so it is dropped when c is recorded.
+        assert!(store.lock().unwrap().range("b", 0, u64::MAX).is_none());
+        assert_eq!(store.lock().unwrap().range("a", 0, u64::MAX).map(|s| s.len()), Some(2));
+        assert_eq!(store.lock().unwrap().range("c", 0, u64::MAX).map(|s| s.len()), Some(1));
+
+        // Only one snapshot in the file is not kept in memory, which is below the
+        // threshold.
+        HistoryStore::compact(&store, 20)?;
+        assert_eq!(count_file()?, 4);
+        // The snapshots of a at 0 and 10 expire.
+        HistoryStore::compact(&store, 112)?;
+        assert_eq!(count_file()?, 1);
+        assert_eq!(store.lock().unwrap().range("c", 0, u64::MAX).map(|s| s.len()), Some(1));
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
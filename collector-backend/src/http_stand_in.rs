@@ -0,0 +1,31 @@
+//! A stand-in for the HTTP services the collector backend posts data to, for
+//! testing the clients.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpListener,
+};
+
+/// Accept a single request on the listener, answer it with an empty `200 OK`
+/// response and return its body.
+pub fn accept_request(listener: TcpListener) -> io::Result<Vec<u8>> {
+    let (stream, _) = listener.accept()?;
+    let mut reader = BufReader::new(stream);
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end().to_ascii_lowercase();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("content-length:") {
+            content_length =
+                length.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")?;
+    Ok(body)
+}
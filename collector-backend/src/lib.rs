@@ -1,3 +1,12 @@
+pub mod alerts;
+pub mod history;
+#[cfg(test)]
+mod http_stand_in;
+pub mod live;
+pub mod metrics;
+pub mod signatures;
+pub mod stream;
+
 use env_logger::{Builder, Env};
 use log::LevelFilter;
 use serde::{de, Deserialize, Deserializer, Serialize};
@@ -104,6 +113,10 @@ pub struct NodeInfo {
     pub finalizedBlockParent: String,
     #[serde(skip)]
     pub last_updated: u64,
+    /// The ed25519 public key of the collector that signed the submission, if
+    /// any. Only used by the collector backend.
+    #[serde(skip)]
+    pub public_key: Option<[u8; 32]>,
     pub averageBytesPerSecondIn: u64,
     pub averageBytesPerSecondOut: u64,
 }
@@ -244,6 +257,39 @@ impl<'a> From<&'a NodeInfo> for NodeInfoChainViz<'a> {
     }
 }
 
+/// Header with the hex encoded ed25519 public key of the collector that signed
+/// a submission.
+pub const PUBLIC_KEY_HEADER: &str = "x-collector-public-key";
+/// Header with the time a submission was signed (Unix time in milliseconds).
+pub const TIMESTAMP_HEADER: &str = "x-collector-timestamp";
+/// Header with the hex encoded ed25519 signature of a submission.
+pub const SIGNATURE_HEADER: &str = "x-collector-signature";
+
+/// The message a collector signs when submitting `body` at time `timestamp`
+/// (Unix time in milliseconds). The timestamp is part of the message so the
+/// backend can reject replayed submissions.
+pub fn submission_message(timestamp: u64, body: &[u8]) -> Vec<u8> {
+    const DOMAIN: &[u8] = b"concordium-collector-submission";
+    let mut message = Vec::with_capacity(DOMAIN.len() + 8 + body.len());
+    message.extend_from_slice(DOMAIN);
+    message.extend_from_slice(&timestamp.to_be_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// Calculates the average, but only from a part of the values. The omitted are
+/// taken from the highest and lowest values.
+pub fn average_without_outer_values(mut values: Vec<u64>, number_to_include: usize) -> u64 {
+    // the unstable sort is faster and stability makes no difference for sorting
+    // sequences of u64.
+    values.sort_unstable();
+    let omitting = values.len() - number_to_include;
+    let start = omitting / 2;
+    let end = start + number_to_include;
+
+    values[start..end].iter().sum::<u64>() / number_to_include as u64
+}
+
 /// Sets up a logger that logs to stderr.
 pub fn setup_logger(trace: bool, debug: bool, no_log_timestamp: bool) {
     let env = if trace {
@@ -268,3 +314,31 @@ pub fn setup_logger(trace: bool, debug: bool, no_log_timestamp: bool) {
     log_builder.filter(Some("h2"), LevelFilter::Warn);
     log_builder.init();
 }
+
+/// A node with the given id, version and finalized block height, for tests.
+#[cfg(test)]
+pub(crate) fn test_node_info(id: &str, client: &str, finalized_height: u64) -> NodeInfo {
+    serde_json::from_value(serde_json::json!({
+        "nodeName": format!("node {}", id),
+        "nodeId": id,
+        "peerType": "Node",
+        "uptime": 0,
+        "client": client,
+        "peersCount": 0,
+        "peersList": [],
+        "bestBlock": "",
+        "bestBlockHeight": finalized_height,
+        "finalizedBlock": "",
+        "finalizedBlockHeight": finalized_height,
+        "packetsSent": 0,
+        "packetsReceived": 0,
+        "consensusRunning": true,
+        "bakingCommitteeMember": "NotInCommittee",
+        "finalizationCommitteeMember": false,
+        "genesisBlock": "",
+        "finalizedBlockParent": "",
+        "averageBytesPerSecondIn": 0,
+        "averageBytesPerSecondOut": 0,
+    }))
+    .expect("Valid node info")
+}
@@ -0,0 +1,161 @@
+//! The live feed of node updates, which dashboards subscribe to with a
+//! WebSocket connection instead of polling the summary of the nodes.
+//!
+//! Every message of the feed is a JSON object. An update of a node is its
+//! [NodeInfoDashboard]. When a node is removed because it stopped sending
+//! updates, a [NodeRemoved] message is sent.
+
+use crate::{NodeInfo, NodeInfoDashboard};
+use gotham_derive::{StateData, StaticResponseExtender};
+use serde::{Deserialize, Serialize};
+use std::{panic::RefUnwindSafe, sync::Arc};
+use tokio::sync::broadcast;
+
+/// The message published when a node is removed.
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+pub struct NodeRemoved<'a> {
+    pub nodeId:   &'a str,
+    pub nodeName: &'a str,
+    /// Always `true`, to tell removals apart from updates.
+    pub removed:  bool,
+}
+
+/// A message published on the live feed.
+pub struct LiveUpdate {
+    node_name: String,
+    client:    String,
+    /// The JSON encoded message.
+    pub json:  String,
+}
+
+impl LiveUpdate {
+    /// An update of the data of a node.
+    pub fn updated(node_info: &NodeInfo) -> Self {
+        Self {
+            node_name: node_info.nodeName.clone(),
+            client:    node_info.client.clone(),
+            json:      serde_json::to_string(&NodeInfoDashboard::from(node_info))
+                .expect("Node info is serializable"),
+        }
+    }
+
+    /// The removal of a node.
+    pub fn removed(node_info: &NodeInfo) -> Self {
+        let removed = NodeRemoved {
+            nodeId:   &node_info.nodeId,
+            nodeName: &node_info.nodeName,
+            removed:  true,
+        };
+        Self {
+            node_name: node_info.nodeName.clone(),
+            client:    node_info.client.clone(),
+            json:      serde_json::to_string(&removed).expect("Removal is serializable"),
+        }
+    }
+}
+
+/// Publishes the messages of the live feed to its subscribers.
+#[derive(Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<Arc<LiveUpdate>>,
+}
+
+// The state shared by the handlers must be unwind safe. A panic while
+// publishing cannot leave the channel in an inconsistent state.
+impl RefUnwindSafe for LiveFeed {}
+
+impl LiveFeed {
+    /// Create a feed buffering up to `capacity` messages for each subscriber.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Whether anyone is subscribed, so the messages need to be encoded.
+    pub fn has_subscribers(&self) -> bool { self.sender.receiver_count() > 0 }
+
+    /// Publish a message to the current subscribers.
+    pub fn publish(&self, update: LiveUpdate) {
+        // Sending only fails if there are no subscribers.
+        let _ = self.sender.send(Arc::new(update));
+    }
+
+    /// Subscribe to the messages published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<LiveUpdate>> { self.sender.subscribe() }
+}
+
+/// The filter of a subscription to the live feed.
+#[derive(Deserialize, StateData, StaticResponseExtender)]
+pub struct LiveFeedQuery {
+    /// Only include nodes whose name contains this, ignoring case.
+    #[serde(rename = "nodeName")]
+    pub node_name: Option<String>,
+    /// Only include nodes whose version starts with this.
+    pub client:    Option<String>,
+}
+
+impl LiveFeedQuery {
+    /// Whether the messages about a node with the given name and version are
+    /// sent to the subscriber.
+    pub fn matches(&self, node_name: &str, client: &str) -> bool {
+        self.node_name
+            .as_ref()
+            .into_iter()
+            .all(|name| node_name.to_lowercase().contains(&name.to_lowercase()))
+            && self.client.as_ref().into_iter().all(|version| client.starts_with(version.as_str()))
+    }
+
+    /// Whether a message is sent to the subscriber.
+    pub fn matches_update(&self, update: &LiveUpdate) -> bool {
+        self.matches(&update.node_name, &update.client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_node_info;
+
+    #[test]
+    fn queries_filter_by_name_and_version() {
+        let query = |node_name: Option<&str>, client: Option<&str>| LiveFeedQuery {
+            node_name: node_name.map(String::from),
+            client:    client.map(String::from),
+        };
+        assert!(query(None, None).matches("My Validator", "6.3.1"));
+        assert!(query(Some("validator"), None).matches("My Validator", "6.3.1"));
+        assert!(!query(Some("baker"), None).matches("My Validator", "6.3.1"));
+        assert!(query(None, Some("6.3")).matches("My Validator", "6.3.1"));
+        assert!(!query(None, Some("6.2")).matches("My Validator", "6.3.1"));
+        assert!(!query(Some("validator"), Some("6.2")).matches("My Validator", "6.3.1"));
+    }
+
+    #[test]
+    fn updates_and_removals_are_published() {
+        let feed = LiveFeed::new(4);
+        assert!(!feed.has_subscribers());
+        let mut receiver = feed.subscribe();
+        assert!(feed.has_subscribers());
+
+        let node = test_node_info("a", "6.3.1", 10);
+        feed.publish(LiveUpdate::updated(&node));
+        feed.publish(LiveUpdate::removed(&node));
+
+        let update = receiver.try_recv().expect("The update is published");
+        let json: serde_json::Value = serde_json::from_str(&update.json).unwrap();
+        assert_eq!(json["nodeId"], "a");
+        assert!(json.get("removed").is_none());
+
+        let removal = receiver.try_recv().expect("The removal is published");
+        assert!(LiveFeedQuery {
+            node_name: Some("node a".into()),
+            client:    None,
+        }
+        .matches_update(&removal));
+        let json: serde_json::Value = serde_json::from_str(&removal.json).unwrap();
+        assert_eq!(json["nodeId"], "a");
+        assert_eq!(json["removed"], true);
+    }
+}
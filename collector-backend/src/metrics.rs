@@ -0,0 +1,257 @@
+//! Prometheus metrics about the nodes publishing to the collector backend.
+//!
+//! The metrics are computed from the current [NodeInfo] of the nodes whenever
+//! they are scraped, so they always reflect the data served on the other
+//! endpoints.
+
+use crate::{IsInBakingCommittee, NodeInfo};
+use anyhow::ensure;
+use prometheus::{Encoder, GaugeVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::{collections::HashMap, str::FromStr};
+
+/// The quantiles of the finalization lag that are exported.
+const FINALIZATION_LAG_QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 1.0];
+
+/// The label value used for the versions that are not exported individually.
+const OTHER_VERSIONS: &str = "other";
+
+/// A label identifying the node in the per-node metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeLabel {
+    NodeId,
+    NodeName,
+    Client,
+}
+
+impl NodeLabel {
+    fn name(self) -> &'static str {
+        match self {
+            NodeLabel::NodeId => "node_id",
+            NodeLabel::NodeName => "node_name",
+            NodeLabel::Client => "client",
+        }
+    }
+
+    fn value(self, node: &NodeInfo) -> &str {
+        match self {
+            NodeLabel::NodeId => &node.nodeId,
+            NodeLabel::NodeName => &node.nodeName,
+            NodeLabel::Client => &node.client,
+        }
+    }
+}
+
+impl FromStr for NodeLabel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nodeId" => Ok(NodeLabel::NodeId),
+            "nodeName" => Ok(NodeLabel::NodeName),
+            "client" => Ok(NodeLabel::Client),
+            _ => anyhow::bail!("Unknown node label '{}', expected nodeId, nodeName or client", s),
+        }
+    }
+}
+
+/// Limits on the label cardinality of the exported metrics.
+#[derive(Debug, Clone)]
+pub struct MetricsOptions {
+    /// The labels of the per-node metrics. If empty, no per-node metrics are
+    /// exported.
+    pub node_labels:         Vec<NodeLabel>,
+    /// The maximum number of versions the node count is exported for. The
+    /// nodes running the less common versions are counted as `other`.
+    pub max_client_versions: usize,
+}
+
+impl MetricsOptions {
+    /// Check the labels of the per-node metrics. The node id must be among
+    /// them, since other labels do not identify a node and the metrics of
+    /// nodes sharing them would overwrite each other.
+    pub fn new(node_labels: Vec<NodeLabel>, max_client_versions: usize) -> anyhow::Result<Self> {
+        ensure!(
+            node_labels.is_empty() || node_labels.contains(&NodeLabel::NodeId),
+            "The labels of the per-node metrics must include nodeId"
+        );
+        for (i, label) in node_labels.iter().enumerate() {
+            ensure!(
+                !node_labels[..i].contains(label),
+                "The node label '{}' is given more than once",
+                label.name()
+            );
+        }
+        Ok(Self {
+            node_labels,
+            max_client_versions,
+        })
+    }
+}
+
+/// Render the metrics of the nodes in the Prometheus text format.
+pub fn render<'a>(
+    nodes: impl Iterator<Item = &'a NodeInfo>,
+    options: &MetricsOptions,
+) -> anyhow::Result<String> {
+    let nodes: Vec<&NodeInfo> = nodes.collect();
+    let registry = Registry::new();
+
+    let node_count = IntGauge::new("collector_nodes", "Number of nodes publishing data")?;
+    node_count.set(nodes.len() as i64);
+    registry.register(Box::new(node_count))?;
+
+    // The node count by version, limited to the most common versions.
+    let mut versions: HashMap<&str, i64> = HashMap::new();
+    for node in &nodes {
+        *versions.entry(node.client.as_str()).or_default() += 1;
+    }
+    let mut versions: Vec<(&str, i64)> = versions.into_iter().collect();
+    versions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let nodes_by_version = IntGaugeVec::new(
+        Opts::new("collector_nodes_by_client", "Number of nodes publishing data by version"),
+        &["client"],
+    )?;
+    for (i, (version, count)) in versions.into_iter().enumerate() {
+        let version = if i < options.max_client_versions {
+            version
+        } else {
+            OTHER_VERSIONS
+        };
+        nodes_by_version.with_label_values(&[version]).add(count);
+    }
+    registry.register(Box::new(nodes_by_version))?;
+
+    // The finalization lag is relative to the highest finalized block height of the
+    // nodes.
+    let network_finalized_height =
+        nodes.iter().map(|n| n.finalizedBlockHeight).max().unwrap_or_default();
+    let mut lags: Vec<u64> = nodes
+        .iter()
+        .map(|n| network_finalized_height.saturating_sub(n.finalizedBlockHeight))
+        .collect();
+    lags.sort_unstable();
+    let lag_quantiles = IntGaugeVec::new(
+        Opts::new(
+            "collector_finalization_lag_blocks",
+            "Quantiles of the number of blocks the last finalized block of the nodes is behind \
+             the highest last finalized block",
+        ),
+        &["quantile"],
+    )?;
+    if !lags.is_empty() {
+        for quantile in FINALIZATION_LAG_QUANTILES {
+            let index = ((lags.len() - 1) as f64 * quantile).round() as usize;
+            lag_quantiles.with_label_values(&[&quantile.to_string()]).set(lags[index] as i64);
+        }
+    }
+    registry.register(Box::new(lag_quantiles))?;
+
+    if !options.node_labels.is_empty() {
+        let label_names: Vec<&str> = options.node_labels.iter().map(|l| l.name()).collect();
+        let gauge = |name: &str, help: &str| -> anyhow::Result<GaugeVec> {
+            let gauge = GaugeVec::new(Opts::new(name, help), &label_names)?;
+            registry.register(Box::new(gauge.clone()))?;
+            Ok(gauge)
+        };
+        let best_block_height =
+            gauge("collector_node_best_block_height", "Height of the best block of the node")?;
+        let finalized_block_height = gauge(
+            "collector_node_finalized_block_height",
+            "Height of the last finalized block of the node",
+        )?;
+        let finalization_lag = gauge(
+            "collector_node_finalization_lag_blocks",
+            "Number of blocks the last finalized block of the node is behind the highest last \
+             finalized block",
+        )?;
+        let peers = gauge("collector_node_peers", "Number of peers of the node")?;
+        let average_ping = gauge(
+            "collector_node_average_ping_milliseconds",
+            "Average ping of the peers of the node",
+        )?;
+        let bytes_in = gauge(
+            "collector_node_received_bytes_per_second",
+            "Average number of bytes received per second by the node",
+        )?;
+        let bytes_out = gauge(
+            "collector_node_sent_bytes_per_second",
+            "Average number of bytes sent per second by the node",
+        )?;
+        let baking_committee = gauge(
+            "collector_node_baking_committee_member",
+            "Whether the node is an active member of the baking committee",
+        )?;
+        let finalization_committee = gauge(
+            "collector_node_finalization_committee_member",
+            "Whether the node is a member of the finalization committee",
+        )?;
+
+        for node in &nodes {
+            let label_values: Vec<&str> =
+                options.node_labels.iter().map(|l| l.value(node)).collect();
+            let set =
+                |gauge: &GaugeVec, value: f64| gauge.with_label_values(&label_values).set(value);
+            set(&best_block_height, node.bestBlockHeight as f64);
+            set(&finalized_block_height, node.finalizedBlockHeight as f64);
+            set(
+                &finalization_lag,
+                network_finalized_height.saturating_sub(node.finalizedBlockHeight) as f64,
+            );
+            set(&peers, node.peersCount as f64);
+            if let Some(ping) = node.averagePing {
+                set(&average_ping, ping);
+            }
+            set(&bytes_in, node.averageBytesPerSecondIn as f64);
+            set(&bytes_out, node.averageBytesPerSecondOut as f64);
+            let active_baker =
+                matches!(node.bakingCommitteeMember, IsInBakingCommittee::ActiveInCommittee);
+            set(&baking_committee, f64::from(u8::from(active_baker)));
+            set(&finalization_committee, f64::from(u8::from(node.finalizationCommitteeMember)));
+        }
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&registry.gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_node_info;
+
+    #[test]
+    fn metrics_respect_label_limits() -> anyhow::Result<()> {
+        let nodes = [
+            test_node_info("a", "6.3.0", 100),
+            test_node_info("b", "6.3.0", 100),
+            test_node_info("c", "6.2.3", 90),
+            test_node_info("d", "6.1.7", 100),
+        ];
+        let rendered = render(nodes.iter(), &MetricsOptions {
+            node_labels:         vec![NodeLabel::NodeId],
+            max_client_versions: 2,
+        })?;
+        assert!(rendered.contains("collector_nodes 4\n"));
+        assert!(rendered.contains("collector_nodes_by_client{client=\"6.3.0\"} 2\n"));
+        assert!(rendered.contains("collector_nodes_by_client{client=\"other\"} 1\n"));
+        assert!(rendered.contains("collector_finalization_lag_blocks{quantile=\"1\"} 10\n"));
+        assert!(rendered.contains("collector_node_finalization_lag_blocks{node_id=\"c\"} 10\n"));
+        assert!(!rendered.contains("node_name"));
+
+        let rendered = render(nodes.iter(), &MetricsOptions {
+            node_labels:         Vec::new(),
+            max_client_versions: 2,
+        })?;
+        assert!(!rendered.contains("collector_node_"));
+        Ok(())
+    }
+
+    #[test]
+    fn node_labels_must_identify_nodes() {
+        assert!(MetricsOptions::new(vec![NodeLabel::NodeName, NodeLabel::NodeId], 2).is_ok());
+        assert!(MetricsOptions::new(Vec::new(), 2).is_ok());
+        assert!(MetricsOptions::new(vec![NodeLabel::NodeName, NodeLabel::Client], 2).is_err());
+        assert!(MetricsOptions::new(vec![NodeLabel::NodeId, NodeLabel::NodeId], 2).is_err());
+    }
+}
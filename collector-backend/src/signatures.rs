@@ -0,0 +1,346 @@
+//! Verification of the signatures of the data submitted by collectors.
+//!
+//! A signed submission carries the public key of the collector, the time of
+//! signing and the ed25519 signature of [submission_message]. Submissions are
+//! only accepted if they were signed recently, and a [ReplayGuard] rejects
+//! submissions that are not newer than the last one accepted for the same key
+//! and node. [KeyBindings] bind each node id to the first key that signed data
+//! for it, so other collectors cannot publish data for the node.
+
+use crate::{submission_message, PUBLIC_KEY_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use anyhow::{anyhow, bail, ensure, Context};
+use ed25519_dalek::{Signature, VerifyingKey};
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// An ed25519 public key of a collector.
+pub type PublicKey = [u8; 32];
+
+/// Parse a hex encoded ed25519 public key.
+pub fn parse_public_key(hex_key: &str) -> anyhow::Result<PublicKey> {
+    let bytes: PublicKey =
+        hex::decode(hex_key)?.try_into().map_err(|_| anyhow!("A public key must be 32 bytes"))?;
+    VerifyingKey::from_bytes(&bytes)?;
+    Ok(bytes)
+}
+
+/// The rules for the signatures of submissions.
+pub struct SignaturePolicy {
+    /// Whether unsigned submissions are rejected.
+    pub required:          bool,
+    /// If present, only submissions signed by one of these keys are accepted,
+    /// and only for the node ids registered with the key. A key registered
+    /// without node ids can publish data for any node.
+    pub allowed_keys:      Option<HashMap<PublicKey, HashSet<String>>>,
+    /// Maximum difference in ms between the time of signing and receiving a
+    /// submission.
+    pub max_signature_age: u64,
+}
+
+impl SignaturePolicy {
+    /// Check the signature of a submission received at time `now` (Unix time
+    /// in milliseconds), with the signature in the headers. Returns the public
+    /// key of the signer and the time of signing if the submission is signed.
+    pub fn verify_headers(
+        &self,
+        headers: &HeaderMap,
+        body: &[u8],
+        now: u64,
+    ) -> anyhow::Result<Option<(PublicKey, u64)>> {
+        let header = |name: &str| -> anyhow::Result<Option<&str>> {
+            headers
+                .get(name)
+                .map(|value| value.to_str().with_context(|| format!("Invalid header '{}'", name)))
+                .transpose()
+        };
+        let (public_key, timestamp, signature) = match (
+            header(PUBLIC_KEY_HEADER)?,
+            header(TIMESTAMP_HEADER)?,
+            header(SIGNATURE_HEADER)?,
+        ) {
+            (None, None, None) => {
+                ensure!(!self.required, "Submission must be signed");
+                return Ok(None);
+            }
+            (Some(public_key), Some(timestamp), Some(signature)) => {
+                (public_key, timestamp, signature)
+            }
+            _ => bail!(
+                "Headers '{}', '{}' and '{}' must be provided together",
+                PUBLIC_KEY_HEADER,
+                TIMESTAMP_HEADER,
+                SIGNATURE_HEADER
+            ),
+        };
+
+        let public_key = parse_public_key(public_key).context("Invalid public key")?;
+        let timestamp: u64 = timestamp.parse().context("Invalid timestamp")?;
+        let signature = hex::decode(signature).context("Invalid signature")?;
+        self.verify(public_key, timestamp, &signature, body, now).map(Some)
+    }
+
+    /// Check the signature of a submission signed with `public_key` at time
+    /// `timestamp` and received at time `now` (Unix time in milliseconds).
+    pub fn verify(
+        &self,
+        public_key: PublicKey,
+        timestamp: u64,
+        signature: &[u8],
+        body: &[u8],
+        now: u64,
+    ) -> anyhow::Result<(PublicKey, u64)> {
+        if let Some(allowed_keys) = &self.allowed_keys {
+            ensure!(allowed_keys.contains_key(&public_key), "Public key is not registered");
+        }
+        // The timestamp is chosen by the sender, so it must not be used in
+        // arithmetic that can overflow.
+        ensure!(
+            now.abs_diff(timestamp) <= self.max_signature_age,
+            "Submission was not signed recently"
+        );
+        let signature = Signature::from_slice(signature).context("Invalid signature")?;
+        VerifyingKey::from_bytes(&public_key)?
+            .verify_strict(&submission_message(timestamp, body), &signature)
+            .context("Invalid signature")?;
+        Ok((public_key, timestamp))
+    }
+
+    /// Check that data for the node with `node_id` may be signed with
+    /// `public_key`.
+    pub fn check_node_id(&self, public_key: PublicKey, node_id: &str) -> anyhow::Result<()> {
+        if let Some(node_ids) = self.allowed_keys.as_ref().and_then(|keys| keys.get(&public_key)) {
+            ensure!(
+                node_ids.is_empty() || node_ids.contains(node_id),
+                "nodeId is not registered for the public key"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A node id bound to a public key, as stored in the key bindings file.
+#[allow(non_snake_case)]
+#[derive(Serialize, Deserialize)]
+struct KeyBinding {
+    nodeId:    String,
+    publicKey: String,
+}
+
+/// The public key each node id is bound to. A node id is bound to the first key
+/// that signs data for it, and from then on only data for the node signed by
+/// that key is accepted. The bindings are kept when the node is removed as
+/// stale, and if a file is given they are appended to it, one JSON encoded
+/// binding per line, so they also survive restarts of the collector backend.
+#[derive(Default)]
+pub struct KeyBindings {
+    bindings: HashMap<String, PublicKey>,
+    file:     Option<File>,
+}
+
+impl KeyBindings {
+    /// Load the bindings stored at `path`. The file is created if it does not
+    /// exist.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open key bindings file {}", path.display()))?;
+        let mut bindings = HashMap::new();
+        for (number, line) in BufReader::new(&file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let binding: KeyBinding = serde_json::from_str(&line).with_context(|| {
+                format!("Invalid key binding on line {} of {}", number + 1, path.display())
+            })?;
+            bindings.insert(binding.nodeId, parse_public_key(&binding.publicKey)?);
+        }
+        Ok(Self {
+            bindings,
+            file: Some(file),
+        })
+    }
+
+    /// The number of node ids bound to a key.
+    pub fn len(&self) -> usize { self.bindings.len() }
+
+    /// Whether no node id is bound to a key.
+    pub fn is_empty(&self) -> bool { self.bindings.is_empty() }
+
+    /// Check that data for the node with `node_id` signed with `public_key`, or
+    /// unsigned if `None`, may be accepted. Binds the node id to the key if it
+    /// is signed for the first time.
+    pub fn check(&mut self, node_id: &str, public_key: Option<PublicKey>) -> anyhow::Result<()> {
+        match (self.bindings.get(node_id), public_key) {
+            (Some(bound_key), Some(public_key)) => {
+                ensure!(
+                    *bound_key == public_key,
+                    "nodeId is in use by a collector with a different key"
+                )
+            }
+            (Some(_), None) => bail!("nodeId is in use by a collector that signs its data"),
+            (None, Some(public_key)) => {
+                if let Some(file) = &mut self.file {
+                    let binding = KeyBinding {
+                        nodeId:    node_id.to_owned(),
+                        publicKey: hex::encode(public_key),
+                    };
+                    let mut line = serde_json::to_vec(&binding)?;
+                    line.push(b'\n');
+                    file.write_all(&line).context("Could not store key binding")?;
+                }
+                self.bindings.insert(node_id.to_owned(), public_key);
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+}
+
+/// Remembers the time of signing of the last submission accepted for each
+/// public key and node, to reject replayed submissions.
+#[derive(Default)]
+pub struct ReplayGuard {
+    last: HashMap<(PublicKey, String), u64>,
+}
+
+impl ReplayGuard {
+    /// Record a submission for the node signed with `public_key` at time
+    /// `timestamp`, unless it is not newer than the last one recorded.
+    pub fn check(
+        &mut self,
+        public_key: PublicKey,
+        node_id: &str,
+        timestamp: u64,
+    ) -> anyhow::Result<()> {
+        let last = self.last.entry((public_key, node_id.to_owned())).or_insert(0);
+        ensure!(timestamp > *last, "Submission is replayed");
+        *last = timestamp;
+        Ok(())
+    }
+
+    /// Forget the submissions signed more than `max_signature_age` ms before
+    /// `now`. These would be rejected as too old anyway.
+    pub fn prune(&mut self, now: u64, max_signature_age: u64) {
+        self.last.retain(|_, timestamp| now < timestamp.saturating_add(max_signature_age));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn signed_headers(key: &SigningKey, timestamp: u64, body: &[u8]) -> HeaderMap {
+        let signature = key.sign(&submission_message(timestamp, body));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            PUBLIC_KEY_HEADER,
+            hex::encode(key.verifying_key().to_bytes()).parse().unwrap(),
+        );
+        headers.insert(TIMESTAMP_HEADER, timestamp.to_string().parse().unwrap());
+        headers.insert(SIGNATURE_HEADER, hex::encode(signature.to_bytes()).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn signatures_are_verified() -> anyhow::Result<()> {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let mut policy = SignaturePolicy {
+            required:          false,
+            allowed_keys:      None,
+            max_signature_age: 1000,
+        };
+        let now = 1_000_000;
+
+        let headers = signed_headers(&key, now - 10, b"data");
+        assert_eq!(policy.verify_headers(&headers, b"data", now)?, Some((public_key, now - 10)));
+        // unsigned submissions are only accepted if signatures are not required
+        assert_eq!(policy.verify_headers(&HeaderMap::new(), b"data", now)?, None);
+        // the signature does not match a different body
+        assert!(policy.verify_headers(&headers, b"other data", now).is_err());
+        // stale and future timestamps are rejected, also without overflowing
+        assert!(policy
+            .verify_headers(&signed_headers(&key, now - 1001, b"data"), b"data", now)
+            .is_err());
+        assert!(policy
+            .verify_headers(&signed_headers(&key, now + 1001, b"data"), b"data", now)
+            .is_err());
+        assert!(policy
+            .verify_headers(&signed_headers(&key, u64::MAX, b"data"), b"data", now)
+            .is_err());
+
+        policy.required = true;
+        assert!(policy.verify_headers(&HeaderMap::new(), b"data", now).is_err());
+        // only registered keys are accepted
+        let other_key = SigningKey::from_bytes(&[2; 32]);
+        policy.allowed_keys = Some(std::iter::once((public_key, HashSet::new())).collect());
+        assert!(policy.verify_headers(&headers, b"data", now).is_ok());
+        assert!(policy
+            .verify_headers(&signed_headers(&other_key, now, b"data"), b"data", now)
+            .is_err());
+        // a key registered with node ids can only publish data for these
+        policy.check_node_id(public_key, "a")?;
+        policy.allowed_keys =
+            Some(std::iter::once((public_key, std::iter::once("a".into()).collect())).collect());
+        policy.check_node_id(public_key, "a")?;
+        assert!(policy.check_node_id(public_key, "b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn node_ids_stay_bound_to_keys() -> anyhow::Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("collector-backend-key-bindings-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes();
+        let other_key = SigningKey::from_bytes(&[2; 32]).verifying_key().to_bytes();
+
+        let mut bindings = KeyBindings::open(&path)?;
+        bindings.check("a", None)?;
+        bindings.check("a", Some(key))?;
+        bindings.check("a", Some(key))?;
+        assert!(bindings.check("a", Some(other_key)).is_err());
+        assert!(bindings.check("a", None).is_err(), "Bound nodes must sign their data");
+        bindings.check("b", Some(other_key))?;
+        drop(bindings);
+
+        let mut bindings = KeyBindings::open(&path)?;
+        assert_eq!(bindings.len(), 2, "Bindings survive reopening");
+        assert!(bindings.check("a", Some(other_key)).is_err());
+        assert!(bindings.check("b", Some(key)).is_err());
+        bindings.check("c", None)?;
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn replays_are_rejected() -> anyhow::Result<()> {
+        let mut guard = ReplayGuard::default();
+        let public_key = SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes();
+        guard.check(public_key, "a", 100)?;
+        assert!(guard.check(public_key, "a", 100).is_err());
+        assert!(guard.check(public_key, "a", 99).is_err());
+        // the submissions of other nodes are independent
+        guard.check(public_key, "b", 50)?;
+        guard.check(public_key, "a", 101)?;
+
+        guard.prune(1050, 1000);
+        assert!(guard.check(public_key, "b", 50).is_ok(), "Pruned entries are forgotten");
+        assert!(guard.check(public_key, "a", 101).is_err());
+        guard.prune(u64::MAX, 1000);
+        assert!(guard.last.is_empty());
+        Ok(())
+    }
+}
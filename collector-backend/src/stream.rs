@@ -0,0 +1,190 @@
+//! The protocol for streaming the data of nodes from a collector to the
+//! collector backend over a WebSocket.
+//!
+//! Instead of posting the complete [NodeInfo] on every collection, a collector
+//! keeps a WebSocket open and sends a [StreamFrame] for every collection. The
+//! first update of a node on a connection contains the complete [NodeInfo],
+//! and later updates only the fields that changed. The backend answers every
+//! frame with a [StreamAck] before the collector sends the next one, which
+//! limits the number of updates in flight to one per connection.
+//!
+//! If the collector signs its submissions, the public key is sent in the
+//! [PUBLIC_KEY_HEADER](crate::PUBLIC_KEY_HEADER) of the WebSocket handshake,
+//! and every frame carries the time of signing and the signature of
+//! [submission_message](crate::submission_message) for the encoded update.
+
+use crate::NodeInfo;
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// A frame sent by the collector.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamFrame {
+    /// The time the update was signed (Unix time in milliseconds), if signed.
+    pub timestamp: Option<u64>,
+    /// The ed25519 signature of the update, if signed.
+    #[serde(with = "serde_bytes")]
+    pub signature: Option<Vec<u8>>,
+    /// The msgpack encoded [NodeUpdate].
+    #[serde(with = "serde_bytes")]
+    pub update:    Vec<u8>,
+}
+
+/// An update of the data of a node.
+#[derive(Serialize, Deserialize)]
+pub enum NodeUpdate {
+    /// The complete data of the node.
+    Full(Box<NodeInfo>),
+    /// The fields of the data of the node that changed since the previous
+    /// update of the node on the same connection.
+    Delta {
+        node_id: String,
+        changes: Map<String, Value>,
+    },
+}
+
+/// The answer of the collector backend to a [StreamFrame].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum StreamAck {
+    Accepted,
+    /// The update was rejected for the given reason. The next update of the
+    /// node on the connection must be a [NodeUpdate::Full].
+    Rejected(String),
+}
+
+/// The fields of a [NodeInfo] as sent by the collector.
+fn fields(node_info: &NodeInfo) -> anyhow::Result<Map<String, Value>> {
+    match serde_json::to_value(node_info)? {
+        Value::Object(fields) => Ok(fields),
+        _ => bail!("Node info is not serialized as a map"),
+    }
+}
+
+/// Computes the updates sent by a collector on a connection.
+#[derive(Default)]
+pub struct DeltaEncoder {
+    /// The fields of each node as of the last update sent.
+    sent: HashMap<String, Map<String, Value>>,
+}
+
+impl DeltaEncoder {
+    /// The update bringing the backend from the last update sent for the node
+    /// to `node_info`.
+    pub fn encode(&mut self, node_info: &NodeInfo) -> anyhow::Result<NodeUpdate> {
+        let current = fields(node_info)?;
+        let update = match self.sent.get(&node_info.nodeId) {
+            Some(previous) => NodeUpdate::Delta {
+                node_id: node_info.nodeId.clone(),
+                changes: current
+                    .iter()
+                    .filter(|(name, value)| previous.get(*name) != Some(value))
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+            },
+            None => {
+                NodeUpdate::Full(Box::new(serde_json::from_value(Value::Object(current.clone()))?))
+            }
+        };
+        self.sent.insert(node_info.nodeId.clone(), current);
+        Ok(update)
+    }
+
+    /// Make the next update of the node a [NodeUpdate::Full], e.g., because the
+    /// backend rejected the last one.
+    pub fn reset(&mut self, node_id: &str) { self.sent.remove(node_id); }
+
+    /// Make the next update of every node a [NodeUpdate::Full], e.g., because
+    /// the connection was reestablished.
+    pub fn clear(&mut self) { self.sent.clear(); }
+}
+
+/// Reconstructs the data of the nodes from the updates received on a
+/// connection.
+#[derive(Default)]
+pub struct DeltaDecoder {
+    /// The fields of each node as of the last update received.
+    received: HashMap<String, Map<String, Value>>,
+}
+
+impl DeltaDecoder {
+    /// Apply the update and return the resulting data of the node.
+    pub fn decode(&mut self, update: NodeUpdate) -> anyhow::Result<NodeInfo> {
+        let fields = match update {
+            NodeUpdate::Full(node_info) => fields(&node_info)?,
+            NodeUpdate::Delta {
+                node_id,
+                changes,
+            } => {
+                let mut fields = self
+                    .received
+                    .remove(&node_id)
+                    .context("Delta update for a node without a full update")?;
+                for (name, value) in changes {
+                    let field = fields.get_mut(&name).with_context(|| {
+                        format!("Delta update contains the unknown field '{}'", name)
+                    })?;
+                    *field = value;
+                }
+                fields
+            }
+        };
+        let node_info: NodeInfo = serde_json::from_value(Value::Object(fields.clone()))
+            .context("Invalid delta update")?;
+        self.received.insert(node_info.nodeId.clone(), fields);
+        Ok(node_info)
+    }
+
+    /// Forget the data of the node, so the next update of it must be a
+    /// [NodeUpdate::Full].
+    pub fn reset(&mut self, node_id: &str) { self.received.remove(node_id); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_node_info;
+
+    /// Send the update through msgpack, as on the wire.
+    fn roundtrip(update: NodeUpdate) -> anyhow::Result<NodeUpdate> {
+        Ok(rmp_serde::from_slice(&rmp_serde::to_vec(&update)?)?)
+    }
+
+    #[test]
+    fn deltas_reconstruct_node_info() -> anyhow::Result<()> {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::default();
+
+        let mut node = test_node_info("a", "6.3.0", 100);
+        node.averagePing = Some(12.5);
+        let update = roundtrip(encoder.encode(&node)?)?;
+        assert!(matches!(update, NodeUpdate::Full(_)));
+        assert_eq!(decoder.decode(update)?.finalizedBlockHeight, 100);
+
+        node.finalizedBlockHeight = 101;
+        node.averagePing = None;
+        let update = roundtrip(encoder.encode(&node)?)?;
+        match &update {
+            NodeUpdate::Delta {
+                changes,
+                ..
+            } => {
+                let mut changed: Vec<&str> = changes.keys().map(String::as_str).collect();
+                changed.sort_unstable();
+                assert_eq!(changed, vec!["averagePing", "finalizedBlockHeight"]);
+            }
+            NodeUpdate::Full(_) => panic!("Expected a delta update"),
+        }
+        let decoded = decoder.decode(update)?;
+        assert_eq!((decoded.finalizedBlockHeight, decoded.averagePing), (101, None));
+        assert_eq!(decoded.bestBlockHeight, 100);
+
+        // A delta for a node the decoder does not know is rejected.
+        decoder.reset("a");
+        assert!(decoder.decode(roundtrip(encoder.encode(&node)?)?).is_err());
+        encoder.reset("a");
+        assert!(matches!(encoder.encode(&node)?, NodeUpdate::Full(_)));
+        Ok(())
+    }
+}
@@ -1,11 +1,27 @@
 use anyhow::{anyhow, bail};
 use chrono::{SecondsFormat, TimeZone};
-use collector_backend::{IsInBakingCommittee, NodeInfo};
+use collector_backend::{
+    stream::{DeltaEncoder, StreamAck, StreamFrame},
+    submission_message, IsInBakingCommittee, NodeInfo, PUBLIC_KEY_HEADER, SIGNATURE_HEADER,
+    TIMESTAMP_HEADER,
+};
+use ed25519_dalek::{Signer, SigningKey};
 use env_logger::{Builder, Env};
-use futures::TryStreamExt;
+use futures::{SinkExt, StreamExt, TryStreamExt};
 use log::LevelFilter;
-use std::{borrow::ToOwned, fmt, io::Write, process::exit, str::FromStr, time::Duration};
+use serde::Serialize;
+use std::{
+    borrow::ToOwned,
+    fmt, fs,
+    io::{LineWriter, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    time::Duration,
+};
 use structopt::StructOpt;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
 use tonic::transport::{channel::Channel, ClientTlsConfig};
 
 #[macro_use]
@@ -44,6 +60,49 @@ struct ConfigCli {
         env = "CONCORDIUM_NODE_COLLECTOR_URL"
     )]
     pub collector_url:          String,
+    #[structopt(
+        long = "collector-stream-url",
+        help = "WebSocket URL of the collector backend to stream the data to, e.g. \
+                'ws://localhost:3000/stream/nodes'. If given, the data is sent over a long-lived \
+                connection instead of being posted to --collector-url.",
+        env = "CONCORDIUM_NODE_COLLECTOR_STREAM_URL"
+    )]
+    pub collector_stream_url:   Option<String>,
+    #[structopt(
+        long = "stream-max-backoff",
+        help = "Maximum time (in ms) to wait before reconnecting to the collector backend when \
+                streaming",
+        default_value = "60000",
+        env = "CONCORDIUM_NODE_COLLECTOR_STREAM_MAX_BACKOFF"
+    )]
+    pub stream_max_backoff:     u64,
+    #[structopt(
+        long = "stream-queue-size",
+        help = "Maximum number of collected updates waiting to be streamed to the collector \
+                backend. Further updates are dropped until the backend catches up.",
+        default_value = "100",
+        env = "CONCORDIUM_NODE_COLLECTOR_STREAM_QUEUE_SIZE"
+    )]
+    pub stream_queue_size:      usize,
+    #[structopt(
+        long = "collect",
+        help = "The data to collect from the nodes. Possible values are consensus, peers, blocks \
+                and ancestors, where ancestors requires consensus. Information about the node \
+                itself is always collected. The collector backend requires all the data, so only \
+                part of it can be collected when writing to an output file.",
+        default_value = "consensus,peers,blocks,ancestors",
+        env = "CONCORDIUM_NODE_COLLECTOR_COLLECT",
+        use_delimiter = true
+    )]
+    pub metric_sets:            Vec<MetricSet>,
+    #[structopt(
+        long = "output-file",
+        help = "Write the collected data as JSON lines to this file instead of sending it to the \
+                collector backend. Use '-' to write to stdout.",
+        env = "CONCORDIUM_NODE_COLLECTOR_OUTPUT_FILE",
+        conflicts_with = "collector-stream-url"
+    )]
+    pub output_file:            Option<PathBuf>,
     #[structopt(
         long = "print-config",
         help = "Print out config struct",
@@ -89,6 +148,14 @@ struct ConfigCli {
         env = "CONCORDIUM_NODE_COLLECTOR_GRPC_TIMEOUT"
     )]
     pub grpc_timeout:           u64,
+    #[structopt(
+        long = "signing-key-file",
+        help = "Path to a file containing the hex encoded ed25519 key used to sign the data sent \
+                to the collector backend. A new key is generated and written to the file if it \
+                does not exist. If not given, the data is sent unsigned.",
+        env = "CONCORDIUM_NODE_COLLECTOR_SIGNING_KEY_FILE"
+    )]
+    pub signing_key_file:       Option<PathBuf>,
     #[cfg(target_os = "macos")]
     #[structopt(
         long = "use-mac-log",
@@ -126,11 +193,77 @@ async fn main() {
         exit(1);
     }
 
+    if conf.metric_sets.contains(&MetricSet::Ancestors)
+        && !conf.metric_sets.contains(&MetricSet::Consensus)
+    {
+        error!("Collecting ancestors requires collecting consensus.");
+        exit(1);
+    }
+
+    if conf.output_file.is_none()
+        && !MetricSet::ALL.iter().all(|set| conf.metric_sets.contains(set))
+    {
+        error!(
+            "The collector backend requires all the data, so only part of it can be collected \
+             with --output-file."
+        );
+        exit(1);
+    }
+
+    let mut output = match conf.output_file.as_deref().map(open_output) {
+        Some(Ok(output)) => Some(output),
+        Some(Err(e)) => {
+            error!("Could not open the output file: {}", e);
+            exit(1);
+        }
+        None => None,
+    };
+
+    let signing_key = match conf.signing_key_file.as_deref().map(load_or_create_signing_key) {
+        Some(Ok(key)) => {
+            info!(
+                "Signing submissions with public key {}",
+                hex::encode(key.verifying_key().as_bytes())
+            );
+            Some(key)
+        }
+        Some(Err(e)) => {
+            error!("Could not load the signing key: {:#}", e);
+            exit(1);
+        }
+        None => None,
+    };
+
     if conf.artificial_start_delay > 0 {
         info!("Delaying first collection from the node for {} ms", conf.artificial_start_delay);
         tokio::time::sleep(Duration::from_millis(conf.artificial_start_delay)).await;
     }
 
+    // When streaming, the collected data is queued for the task maintaining the
+    // connection to the collector backend. Otherwise it is posted on every
+    // collection.
+    let stream_sender = conf.collector_stream_url.clone().map(|url| {
+        let (sender, receiver) = mpsc::channel(conf.stream_queue_size);
+        tokio::spawn(stream_node_info(
+            url,
+            signing_key.clone(),
+            receiver,
+            Duration::from_millis(conf.stream_max_backoff),
+        ));
+        sender
+    });
+    let client = match reqwest::Client::builder()
+        .connect_timeout(Duration::from_millis(conf.collector_interval))
+        .timeout(Duration::from_millis(conf.collector_interval))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Error constructing a network client: {}", e);
+            exit(1);
+        }
+    };
+
     let mut interval = tokio::time::interval(Duration::from_millis(conf.collector_interval));
     // If for some reason we cannot submit the statistics for a given period, we
     // skip it. This also handles cases such as when a computer goes to sleep. By
@@ -145,19 +278,37 @@ async fn main() {
             match collect_data(node_name.clone(), grpc_host.to_owned(), &conf).await {
                 Ok(node_info) => {
                     trace!("Node data collected successfully from {}/{}", node_name, grpc_host);
+                    if let Some(output) = &mut output {
+                        if let Err(e) = write_output(output, &node_info, &conf.metric_sets) {
+                            error!("Error writing the data to the output due to \"{}\"", e);
+                        }
+                        continue;
+                    }
+                    if let Some(sender) = &stream_sender {
+                        // Drop the update rather than delaying the collection if the
+                        // backend does not keep up. The next update of the node contains
+                        // the changes anyway.
+                        if let Err(e) = sender.try_send(node_info) {
+                            warn!("Dropping the data of {} as the stream is full", node_name);
+                            trace!("Stream error: {}", e);
+                        }
+                        continue;
+                    }
                     match rmp_serde::encode::to_vec(&node_info) {
                         Ok(msgpack) => {
-                            let client_builder = reqwest::Client::builder()
-                                .connect_timeout(Duration::from_millis(conf.collector_interval))
-                                .timeout(Duration::from_millis(conf.collector_interval));
-                            let client = match client_builder.build() {
-                                Ok(client) => client,
-                                Err(e) => {
-                                    error!("Error constructing a network client: {}", e);
-                                    continue;
-                                }
-                            };
-                            match client.post(&conf.collector_url).body(msgpack).send().await {
+                            let mut request = client.post(&conf.collector_url);
+                            if let Some(key) = &signing_key {
+                                let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+                                let signature = key.sign(&submission_message(timestamp, &msgpack));
+                                request = request
+                                    .header(
+                                        PUBLIC_KEY_HEADER,
+                                        hex::encode(key.verifying_key().as_bytes()),
+                                    )
+                                    .header(TIMESTAMP_HEADER, timestamp.to_string())
+                                    .header(SIGNATURE_HEADER, hex::encode(signature.to_bytes()));
+                            }
+                            match request.body(msgpack).send().await {
                                 Ok(v) if v.status().is_success() => {
                                     trace!("Payload sent successfully to collector backend.")
                                 }
@@ -206,6 +357,7 @@ async fn collect_data<'a>(
     };
 
     let mut client = grpc::queries_client::QueriesClient::connect(channel.clone()).await?;
+    let metrics = &conf.metric_sets;
 
     // Blocks
     let best = grpc::BlockHashInput {
@@ -215,88 +367,114 @@ async fn collect_data<'a>(
         block_hash_input: Some(grpc::block_hash_input::BlockHashInput::LastFinal(grpc::Empty {})),
     };
 
-    // Client calls
+    // Client calls. The node info is always collected, as it identifies the node.
     let node_info = client.get_node_info(grpc::Empty {}).await?.into_inner();
-    let peers = client.get_peers_info(grpc::Empty {}).await?.into_inner().peers;
-    let consensus = client.get_consensus_info(grpc::Empty {}).await?.into_inner();
-    let best_block = client.get_block_info(best.clone()).await?.into_inner();
-    let finalized_block = client.get_block_info(last_final).await?.into_inner();
-    let tokenomics_info =
-        client.get_tokenomics_info(get_block_hash_input(best_block.hash)?).await?.into_inner();
+    let peers = if metrics.contains(&MetricSet::Peers) {
+        Some(client.get_peers_info(grpc::Empty {}).await?.into_inner().peers)
+    } else {
+        None
+    };
+    let consensus = if metrics.contains(&MetricSet::Consensus) {
+        Some(client.get_consensus_info(grpc::Empty {}).await?.into_inner())
+    } else {
+        None
+    };
+    let blocks = if metrics.contains(&MetricSet::Blocks) {
+        let best_block = client.get_block_info(best.clone()).await?.into_inner();
+        let finalized_block = client.get_block_info(last_final).await?.into_inner();
+        let tokenomics_info = client
+            .get_tokenomics_info(get_block_hash_input(best_block.hash.clone())?)
+            .await?
+            .into_inner();
+        Some((best_block, finalized_block, tokenomics_info))
+    } else {
+        None
+    };
 
     // Helper variables
-    let latencies = peers
-        .iter()
-        .map(|p| Ok(p.network_stats.req()?.latency))
-        .collect::<anyhow::Result<Vec<u64>>>()?;
-    let valid_latencies: Vec<u64> = latencies.clone().into_iter().filter(|x| *x != 0).collect();
-    let latency_sum = valid_latencies.iter().sum::<u64>() as f64;
-
     let peer_type = match node_info.details.req()? {
         NodeDetails::Bootstrapper(_) => "Bootstrapper",
         NodeDetails::Node(_) => "Node",
     };
-    let peer_list = peers
-        .iter()
-        .map(|p| Ok(p.peer_id.req()?.value.clone()))
-        .collect::<anyhow::Result<Vec<_>>>()?;
+    let (average_ping, peers_count, peer_list) = match &peers {
+        Some(peers) => {
+            let latencies = peers
+                .iter()
+                .map(|p| Ok(p.network_stats.req()?.latency))
+                .collect::<anyhow::Result<Vec<u64>>>()?;
+            let valid_latencies: Vec<u64> =
+                latencies.clone().into_iter().filter(|x| *x != 0).collect();
+            let latency_sum = valid_latencies.iter().sum::<u64>() as f64;
+            let peer_list = peers
+                .iter()
+                .map(|p| Ok(p.peer_id.req()?.value.clone()))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            (checked_div(latency_sum, valid_latencies.len() as f64), peers.len() as u64, peer_list)
+        }
+        None => (None, 0, Vec::new()),
+    };
 
     let (consensus_is_running, baker_id, committee_info, final_committee_info) =
         get_node_baker_info(&node_info)?;
 
-    let (total_amount, total_encrypted_amount, foundation_amount) =
-        match tokenomics_info.tokenomics.req()?.clone() {
+    let (total_amount, total_encrypted_amount, foundation_amount) = match &blocks {
+        Some((_, _, tokenomics_info)) => match tokenomics_info.tokenomics.req()?.clone() {
             Tokenomics::V0(t) => (t.total_amount, t.total_encrypted_amount, None),
             Tokenomics::V1(t) => {
                 (t.total_amount, t.total_encrypted_amount, t.foundation_transaction_rewards)
             }
-        };
+        },
+        None => (None, None, None),
+    };
+
+    let ancestors_since_best_block = match &consensus {
+        Some(consensus) if metrics.contains(&MetricSet::Ancestors) => {
+            let best_block_height = consensus.best_block_height.req()?.value;
+            let last_finalized_block_height = consensus.last_finalized_block_height.req()?.value;
+            if best_block_height > last_finalized_block_height {
+                let height_diff = best_block_height - last_finalized_block_height;
 
-    let ancestors_since_best_block = {
-        let best_block_height = consensus.best_block_height.req()?.value;
-        let last_finalized_block_height = consensus.last_finalized_block_height.req()?.value;
-        if best_block_height > last_finalized_block_height {
-            let height_diff = best_block_height - last_finalized_block_height;
-
-            let req = grpc::AncestorsRequest {
-                block_hash: Some(best),
-                amount:     height_diff,
-            };
-            let ancestors = client.get_ancestors(req).await?.into_inner();
-            let hex_ancestors: Vec<String> =
-                ancestors.map_ok(|x| hex::encode(x.value)).try_collect().await?;
-            Some(hex_ancestors)
-        } else {
-            None
+                let req = grpc::AncestorsRequest {
+                    block_hash: Some(best),
+                    amount:     height_diff,
+                };
+                let ancestors = client.get_ancestors(req).await?.into_inner();
+                let hex_ancestors: Vec<String> =
+                    ancestors.map_ok(|x| hex::encode(x.value)).try_collect().await?;
+                Some(hex_ancestors)
+            } else {
+                None
+            }
         }
+        _ => None,
     };
 
-    Ok(NodeInfo {
+    let mut result = NodeInfo {
         nodeName: node_name.to_string(),
         nodeId: node_info.network_info.req()?.node_id.req()?.value.clone(),
         peerType: peer_type.to_string(),
         uptime: node_info.peer_uptime.req()?.value,
-        client: node_info.peer_version,
-        averagePing: checked_div(latency_sum, valid_latencies.len() as f64),
-        peersCount: peers.len() as u64,
+        client: node_info.peer_version.clone(),
+        averagePing: average_ping,
+        peersCount: peers_count,
         peersList: peer_list,
-        bestBlock: hash_to_hex(consensus.best_block)?,
-        bestBlockHeight: consensus.best_block_height.req()?.value,
-        bestBlockBakerId: best_block.baker.map(|id| id.value),
-        bestArrivedTime: Some(from_unix(best_block.arrive_time.req()?.clone())?),
-        blockArrivePeriodEMA: consensus.block_arrive_period_ema,
-        blockArrivePeriodEMSD: consensus.block_arrive_period_emsd,
-        blockArriveLatencyEMA: Some(consensus.block_arrive_latency_ema),
-        blockArriveLatencyEMSD: Some(consensus.block_arrive_latency_emsd),
-        blockReceivePeriodEMA: consensus.block_receive_period_ema,
-        blockReceivePeriodEMSD: consensus.block_receive_period_emsd,
-        blockReceiveLatencyEMA: Some(consensus.block_receive_latency_ema),
-        blockReceiveLatencyEMSD: Some(consensus.block_receive_latency_emsd),
-        finalizedBlock: hash_to_hex(consensus.last_finalized_block)?,
-        finalizedBlockHeight: consensus.last_finalized_block_height.req()?.value,
-        finalizedTime: consensus.last_finalized_time.map(from_unix).transpose()?,
-        finalizationPeriodEMA: consensus.finalization_period_ema,
-        finalizationPeriodEMSD: consensus.finalization_period_emsd,
+        bestBlock: String::new(),
+        bestBlockHeight: 0,
+        bestBlockBakerId: None,
+        bestArrivedTime: None,
+        blockArrivePeriodEMA: None,
+        blockArrivePeriodEMSD: None,
+        blockArriveLatencyEMA: None,
+        blockArriveLatencyEMSD: None,
+        blockReceivePeriodEMA: None,
+        blockReceivePeriodEMSD: None,
+        blockReceiveLatencyEMA: None,
+        blockReceiveLatencyEMSD: None,
+        finalizedBlock: String::new(),
+        finalizedBlockHeight: 0,
+        finalizedTime: None,
+        finalizationPeriodEMA: None,
+        finalizationPeriodEMSD: None,
         packetsSent: node_info.network_info.req()?.peer_total_sent,
         packetsReceived: node_info.network_info.req()?.peer_total_received,
         consensusRunning: consensus_is_running,
@@ -306,27 +484,248 @@ async fn collect_data<'a>(
         ancestorsSinceBestBlock: ancestors_since_best_block,
         stagingNetUsername: None,
         last_updated: 0,
-        transactionsPerBlockEMA: Some(consensus.transactions_per_block_ema),
-        transactionsPerBlockEMSD: Some(consensus.transactions_per_block_emsd),
-        bestBlockTransactionsSize: Some(best_block.transactions_size.into()),
-        bestBlockTotalEncryptedAmount: Some(total_encrypted_amount.req()?.value),
-        bestBlockTotalAmount: Some(total_amount.req()?.value),
-        bestBlockTransactionCount: Some(best_block.transaction_count.into()),
-        bestBlockTransactionEnergyCost: Some(best_block.transactions_energy_cost.req()?.value),
+        public_key: None,
+        transactionsPerBlockEMA: None,
+        transactionsPerBlockEMSD: None,
+        bestBlockTransactionsSize: None,
+        bestBlockTotalEncryptedAmount: None,
+        bestBlockTotalAmount: None,
+        bestBlockTransactionCount: None,
+        bestBlockTransactionEnergyCost: None,
         bestBlockExecutionCost: None,
-        bestBlockCentralBankAmount: foundation_amount.map(|x| x.value),
-        blocksReceivedCount: Some(consensus.blocks_received_count.into()),
-        blocksVerifiedCount: Some(consensus.blocks_verified_count.into()),
-        finalizationCount: Some(consensus.finalization_count.into()),
-        genesisBlock: hash_to_hex(consensus.genesis_block)?,
+        bestBlockCentralBankAmount: None,
+        blocksReceivedCount: None,
+        blocksVerifiedCount: None,
+        finalizationCount: None,
+        genesisBlock: String::new(),
         averageBytesPerSecondIn: node_info.network_info.req()?.avg_bps_in,
         averageBytesPerSecondOut: node_info.network_info.req()?.avg_bps_out,
-        finalizedBlockParent: hash_to_hex(finalized_block.parent_block)?,
-    })
+        finalizedBlockParent: String::new(),
+    };
+
+    if let Some(consensus) = consensus {
+        result.bestBlock = hash_to_hex(consensus.best_block)?;
+        result.bestBlockHeight = consensus.best_block_height.req()?.value;
+        result.blockArrivePeriodEMA = consensus.block_arrive_period_ema;
+        result.blockArrivePeriodEMSD = consensus.block_arrive_period_emsd;
+        result.blockArriveLatencyEMA = Some(consensus.block_arrive_latency_ema);
+        result.blockArriveLatencyEMSD = Some(consensus.block_arrive_latency_emsd);
+        result.blockReceivePeriodEMA = consensus.block_receive_period_ema;
+        result.blockReceivePeriodEMSD = consensus.block_receive_period_emsd;
+        result.blockReceiveLatencyEMA = Some(consensus.block_receive_latency_ema);
+        result.blockReceiveLatencyEMSD = Some(consensus.block_receive_latency_emsd);
+        result.finalizedBlock = hash_to_hex(consensus.last_finalized_block)?;
+        result.finalizedBlockHeight = consensus.last_finalized_block_height.req()?.value;
+        result.finalizedTime = consensus.last_finalized_time.map(from_unix).transpose()?;
+        result.finalizationPeriodEMA = consensus.finalization_period_ema;
+        result.finalizationPeriodEMSD = consensus.finalization_period_emsd;
+        result.transactionsPerBlockEMA = Some(consensus.transactions_per_block_ema);
+        result.transactionsPerBlockEMSD = Some(consensus.transactions_per_block_emsd);
+        result.blocksReceivedCount = Some(consensus.blocks_received_count.into());
+        result.blocksVerifiedCount = Some(consensus.blocks_verified_count.into());
+        result.finalizationCount = Some(consensus.finalization_count.into());
+        result.genesisBlock = hash_to_hex(consensus.genesis_block)?;
+    }
+
+    if let Some((best_block, finalized_block, _)) = blocks {
+        result.bestBlockBakerId = best_block.baker.map(|id| id.value);
+        result.bestArrivedTime = Some(from_unix(best_block.arrive_time.req()?.clone())?);
+        result.bestBlockTransactionsSize = Some(best_block.transactions_size.into());
+        result.bestBlockTotalEncryptedAmount = Some(total_encrypted_amount.req()?.value);
+        result.bestBlockTotalAmount = Some(total_amount.req()?.value);
+        result.bestBlockTransactionCount = Some(best_block.transaction_count.into());
+        result.bestBlockTransactionEnergyCost =
+            Some(best_block.transactions_energy_cost.req()?.value);
+        result.bestBlockCentralBankAmount = foundation_amount.map(|x| x.value);
+        result.finalizedBlockParent = hash_to_hex(finalized_block.parent_block)?;
+    }
+
+    Ok(result)
+}
+
+/// Time to wait before the first attempt to reconnect to the collector
+/// backend when streaming.
+const STREAM_MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Stream the data of nodes received on `receiver` to the collector backend at
+/// `url` over a WebSocket, see [collector_backend::stream]. The connection is
+/// reestablished with exponential backoff, up to `max_backoff`, when it fails.
+async fn stream_node_info(
+    url: String,
+    signing_key: Option<SigningKey>,
+    mut receiver: mpsc::Receiver<NodeInfo>,
+    max_backoff: Duration,
+) {
+    let mut backoff = STREAM_MIN_BACKOFF;
+    let mut encoder = DeltaEncoder::default();
+    loop {
+        // The backend only knows the nodes sent on the current connection.
+        encoder.clear();
+        match stream_connection(
+            &url,
+            signing_key.as_ref(),
+            &mut receiver,
+            &mut encoder,
+            &mut backoff,
+        )
+        .await
+        {
+            Ok(()) => {
+                info!("Stopped streaming to the collector backend");
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "Streaming to the collector backend failed due to \"{}\", reconnecting in {} \
+                     ms",
+                    e,
+                    backoff.as_millis()
+                );
+            }
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, max_backoff);
+    }
+}
+
+/// Stream the data of nodes over a single connection. Returns when the sender
+/// of the updates is dropped, or with an error when the connection fails.
+async fn stream_connection(
+    url: &str,
+    signing_key: Option<&SigningKey>,
+    receiver: &mut mpsc::Receiver<NodeInfo>,
+    encoder: &mut DeltaEncoder,
+    backoff: &mut Duration,
+) -> anyhow::Result<()> {
+    let mut request = url.into_client_request()?;
+    if let Some(key) = signing_key {
+        request
+            .headers_mut()
+            .insert(PUBLIC_KEY_HEADER, hex::encode(key.verifying_key().as_bytes()).parse()?);
+    }
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+    info!("Streaming to the collector backend at {}", url);
+    *backoff = STREAM_MIN_BACKOFF;
+    while let Some(node_info) = receiver.recv().await {
+        let update = rmp_serde::to_vec(&encoder.encode(&node_info)?)?;
+        let (timestamp, signature) = match signing_key {
+            Some(key) => {
+                let timestamp = chrono::Utc::now().timestamp_millis() as u64;
+                let signature = key.sign(&submission_message(timestamp, &update));
+                (Some(timestamp), Some(signature.to_bytes().to_vec()))
+            }
+            None => (None, None),
+        };
+        let frame = StreamFrame {
+            timestamp,
+            signature,
+            update,
+        };
+        socket.send(Message::Binary(rmp_serde::to_vec(&frame)?)).await?;
+        // Wait for the backend to process the update before sending the next one.
+        let ack = loop {
+            match socket.next().await {
+                Some(Ok(Message::Binary(ack))) => break rmp_serde::from_slice(&ack)?,
+                Some(Ok(Message::Close(_))) | None => bail!("Connection closed by the backend"),
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            }
+        };
+        match ack {
+            StreamAck::Accepted => trace!("Payload streamed successfully to collector backend."),
+            StreamAck::Rejected(reason) => {
+                error!("The collector backend rejected the data of a node: {}", reason);
+                // The backend discards the node, so the next update must be complete.
+                encoder.reset(&node_info.nodeId);
+            }
+        }
+    }
+    socket.close(None).await?;
+    Ok(())
+}
+
+/// A record of the collected data, as written to the output file.
+#[derive(Serialize)]
+struct OutputRecord {
+    /// The time the data was collected.
+    timestamp: String,
+    /// The fields of the collected data. Fields of data that was not collected
+    /// are left out.
+    #[serde(flatten)]
+    node_info: serde_json::Map<String, serde_json::Value>,
+}
+
+impl OutputRecord {
+    fn new(
+        timestamp: String,
+        node_info: &NodeInfo,
+        metric_sets: &[MetricSet],
+    ) -> anyhow::Result<Self> {
+        let mut node_info = match serde_json::to_value(node_info)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => bail!("The node data is not serialized as an object"),
+        };
+        for set in MetricSet::ALL.iter().filter(|set| !metric_sets.contains(set)) {
+            for field in set.fields() {
+                node_info.remove(*field);
+            }
+        }
+        Ok(OutputRecord {
+            timestamp,
+            node_info,
+        })
+    }
+}
+
+/// Open the output file for appending, or stdout if the path is `-`.
+fn open_output(path: &Path) -> anyhow::Result<Box<dyn Write + Send>> {
+    if path == Path::new("-") {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Box::new(LineWriter::new(file)))
+    }
+}
+
+/// Write the collected data of a node to the output as a line of JSON.
+fn write_output(
+    output: &mut impl Write,
+    node_info: &NodeInfo,
+    metric_sets: &[MetricSet],
+) -> anyhow::Result<()> {
+    let record = OutputRecord::new(
+        chrono::Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        node_info,
+        metric_sets,
+    )?;
+    writeln!(output, "{}", serde_json::to_string(&record)?)?;
+    output.flush()?;
+    Ok(())
 }
 
 // Helper functions and helper traits
 
+/// Read the hex encoded signing key from the file, or generate a new key and
+/// write it to the file if the file does not exist.
+fn load_or_create_signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    if path.exists() {
+        let bytes: [u8; 32] = hex::decode(fs::read_to_string(path)?.trim())?
+            .try_into()
+            .map_err(|_| anyhow!("The signing key must be 32 bytes"))?;
+        Ok(SigningKey::from_bytes(&bytes))
+    } else {
+        let key = SigningKey::from_bytes(&rand::random());
+        // The key must only be readable by the owner.
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(hex::encode(key.to_bytes()).as_bytes())?;
+        info!("Generated a new signing key in {}", path.display());
+        Ok(key)
+    }
+}
+
 /// Return whether the consensus is running, the baker id if running as a baker,
 /// whether the node is in a baking committee, and whether the node is in the
 /// finalization committee.
@@ -412,6 +811,82 @@ impl<A> Require for Option<A> {
     }
 }
 
+/// A set of data that can be collected from a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MetricSet {
+    /// Block heights, block and finalization statistics from the consensus
+    /// info.
+    Consensus,
+    /// Peer count, peer list and ping.
+    Peers,
+    /// Information about the best and last finalized blocks, and tokenomics.
+    Blocks,
+    /// The ancestors of the best block down to the last finalized block.
+    Ancestors,
+}
+
+impl MetricSet {
+    const ALL: [MetricSet; 4] =
+        [MetricSet::Consensus, MetricSet::Peers, MetricSet::Blocks, MetricSet::Ancestors];
+
+    /// The fields of [NodeInfo] that are filled in from this set of data.
+    fn fields(self) -> &'static [&'static str] {
+        match self {
+            MetricSet::Consensus => &[
+                "bestBlock",
+                "bestBlockHeight",
+                "blockArrivePeriodEMA",
+                "blockArrivePeriodEMSD",
+                "blockArriveLatencyEMA",
+                "blockArriveLatencyEMSD",
+                "blockReceivePeriodEMA",
+                "blockReceivePeriodEMSD",
+                "blockReceiveLatencyEMA",
+                "blockReceiveLatencyEMSD",
+                "finalizedBlock",
+                "finalizedBlockHeight",
+                "finalizedTime",
+                "finalizationPeriodEMA",
+                "finalizationPeriodEMSD",
+                "transactionsPerBlockEMA",
+                "transactionsPerBlockEMSD",
+                "blocksReceivedCount",
+                "blocksVerifiedCount",
+                "finalizationCount",
+                "genesisBlock",
+            ],
+            MetricSet::Peers => &["averagePing", "peersCount", "peersList"],
+            MetricSet::Blocks => &[
+                "bestBlockBakerId",
+                "bestArrivedTime",
+                "bestBlockTransactionsSize",
+                "bestBlockTotalEncryptedAmount",
+                "bestBlockTotalAmount",
+                "bestBlockTransactionCount",
+                "bestBlockTransactionEnergyCost",
+                "bestBlockExecutionCost",
+                "bestBlockCentralBankAmount",
+                "finalizedBlockParent",
+            ],
+            MetricSet::Ancestors => &["ancestorsSinceBestBlock"],
+        }
+    }
+}
+
+impl FromStr for MetricSet {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "consensus" => Ok(MetricSet::Consensus),
+            "peers" => Ok(MetricSet::Peers),
+            "blocks" => Ok(MetricSet::Blocks),
+            "ancestors" => Ok(MetricSet::Ancestors),
+            _ => bail!("Unknown data to collect '{}'", input),
+        }
+    }
+}
+
 /// Parsing user NodeName input.
 #[derive(Clone, Debug)]
 struct NodeName(Vec<String>);
@@ -481,3 +956,72 @@ pub fn setup_macos_logger(trace: bool, debug: bool, subsystem: &str) {
         .init()
         .expect("Failed to initialise MacOsLogger");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_info() -> NodeInfo {
+        serde_json::from_value(serde_json::json!({
+            "nodeName": "node",
+            "nodeId": "0000000000000001",
+            "peerType": "Node",
+            "uptime": 10,
+            "client": "6.0.0",
+            "averagePing": 12.5,
+            "peersCount": 1,
+            "peersList": ["0000000000000002"],
+            "bestBlock": "aa",
+            "bestBlockHeight": 5,
+            "bestArrivedTime": "2024-01-01T00:00:00Z",
+            "finalizedBlock": "bb",
+            "finalizedBlockHeight": 4,
+            "packetsSent": 0,
+            "packetsReceived": 0,
+            "consensusRunning": true,
+            "bakingCommitteeMember": "NotInCommittee",
+            "finalizationCommitteeMember": false,
+            "ancestorsSinceBestBlock": ["aa"],
+            "genesisBlock": "cc",
+            "finalizedBlockParent": "dd",
+            "averageBytesPerSecondIn": 0,
+            "averageBytesPerSecondOut": 0,
+        }))
+        .expect("Valid node info")
+    }
+
+    #[test]
+    fn test_parse_metric_sets() -> anyhow::Result<()> {
+        let conf = ConfigCli::from_iter_safe(["collector", "--collect", "peers,ancestors"])?;
+        assert_eq!(conf.metric_sets, vec![MetricSet::Peers, MetricSet::Ancestors]);
+        let conf = ConfigCli::from_iter_safe(["collector"])?;
+        assert_eq!(conf.metric_sets, MetricSet::ALL.to_vec());
+        assert!("blocks".parse::<MetricSet>().is_ok());
+        assert!("transactions".parse::<MetricSet>().is_err());
+        assert!(ConfigCli::from_iter_safe(["collector", "--collect", "peers,unknown"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_record() -> anyhow::Result<()> {
+        let record = OutputRecord::new("now".to_owned(), &node_info(), &MetricSet::ALL)?;
+        let value = serde_json::to_value(&record)?;
+        assert_eq!(value["timestamp"], "now");
+        assert_eq!(value["nodeId"], "0000000000000001");
+        assert_eq!(value["peersCount"], 1);
+        assert_eq!(value["bestBlockHeight"], 5);
+        assert_eq!(value["bestArrivedTime"], "2024-01-01T00:00:00Z");
+
+        // the fields of data that is not collected are left out
+        let record = OutputRecord::new("now".to_owned(), &node_info(), &[MetricSet::Peers])?;
+        let value = serde_json::to_value(&record)?;
+        assert_eq!(value["nodeId"], "0000000000000001");
+        assert_eq!(value["peersList"], serde_json::json!(["0000000000000002"]));
+        for set in [MetricSet::Consensus, MetricSet::Blocks, MetricSet::Ancestors] {
+            for field in set.fields() {
+                assert!(value.get(field).is_none(), "{} is not collected", field);
+            }
+        }
+        Ok(())
+    }
+}
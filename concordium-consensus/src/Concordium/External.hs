@@ -321,6 +321,9 @@ type NotifyCallback =
     Word64 ->
     -- | Absolute block height of either the arrived block or finalized depending on the type of event.
     Word64 ->
+    -- | Timestamp (slot time) of either the arrived block or finalized depending on the type of
+    --  event, in milliseconds since the Unix epoch.
+    Word64 ->
     -- | Byte where a value of 1 indicates the block arrived/finalized was baked by the baker ID
     --  setup for this node.
     Word8 ->
@@ -330,27 +333,28 @@ foreign import ccall "dynamic" callNotifyCallback :: FunPtr NotifyCallback -> No
 
 -- | Serialize the provided arguments (block hash, absolute block height) into an appropriate Proto
 --  message, and invoke the provided FFI callback with the additional arguments providing the rust layer:
---  block height and whether it was baked by the node.
-mkNotifyBlockArrived :: (Word8 -> Ptr Word8 -> Word64 -> Word64 -> Word8 -> IO ()) -> BlockHash -> AbsoluteBlockHeight -> Bool -> IO ()
-mkNotifyBlockArrived f = \bh height isHomeBaked -> do
+--  block height, block timestamp and whether it was baked by the node.
+mkNotifyBlockArrived :: (Word8 -> Ptr Word8 -> Word64 -> Word64 -> Word64 -> Word8 -> IO ()) -> BlockHash -> AbsoluteBlockHeight -> Timestamp -> Bool -> IO ()
+mkNotifyBlockArrived f = \bh height timestamp isHomeBaked -> do
     let msg :: Proto.ArrivedBlockInfo = Proto.make $ do
             ProtoFields.hash . ProtoFields.value .= S.encode bh
             ProtoFields.height . ProtoFields.value .= fromIntegral height
     let isHomeBakedByte = if isHomeBaked then 1 else 0
     BS.unsafeUseAsCStringLen (Proto.encodeMessage msg) $ \(cPtr, len) -> do
-        f 0 (castPtr cPtr) (fromIntegral len) (fromIntegral height) isHomeBakedByte
+        f 0 (castPtr cPtr) (fromIntegral len) (fromIntegral height) (tsMillis timestamp) isHomeBakedByte
 
 -- | Serialize the provided arguments (block hash, block height) into an appropriate Proto message,
 --  and invoke the provided FFI callback with the additional arguments providing the rust layer:
---  block height, whether it was baked by the same baker ID as the one setup for the node.
-mkNotifyBlockFinalized :: (Word8 -> Ptr Word8 -> Word64 -> Word64 -> Word8 -> IO ()) -> BlockHash -> AbsoluteBlockHeight -> Bool -> IO ()
-mkNotifyBlockFinalized f = \bh height isHomeBaked -> do
+--  block height, block timestamp and whether it was baked by the same baker ID as the one setup for
+--  the node.
+mkNotifyBlockFinalized :: (Word8 -> Ptr Word8 -> Word64 -> Word64 -> Word64 -> Word8 -> IO ()) -> BlockHash -> AbsoluteBlockHeight -> Timestamp -> Bool -> IO ()
+mkNotifyBlockFinalized f = \bh height timestamp isHomeBaked -> do
     let msg :: Proto.FinalizedBlockInfo = Proto.make $ do
             ProtoFields.hash . ProtoFields.value .= S.encode bh
             ProtoFields.height . ProtoFields.value .= fromIntegral height
     let isHomeBakedByte = if isHomeBaked then 1 else 0
     BS.unsafeUseAsCStringLen (Proto.encodeMessage msg) $ \(cPtr, len) -> do
-        f 1 (castPtr cPtr) (fromIntegral len) (fromIntegral height) isHomeBakedByte
+        f 1 (castPtr cPtr) (fromIntegral len) (fromIntegral height) (tsMillis timestamp) isHomeBakedByte
 
 -- | Context for when signalling a unsupported protocol update is pending.
 data NotifyUnsupportedUpdatesContext
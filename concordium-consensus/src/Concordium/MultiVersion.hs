@@ -57,6 +57,7 @@ import Concordium.GlobalState.Parameters
 import qualified Concordium.GlobalState.Persistent.TreeState as SkovV0
 import Concordium.GlobalState.TreeState (PVInit (..), TreeStateMonad (getLastFinalizedHeight))
 import Concordium.ImportExport
+import Concordium.Kontrol (getSlotTimestamp)
 import qualified Concordium.KonsensusV1 as KonsensusV1
 import qualified Concordium.KonsensusV1.Consensus as SkovV1
 import qualified Concordium.KonsensusV1.Consensus.Blocks as SkovV1
@@ -133,7 +134,8 @@ instance
                 let isHomeBaked = case nodeBakerIdMaybe of
                         Nothing -> False
                         Just nodeBakerId -> Just nodeBakerId == (blockBaker <$> blockFields (_bpBlock bp))
-                liftIO (notifyCallback (bpHash bp) height isHomeBaked)
+                timestamp <- getSlotTimestamp (blockSlot bp)
+                liftIO (notifyCallback (bpHash bp) height timestamp isHomeBaked)
 
     -- Notice that isHomeBaked (in the code below) represents whether this block is baked by the
     -- baker ID of this node and it could be the case that the block was not baked by this node,
@@ -156,12 +158,14 @@ instance
                     let isHomeBaked = case nodeBakerIdMaybe of
                             Nothing -> False
                             Just nodeBakerId -> Just nodeBakerId == (blockBaker <$> blockFields (_bpBlock bp))
-                    liftIO (notifyCallback (bpHash bp) height isHomeBaked)
+                    timestamp <- getSlotTimestamp (blockSlot bp)
+                    liftIO (notifyCallback (bpHash bp) height timestamp isHomeBaked)
                 let height = localToAbsoluteBlockHeight latestEraGenesisHeight (bpHeight lfbp)
                 let isHomeBaked = case nodeBakerIdMaybe of
                         Nothing -> False
                         Just myBakerId -> Just myBakerId == (blockBaker <$> blockFields (_bpBlock lfbp))
-                liftIO (notifyCallback (bpHash lfbp) height isHomeBaked)
+                timestamp <- getSlotTimestamp (blockSlot lfbp)
+                liftIO (notifyCallback (bpHash lfbp) height timestamp isHomeBaked)
         -- And then check for protocol update.
         checkForProtocolUpdateV0
 
@@ -239,11 +243,13 @@ data Callbacks = Callbacks
       --  if an unrecognized update took effect.
       notifyRegenesis :: Maybe BlockHash -> IO (),
       -- | Notify a block was added to the tree. The arguments are
-      --  the hash of the block, its absolute height and whether the block was produced by the baker id configured for this node.
-      notifyBlockArrived :: Maybe (BlockHash -> AbsoluteBlockHeight -> Bool -> IO ()),
+      --  the hash of the block, its absolute height, its timestamp (slot time) and whether the block
+      --  was produced by the baker id configured for this node.
+      notifyBlockArrived :: Maybe (BlockHash -> AbsoluteBlockHeight -> Timestamp -> Bool -> IO ()),
       -- | Notify a block was finalized. The arguments are the hash of the block,
-      --  its absolute height and whether the block was produced by the baker id configured for this node.
-      notifyBlockFinalized :: Maybe (BlockHash -> AbsoluteBlockHeight -> Bool -> IO ()),
+      --  its absolute height, its timestamp (slot time) and whether the block was produced by the
+      --  baker id configured for this node.
+      notifyBlockFinalized :: Maybe (BlockHash -> AbsoluteBlockHeight -> Timestamp -> Bool -> IO ()),
       -- | Notify unsupported protocol update is pending when called.
       --  Takes the effective time of the update as argument.
       notifyUnsupportedProtocolUpdate :: Maybe (Timestamp -> IO ())
@@ -300,7 +306,7 @@ skovV1Handlers gi genHeight = SkovV1.HandlerContext{..}
                         Just nodeBakerId ->
                             Present nodeBakerId
                                 == (KonsensusV1.blockBaker <$> KonsensusV1.blockBakedData block)
-                liftIO (notifyCallback (getHash block) height isHomeBaked)
+                liftIO (notifyCallback (getHash block) height (KonsensusV1.blockTimestamp block) isHomeBaked)
 
     _onFinalizeHandler _ finalizedBlocks = do
         lift $
@@ -315,7 +321,7 @@ skovV1Handlers gi genHeight = SkovV1.HandlerContext{..}
                                 Just nodeBakerId ->
                                     Present nodeBakerId
                                         == (KonsensusV1.blockBaker <$> KonsensusV1.blockBakedData bp)
-                        liftIO (notifyCallback (getHash bp) height isHomeBaked)
+                        liftIO (notifyCallback (getHash bp) height (KonsensusV1.blockTimestamp bp) isHomeBaked)
         checkForProtocolUpdateV1
 
     _onPendingLiveHandler = do
@@ -530,7 +530,7 @@ fn build_grpc2(proto_root_input: &str) -> std::io::Result<()> {
                 .name("get_peers_info")
                 .route_name("GetPeersInfo")
                 .input_type("crate::grpc2::types::Empty")
-                .output_type("crate::grpc2::types::PeersInfo")
+                .output_type("crate::grpc2::PeersInfoWithTraffic")
                 .codec_path("tonic::codec::ProstCodec")
                 .build(),
         )
@@ -602,7 +602,7 @@ fn build_grpc2(proto_root_input: &str) -> std::io::Result<()> {
                 .name("get_node_info")
                 .route_name("GetNodeInfo")
                 .input_type("crate::grpc2::types::Empty")
-                .output_type("crate::grpc2::types::NodeInfo")
+                .output_type("crate::grpc2::NodeInfoWithConnections")
                 .codec_path("tonic::codec::ProstCodec")
                 .build(),
         )
@@ -675,10 +675,35 @@ fn build_grpc2(proto_root_input: &str) -> std::io::Result<()> {
                 .build(),
         )
         .build();
+    // The administrative endpoints that are specific to this node.
+    let admin_service = tonic_build::manual::Service::builder()
+        .name("Admin")
+        .package("concordium.v2")
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("update_dynamic_config")
+                .route_name("UpdateDynamicConfig")
+                .input_type("crate::grpc2::UpdateDynamicConfigRequest")
+                .output_type("crate::grpc2::types::Empty")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .method(
+            tonic_build::manual::Method::builder()
+                .name("dump_start_with_filters")
+                .route_name("DumpStartWithFilters")
+                .input_type("crate::grpc2::DumpStartWithFiltersRequest")
+                .output_type("crate::grpc2::types::Empty")
+                .codec_path("tonic::codec::ProstCodec")
+                .build(),
+        )
+        .build();
     // Due to the slightly hacky nature of the RawCodec (i.e., it does not support
     // deserialization) we cannot build the client. But we also don't need it in the
     // node.
-    tonic_build::manual::Builder::new().build_client(false).compile(&[query_service]);
+    tonic_build::manual::Builder::new()
+        .build_client(false)
+        .compile(&[query_service, admin_service]);
 
     {
         let health = format!("{}/v2/concordium/health.proto", proto_root_input);
@@ -5,7 +5,7 @@ use std::{alloc::System, sync::Arc};
 #[global_allocator]
 static A: System = System;
 
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
 use concordium_base::hashes::BlockHash;
 use concordium_node::{
     common::PeerType,
@@ -15,7 +15,96 @@ use concordium_node::{
     utils::get_config_and_logging_setup,
 };
 
-use std::{env, process::Command, thread, time::Duration};
+use serde::Serialize;
+use std::{
+    env, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// An alert in the format delivered by the alerting of the collector backend.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Alert {
+    rule:      &'static str,
+    status:    &'static str,
+    subject:   &'static str,
+    node_name: Option<String>,
+    message:   &'static str,
+    timestamp: u64,
+}
+
+/// Where the alert is sent if bootstrapping fails.
+enum AlertTarget {
+    /// Post the alert to the webhook at the URL.
+    Webhook(String),
+    /// Create a PagerDuty incident for the service.
+    PagerDuty {
+        token:      String,
+        email:      String,
+        service_id: String,
+    },
+}
+
+impl AlertTarget {
+    /// Use the webhook in `ALERT_WEBHOOK_URL` if set, and PagerDuty with the
+    /// credentials in `PD_TOKEN`, `PD_EMAIL` and `PD_SVCID` otherwise.
+    fn from_env() -> anyhow::Result<Self> {
+        if let Ok(url) = env::var("ALERT_WEBHOOK_URL") {
+            return Ok(AlertTarget::Webhook(url));
+        }
+        match (env::var("PD_TOKEN"), env::var("PD_EMAIL"), env::var("PD_SVCID")) {
+            (Ok(token), Ok(email), Ok(service_id)) => Ok(AlertTarget::PagerDuty {
+                token,
+                email,
+                service_id,
+            }),
+            _ => bail!("Either ALERT_WEBHOOK_URL or PD_TOKEN, PD_EMAIL and PD_SVCID must be set."),
+        }
+    }
+
+    async fn send(&self, message: &'static str) -> anyhow::Result<()> {
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?;
+        let request = match self {
+            AlertTarget::Webhook(url) => {
+                let alert = Alert {
+                    rule: "bootstrap",
+                    status: "firing",
+                    subject: "network",
+                    node_name: None,
+                    message,
+                    timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+                };
+                client.post(url).body(serde_json::to_string(&alert)?)
+            }
+            AlertTarget::PagerDuty {
+                token,
+                email,
+                service_id,
+            } => {
+                let incident = serde_json::json!({
+                    "incident": {
+                        "type": "incident",
+                        "title": message,
+                        "service": { "id": service_id, "type": "service_reference" },
+                    }
+                });
+                client
+                    .post("https://api.pagerduty.com/incidents")
+                    .header(reqwest::header::ACCEPT, "application/vnd.pagerduty+json;version=2")
+                    .header(reqwest::header::FROM, email)
+                    .header(reqwest::header::AUTHORIZATION, format!("Token token={}", token))
+                    .body(incident.to_string())
+            }
+        };
+        request
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .send()
+            .await
+            .context("Could not send the alert.")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
 
 fn main() -> anyhow::Result<()> {
     let (mut conf, app_prefs) = get_config_and_logging_setup()?;
@@ -23,9 +112,7 @@ fn main() -> anyhow::Result<()> {
 
     conf.connection.max_allowed_nodes = Some(0);
     conf.connection.thread_pool_size = 1;
-    let pager_duty_token = env::var("PD_TOKEN")?;
-    let pager_duty_email = env::var("PD_EMAIL")?;
-    let pager_duty_svcid = env::var("PD_SVCID")?;
+    let alert_target = AlertTarget::from_env()?;
 
     let stats_export_service = instantiate_stats_export_engine(&conf.prometheus)?;
     let fname = conf
@@ -58,39 +145,13 @@ fn main() -> anyhow::Result<()> {
             return node.close_and_join();
         }
         if node.get_uptime() > 10_000 {
-            Command::new("curl")
-                .arg("-i")
-                .arg("-X")
-                .arg("POST")
-                .arg("--header")
-                .arg("Content-Type: application/json")
-                .arg("--header")
-                .arg("Accept: application/vnd.pagerduty+json;version=2")
-                .arg("--header")
-                .arg(&format!("From: {}", pager_duty_email))
-                .arg("--header")
-                .arg(&format!("Authorization: Token token={}", pager_duty_token))
-                .arg("-d")
-                .arg(&format!(
-                    "
-                    {{
-                      \"incident\": {{
-                        \"type\": \"incident\",
-                        \"title\": \"Bootstrapping failed!\",
-                        \"service\": {{
-                          \"id\": \"{}\",
-                          \"type\": \"service_reference\"
-                        }}
-                      }}
-                    }}
-                ",
-                    pager_duty_svcid
-                ))
-                .arg("https://api.pagerduty.com/incidents")
-                .output()
-                .expect("Couldn't send a bootstrap error notification");
             println!("Error - I was not able to bootstrap");
-            return node.close_and_join();
+            let sent = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(alert_target.send("Bootstrapping failed!"));
+            node.close_and_join()?;
+            return sent;
         }
     }
 }
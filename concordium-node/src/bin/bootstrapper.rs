@@ -10,9 +10,14 @@ use concordium_base::hashes::BlockHash;
 use concordium_node::{
     common::PeerType,
     consensus_ffi::consensus::Regenesis,
-    p2p::{maintenance::spawn, *},
+    p2p::{
+        dns_seed::{self, DnsSeed, SeedZone},
+        maintenance::spawn,
+        *,
+    },
     stats_export_service::instantiate_stats_export_engine,
     utils::get_config_and_logging_setup,
+    write_or_die,
 };
 
 use concordium_node::stats_export_service::start_push_gateway;
@@ -66,8 +71,33 @@ async fn main() -> anyhow::Result<()> {
     // Set the startime in the stats.
     node.stats.node_startup_timestamp.set(node.start_time.timestamp_millis());
 
+    // Bind the DNS seed before starting the node, so that a misconfiguration is
+    // reported right away.
+    let dns_seed = match &conf.bootstrapper.dns_seed_name {
+        Some(name) => {
+            // The name server is required along with the name, and an empty name is
+            // rejected.
+            let nameserver = conf.bootstrapper.dns_seed_nameserver.as_deref().unwrap_or_default();
+            let zone = SeedZone::new(
+                name,
+                nameserver,
+                conf.bootstrapper.dns_seed_ttl,
+                conf.bootstrapper.dns_seed_max_records,
+            )?;
+            let seed = DnsSeed::bind(conf.bootstrapper.dns_seed_listen_address, zone)?;
+            log::info!("Serving the DNS seed {} on {}", name, seed.local_addr()?);
+            Some(seed)
+        }
+        None => None,
+    };
+
     spawn(&node, server, poll, None);
 
+    if let Some(seed) = dns_seed {
+        let seed_thread = dns_seed::spawn(&node, seed);
+        write_or_die!(node.threads).push(seed_thread);
+    }
+
     node.join().expect("Node thread panicked!");
 
     Ok(())
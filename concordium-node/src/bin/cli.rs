@@ -32,6 +32,7 @@ use concordium_node::{
     stats_export_service::{
         instantiate_stats_export_engine, StatsConsensusCollector, StatsExportService,
     },
+    telemetry,
     utils::get_config_and_logging_setup,
 };
 use mio::{net::TcpListener, Poll};
@@ -80,6 +81,19 @@ async fn main() -> anyhow::Result<()> {
     // The push gateway to Prometheus thread
     start_push_gateway(&conf.prometheus, &node.stats, node.id());
 
+    telemetry::start_exporter(&conf.tracing, node.id());
+
+    // Apply changes to the dynamic settings of the configuration file on SIGHUP.
+    #[cfg(unix)]
+    if let Some(config_file) = conf.common.config_file.clone() {
+        let node = node.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reload_config_on_hangup(&node, &config_file).await {
+                error!("Could not set up reloading of the configuration file: {}", e);
+            }
+        });
+    }
+
     let (gen_data, priv_data) = get_baker_data(&app_prefs, &conf.cli.baker)
         .context("Can't get genesis data or private data. Aborting")?;
 
@@ -102,6 +116,10 @@ async fn main() -> anyhow::Result<()> {
             last_arrived_block_timestamp: node.stats.last_arrived_block_timestamp.clone(),
             baked_blocks: node.stats.baked_blocks.clone(),
             finalized_baked_blocks: node.stats.finalized_baked_blocks.clone(),
+            block_arrive_latency: node.stats.block_arrive_latency.clone(),
+            block_finalization_latency: node.stats.block_finalization_latency.clone(),
+            block_interval: node.stats.block_interval.clone(),
+            block_timings: Default::default(),
         };
         let notification_handlers = ffi::NotificationHandlers {
             blocks:           receiver_blocks,
@@ -118,6 +136,10 @@ async fn main() -> anyhow::Result<()> {
             last_arrived_block_timestamp: node.stats.last_arrived_block_timestamp.clone(),
             baked_blocks: node.stats.baked_blocks.clone(),
             finalized_baked_blocks: node.stats.finalized_baked_blocks.clone(),
+            block_arrive_latency: node.stats.block_arrive_latency.clone(),
+            block_finalization_latency: node.stats.block_finalization_latency.clone(),
+            block_interval: node.stats.block_interval.clone(),
+            block_timings: Default::default(),
         };
         (Some(notify_context), None)
     } else {
@@ -314,6 +336,23 @@ async fn get_shutdown_signal() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Reload the dynamic settings from the configuration file whenever the node
+/// receives SIGHUP. Invalid configurations are logged and otherwise ignored.
+#[cfg(unix)]
+async fn reload_config_on_hangup(node: &P2PNode, config_file: &Path) -> anyhow::Result<()> {
+    let mut hangup_stream = unix_signal::signal(unix_signal::SignalKind::hangup())?;
+    let args = std::env::args_os().collect::<Vec<_>>();
+    while hangup_stream.recv().await.is_some() {
+        info!("Reloading the configuration file {}", config_file.display());
+        if let Err(e) = config::read_dynamic_config(config_file, &args)
+            .and_then(|update| node.apply_config_update(update))
+        {
+            error!("Could not reload the configuration: {:#}", e);
+        }
+    }
+    Ok(())
+}
+
 fn instantiate_node(
     conf: &config::Config,
     app_prefs: &mut config::AppPreferences,
@@ -0,0 +1,345 @@
+//! A tool for analysing structured network dumps created by the node and for
+//! replaying the packets they contain to a running node.
+
+// Force the system allocator on every platform
+use std::alloc::System;
+#[global_allocator]
+static A: System = System;
+
+use anyhow::{bail, ensure, Context};
+use concordium_base::hashes::BlockHash;
+use concordium_node::{
+    common::{P2PNodeId, PeerType},
+    configuration::Config,
+    connection::traffic::TrafficCategory,
+    consensus_ffi::consensus::Regenesis,
+    dumper::{pcap::PcapWriter, DumpItem, DumpReader},
+    network::{NetworkMessage, NetworkPayload, PacketDestination},
+    p2p::{
+        connectivity::{connect, send_broadcast_message, send_direct_message},
+        maintenance::{spawn, P2PNode},
+    },
+    stats_export_service::StatsExportService,
+};
+use env_logger::{Builder, Env};
+use structopt::StructOpt;
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Inspect and replay structured network dumps of the Concordium node.")]
+enum Command {
+    /// Print the records of a dump, decoding the network messages.
+    Print {
+        #[structopt(flatten)]
+        filter:  Filter,
+        #[structopt(long = "summary", help = "Only print per-message-type totals")]
+        summary: bool,
+    },
+    /// Convert a dump to the pcapng format, to be analysed with Wireshark.
+    ExportPcap {
+        #[structopt(flatten)]
+        filter:     Filter,
+        #[structopt(long = "output", help = "Path of the pcapng file", parse(from_os_str))]
+        output:     PathBuf,
+        #[structopt(
+            long = "local-port",
+            help = "Port of the node that created the dump",
+            default_value = "8888"
+        )]
+        local_port: u16,
+    },
+    /// Connect to a node and send it the packets recorded in a dump.
+    Replay {
+        #[structopt(flatten)]
+        filter:                 Filter,
+        #[structopt(long = "to", help = "Address of the node to send the packets to")]
+        target:                 SocketAddr,
+        #[structopt(
+            long = "regenesis-block-hashes-file",
+            help = "Path to a file with the genesis block hashes of the target node's chain"
+        )]
+        regenesis_block_hashes: PathBuf,
+        #[structopt(
+            long = "listen-port",
+            help = "Port the replaying node listens on",
+            default_value = "8889"
+        )]
+        listen_port:            u16,
+        #[structopt(
+            long = "speed",
+            help = "Replay the packets with the recorded timing, sped up by the given factor. If \
+                    not given, the packets are sent as fast as possible"
+        )]
+        speed:                  Option<f64>,
+        #[structopt(
+            long = "linger",
+            help = "Time (in ms) to keep the connection open after the last packet was queued",
+            default_value = "2000"
+        )]
+        linger:                 u64,
+    },
+}
+
+/// Selects the records of a dump to process.
+#[derive(StructOpt, Debug)]
+struct Filter {
+    #[structopt(help = "Path to the structured dump", parse(from_os_str))]
+    file:      PathBuf,
+    #[structopt(
+        long = "direction",
+        help = "Only process records of the given direction",
+        possible_values = &["in", "out"]
+    )]
+    direction: Option<String>,
+    #[structopt(long = "peer", help = "Only process records of the given peer id")]
+    peer:      Option<P2PNodeId>,
+}
+
+impl Filter {
+    fn matches(&self, item: &DumpItem) -> bool {
+        let direction_matches = match self.direction.as_deref() {
+            Some("in") => item.inbound,
+            Some("out") => !item.inbound,
+            _ => true,
+        };
+        direction_matches && (self.peer.is_none() || self.peer == item.peer_id)
+    }
+
+    /// Open the dump.
+    fn open(&self) -> anyhow::Result<DumpReader<BufReader<File>>> {
+        let file = File::open(&self.file)
+            .with_context(|| format!("Could not open the dump {}.", self.file.display()))?;
+        DumpReader::new(BufReader::new(file))
+    }
+
+    /// Read the dump, calling `f` for each record that matches the filter.
+    fn for_each(&self, mut f: impl FnMut(&DumpItem) -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let reader = self.open()?;
+        let header = reader.header();
+        println!(
+            "Dump of node {} ({}) started at {}",
+            header.local_id, header.local_ip, header.started
+        );
+        for item in reader {
+            let item = item?;
+            if self.matches(&item) {
+                f(&item)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn print(filter: &Filter, summary: bool) -> anyhow::Result<()> {
+    let mut totals: BTreeMap<(&'static str, bool), (u64, u64)> = BTreeMap::new();
+    filter.for_each(|item| {
+        let msg_type = match item.message() {
+            Ok(msg) => {
+                if !summary {
+                    println!(
+                        "{} {} {} ({}): {:?}",
+                        item.timestamp,
+                        if item.inbound {
+                            "IN "
+                        } else {
+                            "OUT"
+                        },
+                        item.remote_addr,
+                        item.peer_id.map(|id| id.to_string()).unwrap_or_else(|| "unknown".into()),
+                        msg
+                    );
+                }
+                TrafficCategory::of_message(&msg).label()
+            }
+            Err(e) => {
+                if !summary {
+                    println!("{} couldn't deserialize a message: {}", item.timestamp, e);
+                }
+                "invalid"
+            }
+        };
+        let total = totals.entry((msg_type, item.inbound)).or_default();
+        total.0 += 1;
+        total.1 += item.msg.len() as u64;
+        Ok(())
+    })?;
+    if summary {
+        for ((msg_type, inbound), (count, bytes)) in totals {
+            println!(
+                "{:<3} {:<32} {:>10} messages {:>14} bytes",
+                if inbound {
+                    "IN"
+                } else {
+                    "OUT"
+                },
+                msg_type,
+                count,
+                bytes
+            );
+        }
+    }
+    Ok(())
+}
+
+fn export_pcap(filter: &Filter, output: &Path, local_port: u16) -> anyhow::Result<()> {
+    let reader = filter.open()?;
+    let local_addr = SocketAddr::new(reader.header().local_ip, local_port);
+    let file =
+        File::create(output).with_context(|| format!("Could not create {}.", output.display()))?;
+    let mut writer = PcapWriter::new(BufWriter::new(file), local_addr)?;
+    let mut exported = 0u64;
+    for item in reader {
+        let item = item?;
+        if filter.matches(&item) {
+            writer.write_item(&item)?;
+            exported += 1;
+        }
+    }
+    writer.flush()?;
+    println!("Exported {} messages to {}", exported, output.display());
+    Ok(())
+}
+
+/// Start a node with its data in `data_dir` that only connects to the target,
+/// and wait for the handshake.
+fn start_replay_node(
+    target: SocketAddr,
+    regenesis_block_hashes: &Path,
+    listen_port: u16,
+    data_dir: &Path,
+) -> anyhow::Result<Arc<P2PNode>> {
+    let regenesis_blocks: Vec<BlockHash> = serde_json::from_slice(
+        &std::fs::read(regenesis_block_hashes)
+            .context("Could not open the file with genesis hashes.")?,
+    )
+    .context("Could not parse genesis hashes.")?;
+    ensure!(!regenesis_blocks.is_empty(), "The genesis hashes must not be empty.");
+    let regenesis_arc = Arc::new(Regenesis::from_blocks(regenesis_blocks));
+
+    let data_dir = data_dir.to_string_lossy().into_owned();
+    let conf = Config::from_iter(&[
+        "dump-replay",
+        "--no-bootstrap",
+        "--config-dir",
+        &data_dir,
+        "--data-dir",
+        &data_dir,
+        "--listen-port",
+        &listen_port.to_string(),
+    ]);
+
+    let stats = Arc::new(StatsExportService::new(Vec::new(), Vec::new())?);
+    let (node, server, poll) = P2PNode::new(None, &conf, PeerType::Node, stats, regenesis_arc)
+        .context("Failed to create the node.")?;
+    spawn(&node, server, poll, None);
+
+    connect(&node, PeerType::Node, target, None, false)?;
+    let start = Instant::now();
+    while node.get_node_peer_tokens().is_empty() {
+        ensure!(
+            start.elapsed() < Duration::from_secs(10),
+            "Could not complete a handshake with {}.",
+            target
+        );
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(node)
+}
+
+fn replay(
+    filter: &Filter,
+    target: SocketAddr,
+    regenesis_block_hashes: &Path,
+    listen_port: u16,
+    speed: Option<f64>,
+    linger: u64,
+) -> anyhow::Result<()> {
+    if let Some(speed) = speed {
+        ensure!(speed > 0.0, "The replay speed must be positive.");
+    }
+    // the data of the node is removed when the replay is done
+    let data_dir = tempfile::Builder::new()
+        .prefix("dump-replay-")
+        .tempdir()
+        .context("Could not create the data directory of the node.")?;
+    let node = start_replay_node(target, regenesis_block_hashes, listen_port, data_dir.path())?;
+    let target_id = match node.get_node_peer_tokens().first() {
+        Some(&id) => id,
+        None => bail!("Lost the connection to {}.", target),
+    };
+    println!("Connected to {}, replaying packets", target);
+
+    let mut first_timestamp = None;
+    let replay_start = Instant::now();
+    let mut sent = 0u64;
+    let res = filter.for_each(|item| {
+        let packet = match item.message() {
+            Ok(NetworkMessage {
+                payload: NetworkPayload::NetworkPacket(packet),
+                ..
+            }) => packet,
+            // only packets are replayed; the rest of the messages are specific to the
+            // recorded connection
+            _ => return Ok(()),
+        };
+        if let Some(speed) = speed {
+            let first = *first_timestamp.get_or_insert(item.timestamp);
+            let offset = (item.timestamp - first).num_milliseconds().max(0) as f64 / speed;
+            let due = replay_start + Duration::from_millis(offset as u64);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        let msg = packet.message.into();
+        let sent_to = match packet.destination {
+            PacketDestination::Direct(_) => {
+                send_direct_message(&node, target_id, packet.network_id, msg)
+            }
+            PacketDestination::Broadcast(_) => {
+                send_broadcast_message(&node, vec![], packet.network_id, msg)
+            }
+        };
+        ensure!(sent_to > 0, "Lost the connection to {}.", target);
+        sent += 1;
+        Ok(())
+    });
+    thread::sleep(Duration::from_millis(linger));
+    println!("Replayed {} packets", sent);
+    node.close_and_join()?;
+    res
+}
+
+fn main() -> anyhow::Result<()> {
+    let env = Env::default().filter_or("LOG_LEVEL", "warn");
+    Builder::from_env(env).init();
+
+    match Command::from_args() {
+        Command::Print {
+            filter,
+            summary,
+        } => print(&filter, summary),
+        Command::ExportPcap {
+            filter,
+            output,
+            local_port,
+        } => export_pcap(&filter, &output, local_port),
+        Command::Replay {
+            filter,
+            target,
+            regenesis_block_hashes,
+            listen_port,
+            speed,
+            linger,
+        } => replay(&filter, target, &regenesis_block_hashes, listen_port, speed, linger),
+    }
+}
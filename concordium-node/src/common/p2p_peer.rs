@@ -1,6 +1,9 @@
 //! Types related to identifying peers.
 
-use crate::{common::P2PNodeId, connection::ConnectionStats};
+use crate::{
+    common::P2PNodeId,
+    connection::{traffic::TrafficCounts, ConnectionStats},
+};
 use anyhow::bail;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use concordium_base::common::{Buffer, Deserial, Serial};
@@ -221,6 +224,8 @@ pub struct PeerStats {
     pub msgs_received:  u64,
     pub bytes_sent:     u64,
     pub bytes_received: u64,
+    /// Messages and bytes by message category and direction.
+    pub traffic:        TrafficCounts,
 }
 
 impl PeerStats {
@@ -244,6 +249,7 @@ impl PeerStats {
             msgs_received: conn_stats.messages_received.load(AtomicOrdering::Relaxed),
             bytes_sent: conn_stats.bytes_sent.load(AtomicOrdering::Relaxed),
             bytes_received: conn_stats.bytes_received.load(AtomicOrdering::Relaxed),
+            traffic: conn_stats.traffic.snapshot(),
         }
     }
 
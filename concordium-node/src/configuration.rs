@@ -2,13 +2,19 @@
 
 use crate::{
     common::P2PNodeId,
-    connection::DeduplicationHashAlgorithm,
+    connection::{
+        queues::{CategorySetting, DropPolicy},
+        DeduplicationHashAlgorithm,
+    },
+    logging::{LogFormat, LogLevelSetting},
     network::{WireProtocolVersion, WIRE_PROTOCOL_VERSIONS},
 };
-use anyhow::{ensure, Context};
+use anyhow::{bail, ensure, Context};
 use app_dirs2::*;
 use preferences::{Preferences, PreferencesMap};
 use std::{
+    collections::BTreeMap,
+    ffi::OsString,
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Write},
     path::{Path, PathBuf},
@@ -84,6 +90,20 @@ pub const DATABASE_SUB_DIRECTORY_NAME: &str = "database-v4";
 /// being dropped prematurely.
 const KEEP_ALIVE_FACTOR: u8 = 3;
 
+/// A configuration value that is not shown when the configuration is printed.
+#[derive(Clone)]
+pub struct Secret(pub String);
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> { Ok(Secret(s.to_owned())) }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { f.write_str("<redacted>") }
+}
+
 #[derive(StructOpt, Debug)]
 // Parameters related to Prometheus.
 pub struct PrometheusConfig {
@@ -134,7 +154,7 @@ pub struct PrometheusConfig {
         env = "CONCORDIUM_NODE_PROMETHEUS_PUSH_GATEWAY_PASSWORD",
         hide_env_values = true
     )]
-    pub prometheus_push_password: Option<String>,
+    pub prometheus_push_password: Option<Secret>,
     #[structopt(
         long = "prometheus-push-gateway-interval",
         help = "Interval in seconds between pushes",
@@ -153,6 +173,25 @@ pub struct PrometheusConfig {
         use_delimiter = true
     )]
     pub prometheus_metric_grpc_response_time_buckets: Vec<f64>,
+    #[structopt(
+        long = "prometheus-block-latency-buckets",
+        help = "Define the observation buckets (in seconds) of the block arrival latency, block \
+                finalization latency and block interval histograms used by the prometheus \
+                exporter. A bucket with +Infinity is always added. The values must be sorted in \
+                strictly increasing order.",
+        default_value = "0.5,1,2,4,8,16,32,64,128",
+        env = "CONCORDIUM_NODE_PROMETHEUS_BLOCK_LATENCY_BUCKETS",
+        use_delimiter = true
+    )]
+    pub prometheus_metric_block_latency_buckets: Vec<f64>,
+    #[structopt(
+        long = "prometheus-traffic-peers",
+        help = "Number of peers with the most traffic whose traffic is exported individually. The \
+                traffic of the remaining peers is aggregated under the peer label `other`.",
+        default_value = "10",
+        env = "CONCORDIUM_NODE_PROMETHEUS_TRAFFIC_PEERS"
+    )]
+    pub prometheus_traffic_peers: usize,
 }
 
 impl PrometheusConfig {
@@ -569,6 +608,21 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CONNECTION_MAX_ALLOWED_NODES_PERCENTAGE"
     )]
     pub max_allowed_nodes_percentage: u16,
+    #[structopt(
+        long = "max-outbound-nodes",
+        help = "Maximum number of connections to peers that the node initiated itself. Defaults \
+                to the value of desired-nodes.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_OUTBOUND_NODES"
+    )]
+    pub max_outbound_nodes: Option<u16>,
+    #[structopt(
+        long = "max-inbound-nodes",
+        help = "Maximum number of connections accepted from other peers. When exceeded, inbound \
+                connections are dropped, sparing the longest-lived and lowest-latency ones. \
+                Defaults to the maximum allowed nodes.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_INBOUND_NODES"
+    )]
+    pub max_inbound_nodes: Option<u16>,
     #[structopt(
         long = "no-bootstrap",
         help = "Do not bootstrap via DNS",
@@ -633,7 +687,7 @@ pub struct ConnectionConfig {
     pub bootstrapping_interval: u64,
     #[structopt(
         long = "max-latency",
-        help = "The maximum allowed connection latency in ms",
+        help = "The maximum allowed connection latency in ms. 0 disables the limit.",
         env = "CONCORDIUM_NODE_CONNECTION_MAX_LATENCY"
     )]
     pub max_latency: Option<u64>,
@@ -728,6 +782,110 @@ pub struct ConnectionConfig {
         env = "CONCORDIUM_NODE_CLEAR_PERSISTED_PEERS"
     )]
     pub clear_persisted_peers: bool,
+    #[structopt(
+        long = "persisted-peers-max-age",
+        help = "Remove peers from the persisted set of peers if the node has not been connected \
+                to them for this many seconds. The persisted peers are pruned upon startup.",
+        default_value = "604800",
+        env = "CONCORDIUM_NODE_PERSISTED_PEERS_MAX_AGE"
+    )]
+    pub persisted_peers_max_age: u64,
+    #[structopt(
+        long = "max-addresses-per-source",
+        help = "Maximum number of not yet tried peer addresses to keep that were learned from \
+                peers in the same address group (/16 for IPv4, /32 for IPv6).",
+        default_value = "64",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_ADDRESSES_PER_SOURCE"
+    )]
+    pub max_addresses_per_source: usize,
+    #[structopt(
+        long = "max-local-addresses",
+        help = "Maximum number of not yet tried peer addresses to keep that are not globally \
+                routable, e.g., loopback or private addresses.",
+        default_value = "16",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_LOCAL_ADDRESSES"
+    )]
+    pub max_local_addresses: usize,
+    #[structopt(
+        long = "max-local-outbound-nodes",
+        help = "Maximum number of outbound connections to known peer addresses that are not \
+                globally routable. Such addresses form a single address group, so by default at \
+                most one of them is connected to. Increase this in local test networks.",
+        default_value = "1",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_LOCAL_OUTBOUND_NODES"
+    )]
+    pub max_local_outbound_nodes: usize,
+    #[structopt(
+        long = "max-upload-rate",
+        help = "Maximum number of bytes per second sent to all peers combined. Unlimited if not \
+                given.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_UPLOAD_RATE"
+    )]
+    pub max_upload_rate: Option<u64>,
+    #[structopt(
+        long = "max-download-rate",
+        help = "Maximum number of bytes per second received from all peers combined. Unlimited if \
+                not given.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_DOWNLOAD_RATE"
+    )]
+    pub max_download_rate: Option<u64>,
+    #[structopt(
+        long = "max-peer-upload-rate",
+        help = "Maximum number of bytes per second sent to a single peer. Unlimited if not given.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEER_UPLOAD_RATE"
+    )]
+    pub max_peer_upload_rate: Option<u64>,
+    #[structopt(
+        long = "max-peer-download-rate",
+        help = "Maximum number of bytes per second received from a single peer. Unlimited if not \
+                given.",
+        env = "CONCORDIUM_NODE_CONNECTION_MAX_PEER_DOWNLOAD_RATE"
+    )]
+    pub max_peer_download_rate: Option<u64>,
+    #[structopt(
+        long = "high-priority-bandwidth-share",
+        help = "The share (between 0.0 and 1.0, exclusive) of the upload limits that is reserved \
+                for high priority messages.",
+        default_value = "0.25",
+        env = "CONCORDIUM_NODE_CONNECTION_HIGH_PRIORITY_BANDWIDTH_SHARE"
+    )]
+    pub high_priority_bandwidth_share: f64,
+    #[structopt(
+        long = "outbound-queue-capacity",
+        help = "Maximum number of messages queued for sending to a single peer.",
+        default_value = "16384",
+        env = "CONCORDIUM_NODE_CONNECTION_OUTBOUND_QUEUE_CAPACITY"
+    )]
+    pub outbound_queue_capacity: usize,
+    #[structopt(
+        long = "outbound-queue-drop-policy",
+        help = "Which message to drop when a message is queued for a peer whose queue is full \
+                [newest|oldest|lowest-weight]. `newest` drops the new message, `oldest` the \
+                oldest message of the same category and `lowest-weight` the oldest message of the \
+                category with the lowest weight.",
+        default_value = "lowest-weight",
+        env = "CONCORDIUM_NODE_CONNECTION_OUTBOUND_QUEUE_DROP_POLICY"
+    )]
+    pub outbound_queue_drop_policy: DropPolicy,
+    #[structopt(
+        long = "outbound-queue-weights",
+        help = "Comma separated list of `<category>=<weight>` pairs that set the share of a \
+                connection each message category gets when sending. The categories are `block`, \
+                `transaction`, `finalization-record`, `finalization-message`, `catch-up-status` \
+                and `network`. Categories that are not listed keep their default weight.",
+        use_delimiter = true,
+        env = "CONCORDIUM_NODE_CONNECTION_OUTBOUND_QUEUE_WEIGHTS"
+    )]
+    pub outbound_queue_weights: Vec<CategorySetting>,
+    #[structopt(
+        long = "outbound-queue-deadlines",
+        help = "Comma separated list of `<category>=<milliseconds>` pairs that set how long \
+                messages of a category may wait for sending before they are dropped. A value of 0 \
+                disables the deadline. Categories that are not listed keep their default deadline.",
+        use_delimiter = true,
+        env = "CONCORDIUM_NODE_CONNECTION_OUTBOUND_QUEUE_DEADLINES"
+    )]
+    pub outbound_queue_deadlines: Vec<CategorySetting>,
 }
 
 #[derive(StructOpt, Debug)]
@@ -820,6 +978,30 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_LOG_CONFIG"
     )]
     pub log_config: Option<PathBuf>,
+    #[structopt(
+        long = "log-format",
+        help = "Format of the log output, either 'text' or 'json'. The JSON format outputs an \
+                object per line with the timestamp, level, module and message, and the peer, \
+                block hash and genesis index if they are known.",
+        default_value = "text",
+        env = "CONCORDIUM_NODE_LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
+    #[structopt(
+        long = "log-levels",
+        help = "Log levels of subsystems ('network', 'consensus' or 'grpc') or modules, e.g., \
+                'network=debug,grpc=warn'. These take precedence over the general log level.",
+        env = "CONCORDIUM_NODE_LOG_LEVELS",
+        use_delimiter = true
+    )]
+    pub log_levels: Vec<LogLevelSetting>,
+    #[structopt(
+        long = "log-trace-sample-rate",
+        help = "Only log one in this many TRACE-level messages from each place in the code.",
+        default_value = "1",
+        env = "CONCORDIUM_NODE_LOG_TRACE_SAMPLE_RATE"
+    )]
+    pub log_trace_sample_rate: u64,
     #[structopt(
         long = "minimum-peers-bucket",
         help = "Minimum peers to keep in each bucket always",
@@ -833,6 +1015,21 @@ pub struct CommonConfig {
         env = "CONCORDIUM_NODE_PRINT_CONFIG"
     )]
     pub print_config: bool,
+    #[structopt(
+        long = "config-file",
+        help = "Path to a TOML file with configuration values. The keys are the names of the \
+                environment variables without the CONCORDIUM_NODE_ prefix, either in full or \
+                split into tables (e.g., `[connection]` with `desired_nodes = 7`). Values on the \
+                command line or in the environment take precedence over the file.",
+        env = "CONCORDIUM_NODE_CONFIG_FILE"
+    )]
+    pub config_file: Option<PathBuf>,
+    #[structopt(
+        long = "check-config",
+        help = "Validate the configuration, print the effective configuration and exit",
+        env = "CONCORDIUM_NODE_CHECK_CONFIG"
+    )]
+    pub check_config: bool,
     #[structopt(
         long = "bucket-cleanup-interval",
         help = "Try to timeout entries in the buckets every set interval (in ms)",
@@ -905,6 +1102,101 @@ pub struct BootstrapperConfig {
         env = "CONCORDIUM_NODE_BOOTSTRAPPER_PEER_LIST_SIZE"
     )]
     pub peer_list_size: usize,
+    #[structopt(
+        long = "bootstrapper-recently-seen-period",
+        help = "Peers seen within this period (in ms) are preferred over the others when sharing \
+                peers in a PeerList",
+        default_value = "1800000",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_RECENTLY_SEEN_PERIOD"
+    )]
+    pub recently_seen_period: u64,
+    #[structopt(
+        long = "bootstrapper-max-peers-per-group",
+        help = "The maximum number of peers from the same IP address group (/16 for IPv4, /32 for \
+                IPv6) shared in a PeerList, 0 means no limit",
+        default_value = "2",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_MAX_PEERS_PER_GROUP"
+    )]
+    pub max_peers_per_group: usize,
+    #[structopt(
+        long = "bootstrapper-min-peer-version",
+        help = "Do not share peers running a version older than this one",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_MIN_PEER_VERSION"
+    )]
+    pub min_peer_version: Option<semver::Version>,
+    #[structopt(
+        long = "bootstrapper-peer-list-interval",
+        help = "The interval (in ms) in which at most `--bootstrapper-peer-lists-per-ip` \
+                PeerLists are sent to the same IP address, 0 means no limit",
+        default_value = "60000",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_PEER_LIST_INTERVAL"
+    )]
+    pub peer_list_interval: u64,
+    #[structopt(
+        long = "bootstrapper-peer-lists-per-ip",
+        help = "The number of PeerLists sent to the same IP address per \
+                `--bootstrapper-peer-list-interval`, e.g., to serve several nodes behind one NAT",
+        default_value = "1",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_PEER_LISTS_PER_IP"
+    )]
+    pub peer_lists_per_ip: u16,
+    #[structopt(
+        long = "bootstrapper-probe-interval",
+        help = "The interval (in ms) at which the external address of each peer is probed to \
+                check that the peer accepts connections, 0 means peers are not probed",
+        default_value = "600000",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_PROBE_INTERVAL"
+    )]
+    pub probe_interval: u64,
+    #[structopt(
+        long = "bootstrapper-probe-timeout",
+        help = "The time (in ms) to wait for a probe of a peer to complete",
+        default_value = "5000",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_PROBE_TIMEOUT"
+    )]
+    pub probe_timeout: u64,
+    #[structopt(
+        long = "bootstrapper-share-undialable",
+        help = "Share peers that failed their last probe in a PeerList, after all other peers",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_SHARE_UNDIALABLE"
+    )]
+    pub share_undialable: bool,
+    #[structopt(
+        long = "dns-seed-name",
+        help = "Run a DNS seed server answering A, AAAA and TXT queries for this name with the \
+                addresses of healthy peers",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_DNS_SEED_NAME",
+        requires = "dns-seed-nameserver"
+    )]
+    pub dns_seed_name: Option<String>,
+    #[structopt(
+        long = "dns-seed-nameserver",
+        help = "The host name of the DNS seed server, served in the NS and SOA records of the \
+                DNS seed name",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_DNS_SEED_NAMESERVER"
+    )]
+    pub dns_seed_nameserver: Option<String>,
+    #[structopt(
+        long = "dns-seed-listen-address",
+        help = "The UDP address the DNS seed server listens on",
+        default_value = "0.0.0.0:53",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_DNS_SEED_LISTEN_ADDRESS"
+    )]
+    pub dns_seed_listen_address: std::net::SocketAddr,
+    #[structopt(
+        long = "dns-seed-ttl",
+        help = "The time to live (in seconds) of the records served by the DNS seed server",
+        default_value = "60",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_DNS_SEED_TTL"
+    )]
+    pub dns_seed_ttl: u32,
+    #[structopt(
+        long = "dns-seed-max-records",
+        help = "The maximum number of records in an answer of the DNS seed server",
+        default_value = "16",
+        env = "CONCORDIUM_NODE_BOOTSTRAPPER_DNS_SEED_MAX_RECORDS"
+    )]
+    pub dns_seed_max_records: usize,
     #[structopt(
         long = "regenesis-block-hashes-file",
         help = "Path to a file that contains a json array of regenesis hashes.",
@@ -913,6 +1205,93 @@ pub struct BootstrapperConfig {
     pub regenesis_block_hashes: Option<PathBuf>,
 }
 
+#[cfg(feature = "network_dump")]
+#[derive(StructOpt, Debug)]
+// Filters and limits applied to network dumps.
+pub struct NetworkDumpConfig {
+    #[structopt(
+        long = "dump-peers",
+        help = "Only dump the messages of the given peers, identified by node id or IP address",
+        env = "CONCORDIUM_NODE_DUMP_PEERS",
+        use_delimiter = true
+    )]
+    pub dump_peers:            Vec<crate::dumper::DumpPeer>,
+    #[structopt(
+        long = "dump-categories",
+        help = "Only dump messages of the given categories (block, transaction, \
+                finalization-record, finalization-message, catch-up-status, network)",
+        env = "CONCORDIUM_NODE_DUMP_CATEGORIES",
+        use_delimiter = true
+    )]
+    pub dump_categories:       Vec<crate::connection::traffic::TrafficCategory>,
+    #[structopt(
+        long = "dump-network-messages",
+        help = "Only dump the messages of the network category of the given kinds (ping, pong, \
+                get-peers, peer-list, handshake, join-network, leave-network)",
+        env = "CONCORDIUM_NODE_DUMP_NETWORK_MESSAGES",
+        use_delimiter = true
+    )]
+    pub dump_network_messages: Vec<crate::dumper::NetworkMessageKind>,
+    #[structopt(
+        long = "dump-direction",
+        help = "Only dump messages received from ('in') or sent to ('out') peers",
+        env = "CONCORDIUM_NODE_DUMP_DIRECTION",
+        possible_values = &["in", "out"]
+    )]
+    pub dump_direction:        Option<crate::dumper::DumpDirection>,
+    #[structopt(
+        long = "dump-max-duration",
+        help = "Stop a network dump after the given number of seconds",
+        env = "CONCORDIUM_NODE_DUMP_MAX_DURATION"
+    )]
+    pub dump_max_duration:     Option<u64>,
+    #[structopt(
+        long = "dump-max-bytes",
+        help = "Stop a network dump once the given number of bytes was written",
+        env = "CONCORDIUM_NODE_DUMP_MAX_BYTES"
+    )]
+    pub dump_max_bytes:        Option<u64>,
+    #[structopt(
+        long = "dump-rotate-bytes",
+        help = "Start new network dump files once the current ones reach the given number of bytes",
+        env = "CONCORDIUM_NODE_DUMP_ROTATE_BYTES"
+    )]
+    pub dump_rotate_bytes:     Option<u64>,
+    #[structopt(
+        long = "dump-pcap",
+        help = "Also export network dumps in the pcapng format",
+        env = "CONCORDIUM_NODE_DUMP_PCAP"
+    )]
+    pub dump_pcap:             bool,
+}
+
+#[derive(StructOpt, Debug)]
+// Parameters related to exporting traces.
+pub struct TracingConfig {
+    #[structopt(
+        long = "otlp-endpoint",
+        help = "Base URL of an OpenTelemetry collector to export traces to over OTLP/HTTP, e.g. \
+                'http://localhost:4318'. Traces are only exported if this is set.",
+        env = "CONCORDIUM_NODE_OTLP_ENDPOINT"
+    )]
+    pub otlp_endpoint:     Option<url::Url>,
+    #[structopt(
+        long = "otlp-sample-ratio",
+        help = "Fraction of the traces started by the node that are exported. Traces started by a \
+                client are exported if the client samples them.",
+        default_value = "1",
+        env = "CONCORDIUM_NODE_OTLP_SAMPLE_RATIO"
+    )]
+    pub otlp_sample_ratio: f64,
+    #[structopt(
+        long = "otlp-service-name",
+        help = "Service name the traces are exported under.",
+        default_value = "concordium-node",
+        env = "CONCORDIUM_NODE_OTLP_SERVICE_NAME"
+    )]
+    pub otlp_service_name: String,
+}
+
 #[cfg(target_os = "macos")]
 #[derive(StructOpt, Debug)]
 // Parameters applicable to macOS.
@@ -943,6 +1322,11 @@ pub struct Config {
     pub cli:          CliConfig,
     #[structopt(flatten)]
     pub bootstrapper: BootstrapperConfig,
+    #[structopt(flatten)]
+    pub tracing:      TracingConfig,
+    #[cfg(feature = "network_dump")]
+    #[structopt(flatten)]
+    pub dump:         NetworkDumpConfig,
     #[cfg(target_os = "macos")]
     #[structopt(flatten)]
     pub macos:        MacOsConfig,
@@ -964,14 +1348,408 @@ impl Config {
     }
 }
 
+/// Prefix of the environment variables that configure the node.
+const ENV_PREFIX: &str = "CONCORDIUM_NODE_";
+
+/// The options that can be given in the configuration file, as the name of
+/// their environment variable without the prefix, their long name and their
+/// short name on the command line. The configuration file itself and
+/// `CHECK_CONFIG` cannot be given in the file.
+const CONFIG_FILE_OPTIONS: &[(&str, &str, Option<char>)] = &[
+    ("PROMETHEUS_LISTEN_ADDRESS", "prometheus-listen-addr", None),
+    ("PROMETHEUS_LISTEN_PORT", "prometheus-listen-port", None),
+    ("PROMETHEUS_PUSH_GATEWAY", "prometheus-push-gateway", None),
+    ("PROMETHEUS_JOB_NAME", "prometheus-job-name", None),
+    ("PROMETHEUS_INSTANCE_NAME", "prometheus-instance-name", None),
+    ("PROMETHEUS_PUSH_GATEWAY_USERNAME", "prometheus-push-gateway-username", None),
+    ("PROMETHEUS_PUSH_GATEWAY_PASSWORD", "prometheus-push-gateway-password", None),
+    ("PROMETHEUS_PUSH_GATEWAY_INTERVAL", "prometheus-push-gateway-interval", None),
+    ("PROMETHEUS_GRPC_RESPONSE_TIME_BUCKETS", "prometheus-grpc-response-time-buckets", None),
+    ("PROMETHEUS_BLOCK_LATENCY_BUCKETS", "prometheus-block-latency-buckets", None),
+    ("PROMETHEUS_TRAFFIC_PEERS", "prometheus-traffic-peers", None),
+    #[cfg(feature = "profiling")]
+    ("RUNTIME_HEAP_PROFILING", "heap-profiling", None),
+    #[cfg(feature = "profiling")]
+    ("RUNTIME_TIME_PROFILING", "time-profiling", None),
+    #[cfg(feature = "profiling")]
+    ("RUNTIME_SHOW_BACKTRACES", "backtraces", None),
+    #[cfg(feature = "profiling")]
+    ("RUNTIME_STACK_PROFILING", "stack-profiling", None),
+    #[cfg(feature = "profiling")]
+    ("PROFILING_SAMPLING_INTERVAL", "profiling-sampling-interval", None),
+    ("RUNTIME_HASKELL_GC_LOGGING", "haskell-gc-logging", None),
+    ("RUNTIME_HASKELL_RTS_FLAGS", "haskell-rts-flags", None),
+    ("BAKER_MAXIMUM_BLOCK_SIZE", "maximum-block-size", None),
+    ("BAKER_BLOCK_CONSTRUCTION_TIMEOUT", "block-construction-timeout", None),
+    ("CONSENSUS_TRANSACTION_INSERTIONS_BEFORE_PURGE", "transaction-insertions-before-purge", None),
+    ("CONSENSUS_TRANSACTION_KEEP_ALIVE", "transaction-keep-alive", None),
+    ("CONSENSUS_TRANSACTIONS_PURGING_DELAY", "transactions-purging-delay", None),
+    ("CONSENSUS_IMPORT_BLOCKS_FROM", "import-blocks-from", None),
+    ("CONSENSUS_DOWNLOAD_BLOCKS_FROM", "download-blocks-from", None),
+    ("CONSENSUS_DOWNLOAD_BLOCKS_TIMEOUT", "download-blocks-timeout", None),
+    ("CONSENSUS_GENESIS_DATA_FILE", "genesis-data-file", None),
+    ("CONSENSUS_ACCOUNTS_CACHE_SIZE", "accounts-cache-size", None),
+    ("BAKER_CREDENTIALS_FILE", "baker-credentials-file", None),
+    ("VALIDATOR_CREDENTIALS_FILE", "validator-credentials-file", None),
+    ("BAKER_DECRYPT_CREDENTIALS", "decrypt-baker-credentials", None),
+    ("VALIDATOR_DECRYPT_CREDENTIALS", "decrypt-validator-credentials", None),
+    ("CONSENSUS_MODULES_CACHE_SIZE", "modules-cache-size", None),
+    ("GRPC2_LISTEN_ADDRESS", "grpc2-listen-addr", None),
+    ("GRPC2_LISTEN_PORT", "grpc2-listen-port", None),
+    ("GRPC2_X509_CERT", "grpc2-x509-cert", None),
+    ("GRPC2_CERT_PRIVATE_KEY", "grpc2-cert-private-key", None),
+    ("GRPC2_ENABLE_GRPC_WEB", "grpc2-enable-grpc-web", None),
+    ("GRPC2_ENDPOINT_CONFIG", "grpc2-endpoint-config", None),
+    ("GRPC2_INVOKE_MAX_ENERGY", "grpc2-invoke-max-energy", None),
+    ("GRPC2_DRY_RUN_TIMEOUT", "grpc2-dry-run-timeout", None),
+    ("GRPC2_DRY_RUN_CONCURRENCY", "grpc2-dry-run-concurrency", None),
+    ("GRPC2_HEALTH_MAX_FINALIZED_DELAY", "grpc2-health-max-finalized-delay", None),
+    ("GRPC2_HEALTH_MIN_PEERS", "grpc2-health-min-peers", None),
+    ("GRPC2_MAX_CONNECTIONS", "grpc2-max-connections", None),
+    ("GRPC2_TCP_KEEPALIVE", "grpc2-tcp-keep-alive-interval", None),
+    (
+        "GRPC2_MAX_CONCURRENT_REQUESTS_PER_CONNECTION",
+        "grpc2-max-concurrent-requests-per-connection",
+        None,
+    ),
+    ("GRPC2_MAX_CONCURRENT_STREAMS", "grpc2-max-concurrent-streams", None),
+    ("GRPC2_MAX_CONCURRENT_REQUESTS", "grpc2-max-concurrent-requests", None),
+    ("GRPC2_REQUEST_TIMEOUT", "grpc2-request-timeout", None),
+    ("GRPC2_KEEPALIVE_INTERVAL", "grpc2-keep-alive-interval", None),
+    ("GRPC2_KEEPALIVE_TIMEOUT", "grpc2-keep-alive-timeout", None),
+    ("CONNECTION_DESIRED_NODES", "desired-nodes", None),
+    ("CONNECTION_MAX_ALLOWED_NODES", "max-allowed-nodes", None),
+    ("CONNECTION_MAX_ALLOWED_NODES_PERCENTAGE", "max-allowed-nodes-percentage", None),
+    ("CONNECTION_MAX_OUTBOUND_NODES", "max-outbound-nodes", None),
+    ("CONNECTION_MAX_INBOUND_NODES", "max-inbound-nodes", None),
+    ("CONNECTION_NO_BOOTSTRAP_DNS", "no-bootstrap", None),
+    ("CONNECTION_CLEAR_BANS", "clear-bans", None),
+    ("CONNECTION_RELAY_BROADCAST_PERCENTAGE", "relay-broadcast-percentage", None),
+    ("CONNECTION_CONNECT_TO", "connect-to", Some('c')),
+    ("CONNECTION_DISALLOW_MULTIPLE_PEERS_ON_SAME_IP", "disallow-multiple-peers-on-ip", None),
+    ("CONNECTION_DNS_RESOLVER", "dns-resolver", None),
+    ("CONNECTION_BOOTSTRAP_NODES", "bootstrap-node", None),
+    ("CONNECTION_HOUSEKEEPING_INTERVAL", "housekeeping-interval", None),
+    ("CONNECTION_BOOTSTRAPPING_INTERVAL", "bootstrapping-interval", None),
+    ("CONNECTION_MAX_LATENCY", "max-latency", None),
+    ("CONNECTION_HARD_CONNECTION_LIMIT", "hard-connection-limit", None),
+    ("CONNECTION_REQUESTS_BATCH_LIMIT", "connection-requests-batch-limit", None),
+    ("CONNECTION_CATCH_UP_BATCH_LIMIT", "catch-up-batch-limit", None),
+    ("CONNECTION_THREAD_POOL_SIZE", "thread-pool-size", None),
+    ("CONNECTION_DEDUP_SIZE_LONG", "dedup-size-long", None),
+    ("CONNECTION_DEDUP_SIZE_SHORT", "dedup-size-short", None),
+    ("CONNECTION_SOCKET_WRITE_SIZE", "socket-write-size", None),
+    ("CONNECTION_SOCKET_READ_SIZE", "socket-read-size", None),
+    ("CONNECTION_SOCKET_SO_LINGER", "linger-time", None),
+    ("CONNECTION_EVENTS_QUEUE_SIZE", "events-queue-size", None),
+    ("CONNECTION_DEDUPLICATION_HASHING_ALGORITHM", "deduplication-hashing-algorithm", None),
+    ("MAX_NORMAL_KEEP_ALIVE", "max-normal-keep-alive", None),
+    ("CLEAR_PERSISTED_PEERS", "clear-persisted-peers", None),
+    ("PERSISTED_PEERS_MAX_AGE", "persisted-peers-max-age", None),
+    ("CONNECTION_MAX_ADDRESSES_PER_SOURCE", "max-addresses-per-source", None),
+    ("CONNECTION_MAX_LOCAL_ADDRESSES", "max-local-addresses", None),
+    ("CONNECTION_MAX_LOCAL_OUTBOUND_NODES", "max-local-outbound-nodes", None),
+    ("CONNECTION_MAX_UPLOAD_RATE", "max-upload-rate", None),
+    ("CONNECTION_MAX_DOWNLOAD_RATE", "max-download-rate", None),
+    ("CONNECTION_MAX_PEER_UPLOAD_RATE", "max-peer-upload-rate", None),
+    ("CONNECTION_MAX_PEER_DOWNLOAD_RATE", "max-peer-download-rate", None),
+    ("CONNECTION_HIGH_PRIORITY_BANDWIDTH_SHARE", "high-priority-bandwidth-share", None),
+    ("CONNECTION_OUTBOUND_QUEUE_CAPACITY", "outbound-queue-capacity", None),
+    ("CONNECTION_OUTBOUND_QUEUE_DROP_POLICY", "outbound-queue-drop-policy", None),
+    ("CONNECTION_OUTBOUND_QUEUE_WEIGHTS", "outbound-queue-weights", None),
+    ("CONNECTION_OUTBOUND_QUEUE_DEADLINES", "outbound-queue-deadlines", None),
+    ("EXTERNAL_PORT", "external-port", None),
+    ("ID", "id", Some('i')),
+    ("LISTEN_PORT", "listen-port", Some('p')),
+    ("LISTEN_ADDRESS", "listen-address", Some('l')),
+    ("LOG_LEVEL_DEBUG", "debug", Some('d')),
+    ("LOG_LEVEL_TRACE", "trace", None),
+    ("LOG_LEVEL_INFO", "info", None),
+    ("NO_CONSENSUS_LOG", "no-consensus-logs", None),
+    ("NETWORK_ID", "network-id", Some('n')),
+    ("CONFIG_DIR", "config-dir", None),
+    ("DATA_DIR", "data-dir", None),
+    ("NO_LOG_TIMESTAMP", "no-log-timestamp", None),
+    ("LOG_CONFIG", "log-config", None),
+    ("LOG_FORMAT", "log-format", None),
+    ("LOG_LEVELS", "log-levels", None),
+    ("LOG_TRACE_SAMPLE_RATE", "log-trace-sample-rate", None),
+    ("MINIMUM_PEERS_BUCKET", "minimum-peers-bucket", None),
+    ("PRINT_CONFIG", "print-config", None),
+    ("BUCKET_CLEANUP_INTERVAL", "bucket-cleanup-interval", None),
+    ("NO_NETWORK", "no-network", None),
+    ("POLL_INTERVAL", "poll-interval", None),
+    ("TIMEOUT_BUCKET_ENTRY_PERIOD", "timeout-bucket-entry-period", None),
+    ("DROP_REBROADCSAT_PROBABILITY", "drop-rebroadcast-probability", None),
+    ("BOOTSTRAPPER_MAX_NODES", "max-nodes", None),
+    ("BOOTSTRAPPER_WAIT_UNTIL_MINIMUM_NODES", "wait-until-minimum-nodes", None),
+    ("BOOTSTRAPPER_TIMEOUT_BUCKET_ENTRY_PERIOD", "bootstrapper-timeout-bucket-entry-period", None),
+    ("BOOTSTRAPPER_PEER_LIST_SIZE", "peer-list-size", None),
+    ("BOOTSTRAPPER_RECENTLY_SEEN_PERIOD", "bootstrapper-recently-seen-period", None),
+    ("BOOTSTRAPPER_MAX_PEERS_PER_GROUP", "bootstrapper-max-peers-per-group", None),
+    ("BOOTSTRAPPER_MIN_PEER_VERSION", "bootstrapper-min-peer-version", None),
+    ("BOOTSTRAPPER_PEER_LIST_INTERVAL", "bootstrapper-peer-list-interval", None),
+    ("BOOTSTRAPPER_PEER_LISTS_PER_IP", "bootstrapper-peer-lists-per-ip", None),
+    ("BOOTSTRAPPER_PROBE_INTERVAL", "bootstrapper-probe-interval", None),
+    ("BOOTSTRAPPER_PROBE_TIMEOUT", "bootstrapper-probe-timeout", None),
+    ("BOOTSTRAPPER_SHARE_UNDIALABLE", "bootstrapper-share-undialable", None),
+    ("BOOTSTRAPPER_DNS_SEED_NAME", "dns-seed-name", None),
+    ("BOOTSTRAPPER_DNS_SEED_NAMESERVER", "dns-seed-nameserver", None),
+    ("BOOTSTRAPPER_DNS_SEED_LISTEN_ADDRESS", "dns-seed-listen-address", None),
+    ("BOOTSTRAPPER_DNS_SEED_TTL", "dns-seed-ttl", None),
+    ("BOOTSTRAPPER_DNS_SEED_MAX_RECORDS", "dns-seed-max-records", None),
+    ("BOOTSTRAPPER_REGENESIS_BLOCK_HASHES_FILE", "regenesis-block-hashes-file", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_PEERS", "dump-peers", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_CATEGORIES", "dump-categories", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_NETWORK_MESSAGES", "dump-network-messages", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_DIRECTION", "dump-direction", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_MAX_DURATION", "dump-max-duration", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_MAX_BYTES", "dump-max-bytes", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_ROTATE_BYTES", "dump-rotate-bytes", None),
+    #[cfg(feature = "network_dump")]
+    ("DUMP_PCAP", "dump-pcap", None),
+    ("OTLP_ENDPOINT", "otlp-endpoint", None),
+    ("OTLP_SAMPLE_RATIO", "otlp-sample-ratio", None),
+    ("OTLP_SERVICE_NAME", "otlp-service-name", None),
+    #[cfg(target_os = "macos")]
+    ("MACOS_USE_MAC_LOG", "use-mac-log", None),
+];
+
+/// Look up an option of the configuration file by the full name of its
+/// environment variable.
+fn config_file_option(name: &str) -> Option<&'static (&'static str, &'static str, Option<char>)> {
+    let key = name.strip_prefix(ENV_PREFIX)?;
+    CONFIG_FILE_OPTIONS.iter().find(|(option, ..)| *option == key)
+}
+
+/// Collect the values of a configuration file by the name of their
+/// environment variable. Nested tables contribute their name to the keys of
+/// their values, so `desired_nodes` in the `connection` table is the value of
+/// `CONCORDIUM_NODE_CONNECTION_DESIRED_NODES`.
+fn collect_config_values(
+    prefix: &str,
+    table: &toml::value::Table,
+    out: &mut BTreeMap<String, toml::Value>,
+) -> anyhow::Result<()> {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key.to_ascii_uppercase().replace('-', "_"));
+        if let toml::Value::Table(table) = value {
+            collect_config_values(&format!("{}_", name), table, out)?;
+        } else if out.insert(name.clone(), value.clone()).is_some() {
+            bail!("The value of '{}' is given more than once.", name);
+        }
+    }
+    Ok(())
+}
+
+/// Convert a value of the configuration file to the value of its option.
+/// Returns `None` if the option should not be given.
+fn config_value_to_arg(name: &str, value: &toml::Value) -> anyhow::Result<Option<String>> {
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => f.to_string(),
+        // Flags are enabled by giving them, regardless of their value.
+        toml::Value::Boolean(true) => "true".to_owned(),
+        toml::Value::Boolean(false) => return Ok(None),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                toml::Value::String(s) => Ok(s.clone()),
+                toml::Value::Integer(i) => Ok(i.to_string()),
+                toml::Value::Float(f) => Ok(f.to_string()),
+                _ => bail!("Unsupported value in the list '{}'.", name),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .join(","),
+        toml::Value::Table(_) | toml::Value::Datetime(_) => {
+            bail!("Unsupported value for '{}'.", name)
+        }
+    };
+    Ok(Some(value))
+}
+
+/// Read the configuration file, returning its values by the name of their
+/// environment variable.
+fn read_config_file(path: &Path) -> anyhow::Result<BTreeMap<String, toml::Value>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read configuration file '{}'.", path.display()))?;
+    let table: toml::value::Table = toml::from_str(&contents)
+        .with_context(|| format!("Could not parse configuration file '{}'.", path.display()))?;
+    let mut values = BTreeMap::new();
+    collect_config_values(ENV_PREFIX, &table, &mut values)?;
+
+    for name in [format!("{}CONFIG_FILE", ENV_PREFIX), format!("{}CHECK_CONFIG", ENV_PREFIX)] {
+        ensure!(!values.contains_key(&name), "'{}' cannot be set in the configuration file.", name);
+    }
+    let unknown =
+        values.keys().filter(|name| config_file_option(name).is_none()).collect::<Vec<_>>();
+    ensure!(
+        unknown.is_empty(),
+        "Unknown settings in configuration file '{}': {:?}",
+        path.display(),
+        unknown
+    );
+    Ok(values)
+}
+
+/// The arguments before the `--` that ends the options, without the name of
+/// the program.
+fn option_args(args: &[OsString]) -> impl Iterator<Item = &str> {
+    args.iter().skip(1).map(|arg| arg.to_str().unwrap_or_default()).take_while(|arg| *arg != "--")
+}
+
+/// Whether an option is given in the arguments, either by its long name, as
+/// `--long value` or `--long=value`, or by its short name, as `-s value`,
+/// `-s=value` or `-svalue`.
+fn is_option_given(args: &[OsString], long: &str, short: Option<char>) -> bool {
+    option_args(args).any(|arg| {
+        if let Some(name) = arg.strip_prefix("--") {
+            name == long || name.strip_prefix(long).map_or(false, |rest| rest.starts_with('='))
+        } else {
+            let mut chars = arg.chars();
+            chars.next() == Some('-') && short.is_some() && chars.next() == short
+        }
+    })
+}
+
+/// Whether an option is given in the arguments or in the environment.
+fn is_option_set(args: &[OsString], name: &str) -> bool {
+    std::env::var_os(name).is_some()
+        || config_file_option(name)
+            .map_or(false, |(_, long, short)| is_option_given(args, long, *short))
+}
+
+/// The path of the configuration file given in the arguments or, if it is not
+/// given there, in the environment.
+fn config_file_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut option_args = option_args(args);
+    while let Some(arg) = option_args.next() {
+        if arg == "--config-file" {
+            return option_args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config-file=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(format!("{}CONFIG_FILE", ENV_PREFIX)).map(PathBuf::from)
+}
+
+/// Supply the values of the configuration file given by the arguments (or the
+/// environment) to the argument parser, by adding them to the arguments. A
+/// value is only added if its option is neither given as an argument nor in
+/// the environment, so the precedence is: command line arguments, then
+/// environment variables, then the configuration file, then the defaults.
+/// The file is read before the arguments are parsed, so it can also supply
+/// the required options, and the arguments are then parsed once, which
+/// reports invalid values in the file like those of the arguments.
+fn args_with_config_file(mut args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let path = match config_file_path(&args) {
+        Some(path) => path,
+        None => return Ok(args),
+    };
+    let mut file_args = Vec::new();
+    for (name, value) in read_config_file(&path)? {
+        if is_option_set(&args, &name) {
+            continue;
+        }
+        if let (Some((_, long, _)), Some(value)) =
+            (config_file_option(&name), config_value_to_arg(&name, &value)?)
+        {
+            file_args.push(OsString::from(format!("--{}={}", long, value)));
+        }
+    }
+    // The values are added before the arguments, so they are never taken as
+    // positional arguments.
+    args.splice(1..1, file_args);
+    Ok(args)
+}
+
+/// The settings that can be changed while the node is running, by reloading
+/// the configuration file. Settings that are not present in the file are
+/// `None` and are left unchanged.
+#[derive(Debug, Default)]
+pub struct DynamicConfigUpdate {
+    pub desired_nodes:              Option<u16>,
+    pub max_allowed_nodes:          Option<u16>,
+    pub max_outbound_nodes:         Option<u16>,
+    pub max_inbound_nodes:          Option<u16>,
+    pub relay_broadcast_percentage: Option<f64>,
+    /// A maximum latency of 0 removes the limit.
+    pub max_latency:                Option<u64>,
+    pub log_level:                  Option<log::LevelFilter>,
+}
+
+/// Read the dynamic settings from the configuration file. As on startup, the
+/// settings that are given in the arguments the node was started with, or in
+/// the environment, take precedence over the file and are not updated.
+pub fn read_dynamic_config(path: &Path, args: &[OsString]) -> anyhow::Result<DynamicConfigUpdate> {
+    let mut values = read_config_file(path)?;
+    let is_set = |key: &str| is_option_set(args, &format!("{}{}", ENV_PREFIX, key));
+    // The log level is given by the three flags together, so it is kept if any
+    // of them is given.
+    let log_level_is_set =
+        ["LOG_LEVEL_TRACE", "LOG_LEVEL_DEBUG", "LOG_LEVEL_INFO"].iter().any(|key| is_set(key));
+    values.retain(|name, _| !is_option_set(args, name));
+    fn get<T: FromStr>(
+        values: &BTreeMap<String, toml::Value>,
+        key: &str,
+    ) -> anyhow::Result<Option<T>>
+    where
+        T::Err: std::fmt::Display, {
+        let name = format!("{}{}", ENV_PREFIX, key);
+        match values.get(&name).map(|value| config_value_to_arg(&name, value)).transpose()? {
+            Some(Some(value)) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(e) => bail!("Invalid value for '{}': {}", name, e),
+            },
+            _ => Ok(None),
+        }
+    }
+    let flag = |key: &str| -> anyhow::Result<Option<bool>> {
+        let name = format!("{}{}", ENV_PREFIX, key);
+        match values.get(&name) {
+            Some(toml::Value::Boolean(b)) => Ok(Some(*b)),
+            Some(value) => bail!("Invalid value for '{}': expected a boolean, got {}", name, value),
+            None => Ok(None),
+        }
+    };
+    let log_level =
+        match (flag("LOG_LEVEL_TRACE")?, flag("LOG_LEVEL_DEBUG")?, flag("LOG_LEVEL_INFO")?) {
+            (Some(true), ..) => Some(log::LevelFilter::Trace),
+            (_, Some(true), _) => Some(log::LevelFilter::Debug),
+            (None, None, None) => None,
+            _ => Some(log::LevelFilter::Info),
+        }
+        .filter(|_| !log_level_is_set);
+    Ok(DynamicConfigUpdate {
+        desired_nodes: get(&values, "CONNECTION_DESIRED_NODES")?,
+        max_allowed_nodes: get(&values, "CONNECTION_MAX_ALLOWED_NODES")?,
+        max_outbound_nodes: get(&values, "CONNECTION_MAX_OUTBOUND_NODES")?,
+        max_inbound_nodes: get(&values, "CONNECTION_MAX_INBOUND_NODES")?,
+        relay_broadcast_percentage: get(&values, "CONNECTION_RELAY_BROADCAST_PERCENTAGE")?,
+        max_latency: get(&values, "CONNECTION_MAX_LATENCY")?,
+        log_level,
+    })
+}
+
 /// Verifies the validity of the configuration.
 pub fn parse_config() -> anyhow::Result<Config> {
+    let args = args_with_config_file(std::env::args_os().collect())?;
+
     let conf = {
         let app = Config::clap()
             .setting(AppSettings::ArgRequiredElseHelp)
             .setting(AppSettings::NextLineHelp)
             .global_setting(AppSettings::ColoredHelp);
-        Config::from_clap(&app.get_matches())
+        Config::from_clap(&app.get_matches_from(args))
     };
 
     ensure!(
@@ -990,6 +1768,13 @@ pub fn parse_config() -> anyhow::Result<Config> {
         );
     }
 
+    if let Some(max_outbound_nodes) = conf.connection.max_outbound_nodes {
+        ensure!(
+            max_outbound_nodes > 0,
+            "Max outbound nodes must be positive, otherwise the node cannot connect to any peers"
+        );
+    }
+
     ensure!(
         conf.connection.hard_connection_limit >= conf.connection.desired_nodes,
         "Hard connection limit can't be less than what desired nodes is set to"
@@ -1001,6 +1786,31 @@ pub fn parse_config() -> anyhow::Result<Config> {
         "Percentage of peers to relay broadcasted packets to, must be between 0.0 and 1.0"
     );
 
+    for (rate, name) in [
+        (conf.connection.max_upload_rate, "max-upload-rate"),
+        (conf.connection.max_download_rate, "max-download-rate"),
+        (conf.connection.max_peer_upload_rate, "max-peer-upload-rate"),
+        (conf.connection.max_peer_download_rate, "max-peer-download-rate"),
+    ] {
+        ensure!(rate != Some(0), "{} must be positive", name);
+    }
+
+    ensure!(
+        conf.connection.outbound_queue_capacity > 0,
+        "The outbound queue capacity must be positive"
+    );
+
+    ensure!(
+        conf.connection.outbound_queue_weights.iter().all(|setting| setting.value > 0),
+        "The outbound queue weights must be positive"
+    );
+
+    ensure!(
+        conf.connection.high_priority_bandwidth_share >= 0.0
+            && conf.connection.high_priority_bandwidth_share < 1.0,
+        "The high priority bandwidth share must be at least 0.0 and less than 1.0"
+    );
+
     ensure!(
         conf.cli.baker.maximum_block_size <= 4_000_000_000
             && ((f64::from(conf.cli.baker.maximum_block_size) * 0.9).ceil()) as u32
@@ -1009,6 +1819,13 @@ pub fn parse_config() -> anyhow::Result<Config> {
         PROTOCOL_MAX_MESSAGE_SIZE
     );
 
+    ensure!(conf.common.log_trace_sample_rate > 0, "log-trace-sample-rate must be positive");
+
+    ensure!(
+        (0.0..=1.0).contains(&conf.tracing.otlp_sample_ratio),
+        "otlp-sample-ratio must be between 0 and 1"
+    );
+
     ensure!(
         conf.connection.socket_read_size >= 65535,
         "Socket read size must be set to at least 65535"
@@ -1024,6 +1841,11 @@ pub fn parse_config() -> anyhow::Result<Config> {
         "wait-until-minimum-nodes must be lower than or equal to peer-list-size"
     );
 
+    ensure!(
+        conf.bootstrapper.probe_interval == 0 || conf.bootstrapper.probe_timeout > 0,
+        "bootstrapper-probe-timeout must be positive"
+    );
+
     ensure!(
         conf.connection.max_normal_keep_alive
             >= conf.connection.housekeeping_interval * (KEEP_ALIVE_FACTOR as u64),
@@ -1034,6 +1856,11 @@ pub fn parse_config() -> anyhow::Result<Config> {
         conf.connection.housekeeping_interval
     );
 
+    #[cfg(feature = "network_dump")]
+    if let Some(rotate_bytes) = conf.dump.dump_rotate_bytes {
+        ensure!(rotate_bytes > 0, "dump-rotate-bytes must be positive");
+    }
+
     Ok(conf)
 }
 
@@ -1141,3 +1968,139 @@ impl AppPreferences {
     /// Returns the path to the config directory.
     pub fn get_config_dir(&self) -> &Path { &self.override_config_dir }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn read_dynamic(contents: &str) -> anyhow::Result<DynamicConfigUpdate> {
+        read_dynamic_with_args(contents, &[])
+    }
+
+    fn read_dynamic_with_args(
+        contents: &str,
+        args: &[&str],
+    ) -> anyhow::Result<DynamicConfigUpdate> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(contents.as_bytes())?;
+        let mut all_args = vec![OsString::from("concordium-node")];
+        all_args.extend(args.iter().map(OsString::from));
+        read_dynamic_config(file.path(), &all_args)
+    }
+
+    fn parse_with_config_file(contents: &str, args: &[&str]) -> anyhow::Result<Config> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(contents.as_bytes())?;
+        let mut all_args =
+            vec![OsString::from("concordium-node"), "--config-file".into(), file.path().into()];
+        all_args.extend(args.iter().map(OsString::from));
+        Ok(Config::from_iter_safe(args_with_config_file(all_args)?)?)
+    }
+
+    #[test]
+    fn test_config_file() -> anyhow::Result<()> {
+        // The required options can be given in the file only.
+        let conf = parse_with_config_file(
+            "config_dir = \".\"\ndata_dir = \".\"\nprometheus_push_gateway_password = \
+             \"hunter2\"\n[connection]\ndesired_nodes = 12\nmax_allowed_nodes = 20\nconnect_to = \
+             [\"10.0.0.1:8888\", \"10.0.0.2:8888\"]\nno_bootstrap_dns = true\nclear_bans = false\n",
+            &["--desired-nodes", "9"],
+        )?;
+        // command line arguments take precedence over the file
+        assert_eq!(conf.connection.desired_nodes, 9);
+        assert_eq!(conf.connection.max_allowed_nodes, Some(20));
+        assert_eq!(conf.connection.connect_to, vec!["10.0.0.1:8888", "10.0.0.2:8888"]);
+        // flags are enabled by true and left disabled by false
+        assert!(conf.connection.no_bootstrap_dns);
+        assert!(!conf.connection.clear_bans);
+        // secrets are not printed
+        assert_eq!(
+            conf.prometheus.prometheus_push_password.as_ref().map(|p| p.0.as_str()),
+            Some("hunter2")
+        );
+        assert!(!format!("{:?}", conf).contains("hunter2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file_options() {
+        // The options of the configuration file are those of the argument parser
+        // that have an environment variable.
+        let mut help = Vec::new();
+        Config::clap().write_long_help(&mut help).unwrap();
+        let help =
+            String::from_utf8(help).unwrap().split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut env_names = help
+            .split("[env: ")
+            .skip(1)
+            .filter_map(|s| s.split('=').next()?.strip_prefix(ENV_PREFIX))
+            .filter(|name| !["CONFIG_FILE", "CHECK_CONFIG"].contains(name))
+            .collect::<Vec<_>>();
+        let mut options = CONFIG_FILE_OPTIONS.iter().map(|(name, ..)| *name).collect::<Vec<_>>();
+        env_names.sort_unstable();
+        options.sort_unstable();
+        assert_eq!(env_names, options);
+        for (name, long, short) in CONFIG_FILE_OPTIONS {
+            assert!(help.contains(&format!("--{} ", long)), "Unknown option for {}", name);
+            if let Some(short) = short {
+                assert!(
+                    help.contains(&format!("-{}, --{} ", short, long)),
+                    "Unknown option for {}",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_file_rejects_invalid_settings() {
+        assert!(parse_with_config_file("[connection]\nno_such_setting = 1\n", &[]).is_err());
+        assert!(parse_with_config_file("config_file = \"other.toml\"\n", &[]).is_err());
+        assert!(parse_with_config_file("[connection]\ndesired_nodes = \"many\"\n", &[]).is_err());
+    }
+
+    #[test]
+    fn test_read_dynamic_config() -> anyhow::Result<()> {
+        let update = read_dynamic(
+            "log_level_debug = true\n[connection]\ndesired_nodes = 12\nmax_latency = \
+             0\nrelay_broadcast_percentage = 0.5\n",
+        )?;
+        assert_eq!(update.desired_nodes, Some(12));
+        assert_eq!(update.max_allowed_nodes, None);
+        assert_eq!(update.max_outbound_nodes, None);
+        assert_eq!(update.max_latency, Some(0));
+        assert_eq!(update.relay_broadcast_percentage, Some(0.5));
+        assert_eq!(update.log_level, Some(log::LevelFilter::Debug));
+
+        assert_eq!(read_dynamic("")?.log_level, None);
+        assert_eq!(
+            read_dynamic("log_level_trace = true\nlog_level_debug = true\n")?.log_level,
+            Some(log::LevelFilter::Trace)
+        );
+        // disabling the flags goes back to the default level
+        assert_eq!(
+            read_dynamic("log_level_debug = false\n")?.log_level,
+            Some(log::LevelFilter::Info)
+        );
+
+        // the arguments the node was started with take precedence over the file
+        let update = read_dynamic_with_args(
+            "log_level_debug = true\n[connection]\ndesired_nodes = 12\nmax_latency = 0\n",
+            &["--desired-nodes=9", "--trace"],
+        )?;
+        assert_eq!(update.desired_nodes, None);
+        assert_eq!(update.max_latency, Some(0));
+        assert_eq!(update.log_level, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_dynamic_config_rejects_invalid_values() {
+        assert!(read_dynamic("log_level_debug = \"false\"\n").is_err());
+        assert!(read_dynamic("log_level_trace = 1\n").is_err());
+        assert!(read_dynamic("[connection]\ndesired_nodes = \"many\"\n").is_err());
+        assert!(read_dynamic("[connection]\ndesired_nodes = -1\n").is_err());
+        assert!(read_dynamic("[connection]\nno_such_setting = 1\n").is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Bandwidth shaping with token buckets.
+//!
+//! Each direction of traffic can be limited both globally, i.e., for all the
+//! connections combined, and for every connection separately. A message is
+//! admitted as long as the buckets it draws from are not empty and its whole
+//! size is then taken from them, possibly leaving them in debt. This way
+//! messages larger than a bucket's capacity are still sent, while the average
+//! rate stays within the limit.
+//!
+//! Normal priority messages are only admitted as long as a share of each
+//! bucket's capacity remains, which is reserved for high priority messages.
+
+use crate::{configuration::ConnectionConfig, connection::MessageSendingPriority, lock_or_die};
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// A token bucket refilled with `rate` bytes per second, holding at most one
+/// second worth of bytes.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// The number of bytes per second.
+    rate:        f64,
+    /// The current number of bytes that may be consumed. Negative if the
+    /// bucket is in debt.
+    tokens:      f64,
+    /// The time of the last refill.
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket with the given rate in bytes per second.
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate:        rate as f64,
+            tokens:      rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    /// Check whether more than `reserve` (a fraction of the capacity) is
+    /// available in the bucket.
+    fn has_tokens(&mut self, now: Instant, reserve: f64) -> bool {
+        self.refill(now);
+        self.tokens > reserve * self.rate
+    }
+
+    fn consume(&mut self, bytes: usize) { self.tokens -= bytes as f64; }
+}
+
+/// The limits that apply to all the connections combined.
+#[derive(Debug)]
+pub struct GlobalBandwidth {
+    upload:              Option<Mutex<TokenBucket>>,
+    download:            Option<Mutex<TokenBucket>>,
+    peer_upload_rate:    Option<u64>,
+    peer_download_rate:  Option<u64>,
+    /// The fraction of the upload capacity reserved for high priority
+    /// messages.
+    high_priority_share: f64,
+}
+
+impl GlobalBandwidth {
+    pub fn new(conf: &ConnectionConfig) -> Self {
+        Self {
+            upload:              conf
+                .max_upload_rate
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            download:            conf
+                .max_download_rate
+                .map(|rate| Mutex::new(TokenBucket::new(rate))),
+            peer_upload_rate:    conf.max_peer_upload_rate,
+            peer_download_rate:  conf.max_peer_download_rate,
+            high_priority_share: conf.high_priority_bandwidth_share,
+        }
+    }
+
+    /// Whether the upload is limited, either for all the connections combined
+    /// or for every connection.
+    pub fn limits_upload(&self) -> bool { self.upload.is_some() || self.peer_upload_rate.is_some() }
+}
+
+/// The bandwidth limits of a single connection.
+#[derive(Debug)]
+pub struct BandwidthShaper {
+    global:   Arc<GlobalBandwidth>,
+    upload:   Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl BandwidthShaper {
+    pub fn new(global: Arc<GlobalBandwidth>) -> Self {
+        Self {
+            upload: global.peer_upload_rate.map(TokenBucket::new),
+            download: global.peer_download_rate.map(TokenBucket::new),
+            global,
+        }
+    }
+
+    /// Try to admit a message of the given size and priority for sending,
+    /// consuming the tokens if it is admitted.
+    pub fn admit_upload(&mut self, priority: MessageSendingPriority, bytes: usize) -> bool {
+        let reserve = match priority {
+            MessageSendingPriority::High => 0.0,
+            MessageSendingPriority::Normal => self.global.high_priority_share,
+        };
+        admit(self.global.upload.as_ref(), self.upload.as_mut(), reserve, bytes)
+    }
+
+    /// Check whether more bytes may be read from the socket.
+    pub fn can_download(&mut self) -> bool {
+        let now = Instant::now();
+        self.download.as_mut().map_or(true, |bucket| bucket.has_tokens(now, 0.0))
+            && self
+                .global
+                .download
+                .as_ref()
+                .map_or(true, |bucket| lock_or_die!(bucket).has_tokens(now, 0.0))
+    }
+
+    /// Account bytes read from the socket.
+    pub fn consume_download(&mut self, bytes: usize) {
+        if let Some(bucket) = self.download.as_mut() {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = self.global.download.as_ref() {
+            lock_or_die!(bucket).consume(bytes);
+        }
+    }
+}
+
+/// Admit `bytes` if both the global and the connection's buckets hold more
+/// than the reserve, taking the bytes from both.
+fn admit(
+    global: Option<&Mutex<TokenBucket>>,
+    mut local: Option<&mut TokenBucket>,
+    reserve: f64,
+    bytes: usize,
+) -> bool {
+    let now = Instant::now();
+    // keep the global bucket locked between the check and the consumption so
+    // concurrent connections can't overdraw it
+    let mut global = global.map(|bucket| lock_or_die!(bucket));
+    let admitted = local.as_mut().map_or(true, |bucket| bucket.has_tokens(now, reserve))
+        && global.as_mut().map_or(true, |bucket| bucket.has_tokens(now, reserve));
+    if admitted {
+        if let Some(bucket) = local {
+            bucket.consume(bytes);
+        }
+        if let Some(bucket) = global.as_mut() {
+            bucket.consume(bytes);
+        }
+    }
+    admitted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000);
+        bucket.last_refill = start;
+        assert!(bucket.has_tokens(start, 0.0));
+        // a message larger than the capacity is admitted, leaving the bucket in debt
+        bucket.consume(1500);
+        assert!(!bucket.has_tokens(start, 0.0));
+        assert!(!bucket.has_tokens(start + Duration::from_millis(400), 0.0));
+        assert!(bucket.has_tokens(start + Duration::from_millis(600), 0.0));
+        // the bucket never holds more than a second worth of bytes
+        assert!(bucket.has_tokens(start + Duration::from_secs(10), 0.0));
+        assert_eq!(bucket.tokens, 1000.0);
+        // the reserve is kept for high priority messages
+        bucket.consume(800);
+        assert!(!bucket.has_tokens(start + Duration::from_secs(10), 0.25));
+        assert!(bucket.has_tokens(start + Duration::from_secs(10), 0.0));
+    }
+
+    #[test]
+    fn test_high_priority_share() {
+        let global = Arc::new(GlobalBandwidth {
+            upload:              None,
+            download:            None,
+            peer_upload_rate:    Some(1000),
+            peer_download_rate:  None,
+            high_priority_share: 0.5,
+        });
+        assert!(global.limits_upload());
+        let mut shaper = BandwidthShaper::new(global);
+        assert!(shaper.admit_upload(MessageSendingPriority::Normal, 600));
+        // only the reserved share remains
+        assert!(!shaper.admit_upload(MessageSendingPriority::Normal, 1));
+        assert!(shaper.admit_upload(MessageSendingPriority::High, 1));
+        assert!(shaper.can_download());
+
+        let unlimited = GlobalBandwidth {
+            upload:              None,
+            download:            Some(Mutex::new(TokenBucket::new(1000))),
+            peer_upload_rate:    None,
+            peer_download_rate:  Some(1000),
+            high_priority_share: 0.5,
+        };
+        assert!(!unlimited.limits_upload());
+    }
+}
@@ -1,4 +1,4 @@
-use anyhow::bail;
+use anyhow::{bail, ensure};
 use byteorder::{NetworkEndian, WriteBytesExt};
 use bytesize::ByteSize;
 use mio::net::TcpStream;
@@ -8,6 +8,7 @@ use noiseexplorer_xx::{
     types::Keypair,
 };
 
+use super::bandwidth::BandwidthShaper;
 use crate::{configuration::PROTOCOL_MAX_MESSAGE_SIZE, p2p::maintenance::P2PNode};
 
 use std::{
@@ -16,7 +17,9 @@ use std::{
     convert::TryInto,
     io::{Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
     mem,
+    net::SocketAddr,
     sync::{Arc, Weak},
+    time::Duration,
 };
 
 /// The size of the noise message payload.
@@ -101,6 +104,8 @@ pub enum ReadResult {
     WouldBlock,
     /// The read returned 0 bytes, indicating a closed socket.
     Closed,
+    /// The download limit was reached; reading is resumed once it allows it.
+    Throttled,
 }
 
 /// The `Connection`'s socket, noise session and some helper objects.
@@ -119,10 +124,14 @@ pub struct ConnectionLowLevel {
     write_size:     usize,
     /// Whether the socket is writable.
     is_writable:    bool,
+    /// Whether the socket may have data to be read.
+    is_readable:    bool,
     /// Whether the socket has been initialized
     is_initialized: bool,
     /// If specified, the linger value to set for the socket
     so_linger:      Option<u16>,
+    /// The upload and download limits of the connection.
+    pub bandwidth:  BandwidthShaper,
 }
 
 macro_rules! recv_xx_msg {
@@ -153,6 +162,45 @@ macro_rules! send_xx_msg {
     };
 }
 
+/// Check that a node accepting connections listens at the given address,
+/// without establishing a connection with it. This performs the first round
+/// trip of the XX noise handshake (messages A and B) over a blocking socket,
+/// which only succeeds if the node speaks our noise prologue. The connection is
+/// then shut down, so that the node closes it right away instead of waiting for
+/// the rest of the handshake.
+pub fn probe_noise_handshake(addr: SocketAddr, timeout: Duration) -> anyhow::Result<()> {
+    let mut socket = std::net::TcpStream::connect_timeout(&addr, timeout)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
+    socket.set_nodelay(true)?;
+    let mut noise_session = NoiseSession::init_session(true, PROLOGUE, Keypair::default());
+
+    // send message A, as in `send_handshake_message_a`
+    let pad = 16;
+    let mut msg = vec![];
+    msg.write_u32::<NetworkEndian>((DHLEN + PSK.len() + pad) as u32)?;
+    msg.append(&mut vec![0u8; DHLEN]);
+    msg.extend(PSK);
+    msg.append(&mut vec![0u8; pad]);
+    noise_session.send_message(&mut msg[PAYLOAD_SIZE..])?;
+    socket.write_all(&msg)?;
+
+    // receive message B
+    let mut size_bytes = [0u8; PAYLOAD_SIZE];
+    socket.read_exact(&mut size_bytes)?;
+    let size = PayloadSize::from_be_bytes(size_bytes) as usize;
+    ensure!(
+        size > DHLEN * 2 + MAC_LENGTH * 2 && size < HANDSHAKE_SIZE_LIMIT,
+        "invalid handshake message size ({})",
+        size
+    );
+    let mut msg = vec![0u8; size];
+    socket.read_exact(&mut msg)?;
+    noise_session.recv_message(&mut msg)?;
+    socket.shutdown(std::net::Shutdown::Both)?;
+    Ok(())
+}
+
 impl ConnectionLowLevel {
     /// Creates a new `ConnectionLowLevel` object.
     pub fn new(
@@ -187,8 +235,10 @@ impl ConnectionLowLevel {
             output_queue: VecDeque::with_capacity(WRITE_QUEUE_ALLOC),
             write_size,
             is_writable: false,
+            is_readable: false,
             is_initialized: false,
             so_linger,
+            bandwidth: BandwidthShaper::new(Arc::clone(&handler.connection_handler.bandwidth)),
         }
     }
 
@@ -312,6 +362,9 @@ impl ConnectionLowLevel {
         Ok(payload)
     }
 
+    /// Checks whether we initiated the connection.
+    pub fn is_initiator(&self) -> bool { self.noise_session.is_initiator() }
+
     #[inline]
     /// Checks whether the low-level noise handshake is complete.
     fn is_post_handshake(&self) -> bool {
@@ -324,6 +377,15 @@ impl ConnectionLowLevel {
 
     // input
 
+    /// Notify that the socket has become readable.
+    #[inline]
+    pub fn notify_readable(&mut self) { self.is_readable = true; }
+
+    /// Checks whether there may be data to be read from the socket, i.e.,
+    /// whether the last read neither blocked nor was the socket closed.
+    #[inline]
+    pub fn is_readable(&self) -> bool { self.is_readable }
+
     /// Attempts to read a complete message from the socket.
     #[inline]
     pub fn read_from_socket(&mut self) -> anyhow::Result<ReadResult> {
@@ -333,17 +395,27 @@ impl ConnectionLowLevel {
         // if there's any carryover bytes to be read from the socket buffer,
         // process them before reading from the socket again
         if self.socket_buffer.remaining == 0 {
+            if !self.bandwidth.can_download() {
+                return Ok(ReadResult::Throttled);
+            }
             let len = self.read_size() - self.socket_buffer.offset;
             match self.socket.read(self.socket_buffer.slice_mut(len)) {
-                Ok(0) => return Ok(ReadResult::Closed),
+                Ok(0) => {
+                    self.is_readable = false;
+                    return Ok(ReadResult::Closed);
+                }
                 Ok(num_bytes) => {
+                    self.bandwidth.consume_download(num_bytes);
                     // trace!(
                     //     "Read {} from the socket",
                     //     ByteSize(num_bytes as u64).to_string_as(true)
                     // );
                     self.socket_buffer.remaining = num_bytes;
                 }
-                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(ReadResult::WouldBlock),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    self.is_readable = false;
+                    return Ok(ReadResult::WouldBlock);
+                }
                 Err(e) => return Err(e.into()),
             }
         };
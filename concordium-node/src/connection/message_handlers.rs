@@ -7,6 +7,7 @@ use crate::{
     },
     configuration::{is_compatible_version, is_compatible_wire_version, MAX_PEER_NETWORKS},
     connection::{ConnChange, Connection},
+    lock_or_die,
     network::{
         Handshake, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse,
         PacketDestination,
@@ -57,7 +58,10 @@ impl Connection {
             }
             NetworkPayload::NetworkResponse(NetworkResponse::PeerList(peers), ..) => {
                 debug!("Got a PeerList ({} peers) from peer {}", peers.len(), peer_id);
-                self.handler.register_conn_change(ConnChange::NewPeers(peers));
+                let added = lock_or_die!(self.handler.connection_handler.address_manager)
+                    .add(&peers, self.remote_addr().ip());
+                trace!("Added {} new addresses from peer {}", added, peer_id);
+                self.handler.register_conn_change(ConnChange::ConnectToKnownAddresses);
                 Ok(())
             }
             NetworkPayload::NetworkRequest(NetworkRequest::JoinNetwork(network), ..) => {
@@ -123,12 +127,7 @@ impl Connection {
             }
         }
 
-        self.promote_to_post_handshake(
-            handshake.remote_id,
-            handshake.remote_port,
-            &handshake.networks,
-            wire_version,
-        );
+        self.promote_to_post_handshake(&handshake, wire_version);
 
         if self.handler.peer_type() == PeerType::Bootstrapper {
             debug!("Running in bootstrapper mode; attempting to send a PeerList upon handshake");
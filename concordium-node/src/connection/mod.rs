@@ -1,15 +1,20 @@
 //! Connection handling.
 
-mod low_level;
+pub mod bandwidth;
+pub mod low_level;
 pub mod message_handlers;
+pub mod queues;
 #[cfg(test)]
 mod tests;
+pub mod traffic;
 
 use anyhow::{bail, ensure};
 use bytesize::ByteSize;
 use circular_queue::CircularQueue;
 use low_level::ConnectionLowLevel;
 use mio::{net::TcpStream, Interest, Token};
+use queues::MessageQueues;
+use traffic::{TrafficCategory, TrafficDirection, TrafficStats};
 
 #[cfg(feature = "network_dump")]
 use crate::dumper::DumpItem;
@@ -21,10 +26,10 @@ use crate::{
     },
     configuration::MAX_PEER_NETWORKS,
     connection::low_level::ReadResult,
-    netmsg,
+    logging, netmsg,
     network::{
-        NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest, NetworkResponse,
-        Networks, WireProtocolVersion, WIRE_PROTOCOL_CURRENT_VERSION,
+        Handshake, NetworkId, NetworkMessage, NetworkPacket, NetworkPayload, NetworkRequest,
+        NetworkResponse, Networks, WireProtocolVersion, WIRE_PROTOCOL_CURRENT_VERSION,
     },
     p2p::P2PNode,
     read_or_die, write_or_die,
@@ -33,26 +38,26 @@ use crate::{
 use crate::consensus_ffi::helpers::PacketType;
 
 use std::{
-    collections::VecDeque,
     convert::TryFrom,
     fmt,
     net::SocketAddr,
-    ops::{Index, IndexMut},
     str::FromStr,
     sync::{
         atomic::{AtomicI64, AtomicU64, Ordering},
         Arc, RwLock,
     },
+    time::Instant,
 };
 
 /// Designates the sending priority of outgoing messages.
-// If a message is labelled as having `High` priority it is always pushed to the
-// front of the queue in the sinks when sending, and otherwise to the back.
-#[derive(PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+// If a message is labelled as having `High` priority it is pushed in front of
+// the `Normal` priority messages of the same category when sending, and it may
+// use the share of the upload limits that is reserved for high priority.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
 pub enum MessageSendingPriority {
     /// Queued FIFO-style.
     Normal,
-    /// Sent before all `Normal` messages.
+    /// Sent before all `Normal` messages of the same category.
     High,
 }
 
@@ -235,6 +240,8 @@ pub struct ConnectionStats {
     pub bytes_received:    AtomicU64,
     /// Number of bytes sent.
     pub bytes_sent:        AtomicU64,
+    /// Messages and bytes by message category and direction.
+    pub traffic:           TrafficStats,
 }
 
 impl ConnectionStats {
@@ -250,6 +257,7 @@ impl ConnectionStats {
             messages_received:  AtomicU64::new(0),
             bytes_received:     AtomicU64::new(0),
             bytes_sent:         AtomicU64::new(0),
+            traffic:            TrafficStats::default(),
         }
     }
 
@@ -302,64 +310,20 @@ pub enum ConnChange {
         /// whether the connection was given or discovered
         given:     bool,
     },
-    /// Prospect peers to possibly connect to.
-    NewPeers(Vec<P2PPeer>),
+    /// Attempt to connect to addresses selected from the address manager.
+    ConnectToKnownAddresses,
     /// Promotion to post-handshake.
     Promotion(Token),
-    /// To be removed from the list of connections.
+    /// To be removed from the list of connections. A connected peer is also
+    /// removed from the persisted peers.
     RemovalByToken(Token),
+    /// Closed by the peer and to be removed from the list of connections. The
+    /// persisted entry of the peer is kept, so that it is dialed again on
+    /// startup.
+    ClosedByPeer(Token),
     RemoveAllByTokens(Vec<Token>),
 }
 
-/// Message queues, indexed by priority.
-pub struct MessageQueues {
-    pub low:  VecDeque<Arc<[u8]>>,
-    pub high: VecDeque<Arc<[u8]>>,
-}
-
-impl Index<MessageSendingPriority> for MessageQueues {
-    type Output = VecDeque<Arc<[u8]>>;
-
-    fn index(&self, priority: MessageSendingPriority) -> &Self::Output {
-        match priority {
-            MessageSendingPriority::Normal => &self.low,
-            MessageSendingPriority::High => &self.high,
-        }
-    }
-}
-
-impl IndexMut<MessageSendingPriority> for MessageQueues {
-    fn index_mut(&mut self, priority: MessageSendingPriority) -> &mut Self::Output {
-        match priority {
-            MessageSendingPriority::Normal => &mut self.low,
-            MessageSendingPriority::High => &mut self.high,
-        }
-    }
-}
-
-impl MessageQueues {
-    /// Create queues with the specified initial capacities.
-    pub fn new(low_capacity: usize, high_capacity: usize) -> Self {
-        Self {
-            low:  VecDeque::with_capacity(low_capacity),
-            high: VecDeque::with_capacity(high_capacity),
-        }
-    }
-
-    /// Add a message to the queue with the appropriate priority.
-    pub fn enqueue(&mut self, priority: MessageSendingPriority, message: Arc<[u8]>) {
-        self[priority].push_back(message);
-    }
-
-    /// Dequeue a message, taking from the high priority queue first.
-    pub fn dequeue(&mut self) -> Option<Arc<[u8]>> {
-        match self.high.pop_front() {
-            Some(message) => Some(message),
-            None => self.low.pop_front(),
-        }
-    }
-}
-
 /// A collection of objects related to the connection to a single peer.
 pub struct Connection {
     /// A reference to the parent node.
@@ -429,7 +393,10 @@ impl Connection {
             low_level,
             remote_end_networks: Default::default(),
             stats,
-            pending_messages: MessageQueues::new(1024, 128),
+            pending_messages: MessageQueues::new(
+                Arc::clone(&handler.connection_handler.queue_policy),
+                handler.stats.outbound_dropped_messages.clone(),
+            ),
             // When we create the connection, we set the wire protocol version
             // to the current version, but this is overwritten in the handshake.
             wire_version: WIRE_PROTOCOL_CURRENT_VERSION,
@@ -458,6 +425,19 @@ impl Connection {
     /// Obtain the timestamp of when the connection was interacted with last.
     pub fn last_seen(&self) -> u64 { self.stats.last_seen.load(Ordering::Relaxed) }
 
+    /// Check whether the connection was initiated by us, as opposed to
+    /// accepted from the peer.
+    pub fn is_outbound(&self) -> bool { self.low_level.is_initiator() }
+
+    /// The label of the connection's direction used in metrics.
+    fn direction_label(&self) -> &'static str {
+        if self.is_outbound() {
+            "outbound"
+        } else {
+            "inbound"
+        }
+    }
+
     #[inline]
     fn is_packet_duplicate(&self, packet: &mut NetworkPacket) -> anyhow::Result<bool> {
         use super::network::PacketDestination;
@@ -503,7 +483,7 @@ impl Connection {
             match self.low_level.read_from_socket()? {
                 ReadResult::Complete(msg) => self.process_message(Arc::from(msg), conn_stats)?,
                 ReadResult::Incomplete => {}
-                ReadResult::WouldBlock => return Ok(true),
+                ReadResult::WouldBlock | ReadResult::Throttled => return Ok(true),
                 ReadResult::Closed => return Ok(false),
             }
         }
@@ -515,6 +495,11 @@ impl Connection {
         bytes: Arc<[u8]>,
         conn_stats: &[PeerStats],
     ) -> anyhow::Result<()> {
+        // the node id of the peer is only known once the handshake is done
+        let _log_context = match self.remote_peer.self_id {
+            Some(id) => logging::with_peer(id),
+            None => logging::with_peer(self.remote_peer.local_id),
+        };
         self.update_last_seen();
         self.stats.messages_received.fetch_add(1, Ordering::Relaxed);
         self.stats.bytes_received.fetch_add(bytes.len() as u64, Ordering::Relaxed);
@@ -527,7 +512,13 @@ impl Connection {
             self.send_to_dump(bytes.clone(), true);
         }
 
-        let mut message = NetworkMessage::deserialize(&bytes)?;
+        let message = NetworkMessage::deserialize(&bytes);
+        self.record_traffic(
+            TrafficDirection::Received,
+            message.as_ref().map_or(TrafficCategory::Invalid, TrafficCategory::of_message),
+            bytes.len(),
+        );
+        let mut message = message?;
 
         if let NetworkPayload::NetworkPacket(ref mut packet) = message.payload {
             // disregard packets when in bootstrapper mode
@@ -547,19 +538,25 @@ impl Connection {
     /// Concludes the connection's handshake process.
     pub fn promote_to_post_handshake(
         &mut self,
-        id: P2PNodeId,
-        peer_port: u16,
-        nets: &Networks,
+        handshake: &Handshake,
         wire_version: WireProtocolVersion,
     ) {
+        let id = handshake.remote_id;
         self.remote_peer.self_id = Some(id);
-        self.remote_peer.external_port = peer_port;
+        self.remote_peer.external_port = handshake.remote_port;
         self.handler.stats.connected_peers.inc();
+        if self.remote_peer.peer_type == PeerType::Node {
+            self.handler
+                .stats
+                .connected_peers_by_direction
+                .with_label_values(&[self.direction_label()])
+                .inc();
+        }
         self.handler.stats.total_peers.inc();
         if self.remote_peer.peer_type == PeerType::Bootstrapper {
             self.handler.update_last_bootstrap();
         }
-        self.populate_remote_end_networks(self.remote_peer, nets);
+        self.populate_remote_end_networks(self.remote_peer, handshake);
         self.wire_version = wire_version;
         self.handler.register_conn_change(ConnChange::Promotion(self.token()));
         debug!(
@@ -568,10 +565,15 @@ impl Connection {
         );
     }
 
-    /// Queues a message to be sent to the connection.
+    /// Queues a message of the given category to be sent to the connection.
     #[inline]
-    pub fn async_send(&mut self, message: Arc<[u8]>, priority: MessageSendingPriority) {
-        self.pending_messages.enqueue(priority, message);
+    pub fn async_send(
+        &mut self,
+        message: Arc<[u8]>,
+        category: TrafficCategory,
+        priority: MessageSendingPriority,
+    ) {
+        self.pending_messages.enqueue(priority, category, message, Instant::now());
     }
 
     /// Update the timestamp of when the connection was seen last.
@@ -583,8 +585,8 @@ impl Connection {
     }
 
     /// Register connection's remote end networks.
-    pub fn populate_remote_end_networks(&mut self, peer: RemotePeer, networks: &Networks) {
-        self.remote_end_networks.extend(networks.iter());
+    pub fn populate_remote_end_networks(&mut self, peer: RemotePeer, handshake: &Handshake) {
+        self.remote_end_networks.extend(handshake.networks.iter());
 
         // Since buckets are only used if the node itself is running as a bootstrapper
         // we don't want to add anything to buckets otherwise.
@@ -593,7 +595,9 @@ impl Connection {
         {
             write_or_die!(self.handler.buckets()).insert_into_bucket(
                 peer,
-                networks.to_owned(),
+                handshake.networks.to_owned(),
+                handshake.node_version.clone(),
+                handshake.genesis_blocks.clone(),
                 &self.handler.stats.peer_bucket_size,
             );
         }
@@ -626,7 +630,7 @@ impl Connection {
     #[cfg(feature = "network_dump")]
     fn send_to_dump(&self, buf: Arc<[u8]>, inbound: bool) {
         if let Some(ref sender) = &*read_or_die!(self.handler.connection_handler.log_dumper) {
-            let di = DumpItem::new(inbound, self.remote_peer.addr.ip(), buf);
+            let di = DumpItem::new(inbound, self.remote_peer.addr, self.remote_peer.self_id, buf);
             let _ = sender.send(di);
         }
     }
@@ -642,7 +646,11 @@ impl Connection {
         ping.serialize(&mut serialized)?;
         self.stats.notify_ping();
 
-        self.async_send(Arc::from(serialized), MessageSendingPriority::High);
+        self.async_send(
+            Arc::from(serialized),
+            TrafficCategory::Network,
+            MessageSendingPriority::High,
+        );
 
         Ok(())
     }
@@ -654,7 +662,11 @@ impl Connection {
         let pong = netmsg!(NetworkResponse, NetworkResponse::Pong);
         let mut serialized = Vec::with_capacity(56);
         pong.serialize(&mut serialized)?;
-        self.async_send(Arc::from(serialized), MessageSendingPriority::High);
+        self.async_send(
+            Arc::from(serialized),
+            TrafficCategory::Network,
+            MessageSendingPriority::High,
+        );
 
         Ok(())
     }
@@ -670,23 +682,29 @@ impl Connection {
         let peer_list_resp = match self.handler.peer_type() {
             PeerType::Bootstrapper => {
                 // select random nodes that are post-handshake
+                let config = &self.handler.config;
+                let genesis_blocks = read_or_die!(config.regenesis_arc.blocks).clone();
+                let policy = self.handler.peer_list_policy(&genesis_blocks);
                 let random_nodes = read_or_die!(self.handler.buckets())
                     .get_random_nodes(
-                        requestor,
-                        self.handler.config.bootstrapper_peer_list_size,
+                        Some(requestor),
+                        config.bootstrapper_peer_list_size,
                         &nets,
+                        &policy,
                     )
                     .iter()
                     .filter_map(RemotePeer::peer)
                     .collect::<Vec<_>>();
 
-                if !random_nodes.is_empty()
-                    && random_nodes.len()
-                        >= usize::from(self.handler.config.bootstrapper_wait_minimum_peers)
+                if random_nodes.is_empty()
+                    || random_nodes.len() < usize::from(config.bootstrapper_wait_minimum_peers)
                 {
-                    Some(netmsg!(NetworkResponse, NetworkResponse::PeerList(random_nodes)))
-                } else {
                     None
+                } else if !self.handler.register_peer_list_served(self.remote_peer.addr.ip()) {
+                    debug!("Not sending a PeerList to peer {}; it was served recently", requestor);
+                    return Ok(());
+                } else {
+                    Some(netmsg!(NetworkResponse, NetworkResponse::PeerList(random_nodes)))
                 }
             }
             PeerType::Node => {
@@ -713,7 +731,11 @@ impl Connection {
 
             let mut serialized = Vec::with_capacity(256);
             resp.serialize(&mut serialized)?;
-            self.async_send(Arc::from(serialized), MessageSendingPriority::Normal);
+            self.async_send(
+                Arc::from(serialized),
+                TrafficCategory::Network,
+                MessageSendingPriority::Normal,
+            );
 
             Ok(())
         } else {
@@ -722,10 +744,26 @@ impl Connection {
         }
     }
 
+    /// Account a message in the traffic statistics of the connection and of
+    /// the node.
+    fn record_traffic(&self, direction: TrafficDirection, category: TrafficCategory, bytes: usize) {
+        self.stats.traffic.record(direction, category, bytes);
+        let labels = [direction.label(), category.label()];
+        self.handler.stats.traffic_messages.with_label_values(&labels).inc();
+        self.handler.stats.traffic_bytes.with_label_values(&labels).inc_by(bytes as u64);
+    }
+
     /// Processes a queue with pending messages, writing them to the socket.
     #[inline]
     pub fn send_pending_messages(&mut self) -> anyhow::Result<()> {
-        while let Some(msg) = self.pending_messages.dequeue() {
+        let now = Instant::now();
+        while let Some(next) = self.pending_messages.peek(now) {
+            if !self.low_level.bandwidth.admit_upload(next.priority, next.payload.len()) {
+                // the remaining messages are sent once the upload limits allow it
+                break;
+            }
+            let queued = self.pending_messages.dequeue().unwrap(); // safe - just peeked
+            let msg = queued.payload;
             trace!(
                 "Attempting to send {} to {}",
                 ByteSize(msg.len() as u64).to_string_as(true),
@@ -739,6 +777,7 @@ impl Connection {
             self.handler.stats.sent_bytes.inc_by(msg.len() as u64);
             self.stats.messages_sent.fetch_add(1, Ordering::Relaxed);
             self.stats.bytes_sent.fetch_add(msg.len() as u64, Ordering::Relaxed);
+            self.record_traffic(TrafficDirection::Sent, queued.category, msg.len());
 
             #[cfg(feature = "network_dump")]
             {
@@ -759,6 +798,13 @@ impl Drop for Connection {
         // update peer stats if it was post-handshake
         if self.remote_id().is_some() {
             self.handler.stats.connected_peers.dec();
+            if self.remote_peer.peer_type == PeerType::Node {
+                self.handler
+                    .stats
+                    .connected_peers_by_direction
+                    .with_label_values(&[self.direction_label()])
+                    .dec();
+            }
         }
 
         if let Err(e) = self.handler.poll_registry.deregister(&mut self.low_level.socket) {
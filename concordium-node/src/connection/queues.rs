@@ -0,0 +1,571 @@
+//! The queues of messages waiting to be sent to a connection.
+//!
+//! Messages are queued by their traffic category and the queues are served
+//! by deficit round robin, so that each category gets a share of the
+//! connection proportional to its weight. Messages of some categories expire
+//! after a deadline and are then dropped instead of being sent. Once the
+//! queues are full, the drop policy decides which message is dropped.
+
+use super::{
+    traffic::{TrafficCategory, TRAFFIC_CATEGORY_COUNT},
+    MessageSendingPriority,
+};
+use crate::{configuration::ConnectionConfig, consensus_ffi::helpers::PacketType};
+use anyhow::{bail, Context};
+use prometheus::IntCounterVec;
+use std::{
+    collections::VecDeque,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The number of bytes a queue may send per round for each unit of its
+/// weight.
+const QUANTUM: usize = 16 * 1024;
+
+/// The weights of the categories if they are not configured.
+const DEFAULT_WEIGHTS: [(TrafficCategory, u64); 3] = [
+    (TrafficCategory::Packet(PacketType::Transaction), 1),
+    (TrafficCategory::Packet(PacketType::CatchUpStatus), 2),
+    (TrafficCategory::Packet(PacketType::FinalizationMessage), 4),
+];
+
+/// The weight of the categories that are neither configured nor in
+/// [`DEFAULT_WEIGHTS`].
+const DEFAULT_WEIGHT: u64 = 8;
+
+/// The deadlines (in milliseconds) of the categories if they are not
+/// configured. The other categories don't expire by default.
+const DEFAULT_DEADLINES: [(TrafficCategory, u64); 3] = [
+    (TrafficCategory::Packet(PacketType::Transaction), 30_000),
+    (TrafficCategory::Packet(PacketType::CatchUpStatus), 30_000),
+    (TrafficCategory::Packet(PacketType::FinalizationMessage), 10_000),
+];
+
+/// Decides which message is dropped when a message is queued while the
+/// queues are full. Normal priority messages are always dropped before high
+/// priority ones, and a high priority message is only dropped to make room for
+/// another high priority message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the new message.
+    Newest,
+    /// Drop the oldest message of the new message's category, or the new
+    /// message if there is none.
+    Oldest,
+    /// Drop the oldest message of the category with the lowest weight, unless
+    /// that weight is higher than the new message's, in which case the new
+    /// message is dropped.
+    LowestWeight,
+}
+
+impl FromStr for DropPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "newest" => Ok(DropPolicy::Newest),
+            "oldest" => Ok(DropPolicy::Oldest),
+            "lowest-weight" => Ok(DropPolicy::LowestWeight),
+            _ => bail!("Could not parse the drop policy"),
+        }
+    }
+}
+
+/// A value configured for a traffic category, written as `<category>=<value>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CategorySetting {
+    pub category: TrafficCategory,
+    pub value:    u64,
+}
+
+impl FromStr for CategorySetting {
+    type Err = anyhow::Error;
+
+    fn from_str(setting: &str) -> Result<Self, Self::Err> {
+        let (category, value) =
+            setting.split_once('=').context("Expected a setting of the form <category>=<value>")?;
+        Ok(CategorySetting {
+            category: category.trim().parse()?,
+            value:    value.trim().parse().context("Could not parse the value of the setting")?,
+        })
+    }
+}
+
+/// The reason a queued message was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// The queues were full.
+    Full,
+    /// The message's deadline passed.
+    Expired,
+}
+
+impl DropReason {
+    /// The label of the reason used in the metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            DropReason::Full => "full",
+            DropReason::Expired => "expired",
+        }
+    }
+}
+
+/// The configuration of the queues, shared by all the connections.
+#[derive(Debug)]
+pub struct QueuePolicy {
+    /// The maximum number of messages in the queues of a connection.
+    capacity:    usize,
+    drop_policy: DropPolicy,
+    weights:     [u64; TRAFFIC_CATEGORY_COUNT],
+    deadlines:   [Option<Duration>; TRAFFIC_CATEGORY_COUNT],
+}
+
+impl QueuePolicy {
+    pub fn new(conf: &ConnectionConfig) -> Self {
+        let mut weights = [DEFAULT_WEIGHT; TRAFFIC_CATEGORY_COUNT];
+        for &(category, weight) in DEFAULT_WEIGHTS.iter() {
+            weights[category.index()] = weight;
+        }
+        for setting in &conf.outbound_queue_weights {
+            weights[setting.category.index()] = setting.value;
+        }
+
+        let mut deadlines = [None; TRAFFIC_CATEGORY_COUNT];
+        for &(category, deadline) in DEFAULT_DEADLINES.iter() {
+            deadlines[category.index()] = Some(Duration::from_millis(deadline));
+        }
+        for setting in &conf.outbound_queue_deadlines {
+            // a deadline of 0 disables the deadline of the category
+            deadlines[setting.category.index()] =
+                Some(Duration::from_millis(setting.value)).filter(|d| !d.is_zero());
+        }
+
+        Self {
+            capacity: conf.outbound_queue_capacity,
+            drop_policy: conf.outbound_queue_drop_policy,
+            weights,
+            deadlines,
+        }
+    }
+
+    fn quantum(&self, queue: usize) -> usize { self.weights[queue] as usize * QUANTUM }
+}
+
+/// A message waiting to be sent.
+pub struct QueuedMessage {
+    pub payload:  Arc<[u8]>,
+    pub priority: MessageSendingPriority,
+    pub category: TrafficCategory,
+    deadline:     Option<Instant>,
+}
+
+impl QueuedMessage {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.map_or(false, |deadline| deadline <= now)
+    }
+}
+
+/// A queue of the messages of a single category. High priority messages are
+/// kept in front of the normal priority ones.
+#[derive(Default)]
+struct CategoryQueue {
+    messages:   VecDeque<QueuedMessage>,
+    /// The number of high priority messages at the front of the queue.
+    high_count: usize,
+    /// The number of bytes the queue may send in the current round.
+    deficit:    usize,
+}
+
+impl CategoryQueue {
+    fn push(&mut self, message: QueuedMessage) {
+        if message.priority == MessageSendingPriority::High {
+            self.messages.insert(self.high_count, message);
+            self.high_count += 1;
+        } else {
+            self.messages.push_back(message);
+        }
+    }
+
+    fn pop(&mut self) -> Option<QueuedMessage> {
+        let message = self.messages.pop_front()?;
+        if message.priority == MessageSendingPriority::High {
+            self.high_count -= 1;
+        }
+        Some(message)
+    }
+
+    /// Checks whether the queue has a message that may be dropped to make room
+    /// for a message with the given priority.
+    fn can_evict(&self, priority: MessageSendingPriority) -> bool {
+        self.messages.len() > self.high_count
+            || (priority == MessageSendingPriority::High && !self.messages.is_empty())
+    }
+
+    /// Remove the oldest normal priority message, or the oldest high priority
+    /// message if there are no normal priority ones and the message is
+    /// evicted for a high priority message.
+    fn evict(&mut self, priority: MessageSendingPriority) -> Option<QueuedMessage> {
+        if self.messages.len() > self.high_count {
+            self.messages.remove(self.high_count)
+        } else if priority == MessageSendingPriority::High {
+            self.pop()
+        } else {
+            None
+        }
+    }
+}
+
+/// The queues of the messages waiting to be sent to a connection.
+pub struct MessageQueues {
+    policy:  Arc<QueuePolicy>,
+    /// Counts the dropped messages by category and reason.
+    dropped: IntCounterVec,
+    queues:  [CategoryQueue; TRAFFIC_CATEGORY_COUNT],
+    /// The index of the queue currently being served.
+    current: usize,
+    /// The total number of queued messages.
+    len:     usize,
+}
+
+impl MessageQueues {
+    pub fn new(policy: Arc<QueuePolicy>, dropped: IntCounterVec) -> Self {
+        Self {
+            policy,
+            dropped,
+            queues: Default::default(),
+            current: 0,
+            len: 0,
+        }
+    }
+
+    /// The total number of queued messages.
+    pub fn len(&self) -> usize { self.len }
+
+    /// Checks whether there are no queued messages.
+    pub fn is_empty(&self) -> bool { self.len == 0 }
+
+    /// Add a message to the queue of its category, possibly dropping a message
+    /// if the queues are full.
+    pub fn enqueue(
+        &mut self,
+        priority: MessageSendingPriority,
+        category: TrafficCategory,
+        payload: Arc<[u8]>,
+        now: Instant,
+    ) {
+        let index = category.index();
+        let message = QueuedMessage {
+            payload,
+            priority,
+            category,
+            deadline: self.policy.deadlines[index].map(|deadline| now + deadline),
+        };
+
+        if self.len >= self.policy.capacity {
+            let victim = match self.policy.drop_policy {
+                DropPolicy::Newest => None,
+                DropPolicy::Oldest => Some(index).filter(|&i| self.queues[i].can_evict(priority)),
+                DropPolicy::LowestWeight => (0..TRAFFIC_CATEGORY_COUNT)
+                    .filter(|&i| self.queues[i].can_evict(priority))
+                    // prefer the queues that have normal priority messages
+                    .min_by_key(|&i| {
+                        let queue = &self.queues[i];
+                        (queue.messages.len() == queue.high_count, self.policy.weights[i])
+                    })
+                    .filter(|&i| self.policy.weights[i] <= self.policy.weights[index]),
+            };
+            match victim.and_then(|i| self.queues[i].evict(priority)) {
+                Some(dropped) => {
+                    self.len -= 1;
+                    self.report_drop(dropped.category, DropReason::Full);
+                }
+                None => {
+                    self.report_drop(category, DropReason::Full);
+                    return;
+                }
+            }
+        }
+
+        self.queues[index].push(message);
+        self.len += 1;
+    }
+
+    /// Obtain the message to be sent next, dropping the expired messages
+    /// encountered on the way. It is removed from the queues by a subsequent
+    /// call to [`dequeue`](Self::dequeue).
+    pub fn peek(&mut self, now: Instant) -> Option<&QueuedMessage> {
+        loop {
+            self.drop_expired(now);
+            if self.len == 0 {
+                return None;
+            }
+            let queue = &self.queues[self.current];
+            match queue.messages.front() {
+                Some(message) if message.payload.len() <= queue.deficit => break,
+                _ => self.advance(),
+            }
+        }
+        self.queues[self.current].messages.front()
+    }
+
+    /// Remove the message returned by the last call to [`peek`](Self::peek).
+    pub fn dequeue(&mut self) -> Option<QueuedMessage> {
+        let queue = &mut self.queues[self.current];
+        let message = queue.pop()?;
+        queue.deficit = queue.deficit.saturating_sub(message.payload.len());
+        self.len -= 1;
+        Some(message)
+    }
+
+    /// Drop the expired messages at the front of the current queue.
+    fn drop_expired(&mut self, now: Instant) {
+        while self.queues[self.current].messages.front().map_or(false, |m| m.is_expired(now)) {
+            if let Some(message) = self.queues[self.current].pop() {
+                self.len -= 1;
+                self.report_drop(message.category, DropReason::Expired);
+            }
+        }
+    }
+
+    /// Move on to the next queue, granting it its quantum for the round.
+    fn advance(&mut self) {
+        self.current = (self.current + 1) % TRAFFIC_CATEGORY_COUNT;
+        let quantum = self.policy.quantum(self.current);
+        let queue = &mut self.queues[self.current];
+        if queue.messages.is_empty() {
+            queue.deficit = 0;
+        } else {
+            queue.deficit += quantum;
+        }
+    }
+
+    fn report_drop(&self, category: TrafficCategory, reason: DropReason) {
+        trace!("Dropped an outbound {} message ({})", category.label(), reason.label());
+        self.dropped.with_label_values(&[category.label(), reason.label()]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::get_current_stamp,
+        netmsg,
+        network::{NetworkId, NetworkPacket, NetworkRequest, PacketDestination},
+    };
+    use prometheus::Opts;
+
+    fn packet(packet_type: PacketType, len: usize) -> Arc<[u8]> {
+        let mut message = vec![0u8; len];
+        message[0] = packet_type as u8;
+        let msg = netmsg!(NetworkPacket, NetworkPacket {
+            destination: PacketDestination::Broadcast(Vec::new()),
+            network_id: NetworkId::from(100),
+            message,
+        });
+        let mut buffer = Vec::new();
+        msg.serialize(&mut buffer).unwrap();
+        buffer.into()
+    }
+
+    fn enqueue(
+        queues: &mut MessageQueues,
+        priority: MessageSendingPriority,
+        payload: Arc<[u8]>,
+        now: Instant,
+    ) {
+        queues.enqueue(priority, TrafficCategory::of_serialized(&payload), payload, now)
+    }
+
+    fn new_queues(capacity: usize, drop_policy: DropPolicy) -> MessageQueues {
+        let mut weights = [DEFAULT_WEIGHT; TRAFFIC_CATEGORY_COUNT];
+        weights[PacketType::Transaction as usize] = 1;
+        let mut deadlines = [None; TRAFFIC_CATEGORY_COUNT];
+        deadlines[PacketType::FinalizationMessage as usize] = Some(Duration::from_secs(1));
+        let policy = QueuePolicy {
+            capacity,
+            drop_policy,
+            weights,
+            deadlines,
+        };
+        let dropped =
+            IntCounterVec::new(Opts::new("dropped", "dropped"), &["message", "reason"]).unwrap();
+        MessageQueues::new(Arc::new(policy), dropped)
+    }
+
+    fn dropped(queues: &MessageQueues, category: TrafficCategory, reason: DropReason) -> u64 {
+        queues.dropped.with_label_values(&[category.label(), reason.label()]).get()
+    }
+
+    fn drain(queues: &mut MessageQueues, now: Instant) -> Vec<TrafficCategory> {
+        let mut sent = Vec::new();
+        while queues.peek(now).is_some() {
+            sent.push(queues.dequeue().unwrap().category);
+        }
+        sent
+    }
+
+    #[test]
+    fn test_weighted_queues() {
+        let now = Instant::now();
+        let mut queues = new_queues(100, DropPolicy::Newest);
+        let block = TrafficCategory::Packet(PacketType::Block);
+        let transaction = TrafficCategory::Packet(PacketType::Transaction);
+        for _ in 0..16 {
+            enqueue(
+                &mut queues,
+                MessageSendingPriority::Normal,
+                packet(PacketType::Transaction, 8000),
+                now,
+            );
+            enqueue(
+                &mut queues,
+                MessageSendingPriority::Normal,
+                packet(PacketType::Block, 8000),
+                now,
+            );
+        }
+        let sent = drain(&mut queues, now);
+        assert_eq!(sent.len(), 32);
+        // blocks have 8 times the weight of transactions
+        let blocks_first = sent.iter().take(18).filter(|&&c| c == block).count();
+        assert_eq!(blocks_first, 16);
+        assert_eq!(sent.iter().filter(|&&c| c == transaction).count(), 16);
+
+        // high priority messages overtake normal priority messages of the same category
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 10), now);
+        enqueue(&mut queues, MessageSendingPriority::High, packet(PacketType::Block, 20), now);
+        assert_eq!(queues.peek(now).map(|m| m.priority), Some(MessageSendingPriority::High));
+        assert_eq!(drain(&mut queues, now).len(), 2);
+        assert!(queues.is_empty());
+    }
+
+    #[test]
+    fn test_queue_deadlines() {
+        let now = Instant::now();
+        let mut queues = new_queues(100, DropPolicy::Newest);
+        let fin_msg = TrafficCategory::Packet(PacketType::FinalizationMessage);
+        enqueue(
+            &mut queues,
+            MessageSendingPriority::Normal,
+            packet(PacketType::FinalizationMessage, 10),
+            now,
+        );
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 10), now);
+        let sent = drain(&mut queues, now + Duration::from_secs(2));
+        assert_eq!(sent, [TrafficCategory::Packet(PacketType::Block)]);
+        assert_eq!(dropped(&queues, fin_msg, DropReason::Expired), 1);
+    }
+
+    #[test]
+    fn test_drop_policies() {
+        let now = Instant::now();
+        let block = TrafficCategory::Packet(PacketType::Block);
+        let transaction = TrafficCategory::Packet(PacketType::Transaction);
+
+        let mut queues = new_queues(2, DropPolicy::LowestWeight);
+        enqueue(
+            &mut queues,
+            MessageSendingPriority::Normal,
+            packet(PacketType::Transaction, 10),
+            now,
+        );
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 10), now);
+        // the transaction has the lowest weight, so it is dropped for the block
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 10), now);
+        assert_eq!(dropped(&queues, transaction, DropReason::Full), 1);
+        // a new transaction is dropped instead of a block
+        enqueue(
+            &mut queues,
+            MessageSendingPriority::Normal,
+            packet(PacketType::Transaction, 10),
+            now,
+        );
+        assert_eq!(dropped(&queues, transaction, DropReason::Full), 2);
+        assert_eq!(drain(&mut queues, now), [block, block]);
+
+        let mut queues = queues_with_transaction_and_block(DropPolicy::Newest, now);
+        assert_eq!(dropped(&queues, block, DropReason::Full), 1);
+        let mut sent = drain(&mut queues, now);
+        sent.sort_by_key(|category| category.index());
+        assert_eq!(sent, [block, transaction]);
+
+        let mut queues = queues_with_transaction_and_block(DropPolicy::Oldest, now);
+        assert_eq!(dropped(&queues, block, DropReason::Full), 1);
+        assert_eq!(queues.len(), 2);
+        drain(&mut queues, now);
+        assert!(queues.is_empty());
+
+        let mut queues = new_queues(1, DropPolicy::Oldest);
+        enqueue(
+            &mut queues,
+            MessageSendingPriority::High,
+            netmsg_bytes(netmsg!(NetworkRequest, NetworkRequest::Ping)),
+            now,
+        );
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 10), now);
+        assert_eq!(dropped(&queues, block, DropReason::Full), 1);
+        assert_eq!(drain(&mut queues, now), [TrafficCategory::Network]);
+    }
+
+    #[test]
+    fn test_drop_policies_spare_high_priority() {
+        let now = Instant::now();
+        let block = TrafficCategory::Packet(PacketType::Block);
+        let high = MessageSendingPriority::High;
+        let normal = MessageSendingPriority::Normal;
+
+        // the normal priority block is dropped, although it is not the oldest
+        let mut queues = new_queues(2, DropPolicy::Oldest);
+        enqueue(&mut queues, high, packet(PacketType::Block, 10), now);
+        enqueue(&mut queues, normal, packet(PacketType::Block, 20), now);
+        enqueue(&mut queues, high, packet(PacketType::Block, 30), now);
+        assert_eq!(dropped(&queues, block, DropReason::Full), 1);
+        // a normal priority message does not replace a high priority one
+        enqueue(&mut queues, normal, packet(PacketType::Block, 40), now);
+        assert_eq!(dropped(&queues, block, DropReason::Full), 2);
+        let mut sent = Vec::new();
+        while queues.peek(now).is_some() {
+            let message = queues.dequeue().unwrap();
+            sent.push((message.priority, message.payload.len()));
+        }
+        assert_eq!(sent, [
+            (high, packet(PacketType::Block, 10).len()),
+            (high, packet(PacketType::Block, 30).len())
+        ]);
+
+        // the normal priority block is dropped before the high priority
+        // transaction, although transactions have the lowest weight
+        let mut queues = new_queues(2, DropPolicy::LowestWeight);
+        enqueue(&mut queues, high, packet(PacketType::Transaction, 10), now);
+        enqueue(&mut queues, normal, packet(PacketType::Block, 10), now);
+        enqueue(&mut queues, high, packet(PacketType::Block, 10), now);
+        assert_eq!(dropped(&queues, block, DropReason::Full), 1);
+        let sent: Vec<_> = drain(&mut queues, now);
+        assert_eq!(sent.len(), 2);
+        assert!(sent.contains(&TrafficCategory::Packet(PacketType::Transaction)));
+    }
+
+    fn netmsg_bytes(msg: crate::network::NetworkMessage) -> Arc<[u8]> {
+        let mut buffer = Vec::new();
+        msg.serialize(&mut buffer).unwrap();
+        buffer.into()
+    }
+
+    /// Queue a transaction and two blocks into queues with room for two
+    /// messages.
+    fn queues_with_transaction_and_block(policy: DropPolicy, now: Instant) -> MessageQueues {
+        let mut queues = new_queues(2, policy);
+        enqueue(
+            &mut queues,
+            MessageSendingPriority::Normal,
+            packet(PacketType::Transaction, 10),
+            now,
+        );
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 10), now);
+        enqueue(&mut queues, MessageSendingPriority::Normal, packet(PacketType::Block, 20), now);
+        queues
+    }
+}
@@ -2,6 +2,7 @@ use itertools::Itertools;
 
 use crate::{
     common::PeerType,
+    connection::low_level::probe_noise_handshake,
     consensus_ffi::helpers::PacketType,
     network::NetworkId,
     p2p::connectivity::send_broadcast_message,
@@ -11,7 +12,11 @@ use crate::{
     },
 };
 
-use std::sync::Arc;
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    time::Duration,
+};
 
 const NID: u16 = 100;
 const NODE_COUNT: usize = 10;
@@ -71,3 +76,22 @@ fn basic_connectivity() {
         stop_node_delete_dirs(dp, node);
     }
 }
+
+#[test]
+fn probe_noise_handshake_detects_nodes() {
+    let port = next_available_port();
+    let (node, dp) =
+        make_node_and_sync(port, vec![NID], PeerType::Node, dummy_regenesis_blocks()).unwrap();
+    probe_noise_handshake(SocketAddr::from(([127, 0, 0, 1], port)), Duration::from_secs(5))
+        .expect("A node passes the probe");
+
+    // A listener that accepts the connection but does not answer the handshake
+    // fails the probe.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let acceptor = std::thread::spawn(move || listener.accept().map(|(socket, _)| socket));
+    assert!(probe_noise_handshake(addr, Duration::from_millis(500)).is_err());
+    drop(acceptor.join());
+
+    stop_node_delete_dirs(dp, node);
+}
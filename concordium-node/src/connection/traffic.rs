@@ -0,0 +1,263 @@
+//! Accounting of the traffic of a connection by message category and
+//! direction.
+
+use crate::{
+    consensus_ffi::helpers::PacketType,
+    network::{NetworkMessage, NetworkPayload},
+};
+use anyhow::bail;
+use std::{
+    convert::TryFrom,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The number of distinct traffic categories.
+pub const TRAFFIC_CATEGORY_COUNT: usize = 7;
+
+/// The category a network message is accounted under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficCategory {
+    /// A consensus packet of the given type.
+    Packet(PacketType),
+    /// A message of the network layer itself, e.g., a ping or a handshake.
+    Network,
+    /// A message that could not be decoded.
+    Invalid,
+}
+
+impl TrafficCategory {
+    /// All the categories, in the order of their indices.
+    pub const ALL: [TrafficCategory; TRAFFIC_CATEGORY_COUNT] = [
+        TrafficCategory::Packet(PacketType::Block),
+        TrafficCategory::Packet(PacketType::Transaction),
+        TrafficCategory::Packet(PacketType::FinalizationRecord),
+        TrafficCategory::Packet(PacketType::FinalizationMessage),
+        TrafficCategory::Packet(PacketType::CatchUpStatus),
+        TrafficCategory::Network,
+        TrafficCategory::Invalid,
+    ];
+
+    /// Categorize a deserialized message.
+    pub fn of_message(msg: &NetworkMessage) -> Self {
+        match msg.payload {
+            NetworkPayload::NetworkPacket(ref packet) => {
+                Self::of_packet_tag(packet.message.first().copied())
+            }
+            _ => TrafficCategory::Network,
+        }
+    }
+
+    /// Categorize a serialized message without deserializing it.
+    pub fn of_serialized(buffer: &[u8]) -> Self {
+        match NetworkMessage::peek_packet_type(buffer) {
+            Ok(Some(tag)) => Self::of_packet_tag(Some(tag)),
+            Ok(None) => TrafficCategory::Network,
+            Err(_) => TrafficCategory::Invalid,
+        }
+    }
+
+    fn of_packet_tag(tag: Option<u8>) -> Self {
+        tag.and_then(|tag| PacketType::try_from(tag).ok())
+            .map_or(TrafficCategory::Invalid, TrafficCategory::Packet)
+    }
+
+    /// The position of the category in [`TrafficCategory::ALL`].
+    pub fn index(self) -> usize {
+        match self {
+            TrafficCategory::Packet(packet_type) => packet_type as usize,
+            TrafficCategory::Network => 5,
+            TrafficCategory::Invalid => 6,
+        }
+    }
+
+    /// The label of the category used in the metrics. Packets use the same
+    /// labels as the consensus message metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            TrafficCategory::Packet(PacketType::Block) => "block",
+            TrafficCategory::Packet(PacketType::Transaction) => "transaction",
+            TrafficCategory::Packet(PacketType::FinalizationRecord) => "finalization record",
+            TrafficCategory::Packet(PacketType::FinalizationMessage) => "finalization message",
+            TrafficCategory::Packet(PacketType::CatchUpStatus) => "catch-up status message",
+            TrafficCategory::Network => "network",
+            TrafficCategory::Invalid => "invalid",
+        }
+    }
+}
+
+impl FromStr for TrafficCategory {
+    type Err = anyhow::Error;
+
+    fn from_str(category: &str) -> Result<Self, Self::Err> {
+        match category {
+            "block" => Ok(TrafficCategory::Packet(PacketType::Block)),
+            "transaction" => Ok(TrafficCategory::Packet(PacketType::Transaction)),
+            "finalization-record" => Ok(TrafficCategory::Packet(PacketType::FinalizationRecord)),
+            "finalization-message" => Ok(TrafficCategory::Packet(PacketType::FinalizationMessage)),
+            "catch-up-status" => Ok(TrafficCategory::Packet(PacketType::CatchUpStatus)),
+            "network" => Ok(TrafficCategory::Network),
+            _ => bail!("Unknown message category {}", category),
+        }
+    }
+}
+
+/// The direction of the traffic, seen from the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficDirection {
+    Received,
+    Sent,
+}
+
+impl TrafficDirection {
+    /// Both directions, in the order of their indices.
+    pub const ALL: [TrafficDirection; 2] = [TrafficDirection::Received, TrafficDirection::Sent];
+
+    fn index(self) -> usize { self as usize }
+
+    /// The label of the direction used in the metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            TrafficDirection::Received => "received",
+            TrafficDirection::Sent => "sent",
+        }
+    }
+}
+
+/// The number of messages and bytes of a single category and direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCount {
+    pub messages: u64,
+    pub bytes:    u64,
+}
+
+/// Live traffic counters of a connection.
+#[derive(Default)]
+pub struct TrafficStats {
+    messages: [[AtomicU64; TRAFFIC_CATEGORY_COUNT]; 2],
+    bytes:    [[AtomicU64; TRAFFIC_CATEGORY_COUNT]; 2],
+}
+
+impl TrafficStats {
+    /// Account a message of the given size.
+    pub fn record(&self, direction: TrafficDirection, category: TrafficCategory, bytes: usize) {
+        let (d, c) = (direction.index(), category.index());
+        self.messages[d][c].fetch_add(1, Ordering::Relaxed);
+        self.bytes[d][c].fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Obtain the current values of the counters.
+    pub fn snapshot(&self) -> TrafficCounts {
+        let mut counts = TrafficCounts::default();
+        let live = self.messages.iter().flatten().zip(self.bytes.iter().flatten());
+        for (count, (messages, bytes)) in counts.counts.iter_mut().flatten().zip(live) {
+            *count = TrafficCount {
+                messages: messages.load(Ordering::Relaxed),
+                bytes:    bytes.load(Ordering::Relaxed),
+            };
+        }
+        counts
+    }
+}
+
+/// A snapshot of the traffic counters of a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficCounts {
+    counts: [[TrafficCount; TRAFFIC_CATEGORY_COUNT]; 2],
+}
+
+impl TrafficCounts {
+    /// The traffic of the given direction and category.
+    pub fn get(&self, direction: TrafficDirection, category: TrafficCategory) -> TrafficCount {
+        self.counts[direction.index()][category.index()]
+    }
+
+    /// The total number of bytes in both directions.
+    pub fn total_bytes(&self) -> u64 { self.counts.iter().flatten().map(|c| c.bytes).sum() }
+
+    /// Add the counts of `other` to these counts.
+    pub fn add(&mut self, other: &TrafficCounts) {
+        for (mine, theirs) in self.counts.iter_mut().flatten().zip(other.counts.iter().flatten()) {
+            mine.messages += theirs.messages;
+            mine.bytes += theirs.bytes;
+        }
+    }
+
+    /// Iterate over all the non-empty counts.
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (TrafficDirection, TrafficCategory, TrafficCount)> + '_ {
+        TrafficDirection::ALL.iter().flat_map(move |&direction| {
+            TrafficCategory::ALL.iter().filter_map(move |&category| {
+                let count = self.get(direction, category);
+                if count.messages > 0 {
+                    Some((direction, category, count))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::get_current_stamp,
+        netmsg,
+        network::{NetworkId, NetworkPacket, NetworkRequest, PacketDestination},
+    };
+
+    fn serialize(msg: &NetworkMessage) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        msg.serialize(&mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_traffic_categories() {
+        for (i, category) in TrafficCategory::ALL.iter().enumerate() {
+            assert_eq!(category.index(), i);
+        }
+
+        let ping = netmsg!(NetworkRequest, NetworkRequest::Ping);
+        assert_eq!(TrafficCategory::of_message(&ping), TrafficCategory::Network);
+        assert_eq!(TrafficCategory::of_serialized(&serialize(&ping)), TrafficCategory::Network);
+
+        for (tag, expected) in [
+            (PacketType::FinalizationMessage as u8, TrafficCategory::ALL[3]),
+            (42, TrafficCategory::Invalid),
+        ] {
+            let packet = netmsg!(NetworkPacket, NetworkPacket {
+                destination: PacketDestination::Broadcast(Vec::new()),
+                network_id:  NetworkId::from(100),
+                message:     vec![tag, 1, 2, 3],
+            });
+            assert_eq!(TrafficCategory::of_message(&packet), expected);
+            assert_eq!(TrafficCategory::of_serialized(&serialize(&packet)), expected);
+        }
+
+        assert_eq!(TrafficCategory::of_serialized(&[0, 1, 2]), TrafficCategory::Invalid);
+    }
+
+    #[test]
+    fn test_traffic_counts() {
+        let stats = TrafficStats::default();
+        let block = TrafficCategory::Packet(PacketType::Block);
+        stats.record(TrafficDirection::Received, block, 100);
+        stats.record(TrafficDirection::Received, block, 50);
+        stats.record(TrafficDirection::Sent, TrafficCategory::Network, 10);
+
+        let mut counts = stats.snapshot();
+        assert_eq!(counts.get(TrafficDirection::Received, block), TrafficCount {
+            messages: 2,
+            bytes:    150,
+        });
+        assert_eq!(counts.total_bytes(), 160);
+        assert_eq!(counts.iter().count(), 2);
+
+        counts.add(&stats.snapshot());
+        assert_eq!(counts.get(TrafficDirection::Sent, TrafficCategory::Network).bytes, 20);
+    }
+}
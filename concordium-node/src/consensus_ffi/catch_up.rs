@@ -1,4 +1,7 @@
-use crate::common::{p2p_node_id::PeerId, p2p_peer::RemotePeerId};
+use crate::{
+    common::{p2p_node_id::PeerId, p2p_peer::RemotePeerId},
+    telemetry::Span,
+};
 use nohash_hasher::BuildNoHashHasher;
 use std::{
     cmp::Ordering,
@@ -54,6 +57,8 @@ pub struct PeerList {
     pub catch_up_peer:  Option<RemotePeerId>,
     /// Queue of pending peers.
     pub pending_queue:  VecDeque<RemotePeerId>,
+    /// The span of the current catch-up round (if any).
+    pub catch_up_span:  Option<Span>,
 }
 
 impl PeerList {
@@ -90,10 +95,19 @@ impl PeerList {
         }
     }
 
+    /// Stop catching up with catch_up_peer, recording the outcome of the
+    /// round in its span.
+    pub fn end_catch_up(&mut self, outcome: &str) {
+        self.catch_up_peer = None;
+        if let Some(mut span) = self.catch_up_span.take() {
+            span.set_attribute("outcome", outcome);
+        }
+    }
+
     /// Clear all pending peers.
     pub fn clear(&mut self) {
         self.peer_states.clear();
-        self.catch_up_peer = None;
+        self.end_catch_up("cleared");
         self.pending_queue.clear();
     }
 }
@@ -10,14 +10,17 @@ use crate::{
         },
         messaging::*,
     },
-    write_or_die,
+    grpc2::types::{ArrivedBlockInfo, FinalizedBlockInfo},
+    lock_or_die, logging, telemetry, write_or_die,
 };
 use anyhow::{anyhow, bail, Context};
 use concordium_base::{
     common::Serial,
     hashes::{BlockHash, TransactionHash},
 };
+use prost::Message;
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     ffi::{CStr, CString},
     io::Write,
@@ -26,7 +29,7 @@ use std::{
     ptr, slice,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc, Once,
+        Arc, Mutex, Once,
     },
     u64,
 };
@@ -333,8 +336,92 @@ pub struct NotificationContext {
     pub baked_blocks: prometheus::IntCounter,
     /// Total number of finalized blocks baked by the node since startup.
     pub finalized_baked_blocks: prometheus::IntCounter,
+    /// Delay between the timestamp of a block and its arrival (in seconds).
+    pub block_arrive_latency: prometheus::Histogram,
+    /// Delay between the arrival of a block and its finalization (in seconds).
+    pub block_finalization_latency: prometheus::Histogram,
+    /// Time between the timestamps of consecutive finalized blocks (in
+    /// seconds).
+    pub block_interval: prometheus::Histogram,
+    /// Arrival times of blocks needed for computing the latency histograms.
+    pub block_timings: Mutex<BlockTimings>,
 }
 
+/// Bookkeeping of block arrivals used for computing the
+/// [NotificationContext::block_finalization_latency] and
+/// [NotificationContext::block_interval] histograms.
+#[derive(Default)]
+pub struct BlockTimings {
+    /// The height and arrival time (Unix time in milliseconds) of blocks that
+    /// arrived but are not yet finalized, keyed by the block hash. Entries are
+    /// pruned when a block at the same or a greater height is finalized.
+    arrived:        HashMap<Vec<u8>, (u64, i64)>,
+    /// The height and timestamp (Unix time in milliseconds) of the last
+    /// finalized block.
+    last_finalized: Option<(u64, u64)>,
+}
+
+/// The latencies observed when a block is finalized, see
+/// [BlockTimings::block_finalized].
+#[derive(Debug, PartialEq)]
+pub struct FinalizationTimings {
+    /// Delay between the arrival of the block and its finalization (in
+    /// seconds), if the arrival of the block was recorded.
+    pub finalization_latency: Option<f64>,
+    /// Time between the timestamps of the block and its parent (in seconds),
+    /// if the parent was the last finalized block recorded.
+    pub block_interval:       Option<f64>,
+}
+
+impl BlockTimings {
+    /// Record the arrival of a block at time `now` (Unix time in
+    /// milliseconds), and return the delay between the block's timestamp and
+    /// its arrival in seconds.
+    pub fn block_arrived(
+        &mut self,
+        hash: Option<Vec<u8>>,
+        height: u64,
+        timestamp: u64,
+        now: i64,
+    ) -> f64 {
+        if let Some(hash) = hash {
+            self.arrived.insert(hash, (height, now));
+        }
+        millis_to_seconds(now - timestamp as i64)
+    }
+
+    /// Record the finalization of a block at time `now` (Unix time in
+    /// milliseconds), and return the latencies that can be derived from it.
+    pub fn block_finalized(
+        &mut self,
+        hash: Option<&[u8]>,
+        height: u64,
+        timestamp: u64,
+        now: i64,
+    ) -> FinalizationTimings {
+        let finalization_latency = hash
+            .and_then(|hash| self.arrived.get(hash))
+            .map(|(_, arrived)| millis_to_seconds(now - arrived));
+        // Blocks at or below the finalized height are either finalized or dead.
+        self.arrived.retain(|_, (arrived_height, _)| *arrived_height > height);
+        let block_interval = match self.last_finalized {
+            Some((last_height, last_timestamp)) if height == last_height + 1 => {
+                Some(millis_to_seconds(timestamp as i64 - last_timestamp as i64))
+            }
+            _ => None,
+        };
+        self.last_finalized = Some((height, timestamp));
+        FinalizationTimings {
+            finalization_latency,
+            block_interval,
+        }
+    }
+}
+
+/// Convert a difference in milliseconds to seconds, clamping negative values
+/// (e.g., due to clock skew) to zero.
+fn millis_to_seconds(millis: i64) -> f64 { millis.max(0) as f64 / 1000.0 }
+
 /// A type of callback used to notify Rust code of important events. The
 /// callback is called with
 /// - the context
@@ -342,11 +429,14 @@ pub struct NotificationContext {
 /// - pointer to a byte array containing the serialized event
 /// - length of the data
 /// - block height of either the finalized block or arrived block
+/// - timestamp (slot time) of either the finalized block or arrived block (Unix
+///   time in milliseconds)
 /// - byte where a value of 1 indicates the block arrived/finalized was baked by
 ///   this node.
 ///
 /// The callback should not retain references to supplied data after the exit.
-type NotifyCallback = unsafe extern "C" fn(*mut NotificationContext, u8, *const u8, u64, u64, u8);
+type NotifyCallback =
+    unsafe extern "C" fn(*mut NotificationContext, u8, *const u8, u64, u64, u64, u8);
 
 pub struct NotificationHandlers {
     pub blocks:           futures::channel::mpsc::UnboundedReceiver<Arc<[u8]>>,
@@ -1524,23 +1614,40 @@ unsafe extern "C" fn notify_callback(
     data_ptr: *const u8,
     data_len: u64,
     block_height: u64,
+    block_timestamp: u64,
     home_baked: u8,
 ) {
     let sender = &*notify_context;
     let home_baked = home_baked == 1;
+    let now = chrono::Utc::now().timestamp_millis();
+    let data = slice::from_raw_parts(data_ptr, data_len as usize);
     match ty {
         0u8 => {
             sender.last_arrived_block_height.set(block_height);
-            sender.last_arrived_block_timestamp.set(chrono::Utc::now().timestamp_millis());
+            sender.last_arrived_block_timestamp.set(now);
+            let hash = match ArrivedBlockInfo::decode(data) {
+                Ok(ArrivedBlockInfo {
+                    hash: Some(hash),
+                    ..
+                }) => Some(hash.value),
+                _ => {
+                    error!("Could not decode the hash of the block that arrived.");
+                    None
+                }
+            };
+            let arrive_latency = lock_or_die!(sender.block_timings).block_arrived(
+                hash,
+                block_height,
+                block_timestamp,
+                now,
+            );
+            sender.block_arrive_latency.observe(arrive_latency);
             if home_baked {
                 sender.baked_blocks.inc()
             }
 
             if let Some(blocks) = &sender.blocks {
-                if blocks
-                    .unbounded_send(std::slice::from_raw_parts(data_ptr, data_len as usize).into())
-                    .is_err()
-                {
+                if blocks.unbounded_send(data.into()).is_err() {
                     error!("Failed to enqueue block that arrived.");
                     // do nothing. The error here should only happen if the
                     // receiver is disconnected, which means that the task
@@ -1552,15 +1659,34 @@ unsafe extern "C" fn notify_callback(
         }
         1u8 => {
             sender.last_finalized_block_height.set(block_height);
-            sender.last_finalized_block_timestamp.set(chrono::Utc::now().timestamp_millis());
+            sender.last_finalized_block_timestamp.set(now);
+            let hash = match FinalizedBlockInfo::decode(data) {
+                Ok(FinalizedBlockInfo {
+                    hash: Some(hash),
+                    ..
+                }) => Some(hash.value),
+                _ => {
+                    error!("Could not decode the hash of the finalized block.");
+                    None
+                }
+            };
+            let timings = lock_or_die!(sender.block_timings).block_finalized(
+                hash.as_deref(),
+                block_height,
+                block_timestamp,
+                now,
+            );
+            if let Some(latency) = timings.finalization_latency {
+                sender.block_finalization_latency.observe(latency);
+            }
+            if let Some(interval) = timings.block_interval {
+                sender.block_interval.observe(interval);
+            }
             if home_baked {
                 sender.finalized_baked_blocks.inc()
             }
             if let Some(finalized_blocks) = &sender.finalized_blocks {
-                if finalized_blocks
-                    .unbounded_send(std::slice::from_raw_parts(data_ptr, data_len as usize).into())
-                    .is_err()
-                {
+                if finalized_blocks.unbounded_send(data.into()).is_err() {
                     error!("Failed to enqueue finalized block.");
                     // do nothing. The error here should only happen if the
                     // receiver is disconnected, which means that the task
@@ -2124,6 +2250,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         account_identifier: &crate::grpc2::types::AccountIdentifierInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_account_info_v2");
         use crate::grpc2::Require;
         let bhi = crate::grpc2::types::block_hash_input_to_ffi(block_hash).require()?;
         let (block_id_type, block_hash) = bhi.to_ptr();
@@ -2157,6 +2284,7 @@ impl ConsensusContainer {
         &self,
         account_address: &crate::grpc2::types::AccountAddress,
     ) -> Result<Vec<u8>, tonic::Status> {
+        let _span = telemetry::ffi_span("get_next_account_sequence_number_v2");
         use crate::grpc2::Require;
         let account_address_ptr =
             crate::grpc2::types::account_address_to_ffi(account_address).require()?;
@@ -2177,6 +2305,7 @@ impl ConsensusContainer {
 
     /// Get information of the current state of consensus.
     pub fn get_consensus_info_v2(&self) -> Result<Vec<u8>, tonic::Status> {
+        let _span = telemetry::ffi_span("get_consensus_info_v2");
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
         let _response: ConsensusQueryResponse = unsafe {
@@ -2191,6 +2320,7 @@ impl ConsensusContainer {
         &self,
         block_hash: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], crate::grpc2::types::CryptographicParameters), tonic::Status> {
+        let _span = telemetry::ffi_span("get_cryptographic_parameters_v2");
         use crate::grpc2::Require;
         let bhi = crate::grpc2::types::block_hash_input_to_ffi(block_hash).require()?;
         let (block_id_type, block_hash) = bhi.to_ptr();
@@ -2234,6 +2364,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_account_list_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2268,6 +2399,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_module_list_v2");
         use crate::grpc2::Require;
 
         let sender = Box::new(sender);
@@ -2296,6 +2428,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         module_ref: &crate::grpc2::types::ModuleRef,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_module_source_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2327,6 +2460,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_instance_list_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2354,6 +2488,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         address: &crate::grpc2::types::ContractAddress,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_instance_info_v2");
         use crate::grpc2::Require;
         let bhi = crate::grpc2::types::block_hash_input_to_ffi(block_hash).require()?;
         let (block_id_type, block_hash) = bhi.to_ptr();
@@ -2385,6 +2520,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         address: &crate::grpc2::types::ContractAddress,
     ) -> Result<([u8; 32], ContractStateResponse), tonic::Status> {
+        let _span = telemetry::ffi_span("get_instance_state_v2");
         use crate::grpc2::Require;
         let bhi = crate::grpc2::types::block_hash_input_to_ffi(block_hash).require()?;
         let (block_id_type, block_hash) = bhi.to_ptr();
@@ -2428,6 +2564,7 @@ impl ConsensusContainer {
         amount: u64,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_ancestors_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2454,6 +2591,7 @@ impl ConsensusContainer {
         &self,
         query: &crate::grpc2::types::EpochRequest,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_first_block_epoch_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut buf = [0u8; 32];
@@ -2473,6 +2611,7 @@ impl ConsensusContainer {
         query: &crate::grpc2::types::EpochRequest,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<(), tonic::Status> {
+        let _span = telemetry::ffi_span("get_winning_bakers_epoch_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2497,6 +2636,7 @@ impl ConsensusContainer {
         &self,
         transaction_hash: &crate::grpc2::types::TransactionHash,
     ) -> Result<Vec<u8>, tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_item_status_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2522,6 +2662,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::InvokeInstanceRequest,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("invoke_instance_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2604,6 +2745,7 @@ impl ConsensusContainer {
         &self,
         block_hash: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_info_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2632,6 +2774,7 @@ impl ConsensusContainer {
         block_hash: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_baker_list_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2658,6 +2801,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::PoolInfoRequest,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_pool_info_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2691,6 +2835,7 @@ impl ConsensusContainer {
         &self,
         block_hash: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_passive_delegation_info_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2717,6 +2862,7 @@ impl ConsensusContainer {
         &self,
         height: &crate::grpc2::types::BlocksAtHeightRequest,
     ) -> Result<Vec<u8>, tonic::Status> {
+        let _span = telemetry::ffi_span("get_blocks_at_height_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
 
@@ -2744,6 +2890,7 @@ impl ConsensusContainer {
         &self,
         block_hash: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_tokenomics_info_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2772,6 +2919,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::GetPoolDelegatorsRequest,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_pool_delegators_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2805,6 +2953,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::GetPoolDelegatorsRequest,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_pool_delegators_reward_period_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2837,6 +2986,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_passive_delegators_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2866,6 +3016,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_passive_delegators_reward_period_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2890,6 +3041,7 @@ impl ConsensusContainer {
     /// Get the current branches of blocks starting and including from the last
     /// finalized block.
     pub fn get_branches_v2(&self) -> Result<Vec<u8>, tonic::Status> {
+        let _span = telemetry::ffi_span("get_branches_v2");
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
         let _response: ConsensusQueryResponse =
@@ -2902,6 +3054,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_election_info_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -2931,6 +3084,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_identity_providers_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2960,6 +3114,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_anonymity_revokers_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -2990,6 +3145,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::AccountAddress,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<(), tonic::Status> {
+        let _span = telemetry::ffi_span("get_account_non_finalized_transactions_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -3013,6 +3169,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_items_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let sender = Box::new(sender);
@@ -3042,6 +3199,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_transaction_events_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -3071,6 +3229,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_special_events_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -3100,6 +3259,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_pending_updates_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -3127,6 +3287,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_next_update_sequence_numbers_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -3153,6 +3314,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_chain_parameters_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -3179,6 +3341,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_finalization_summary_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -3204,6 +3367,7 @@ impl ConsensusContainer {
     pub fn get_last_finalized_block_slot_time_v2(
         &self,
     ) -> concordium_base::common::types::Timestamp {
+        let _span = telemetry::ffi_span("get_last_finalized_block_slot_time_v2");
         let consensus = self.consensus.load(Ordering::SeqCst);
         let millis = unsafe { getLastFinalizedBlockSlotTimeV2(consensus) };
         millis.into()
@@ -3216,6 +3380,7 @@ impl ConsensusContainer {
         request: &crate::grpc2::types::BlockHashInput,
         sender: futures::channel::mpsc::Sender<Result<Vec<u8>, tonic::Status>>,
     ) -> Result<[u8; 32], tonic::Status> {
+        let _span = telemetry::ffi_span("get_bakers_reward_period_v2");
         use crate::grpc2::Require;
         let sender = Box::new(sender);
         let consensus = self.consensus.load(Ordering::SeqCst);
@@ -3242,6 +3407,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::BlockHashInput,
     ) -> Result<([u8; 32], Vec<u8>), tonic::Status> {
+        let _span = telemetry::ffi_span("get_block_certificates_v2");
         use crate::grpc2::Require;
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
@@ -3267,6 +3433,7 @@ impl ConsensusContainer {
         &self,
         request: &crate::grpc2::types::BakerId,
     ) -> Result<Vec<u8>, tonic::Status> {
+        let _span = telemetry::ffi_span("get_baker_earliest_win_time_v2");
         let consensus = self.consensus.load(Ordering::SeqCst);
         let mut out_data: Vec<u8> = Vec::new();
         let response: ConsensusQueryResponse = unsafe {
@@ -3576,6 +3743,12 @@ pub extern "C" fn on_log_emited(identifier: c_char, log_level: c_char, log_messa
         .to_str()
         .expect("log_callback: unable to decode as UTF-8");
     let id = identifier_to_string(identifier);
+    // attach the block the message is about, if any, to structured logs
+    let _log_context = if logging::is_structured() {
+        logging::find_block_hash(msg).map(logging::with_block_hash)
+    } else {
+        None
+    };
 
     match log_level as u8 {
         1 => error!("{}: {}", id, msg),
@@ -3585,3 +3758,37 @@ pub extern "C" fn on_log_emited(identifier: c_char, log_level: c_char, log_messa
         _ => trace!("{}: {}", id, msg),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_timings() {
+        let mut timings = BlockTimings::default();
+        // A block with timestamp 1000 arrives at 1500, its sibling at 1800.
+        assert_eq!(timings.block_arrived(Some(vec![1]), 10, 1000, 1500), 0.5);
+        assert_eq!(timings.block_arrived(Some(vec![2]), 10, 1000, 1800), 0.8);
+        // Clock skew does not result in negative latencies.
+        assert_eq!(timings.block_arrived(Some(vec![3]), 11, 3000, 2500), 0.0);
+
+        // The first finalized block has no known parent.
+        assert_eq!(timings.block_finalized(Some(&[1]), 10, 1000, 4000), FinalizationTimings {
+            finalization_latency: Some(2.5),
+            block_interval:       None,
+        });
+        // The dead sibling is pruned, the child is not.
+        assert!(!timings.arrived.contains_key(&vec![2]));
+        assert!(timings.arrived.contains_key(&vec![3]));
+        assert_eq!(timings.block_finalized(Some(&[3]), 11, 3000, 5000), FinalizationTimings {
+            finalization_latency: Some(2.5),
+            block_interval:       Some(2.0),
+        });
+        // A gap in the heights gives no interval, and an unknown block no latency.
+        assert_eq!(timings.block_finalized(None, 13, 6000, 7000), FinalizationTimings {
+            finalization_latency: None,
+            block_interval:       None,
+        });
+        assert!(timings.arrived.is_empty());
+    }
+}
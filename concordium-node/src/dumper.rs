@@ -1,42 +1,181 @@
 //! Handles the `network_dump` feature.
+//!
+//! Network data is dumped to a human-readable log and, if requested, to a
+//! structured dump file and a pcapng file (see [`pcap`]). The structured dump
+//! starts with a header (see [`DumpHeader`]) followed by a sequence of records,
+//! each of which is prefixed with its length as a big-endian `u32` so that the
+//! file can be read back incrementally (see [`DumpReader`]).
 
-cfg_if::cfg_if! {
-    if #[cfg(feature = "network_dump")] {
-        use crate::common::P2PNodeId;
-        use crossbeam_channel::{self, Receiver};
-        use std::io::Write;
-    }
-}
-use crate::{network::NetworkMessage, spawn_or_die};
-use chrono::prelude::{DateTime, Utc};
+pub mod pcap;
+
+use self::pcap::PcapWriter;
+use crate::{
+    common::P2PNodeId,
+    configuration::NetworkDumpConfig,
+    connection::traffic::TrafficCategory,
+    network::{NetworkMessage, NetworkPayload, NetworkRequest, NetworkResponse},
+    spawn_or_die,
+};
+use anyhow::{bail, ensure, Context};
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+use chrono::prelude::{DateTime, TimeZone, Utc};
+use concordium_base::common::{Buffer, Deserial, Serial};
+use crossbeam_channel::{self, Receiver};
 
-use std::{fmt, net::IpAddr, sync::Arc};
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::File,
+    io::{BufWriter, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The magic bytes at the start of a structured dump file.
+pub const DUMP_MAGIC: [u8; 8] = *b"CCDNDUMP";
+/// The version of the structured dump format.
+pub const DUMP_VERSION: u8 = 1;
+/// The maximum size of a single record in a structured dump file. This is only
+/// used to reject corrupted length prefixes when reading a dump.
+const MAX_RECORD_SIZE: u32 = 64 * 1024 * 1024;
+/// How often the limits of a dump are checked when no messages arrive.
+const LIMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// A structure containing network data to be dumped to the disk.
+#[derive(Debug, Clone)]
 pub struct DumpItem {
-    timestamp:   DateTime<Utc>,
-    inbound:     bool,
-    remote_addr: IpAddr,
-    msg:         Arc<[u8]>,
+    /// The time at which the message was received or sent.
+    pub timestamp:   DateTime<Utc>,
+    /// Whether the message was received from or sent to the peer.
+    pub inbound:     bool,
+    /// The address of the remote peer.
+    pub remote_addr: SocketAddr,
+    /// The id of the remote peer, if it has completed the handshake.
+    pub peer_id:     Option<P2PNodeId>,
+    /// The serialized network message.
+    pub msg:         Arc<[u8]>,
 }
 
 impl DumpItem {
     /// Creates a new dump item object.
-    pub fn new(inbound: bool, remote_addr: IpAddr, msg: Arc<[u8]>) -> Self {
+    pub fn new(
+        inbound: bool,
+        remote_addr: SocketAddr,
+        peer_id: Option<P2PNodeId>,
+        msg: Arc<[u8]>,
+    ) -> Self {
         DumpItem {
             timestamp: Utc::now(),
             inbound,
             remote_addr,
+            peer_id,
             msg,
         }
     }
+
+    /// Deserializes the dumped network message.
+    pub fn message(&self) -> anyhow::Result<NetworkMessage> {
+        NetworkMessage::deserialize(&self.msg)
+    }
+
+    /// Writes the item to a structured dump as a length-prefixed record,
+    /// returning the number of bytes written.
+    pub fn write_record<W: Write>(&self, target: &mut W) -> anyhow::Result<u64> {
+        let mut body = Vec::with_capacity(self.msg.len() + 48);
+        self.serial(&mut body);
+        target.write_u32::<NetworkEndian>(body.len() as u32)?;
+        target.write_all(&body)?;
+        Ok(4 + body.len() as u64)
+    }
+}
+
+impl Serial for DumpItem {
+    fn serial<W: Buffer + WriteBytesExt>(&self, target: &mut W) {
+        self.timestamp.timestamp_millis().serial(target);
+        u8::from(self.inbound).serial(target);
+        serial_socket_addr(&self.remote_addr, target);
+        match self.peer_id {
+            None => 0u8.serial(target),
+            Some(id) => {
+                1u8.serial(target);
+                id.serial(target);
+            }
+        }
+        (self.msg.len() as u32).serial(target);
+        target.write_all(&self.msg).expect("Writing to a buffer should succeed.");
+    }
+}
+
+impl Deserial for DumpItem {
+    fn deserial<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Self> {
+        let millis = i64::deserial(source)?;
+        let timestamp = Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .with_context(|| format!("Invalid record timestamp {}.", millis))?;
+        let inbound = match u8::deserial(source)? {
+            0 => false,
+            1 => true,
+            tag => bail!("Invalid tag for the record direction: {}.", tag),
+        };
+        let remote_addr = deserial_socket_addr(source)?;
+        let peer_id = match u8::deserial(source)? {
+            0 => None,
+            1 => Some(P2PNodeId::deserial(source)?),
+            tag => bail!("Invalid tag for the record peer id: {}.", tag),
+        };
+        let len = u32::deserial(source)?;
+        ensure!(len <= MAX_RECORD_SIZE, "Invalid message length {}.", len);
+        let mut msg = vec![0u8; len as usize];
+        source.read_exact(&mut msg)?;
+        Ok(DumpItem {
+            timestamp,
+            inbound,
+            remote_addr,
+            peer_id,
+            msg: msg.into(),
+        })
+    }
+}
+
+fn serial_socket_addr<W: Buffer + WriteBytesExt>(addr: &SocketAddr, target: &mut W) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            4u8.serial(target);
+            target.write_all(&ip.octets()).expect("Writing to a buffer should succeed.");
+        }
+        IpAddr::V6(ip) => {
+            6u8.serial(target);
+            target.write_all(&ip.octets()).expect("Writing to a buffer should succeed.");
+        }
+    }
+    addr.port().serial(target);
+}
+
+fn deserial_socket_addr<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<SocketAddr> {
+    let ip = match u8::deserial(source)? {
+        4 => {
+            let mut octets = [0u8; 4];
+            source.read_exact(&mut octets)?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        6 => {
+            let mut octets = [0u8; 16];
+            source.read_exact(&mut octets)?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        tag => bail!("Invalid tag for the IP address: {}.", tag),
+    };
+    Ok(SocketAddr::new(ip, u16::deserial(source)?))
 }
 
 impl fmt::Display for DumpItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{} - {} - {} - {:?} - {:?}",
+            "{} - {} - {} - {} - {:?} - {:?}",
             self.timestamp,
             if self.inbound {
                 "IN"
@@ -44,100 +183,655 @@ impl fmt::Display for DumpItem {
                 "OUT"
             },
             self.remote_addr,
+            self.peer_id.map(|id| id.to_string()).unwrap_or_else(|| "unknown".to_string()),
             self.msg,
-            NetworkMessage::deserialize(&self.msg)
+            self.message()
                 .map(|m| format!("{:?}", m))
                 .unwrap_or_else(|_| "couldn't deserialize".to_string())
         )
     }
 }
 
+/// The header of a structured dump file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpHeader {
+    /// The time at which the dump was started.
+    pub started:  DateTime<Utc>,
+    /// The IP address of the node that created the dump.
+    pub local_ip: IpAddr,
+    /// The id of the node that created the dump.
+    pub local_id: P2PNodeId,
+}
+
+impl DumpHeader {
+    /// Writes the header, including the magic bytes and the format version.
+    pub fn write<W: Write>(&self, target: &mut W) -> anyhow::Result<()> {
+        let mut buf = Vec::with_capacity(40);
+        buf.write_all(&DUMP_MAGIC)?;
+        DUMP_VERSION.serial(&mut buf);
+        self.started.timestamp_millis().serial(&mut buf);
+        serial_socket_addr(&SocketAddr::new(self.local_ip, 0), &mut buf);
+        self.local_id.serial(&mut buf);
+        target.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Reads the header, checking the magic bytes and the format version.
+    pub fn read<R: Read>(source: &mut R) -> anyhow::Result<Self> {
+        let mut magic = [0u8; DUMP_MAGIC.len()];
+        source.read_exact(&mut magic).context("The dump is too short to contain a header.")?;
+        ensure!(magic == DUMP_MAGIC, "The file is not a structured network dump.");
+        let version = u8::deserial(source)?;
+        ensure!(version == DUMP_VERSION, "Unsupported dump format version {}.", version);
+        let millis = i64::deserial(source)?;
+        let started = Utc
+            .timestamp_millis_opt(millis)
+            .single()
+            .with_context(|| format!("Invalid dump start timestamp {}.", millis))?;
+        let local_ip = deserial_socket_addr(source)?.ip();
+        let local_id = P2PNodeId::deserial(source)?;
+        Ok(DumpHeader {
+            started,
+            local_ip,
+            local_id,
+        })
+    }
+}
+
+/// Reads the records of a structured dump. The reader is an iterator over the
+/// records; the iteration stops at the end of the dump or at the first error.
+pub struct DumpReader<R> {
+    header: DumpHeader,
+    source: R,
+    failed: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Creates a new reader, consuming the header of the dump.
+    pub fn new(mut source: R) -> anyhow::Result<Self> {
+        let header = DumpHeader::read(&mut source)?;
+        Ok(DumpReader {
+            header,
+            source,
+            failed: false,
+        })
+    }
+
+    /// The header of the dump.
+    pub fn header(&self) -> &DumpHeader { &self.header }
+
+    fn read_record(&mut self) -> anyhow::Result<Option<DumpItem>> {
+        let mut len = [0u8; 4];
+        // A dump that is cut off at a record boundary ends cleanly, since the
+        // node might have been stopped while dumping.
+        let mut read = 0;
+        while read < len.len() {
+            match self.source.read(&mut len[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => bail!("The dump ends within a record length."),
+                n => read += n,
+            }
+        }
+        let len = u32::from_be_bytes(len);
+        ensure!(len <= MAX_RECORD_SIZE, "Invalid record length {}.", len);
+        let mut body = vec![0u8; len as usize];
+        self.source.read_exact(&mut body).context("The dump ends within a record.")?;
+        let mut cursor = std::io::Cursor::new(&body);
+        let item = DumpItem::deserial(&mut cursor)?;
+        ensure!(cursor.position() == u64::from(len), "Trailing bytes in a dump record.");
+        Ok(Some(item))
+    }
+}
+
+impl<R: Read> Iterator for DumpReader<R> {
+    type Item = anyhow::Result<DumpItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let res = self.read_record().transpose();
+        if let Some(Err(_)) = res {
+            self.failed = true;
+        }
+        res
+    }
+}
+
+/// A peer whose messages should be dumped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpPeer {
+    /// The peer with the given node id.
+    Id(P2PNodeId),
+    /// The peers connected from the given IP address.
+    Ip(IpAddr),
+}
+
+impl std::str::FromStr for DumpPeer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            Ok(DumpPeer::Ip(ip))
+        } else {
+            s.parse::<P2PNodeId>()
+                .map(DumpPeer::Id)
+                .with_context(|| format!("'{}' is neither an IP address nor a node id.", s))
+        }
+    }
+}
+
+/// The direction of the messages that should be dumped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpDirection {
+    /// Messages received from peers.
+    In,
+    /// Messages sent to peers.
+    Out,
+}
+
+impl std::str::FromStr for DumpDirection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "in" => Ok(DumpDirection::In),
+            "out" => Ok(DumpDirection::Out),
+            _ => bail!("Invalid dump direction '{}', expected 'in' or 'out'.", s),
+        }
+    }
+}
+
+/// The kind of a message of the `network` category, i.e., the variant of its
+/// [`NetworkRequest`] or [`NetworkResponse`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetworkMessageKind {
+    Ping,
+    Pong,
+    GetPeers,
+    PeerList,
+    Handshake,
+    JoinNetwork,
+    LeaveNetwork,
+}
+
+impl NetworkMessageKind {
+    /// The kind of a deserialized message, `None` for packets.
+    pub fn of_message(msg: &NetworkMessage) -> Option<Self> {
+        match msg.payload {
+            NetworkPayload::NetworkRequest(ref request) => Some(match request {
+                NetworkRequest::Ping => NetworkMessageKind::Ping,
+                NetworkRequest::GetPeers(_) => NetworkMessageKind::GetPeers,
+                NetworkRequest::Handshake(_) => NetworkMessageKind::Handshake,
+                NetworkRequest::JoinNetwork(_) => NetworkMessageKind::JoinNetwork,
+                NetworkRequest::LeaveNetwork(_) => NetworkMessageKind::LeaveNetwork,
+            }),
+            NetworkPayload::NetworkResponse(ref response) => Some(match response {
+                NetworkResponse::Pong => NetworkMessageKind::Pong,
+                NetworkResponse::PeerList(_) => NetworkMessageKind::PeerList,
+            }),
+            NetworkPayload::NetworkPacket(_) => None,
+        }
+    }
+}
+
+impl std::str::FromStr for NetworkMessageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "ping" => Ok(NetworkMessageKind::Ping),
+            "pong" => Ok(NetworkMessageKind::Pong),
+            "get-peers" => Ok(NetworkMessageKind::GetPeers),
+            "peer-list" => Ok(NetworkMessageKind::PeerList),
+            "handshake" => Ok(NetworkMessageKind::Handshake),
+            "join-network" => Ok(NetworkMessageKind::JoinNetwork),
+            "leave-network" => Ok(NetworkMessageKind::LeaveNetwork),
+            _ => bail!("Unknown network message kind '{}'.", s),
+        }
+    }
+}
+
+/// Selects the messages that are dumped and limits the size of a dump. Empty
+/// filters and absent limits do not restrict the dump.
+#[derive(Debug, Clone, Default)]
+pub struct DumpOptions {
+    /// Only dump messages of these peers.
+    pub peers:         Vec<DumpPeer>,
+    /// Only dump messages of these categories.
+    pub categories:    HashSet<TrafficCategory>,
+    /// Only dump messages of the `network` category that are of these kinds.
+    /// Messages of the other categories are not affected.
+    pub network_kinds: HashSet<NetworkMessageKind>,
+    /// Only dump messages in this direction.
+    pub direction:     Option<DumpDirection>,
+    /// Stop the dump after this amount of time.
+    pub max_duration:  Option<Duration>,
+    /// Stop the dump once this many bytes were written.
+    pub max_bytes:     Option<u64>,
+    /// Start new dump files once the current ones reach this many bytes.
+    pub rotate_bytes:  Option<u64>,
+    /// Also export the messages in the pcapng format (see [`pcap`]).
+    pub pcap:          bool,
+}
+
+impl From<&NetworkDumpConfig> for DumpOptions {
+    fn from(config: &NetworkDumpConfig) -> Self {
+        DumpOptions {
+            peers:         config.dump_peers.clone(),
+            categories:    config.dump_categories.iter().copied().collect(),
+            network_kinds: config.dump_network_messages.iter().copied().collect(),
+            direction:     config.dump_direction,
+            max_duration:  config.dump_max_duration.map(Duration::from_secs),
+            max_bytes:     config.dump_max_bytes,
+            rotate_bytes:  config.dump_rotate_bytes,
+            pcap:          config.dump_pcap,
+        }
+    }
+}
+
+impl DumpOptions {
+    /// Check whether the item passes the filters.
+    pub fn matches(&self, item: &DumpItem) -> bool {
+        let direction_matches = match self.direction {
+            Some(DumpDirection::In) => item.inbound,
+            Some(DumpDirection::Out) => !item.inbound,
+            None => true,
+        };
+        let peer_matches = self.peers.is_empty()
+            || self.peers.iter().any(|peer| match peer {
+                DumpPeer::Id(id) => item.peer_id == Some(*id),
+                DumpPeer::Ip(ip) => item.remote_addr.ip() == *ip,
+            });
+        if !direction_matches || !peer_matches {
+            return false;
+        }
+        if self.categories.is_empty() && self.network_kinds.is_empty() {
+            return true;
+        }
+        // the message is only deserialized if needed
+        item.message().map_or(false, |msg| {
+            let category = TrafficCategory::of_message(&msg);
+            (self.categories.is_empty() || self.categories.contains(&category))
+                && (self.network_kinds.is_empty()
+                    || NetworkMessageKind::of_message(&msg)
+                        .map_or(true, |kind| self.network_kinds.contains(&kind)))
+        })
+    }
+}
+
+/// A request to start a dump.
+pub struct DumpRequest {
+    /// The directory to dump to, relative to the data directory.
+    pub path:    PathBuf,
+    /// Whether to also write the structured dump.
+    pub raw:     bool,
+    /// The filters and limits of the dump.
+    pub options: DumpOptions,
+}
+
+/// The files of one segment of a dump.
+struct DumpFiles {
+    pretty: BufWriter<File>,
+    raw:    Option<BufWriter<File>>,
+    pcap:   Option<PcapWriter<BufWriter<File>>>,
+}
+
+impl DumpFiles {
+    /// Create the files of a segment of the dump. The first segment keeps the
+    /// plain file names, the following ones are numbered.
+    fn create(
+        dir: &Path,
+        local_addr: SocketAddr,
+        local_id: P2PNodeId,
+        raw: bool,
+        pcap: bool,
+        segment: usize,
+    ) -> anyhow::Result<Self> {
+        let suffix = if segment == 0 {
+            String::new()
+        } else {
+            format!(".{}", segment)
+        };
+        let started = Utc::now();
+
+        // Create and start pretty dump file
+        let mut pretty =
+            BufWriter::new(File::create(dir.join(format!("{}-pretty{}.log", local_id, suffix)))?);
+        pretty.write_fmt(format_args!(
+            "Dumping started at: {}\nLocal IP is: {}\nLocal ID is: {}\n\n",
+            started,
+            local_addr.ip(),
+            local_id
+        ))?;
+
+        // Create and start the structured dump file
+        let raw = if raw {
+            let mut file =
+                BufWriter::new(File::create(dir.join(format!("{}{}.dump", local_id, suffix)))?);
+            DumpHeader {
+                started,
+                local_ip: local_addr.ip(),
+                local_id,
+            }
+            .write(&mut file)?;
+            Some(file)
+        } else {
+            None
+        };
+
+        // Create and start the pcapng export
+        let pcap = if pcap {
+            let file = File::create(dir.join(format!("{}{}.pcapng", local_id, suffix)))?;
+            Some(PcapWriter::new(BufWriter::new(file), local_addr)?)
+        } else {
+            None
+        };
+
+        Ok(DumpFiles {
+            pretty,
+            raw,
+            pcap,
+        })
+    }
+
+    /// Write the item to all files, returning the number of bytes written.
+    fn write(&mut self, item: &DumpItem) -> anyhow::Result<u64> {
+        let mut written = 0;
+        // Raw dump
+        if let Some(ref mut raw) = self.raw {
+            written += item.write_record(raw)?;
+        }
+        // pcapng export
+        if let Some(ref mut pcap) = self.pcap {
+            written += pcap.write_item(item)?;
+        }
+        // Pretty dump
+        let pretty = format!("{}\n\n", item);
+        self.pretty.write_all(pretty.as_bytes())?;
+        written += pretty.len() as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.pretty.flush()?;
+        if let Some(ref mut raw) = self.raw {
+            raw.flush()?;
+        }
+        if let Some(ref mut pcap) = self.pcap {
+            pcap.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of a running dump.
+struct ActiveDump {
+    dir:           PathBuf,
+    local_addr:    SocketAddr,
+    local_id:      P2PNodeId,
+    raw:           bool,
+    options:       DumpOptions,
+    started:       Instant,
+    /// The index of the current set of dump files.
+    segment:       usize,
+    segment_bytes: u64,
+    total_bytes:   u64,
+    files:         DumpFiles,
+}
+
+impl ActiveDump {
+    fn start(
+        base_dir: &Path,
+        local_addr: SocketAddr,
+        local_id: P2PNodeId,
+        request: DumpRequest,
+    ) -> anyhow::Result<Self> {
+        let dir = base_dir.join(&request.path);
+        // Create directory
+        let _ = std::fs::create_dir(&dir);
+        let files =
+            DumpFiles::create(&dir, local_addr, local_id, request.raw, request.options.pcap, 0)?;
+        info!("Starting dump in: {:?}", &dir);
+        Ok(ActiveDump {
+            dir,
+            local_addr,
+            local_id,
+            raw: request.raw,
+            options: request.options,
+            started: Instant::now(),
+            segment: 0,
+            segment_bytes: 0,
+            total_bytes: 0,
+            files,
+        })
+    }
+
+    /// The limit that was reached, if any.
+    fn limit_reached(&self) -> Option<&'static str> {
+        if self.options.max_duration.map_or(false, |max| self.started.elapsed() >= max) {
+            Some("maximum duration")
+        } else if self.options.max_bytes.map_or(false, |max| self.total_bytes >= max) {
+            Some("maximum size")
+        } else {
+            None
+        }
+    }
+
+    /// Write the item if it passes the filters, rotating the files if needed.
+    fn write(&mut self, item: &DumpItem) -> anyhow::Result<()> {
+        if !self.options.matches(item) {
+            return Ok(());
+        }
+        let written = self.files.write(item)?;
+        self.segment_bytes += written;
+        self.total_bytes += written;
+        if self.options.rotate_bytes.map_or(false, |max| self.segment_bytes >= max) {
+            self.files.flush()?;
+            self.segment += 1;
+            self.files = DumpFiles::create(
+                &self.dir,
+                self.local_addr,
+                self.local_id,
+                self.raw,
+                self.options.pcap,
+                self.segment,
+            )?;
+            self.segment_bytes = 0;
+            debug!("Rotated the dump files in {:?} to segment {}", self.dir, self.segment);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> { self.files.flush() }
+}
+
 /// Creates the thread responsible for intercepting and dumping network data.
-#[cfg(feature = "network_dump")]
+/// A dump is started by sending a request on `act_rx`, and stopped by sending
+/// `None`. The thread stops when the channels are closed.
 pub fn create_dump_thread(
-    ip: IpAddr,
+    local_addr: SocketAddr,
     id: P2PNodeId,
     rx: Receiver<DumpItem>,
-    act_rx: Receiver<(std::path::PathBuf, bool)>,
-    base_dir: std::path::PathBuf,
+    act_rx: Receiver<Option<DumpRequest>>,
+    base_dir: PathBuf,
 ) {
     spawn_or_die!("network dump", move || -> anyhow::Result<()> {
-        let mut dir: Option<std::path::PathBuf> = None;
-        let mut pretty_dump: Option<std::fs::File> = None;
-        let mut raw_dump: Option<std::fs::File> = None;
-        let mut count = 0;
+        let mut dump: Option<ActiveDump> = None;
         loop {
-            if let Ok((new_path, raw)) = act_rx.try_recv() {
-                if new_path.components().next().is_none() {
-                    info!("Dump process stopped");
-                    break;
+            crossbeam_channel::select! {
+                recv(act_rx) -> request => {
+                    let request = match request {
+                        Ok(request) => request,
+                        Err(_) => break,
+                    };
+                    if let Some(mut old) = dump.take() {
+                        if let Err(e) = old.flush() {
+                            error!("Could not complete the dump: {}", e);
+                        }
+                        info!("Dump process stopped");
+                    }
+                    if let Some(request) = request {
+                        match ActiveDump::start(&base_dir, local_addr, id, request) {
+                            Ok(active) => dump = Some(active),
+                            Err(e) => error!("Aborting dump due to error: {}", e),
+                        }
+                    }
                 }
-                let new_path = base_dir.join(&new_path);
-                // Create directory
-                let _ = std::fs::create_dir(&new_path.clone());
-
-                // Create and start pretty dump file
-                let mut pretty_dump_file = std::fs::File::create(
-                    base_dir
-                        .join(new_path.join(std::path::Path::new(&format!("{}-pretty.log", id)))),
-                )
-                .map_err(|e| {
-                    error!("Aborting dump due to error: {}", e);
-                    e
-                })?;
-                pretty_dump_file
-                    .write_fmt(format_args!(
-                        "Dumping started at: {}\nLocal IP is: {}\nLocal ID is: {}\n\n",
-                        Utc::now(),
-                        ip,
-                        id
-                    ))
-                    .map_err(|e| {
-                        error!("Aborting dump due to error: {}", e);
-                        e
-                    })?;
-                pretty_dump.replace(pretty_dump_file);
-
-                // Activate raw dump
-                if raw {
-                    count = 1;
-                };
-                info!("Starting dump in: {:?}", &new_path);
-                dir = Some(new_path);
-            };
-            if let Some(ref dir) = dir {
-                let msg = rx.recv()?;
-                // Raw dump
-                if count > 0 {
-                    // Create file
-                    let file = std::fs::File::create(
-                        dir.join(std::path::Path::new(&format!("{}-{}", id, count))),
-                    )
-                    .map_err(|e| {
-                        error!("Aborting dump due to error: {}", e);
-                        e
-                    })?;
-                    raw_dump.replace(file);
-                    // Write message
-                    if let Some(ref mut rd) = raw_dump {
-                        let _ = rd.write(&msg.msg).map_err(|e| {
+                recv(rx) -> item => {
+                    let item = match item {
+                        Ok(item) => item,
+                        Err(_) => break,
+                    };
+                    // Messages that arrive while no dump is active are dropped.
+                    if let Some(ref mut active) = dump {
+                        let res = active.write(&item).and_then(|_| {
+                            // Flush if nothing else is queued, so that the dump can be read
+                            // while the node is running.
+                            if rx.is_empty() {
+                                active.flush()
+                            } else {
+                                Ok(())
+                            }
+                        });
+                        if let Err(e) = res {
                             error!("Aborting dump due to error: {}", e);
-                            e
-                        })?;
-                        count += 1;
+                            dump = None;
+                        }
+                    }
+                }
+                default(LIMIT_CHECK_INTERVAL) => {}
+            }
+            if let Some(limit) = dump.as_ref().and_then(ActiveDump::limit_reached) {
+                info!("Dump process stopped: the {} was reached", limit);
+                if let Some(mut active) = dump.take() {
+                    if let Err(e) = active.flush() {
+                        error!("Could not complete the dump: {}", e);
                     }
-                };
-
-                // Pretty dump
-                if let Some(ref mut pd) = pretty_dump {
-                    pd.write_fmt(format_args!("{}\n\n", msg)).map_err(|e| {
-                        error!("Aborting dump due to error: {}", e);
-                        e
-                    })?;
-                };
-            };
-            std::thread::yield_now();
+                }
+            }
         }
         Ok(())
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_roundtrip() -> anyhow::Result<()> {
+        let header = DumpHeader {
+            started:  Utc.timestamp_millis_opt(1_600_000_000_000).unwrap(),
+            local_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            local_id: P2PNodeId(42),
+        };
+        let items = vec![
+            DumpItem {
+                timestamp:   Utc.timestamp_millis_opt(1_600_000_000_001).unwrap(),
+                inbound:     true,
+                remote_addr: "10.0.0.1:8888".parse()?,
+                peer_id:     None,
+                msg:         vec![1, 2, 3].into(),
+            },
+            DumpItem {
+                timestamp:   Utc.timestamp_millis_opt(1_600_000_000_002).unwrap(),
+                inbound:     false,
+                remote_addr: "[2001:db8::1]:9999".parse()?,
+                peer_id:     Some(P2PNodeId(7)),
+                msg:         vec![].into(),
+            },
+        ];
+
+        let mut buf = Vec::new();
+        header.write(&mut buf)?;
+        for item in &items {
+            item.write_record(&mut buf)?;
+        }
+
+        let reader = DumpReader::new(&buf[..])?;
+        assert_eq!(*reader.header(), header);
+        let read = reader.collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(read.len(), items.len());
+        for (a, b) in read.iter().zip(items.iter()) {
+            assert_eq!(a.timestamp, b.timestamp);
+            assert_eq!(a.inbound, b.inbound);
+            assert_eq!(a.remote_addr, b.remote_addr);
+            assert_eq!(a.peer_id, b.peer_id);
+            assert_eq!(a.msg, b.msg);
+        }
+
+        // a dump cut off within a record is reported as an error
+        let mut reader = DumpReader::new(&buf[..buf.len() - 1])?;
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+        assert!(reader.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_filters() -> anyhow::Result<()> {
+        let item = DumpItem::new(true, "10.0.0.1:8888".parse()?, Some(P2PNodeId(7)), vec![].into());
+
+        assert!(DumpOptions::default().matches(&item));
+
+        let mut options = DumpOptions {
+            direction: Some("out".parse()?),
+            ..Default::default()
+        };
+        assert!(!options.matches(&item));
+        options.direction = Some(DumpDirection::In);
+        assert!(options.matches(&item));
+
+        options.peers = vec!["10.0.0.2".parse()?, "0000000000000007".parse()?];
+        assert!(options.matches(&item));
+        options.peers = vec!["10.0.0.1".parse()?];
+        assert!(options.matches(&item));
+        options.peers = vec!["0000000000000008".parse()?];
+        assert!(!options.matches(&item));
+        options.peers.clear();
+
+        // an undecodable message never matches a category filter
+        options.categories.insert(TrafficCategory::Network);
+        assert!(!options.matches(&item));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_network_message_filter() -> anyhow::Result<()> {
+        let addr: SocketAddr = "10.0.0.1:8888".parse()?;
+        let item = |payload| {
+            let mut buf = Vec::new();
+            NetworkMessage {
+                created: 0,
+                received: None,
+                payload,
+            }
+            .serialize(&mut buf)
+            .unwrap();
+            DumpItem::new(true, addr, None, buf.into())
+        };
+        let ping = item(NetworkPayload::NetworkRequest(NetworkRequest::Ping));
+        let pong = item(NetworkPayload::NetworkResponse(NetworkResponse::Pong));
+        let packet = item(NetworkPayload::NetworkPacket(crate::network::NetworkPacket {
+            destination: crate::network::PacketDestination::Broadcast(Vec::new()),
+            network_id:  crate::network::NetworkId::from(100),
+            message:     vec![crate::consensus_ffi::helpers::PacketType::Block as u8],
+        }));
+
+        let mut options = DumpOptions {
+            network_kinds: [NetworkMessageKind::Ping].into_iter().collect(),
+            ..Default::default()
+        };
+        assert!(options.matches(&ping));
+        assert!(!options.matches(&pong));
+        // the kinds only restrict the messages of the network category
+        assert!(options.matches(&packet));
+        options.categories.insert(TrafficCategory::Network);
+        assert!(options.matches(&ping));
+        assert!(!options.matches(&packet));
+        assert_eq!("peer-list".parse::<NetworkMessageKind>()?, NetworkMessageKind::PeerList);
+        assert!("pang".parse::<NetworkMessageKind>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+//! Export of dumped network messages in the pcapng format.
+//!
+//! The P2P stream is encrypted, so a capture of the actual traffic is of little
+//! use. Instead, the decrypted messages are wrapped in synthetic IP and TCP
+//! headers, one stream per peer and direction, so that standard tools can
+//! filter and follow the conversations. The TCP payload is the sequence of
+//! size-prefixed network messages, which is what the Wireshark dissector in
+//! `scripts/wireshark` decodes. The local port of every stream is the node's
+//! listen port, since the actual port of outbound connections is not known.
+
+use super::DumpItem;
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// The pcapng block types used in the export.
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Raw IPv4 or IPv6 packets, without a link-layer header.
+const LINKTYPE_RAW: u16 = 101;
+
+/// The maximum payload of a single synthetic TCP segment; larger messages are
+/// split into multiple segments to stay within the IP length limits.
+const MAX_SEGMENT_SIZE: usize = 65_000;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_FLAGS_PSH_ACK: u8 = 0x18;
+
+/// Writes dump items to a pcapng file.
+pub struct PcapWriter<W: Write> {
+    target:     W,
+    local_addr: SocketAddr,
+    /// The next sequence number of each stream, indexed by the remote address
+    /// and whether the stream is inbound.
+    sequence:   HashMap<(SocketAddr, bool), u32>,
+    ip_id:      u16,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Creates a writer, writing the section header and the interface
+    /// description of the file.
+    pub fn new(mut target: W, local_addr: SocketAddr) -> anyhow::Result<Self> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        // version 1.0
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // the section length is not specified
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut target, SECTION_HEADER_BLOCK, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        // no limit on the captured length
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut target, INTERFACE_DESCRIPTION_BLOCK, &idb)?;
+
+        Ok(PcapWriter {
+            target,
+            local_addr,
+            sequence: HashMap::new(),
+            ip_id: 0,
+        })
+    }
+
+    /// Writes the message of the item as one or more synthetic TCP segments,
+    /// returning the number of bytes written.
+    pub fn write_item(&mut self, item: &DumpItem) -> anyhow::Result<u64> {
+        let local = SocketAddr::new(
+            local_ip_for(self.local_addr.ip(), item.remote_addr.ip()),
+            self.local_addr.port(),
+        );
+        let (src, dst) = if item.inbound {
+            (item.remote_addr, local)
+        } else {
+            (local, item.remote_addr)
+        };
+        let micros = item.timestamp.timestamp_micros() as u64;
+
+        let mut written = 0;
+        for chunk in item.msg.chunks(MAX_SEGMENT_SIZE) {
+            let seq = *self.sequence.entry((item.remote_addr, item.inbound)).or_insert(0);
+            let ack = *self.sequence.entry((item.remote_addr, !item.inbound)).or_insert(0);
+            self.sequence
+                .insert((item.remote_addr, item.inbound), seq.wrapping_add(chunk.len() as u32));
+            let packet = self.ip_packet(src, dst, seq, ack, chunk);
+
+            let mut epb = Vec::with_capacity(20 + packet.len() + 3);
+            // interface id
+            epb.extend_from_slice(&0u32.to_le_bytes());
+            epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(micros as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&packet);
+            written += write_block(&mut self.target, ENHANCED_PACKET_BLOCK, &epb)?;
+        }
+        Ok(written)
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.target.flush()?;
+        Ok(())
+    }
+
+    /// Builds an IP packet containing a TCP segment with the given payload.
+    fn ip_packet(
+        &mut self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        seq: u32,
+        ack: u32,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut segment = Vec::with_capacity(TCP_HEADER_LEN + payload.len());
+        segment.extend_from_slice(&src.port().to_be_bytes());
+        segment.extend_from_slice(&dst.port().to_be_bytes());
+        segment.extend_from_slice(&seq.to_be_bytes());
+        segment.extend_from_slice(&ack.to_be_bytes());
+        // data offset (in 32-bit words) and flags
+        segment.push(((TCP_HEADER_LEN / 4) as u8) << 4);
+        segment.push(TCP_FLAGS_PSH_ACK);
+        // window size
+        segment.extend_from_slice(&u16::MAX.to_be_bytes());
+        // checksum, filled in below
+        segment.extend_from_slice(&[0, 0]);
+        // urgent pointer
+        segment.extend_from_slice(&[0, 0]);
+        segment.extend_from_slice(payload);
+
+        let mut packet;
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                let mut pseudo_header = Vec::with_capacity(12);
+                pseudo_header.extend_from_slice(&src_ip.octets());
+                pseudo_header.extend_from_slice(&dst_ip.octets());
+                pseudo_header.extend_from_slice(&[0, 6]);
+                pseudo_header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+                let checksum = internet_checksum(&[&pseudo_header, &segment]);
+                segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+                packet = Vec::with_capacity(IPV4_HEADER_LEN + segment.len());
+                // version and header length
+                packet.push(0x45);
+                packet.push(0);
+                packet.extend_from_slice(&((IPV4_HEADER_LEN + segment.len()) as u16).to_be_bytes());
+                packet.extend_from_slice(&self.ip_id.to_be_bytes());
+                self.ip_id = self.ip_id.wrapping_add(1);
+                // don't fragment
+                packet.extend_from_slice(&0x4000u16.to_be_bytes());
+                // TTL and protocol (TCP)
+                packet.push(64);
+                packet.push(6);
+                // checksum, filled in below
+                packet.extend_from_slice(&[0, 0]);
+                packet.extend_from_slice(&src_ip.octets());
+                packet.extend_from_slice(&dst_ip.octets());
+                let checksum = internet_checksum(&[&packet]);
+                packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            }
+            (src_ip, dst_ip) => {
+                let src_ip = to_ipv6(src_ip);
+                let dst_ip = to_ipv6(dst_ip);
+                let mut pseudo_header = Vec::with_capacity(40);
+                pseudo_header.extend_from_slice(&src_ip.octets());
+                pseudo_header.extend_from_slice(&dst_ip.octets());
+                pseudo_header.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+                pseudo_header.extend_from_slice(&[0, 0, 0, 6]);
+                let checksum = internet_checksum(&[&pseudo_header, &segment]);
+                segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+                packet = Vec::with_capacity(IPV6_HEADER_LEN + segment.len());
+                // version, traffic class and flow label
+                packet.extend_from_slice(&0x6000_0000u32.to_be_bytes());
+                packet.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+                // next header (TCP) and hop limit
+                packet.push(6);
+                packet.push(64);
+                packet.extend_from_slice(&src_ip.octets());
+                packet.extend_from_slice(&dst_ip.octets());
+            }
+        }
+        packet.extend_from_slice(&segment);
+        packet
+    }
+}
+
+/// The local address to use in the streams with a peer. The address of the
+/// node is used if it is known and of the same family as the peer's address.
+fn local_ip_for(local: IpAddr, remote: IpAddr) -> IpAddr {
+    match (local, remote) {
+        (IpAddr::V4(ip), IpAddr::V4(_)) if !ip.is_unspecified() => local,
+        (IpAddr::V6(ip), IpAddr::V6(_)) if !ip.is_unspecified() => local,
+        (_, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        (_, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Writes a pcapng block with the given body, padding it to 32 bits. Returns
+/// the total length of the block.
+fn write_block<W: Write>(target: &mut W, block_type: u32, body: &[u8]) -> anyhow::Result<u64> {
+    let padding = (4 - body.len() % 4) % 4;
+    let total_len = (12 + body.len() + padding) as u32;
+    target.write_all(&block_type.to_le_bytes())?;
+    target.write_all(&total_len.to_le_bytes())?;
+    target.write_all(body)?;
+    target.write_all(&[0u8; 3][..padding])?;
+    target.write_all(&total_len.to_le_bytes())?;
+    Ok(u64::from(total_len))
+}
+
+/// The one's complement checksum used by IP and TCP.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd: Option<u8> = None;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            Some(high) => sum += u32::from(u16::from_be_bytes([high, byte])),
+            None => odd = Some(byte),
+        }
+    }
+    if let Some(high) = odd {
+        sum += u32::from(u16::from_be_bytes([high, 0]));
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_internet_checksum() {
+        // the example IPv4 header from RFC 1071 style calculations
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(internet_checksum(&[&header]), 0xb861);
+        // the checksum is independent of how the data is split
+        assert_eq!(internet_checksum(&[&header[..3], &header[3..]]), 0xb861);
+    }
+
+    #[test]
+    fn test_pcap_segments() -> anyhow::Result<()> {
+        let mut buf = Vec::new();
+        let mut writer = PcapWriter::new(&mut buf, "192.168.0.1:8888".parse()?)?;
+        let msg: Arc<[u8]> = vec![0u8; MAX_SEGMENT_SIZE + 1].into();
+        let item = DumpItem::new(true, "10.0.0.1:9999".parse()?, None, msg);
+        let written = writer.write_item(&item)?;
+        // section header and interface description blocks
+        let header_len = 28 + 20;
+        assert_eq!(buf.len() as u64, header_len + written);
+        // two segments, each with its block overhead and IP and TCP headers
+        let headers = (IPV4_HEADER_LEN + TCP_HEADER_LEN) as u64;
+        assert_eq!(written, 2 * (32 + headers) + (MAX_SEGMENT_SIZE as u64 + 1) + 3);
+        Ok(())
+    }
+}
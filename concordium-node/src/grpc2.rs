@@ -1,4 +1,7 @@
-use crate::stats_export_service::StatsExportService;
+use crate::{
+    stats_export_service::StatsExportService,
+    telemetry::{spawn_in_current_context, Instrumented, Span, SpanKind, TraceContext},
+};
 use anyhow::Context;
 use hyper::server::conn::AddrStream;
 use prometheus::core::{Atomic, AtomicU64, GenericGauge};
@@ -593,9 +596,209 @@ pub mod types {
     }
 }
 
-/// The service generated from the configuration in the `build.rs` file.
+/// The services generated from the configuration in the `build.rs` file.
 pub mod service {
     include!(concat!(env!("OUT_DIR"), "/concordium.v2.Queries.rs"));
+    include!(concat!(env!("OUT_DIR"), "/concordium.v2.Admin.rs"));
+}
+
+/// The request of the `DumpStartWithFilters` endpoint. The first two fields
+/// are those of [`types::DumpRequest`], the others restrict the dump in the
+/// same way as the `CONCORDIUM_NODE_DUMP_*` options do for `DumpStart`. Empty
+/// filters and absent limits do not restrict the dump. The message is defined
+/// here since the endpoint is specific to this node.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DumpStartWithFiltersRequest {
+    /// The directory to dump to, relative to the data directory.
+    #[prost(string, tag = "1")]
+    pub file:              String,
+    /// Whether to also write the structured dump.
+    #[prost(bool, tag = "2")]
+    pub raw:               bool,
+    /// Node ids and IP addresses of the peers whose messages are dumped.
+    #[prost(string, repeated, tag = "3")]
+    pub peers:             Vec<String>,
+    /// Categories of the messages that are dumped, e.g., `block` or `network`.
+    #[prost(string, repeated, tag = "4")]
+    pub categories:        Vec<String>,
+    /// Direction of the messages that are dumped, `in` or `out`.
+    #[prost(string, optional, tag = "5")]
+    pub direction:         Option<String>,
+    /// Number of seconds after which the dump is stopped.
+    #[prost(uint64, optional, tag = "6")]
+    pub max_duration_secs: Option<u64>,
+    /// Number of bytes after which the dump is stopped.
+    #[prost(uint64, optional, tag = "7")]
+    pub max_bytes:         Option<u64>,
+    /// Size in bytes at which the dump files are rotated.
+    #[prost(uint64, optional, tag = "8")]
+    pub rotate_bytes:      Option<u64>,
+    /// Whether to also write a pcapng file.
+    #[prost(bool, tag = "9")]
+    pub pcap:              bool,
+    /// Kinds of the messages of the `network` category that are dumped, e.g.,
+    /// `ping` or `peer-list`.
+    #[prost(string, repeated, tag = "10")]
+    pub network_messages:  Vec<String>,
+}
+
+#[cfg(feature = "network_dump")]
+impl DumpStartWithFiltersRequest {
+    /// The filters and limits of the requested dump.
+    fn options(&self) -> anyhow::Result<crate::dumper::DumpOptions> {
+        Ok(crate::dumper::DumpOptions {
+            peers:         self.peers.iter().map(|peer| peer.parse()).collect::<Result<_, _>>()?,
+            categories:    self
+                .categories
+                .iter()
+                .map(|category| category.parse())
+                .collect::<Result<_, _>>()?,
+            network_kinds: self
+                .network_messages
+                .iter()
+                .map(|kind| kind.parse())
+                .collect::<Result<_, _>>()?,
+            direction:     self.direction.as_deref().map(str::parse).transpose()?,
+            max_duration:  self.max_duration_secs.map(std::time::Duration::from_secs),
+            max_bytes:     self.max_bytes,
+            rotate_bytes:  self.rotate_bytes,
+            pcap:          self.pcap,
+        })
+    }
+}
+
+/// The request of the `UpdateDynamicConfig` endpoint of the admin service.
+/// The settings are those that can be reloaded from the configuration file,
+/// and absent settings are left unchanged.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UpdateDynamicConfigRequest {
+    #[prost(uint32, optional, tag = "1")]
+    pub desired_nodes:              Option<u32>,
+    #[prost(uint32, optional, tag = "2")]
+    pub max_allowed_nodes:          Option<u32>,
+    #[prost(uint32, optional, tag = "3")]
+    pub max_outbound_nodes:         Option<u32>,
+    #[prost(uint32, optional, tag = "4")]
+    pub max_inbound_nodes:          Option<u32>,
+    #[prost(double, optional, tag = "5")]
+    pub relay_broadcast_percentage: Option<f64>,
+    /// The maximum latency in milliseconds, where 0 removes the limit.
+    #[prost(uint64, optional, tag = "6")]
+    pub max_latency:                Option<u64>,
+    /// The log level of the node, e.g., `info` or `debug`.
+    #[prost(string, optional, tag = "7")]
+    pub log_level:                  Option<String>,
+}
+
+impl UpdateDynamicConfigRequest {
+    /// The update of the configuration of the node.
+    fn update(&self) -> anyhow::Result<crate::configuration::DynamicConfigUpdate> {
+        let nodes = |count: Option<u32>, name: &str| {
+            count
+                .map(u16::try_from)
+                .transpose()
+                .with_context(|| format!("Invalid value for {}", name))
+        };
+        Ok(crate::configuration::DynamicConfigUpdate {
+            desired_nodes:              nodes(self.desired_nodes, "desired_nodes")?,
+            max_allowed_nodes:          nodes(self.max_allowed_nodes, "max_allowed_nodes")?,
+            max_outbound_nodes:         nodes(self.max_outbound_nodes, "max_outbound_nodes")?,
+            max_inbound_nodes:          nodes(self.max_inbound_nodes, "max_inbound_nodes")?,
+            relay_broadcast_percentage: self.relay_broadcast_percentage,
+            max_latency:                self.max_latency,
+            log_level:                  self
+                .log_level
+                .as_deref()
+                .map(str::parse)
+                .transpose()
+                .context("Invalid log level")?,
+        })
+    }
+}
+
+/// The response of the `GetPeersInfo` endpoint. It is a [`types::PeersInfo`]
+/// whose peers also carry their traffic, see [`PeerWithTraffic`].
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PeersInfoWithTraffic {
+    #[prost(message, repeated, tag = "1")]
+    pub peers: Vec<PeerWithTraffic>,
+}
+
+/// A peer in the response of the `GetPeersInfo` endpoint. It has the fields of
+/// [`types::peers_info::Peer`] and in addition the traffic with the peer, with
+/// a tag well beyond those of `Peer`. Clients that decode the response as a
+/// `PeersInfo` ignore the additional field.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PeerWithTraffic {
+    #[prost(message, optional, tag = "1")]
+    pub peer_id:        Option<types::PeerId>,
+    #[prost(message, optional, tag = "2")]
+    pub socket_address: Option<types::IpSocketAddress>,
+    #[prost(message, optional, tag = "3")]
+    pub network_stats:  Option<types::peers_info::peer::NetworkStats>,
+    #[prost(oneof = "types::peers_info::peer::ConsensusInfo", tags = "4, 5")]
+    pub consensus_info: Option<types::peers_info::peer::ConsensusInfo>,
+    /// The traffic with the peer by message category and direction. Only the
+    /// categories and directions with traffic are listed.
+    #[prost(message, repeated, tag = "100")]
+    pub traffic:        Vec<PeerTraffic>,
+}
+
+/// The messages and bytes of one category exchanged with a peer in one
+/// direction.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PeerTraffic {
+    /// The category of the messages, e.g., `block` or `network`.
+    #[prost(string, tag = "1")]
+    pub category:  String,
+    /// The direction of the messages, `received` or `sent`.
+    #[prost(string, tag = "2")]
+    pub direction: String,
+    #[prost(uint64, tag = "3")]
+    pub messages:  u64,
+    #[prost(uint64, tag = "4")]
+    pub bytes:     u64,
+}
+
+/// The response of the `GetNodeInfo` endpoint. It has the fields of
+/// [`types::NodeInfo`] and in addition the connections of the node, with a tag
+/// well beyond those of `NodeInfo`. The message is defined here since the
+/// connection limits are specific to this node. Clients that decode the
+/// response as a `NodeInfo` ignore the additional field.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeInfoWithConnections {
+    #[prost(string, tag = "1")]
+    pub peer_version: String,
+    #[prost(message, optional, tag = "3")]
+    pub local_time:   Option<types::Timestamp>,
+    #[prost(message, optional, tag = "4")]
+    pub peer_uptime:  Option<types::Duration>,
+    #[prost(message, optional, tag = "5")]
+    pub network_info: Option<types::node_info::NetworkInfo>,
+    #[prost(oneof = "types::node_info::Details", tags = "6, 7")]
+    pub details:      Option<types::node_info::Details>,
+    /// The connections to other nodes and their limits.
+    #[prost(message, optional, tag = "100")]
+    pub connections:  Option<NodeConnections>,
+}
+
+/// The number of post-handshake connections of the node to other nodes (as
+/// opposed to bootstrappers), and the limits that apply to them.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeConnections {
+    /// Connections that the node initiated.
+    #[prost(uint64, tag = "1")]
+    pub outbound:           u64,
+    /// Connections that the peer initiated.
+    #[prost(uint64, tag = "2")]
+    pub inbound:            u64,
+    #[prost(uint64, tag = "3")]
+    pub max_outbound_nodes: u64,
+    #[prost(uint64, tag = "4")]
+    pub max_inbound_nodes:  u64,
+    /// The limit on all connections.
+    #[prost(uint64, tag = "5")]
+    pub max_allowed_nodes:  u64,
 }
 
 /// Service configuration, listing which endpoints are enabled.
@@ -692,6 +895,8 @@ struct ServiceConfig {
     #[serde(default)]
     dump_start: bool,
     #[serde(default)]
+    dump_start_with_filters: bool,
+    #[serde(default)]
     dump_stop: bool,
     #[serde(default)]
     get_peers_info: bool,
@@ -715,6 +920,8 @@ struct ServiceConfig {
     get_winning_bakers_epoch: bool,
     #[serde(default)]
     dry_run: bool,
+    #[serde(default)]
+    update_dynamic_config: bool,
 }
 
 impl ServiceConfig {
@@ -764,6 +971,7 @@ impl ServiceConfig {
             ban_peer: true,
             unban_peer: true,
             dump_start: true,
+            dump_start_with_filters: true,
             dump_stop: true,
             get_peers_info: true,
             get_node_info: true,
@@ -776,6 +984,7 @@ impl ServiceConfig {
             get_first_block_epoch: true,
             get_winning_bakers_epoch: true,
             dry_run: true,
+            update_dynamic_config: true,
         }
     }
 
@@ -1000,7 +1209,7 @@ pub mod server {
                 } = notification_handlers;
 
                 let blocks_channel = server.blocks_channels.clone();
-                let blocks_relay = tokio::spawn(async move {
+                let blocks_relay = spawn_in_current_context(async move {
                     while let Some(v) = blocks.next().await {
                         match blocks_channel.lock() {
                             Ok(mut senders) => senders.retain(|sender| {
@@ -1029,7 +1238,7 @@ pub mod server {
                 });
 
                 let finalized_blocks_channel = server.finalized_blocks_channels.clone();
-                let finalized_blocks_relay = tokio::spawn(async move {
+                let finalized_blocks_relay = spawn_in_current_context(async move {
                     while let Some(v) = finalized_blocks.next().await {
                         match finalized_blocks_channel.lock() {
                             Ok(mut senders) => senders.retain(|sender| {
@@ -1056,7 +1265,9 @@ pub mod server {
                         }
                     }
                 });
-                let service = service::queries_server::QueriesServer::new(server);
+                let server = Arc::new(server);
+                let service = service::queries_server::QueriesServer::from_arc(server.clone());
+                let admin_service = service::admin_server::AdminServer::from_arc(server);
                 let log_layer = tower_http::trace::TraceLayer::new_for_grpc();
                 let stats_layer = StatsLayer {
                     stats: node.stats.clone(),
@@ -1107,9 +1318,11 @@ pub mod server {
                 let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
 
                 let router = if config.enable_grpc_web {
-                    builder.add_service(tonic_web::enable(service))
+                    builder
+                        .add_service(tonic_web::enable(service))
+                        .add_service(tonic_web::enable(admin_service))
                 } else {
-                    builder.add_service(service)
+                    builder.add_service(service).add_service(admin_service)
                 };
 
                 let router = {
@@ -1167,7 +1380,7 @@ pub mod server {
                     grpc_connected_clients: node.stats.grpc_connected_clients.clone(),
                     semaphore: PollSemaphore::new(Arc::new(Semaphore::new(config.max_connections))),
                 };
-                let task = tokio::spawn(async move {
+                let task = spawn_in_current_context(async move {
                     let result = router
                         .serve_with_incoming_shutdown(incoming, shutdown_receiver.map(|_| ()))
                         .await;
@@ -1223,6 +1436,61 @@ pub mod server {
         }
     }
 
+    #[async_trait]
+    impl service::admin_server::Admin for RpcServerImpl {
+        async fn update_dynamic_config(
+            &self,
+            request: tonic::Request<crate::grpc2::UpdateDynamicConfigRequest>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Empty>, tonic::Status> {
+            if !self.service_config.update_dynamic_config {
+                return Err(tonic::Status::unimplemented("`UpdateDynamicConfig` is not enabled."));
+            }
+            let update = request
+                .get_ref()
+                .update()
+                .map_err(|e| tonic::Status::invalid_argument(format!("{:#}", e)))?;
+            info!("Updating the configuration from the admin service");
+            match self.node.apply_config_update(update) {
+                Ok(()) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
+                Err(e) => Err(tonic::Status::invalid_argument(format!("{:#}", e))),
+            }
+        }
+
+        #[cfg(feature = "network_dump")]
+        async fn dump_start_with_filters(
+            &self,
+            request: tonic::Request<crate::grpc2::DumpStartWithFiltersRequest>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Empty>, tonic::Status> {
+            if !self.service_config.dump_start_with_filters {
+                return Err(tonic::Status::unimplemented("`DumpStartWithFilters` is not enabled."));
+            }
+            let request = request.get_ref();
+            if request.file.is_empty() {
+                return Err(tonic::Status::invalid_argument("The supplied path must be non-empty"));
+            }
+            let options = request
+                .options()
+                .map_err(|e| tonic::Status::invalid_argument(format!("Invalid filter: {}", e)))?;
+            match self.node.activate_dump_with_options(&request.file, request.raw, options) {
+                Ok(_) => Ok(tonic::Response::new(crate::grpc2::types::Empty {})),
+                Err(e) => {
+                    Err(tonic::Status::internal(format!("Could not start network dump {}", e)))
+                }
+            }
+        }
+
+        #[cfg(not(feature = "network_dump"))]
+        async fn dump_start_with_filters(
+            &self,
+            _request: tonic::Request<crate::grpc2::DumpStartWithFiltersRequest>,
+        ) -> Result<tonic::Response<crate::grpc2::types::Empty>, tonic::Status> {
+            if !self.service_config.dump_start_with_filters {
+                return Err(tonic::Status::unimplemented("`DumpStartWithFilters` is not enabled."));
+            }
+            Err(tonic::Status::failed_precondition("Feature \"network_dump\" is not active."))
+        }
+    }
+
     #[async_trait]
     impl service::queries_server::Queries for RpcServerImpl {
         /// Return type for the 'DryRun' method.
@@ -1439,7 +1707,7 @@ pub mod server {
                     // We need to return the same type in both branches, so we create a silly
                     // little channel to which we will only send one value.
                     let (sender, receiver) = tokio::sync::mpsc::channel(1);
-                    let _sender = tokio::spawn(async move {
+                    let _sender = spawn_in_current_context(async move {
                         let msg = types::InstanceStateKvPair {
                             key:   Vec::new(),
                             value: state,
@@ -1464,7 +1732,7 @@ pub mod server {
                     // scheduling of the sender task. 10 is also small enough so that not too many
                     // values linger in memory while waiting to be sent.
                     let (sender, receiver) = tokio::sync::mpsc::channel(10);
-                    let _sender = tokio::spawn(async move {
+                    let _sender = spawn_in_current_context(async move {
                         let iter = state.into_iterator(&mut loader);
                         for (key, value) in iter {
                             let msg = types::InstanceStateKvPair {
@@ -2155,7 +2423,7 @@ pub mod server {
         async fn get_peers_info(
             &self,
             _request: tonic::Request<crate::grpc2::types::Empty>,
-        ) -> Result<tonic::Response<crate::grpc2::types::PeersInfo>, tonic::Status> {
+        ) -> Result<tonic::Response<crate::grpc2::PeersInfoWithTraffic>, tonic::Status> {
             if !self.service_config.get_peers_info {
                 return Err(tonic::Status::unimplemented("`GetPeersInfo` is not enabled."));
             }
@@ -2210,15 +2478,26 @@ pub mod server {
                     let peer_id = crate::grpc2::types::PeerId {
                         value: format!("{}", peer_stats.self_id),
                     };
-                    crate::grpc2::types::peers_info::Peer {
+                    let traffic = peer_stats
+                        .traffic
+                        .iter()
+                        .map(|(direction, category, count)| crate::grpc2::PeerTraffic {
+                            category:  category.label().to_owned(),
+                            direction: direction.label().to_owned(),
+                            messages:  count.messages,
+                            bytes:     count.bytes,
+                        })
+                        .collect();
+                    crate::grpc2::PeerWithTraffic {
                         peer_id: Some(peer_id),
                         socket_address: Some(socket_address),
                         consensus_info: Some(consensus_info),
                         network_stats,
+                        traffic,
                     }
                 })
                 .collect();
-            Ok(tonic::Response::new(crate::grpc2::types::PeersInfo {
+            Ok(tonic::Response::new(crate::grpc2::PeersInfoWithTraffic {
                 peers,
             }))
         }
@@ -2226,7 +2505,7 @@ pub mod server {
         async fn get_node_info(
             &self,
             _request: tonic::Request<crate::grpc2::types::Empty>,
-        ) -> Result<tonic::Response<types::NodeInfo>, tonic::Status> {
+        ) -> Result<tonic::Response<crate::grpc2::NodeInfoWithConnections>, tonic::Status> {
             if !self.service_config.get_node_info {
                 return Err(tonic::Status::unimplemented("`GetNodeInfo` is not enabled."));
             }
@@ -2342,12 +2621,25 @@ pub mod server {
                 }
             };
 
-            Ok(tonic::Response::new(types::NodeInfo {
+            let connections = {
+                let counts = self.node.get_connection_counts();
+                let dynamic_config = self.node.config.dynamic();
+                crate::grpc2::NodeConnections {
+                    outbound:           counts.outbound as u64,
+                    inbound:            counts.inbound as u64,
+                    max_outbound_nodes: dynamic_config.max_outbound_nodes.into(),
+                    max_inbound_nodes:  dynamic_config.max_inbound_nodes.into(),
+                    max_allowed_nodes:  dynamic_config.max_allowed_nodes.into(),
+                }
+            };
+
+            Ok(tonic::Response::new(crate::grpc2::NodeInfoWithConnections {
                 peer_version,
                 local_time,
                 peer_uptime,
                 network_info: Some(network_info),
                 details: Some(details),
+                connections: Some(connections),
             }))
         }
 
@@ -2714,6 +3006,14 @@ fn get_grpc_code_label(code: tonic::Code) -> &'static str {
     }
 }
 
+/// Record the gRPC status code of a request in its span.
+fn set_span_status(span: &mut Span, code: tonic::Code) {
+    span.set_attribute("rpc.grpc.status_code", code as i32);
+    if code != tonic::Code::Ok {
+        span.set_error(get_grpc_code_label(code));
+    }
+}
+
 /// Actual middleware implementation updating the stats.
 /// The middleware is called once for each gRPC request, even for the streaming
 /// gRPC methods.
@@ -2746,15 +3046,27 @@ where
         let clone = self.inner.clone();
         let mut inner = std::mem::replace(&mut self.inner, clone);
 
-        let grpc_request_duration = self.stats.grpc_request_response_time.clone();
+        let stats = self.stats.clone();
 
         Box::pin(async move {
             let endpoint_name = req.uri().path().to_owned();
             let request_received = tokio::time::Instant::now();
 
+            // Continue the trace of the client, if any.
+            let parent = req
+                .headers()
+                .get("traceparent")
+                .and_then(|value| value.to_str().ok())
+                .and_then(TraceContext::from_traceparent);
+            let mut span = Span::start(endpoint_name.as_str(), SpanKind::Server, parent);
+            span.set_attribute("rpc.system", "grpc");
+            // The trace of the request, used as exemplar of the response time.
+            let trace_id = span.context().map(|context| context.trace_id);
+
             let (mut response, duration) = {
-                // Forward the request.
-                let result = inner.call(req).await;
+                // Forward the request, with the span as the parent of the spans started
+                // while handling it.
+                let result = Instrumented::new(inner.call(req), span.context()).await;
                 // Time taken for the inner service to send back a response, meaning for
                 // streaming gRPC methods this is the duration for it to first return a stream.
                 let duration = request_received.elapsed().as_secs_f64();
@@ -2775,9 +3087,13 @@ where
                         } else {
                             (tonic::Code::Internal, Err(e))
                         };
-                        grpc_request_duration
-                            .with_label_values(&[endpoint_name.as_str(), get_grpc_code_label(code)])
-                            .observe(duration);
+                        set_span_status(&mut span, code);
+                        stats.observe_grpc_request(
+                            endpoint_name.as_str(),
+                            get_grpc_code_label(code),
+                            duration,
+                            trace_id,
+                        );
                         return response;
                     }
                     Ok(result) => (result, duration),
@@ -2790,30 +3106,38 @@ where
             // header or the trailer.
             let grpc_status_header = response.headers().get("grpc-status");
             if let Some(header_value) = grpc_status_header {
-                let status_code_label =
-                    get_grpc_code_label(tonic::Code::from_bytes(header_value.as_bytes()));
-
-                grpc_request_duration
-                    .with_label_values(&[endpoint_name.as_str(), status_code_label])
-                    .observe(duration);
+                let code = tonic::Code::from_bytes(header_value.as_bytes());
+                set_span_status(&mut span, code);
+                let status_code_label = get_grpc_code_label(code);
+
+                stats.observe_grpc_request(
+                    endpoint_name.as_str(),
+                    status_code_label,
+                    duration,
+                    trace_id,
+                );
             } else if let Ok(Some(trailers)) =
                 hyper::body::HttpBody::trailers(response.body_mut()).await
             {
                 if let Some(header_value) = trailers.get("grpc-status") {
-                    let status_code_label =
-                        get_grpc_code_label(tonic::Code::from_bytes(header_value.as_bytes()));
+                    let code = tonic::Code::from_bytes(header_value.as_bytes());
+                    set_span_status(&mut span, code);
+                    let status_code_label = get_grpc_code_label(code);
 
-                    grpc_request_duration
-                        .with_label_values(&[endpoint_name.as_str(), status_code_label])
-                        .observe(duration);
+                    stats.observe_grpc_request(
+                        endpoint_name.as_str(),
+                        status_code_label,
+                        duration,
+                        trace_id,
+                    );
                 }
             } else {
-                grpc_request_duration
-                    .with_label_values(&[
-                        endpoint_name.as_str(),
-                        get_grpc_code_label(tonic::Code::Unknown),
-                    ])
-                    .observe(duration);
+                stats.observe_grpc_request(
+                    endpoint_name.as_str(),
+                    get_grpc_code_label(tonic::Code::Unknown),
+                    duration,
+                    trace_id,
+                );
             }
 
             Ok(response)
@@ -16,6 +16,7 @@ pub const APPNAME: &str = env!("CARGO_PKG_NAME");
 
 pub mod common;
 pub mod configuration;
+pub mod logging;
 
 pub mod connection;
 
@@ -25,7 +26,9 @@ pub mod plugins;
 
 #[cfg(feature = "network_dump")]
 pub mod dumper;
+pub mod openmetrics;
 pub mod stats_export_service;
+pub mod telemetry;
 pub mod utils;
 
 pub mod consensus_ffi;
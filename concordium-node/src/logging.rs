@@ -0,0 +1,391 @@
+//! The node's logger. It extends `env_logger` with log levels per subsystem or
+//! module, sampling of trace messages and an optional JSON output format.
+//!
+//! In the JSON format every line is an object with the fields `timestamp`,
+//! `level`, `module` and `message`, and, if known, `peer`, `block_hash` and
+//! `genesis_index`. The latter are taken from a per-thread context that is set
+//! with [`with_peer`], [`with_block_hash`] and [`with_genesis_index`] while
+//! handling a message.
+
+use anyhow::{anyhow, bail};
+use env_logger::{Builder, Env};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    fmt::Display,
+    hash::{Hash, Hasher},
+    io::Write,
+    str::FromStr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
+
+/// The modules that make up each of the subsystems that can be given a log
+/// level by name.
+fn subsystem_modules(subsystem: &str) -> Option<&'static [&'static str]> {
+    match subsystem {
+        "network" => Some(&[
+            "concordium_node::common",
+            "concordium_node::connection",
+            "concordium_node::dumper",
+            "concordium_node::network",
+            "concordium_node::p2p",
+        ]),
+        "consensus" => {
+            Some(&["concordium_node::consensus_ffi", "concordium_node::plugins::consensus"])
+        }
+        "grpc" => Some(&["concordium_node::grpc2", "tonic", "tower_http"]),
+        _ => None,
+    }
+}
+
+/// The number of counters used for sampling trace messages. Call sites that
+/// hash to the same counter share it.
+const SAMPLING_COUNTERS: usize = 1024;
+
+const LEVELS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+/// The level of the modules without a specific level, which can be changed at
+/// runtime.
+static BASE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+/// The highest of the specific levels.
+static MAX_MODULE_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+/// Whether the base level is applied by the node's logger. It is not if the
+/// level is given by the `LOG_LEVEL` environment variable or if another logger
+/// is used.
+static LEVEL_IS_DYNAMIC: AtomicBool = AtomicBool::new(false);
+/// Whether the context of log messages is recorded.
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+/// The format of the log output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Free-form lines of text.
+    Text,
+    /// A JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("Unknown log format {}, expected 'text' or 'json'", s),
+        }
+    }
+}
+
+/// The log level of a subsystem (`network`, `consensus` or `grpc`) or of a
+/// module and its submodules, given as `<subsystem or module>=<level>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLevelSetting {
+    pub target: String,
+    pub level:  LevelFilter,
+}
+
+impl LogLevelSetting {
+    /// The module prefixes the setting applies to.
+    fn modules(&self) -> Vec<String> {
+        match subsystem_modules(&self.target) {
+            Some(modules) => modules.iter().map(|m| m.to_string()).collect(),
+            None => vec![self.target.clone()],
+        }
+    }
+}
+
+impl FromStr for LogLevelSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((target, level)) if !target.is_empty() => Ok(LogLevelSetting {
+                target: target.to_owned(),
+                level:  level.parse().map_err(|_| anyhow!("Invalid log level in '{}'", s))?,
+            }),
+            _ => bail!("Invalid log level setting '{}', expected <subsystem or module>=<level>", s),
+        }
+    }
+}
+
+/// The options of the node's logger.
+#[derive(Debug, Clone)]
+pub struct LoggerOptions {
+    /// The level of the modules without a specific level.
+    pub level:             LevelFilter,
+    pub format:            LogFormat,
+    pub no_timestamp:      bool,
+    pub module_levels:     Vec<LogLevelSetting>,
+    /// Only one in this many trace messages from each call site is logged.
+    pub trace_sample_rate: u64,
+}
+
+/// Set up the node's logger, which logs to stderr.
+/// Unless the filter is given by the `LOG_LEVEL` environment variable, the
+/// underlying `env_logger` lets everything through and the levels are applied
+/// by this logger, so that the base level can be changed at runtime. The
+/// module levels are ignored if `LOG_LEVEL` is set.
+pub fn setup(options: &LoggerOptions) {
+    let level_from_env = std::env::var_os("LOG_LEVEL").is_some();
+    let env = Env::default().filter_or("LOG_LEVEL", "trace");
+
+    let mut log_builder = Builder::from_env(env);
+    match options.format {
+        LogFormat::Json => {
+            STRUCTURED.store(true, Ordering::Relaxed);
+            log_builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+        }
+        LogFormat::Text if options.no_timestamp => {
+            log_builder.format_timestamp(None);
+        }
+        LogFormat::Text => {
+            log_builder.format(|buf, record| {
+                writeln!(buf, "{}: {}: {}", buf.timestamp_nanos(), record.level(), record.args())
+            });
+        }
+    }
+    log_builder.filter(Some("tokio_reactor"), LevelFilter::Error);
+    log_builder.filter(Some("hyper"), LevelFilter::Error);
+    log_builder.filter(Some("reqwest"), LevelFilter::Error);
+    log_builder.filter(Some("gotham"), LevelFilter::Error);
+    log_builder.filter(Some("h2"), LevelFilter::Error);
+    let inner = log_builder.build();
+
+    let mut module_levels = Vec::new();
+    if !level_from_env {
+        for setting in &options.module_levels {
+            for module in setting.modules() {
+                module_levels.push((module, setting.level));
+            }
+        }
+    }
+    // the most specific module takes precedence
+    module_levels.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+    let max_module_level =
+        module_levels.iter().map(|(_, level)| *level).max().unwrap_or(LevelFilter::Off);
+    MAX_MODULE_LEVEL.store(max_module_level as usize, Ordering::Relaxed);
+
+    let base_level = if level_from_env {
+        inner.filter()
+    } else {
+        options.level
+    };
+    let logger = NodeLogger {
+        inner,
+        module_levels,
+        trace_sample_rate: options.trace_sample_rate.max(1),
+        trace_counters: (0..SAMPLING_COUNTERS).map(|_| AtomicU64::new(0)).collect(),
+    };
+    log::set_boxed_logger(Box::new(logger)).expect("The logger is set up only once.");
+    set_level(base_level);
+    LEVEL_IS_DYNAMIC.store(!level_from_env, Ordering::Relaxed);
+}
+
+/// The current level of the modules without a specific level.
+pub fn level() -> LevelFilter { LEVELS[BASE_LEVEL.load(Ordering::Relaxed)] }
+
+/// Whether changing the level with [set_level] changes what is logged.
+pub fn level_is_dynamic() -> bool { LEVEL_IS_DYNAMIC.load(Ordering::Relaxed) }
+
+/// Change the level of the modules without a specific level.
+pub fn set_level(level: LevelFilter) {
+    BASE_LEVEL.store(level as usize, Ordering::Relaxed);
+    let max_module_level = LEVELS[MAX_MODULE_LEVEL.load(Ordering::Relaxed)];
+    log::set_max_level(level.max(max_module_level));
+}
+
+struct NodeLogger {
+    inner:             env_logger::Logger,
+    /// Module prefixes and their levels, the longest prefix first.
+    module_levels:     Vec<(String, LevelFilter)>,
+    trace_sample_rate: u64,
+    trace_counters:    Vec<AtomicU64>,
+}
+
+impl NodeLogger {
+    fn level_of(&self, target: &str) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .find(|(module, _)| is_in_module(target, module))
+            .map_or_else(level, |(_, level)| *level)
+    }
+
+    /// Whether a trace message should be logged, letting through one in
+    /// `trace_sample_rate` messages from each call site.
+    fn sample(&self, record: &Record) -> bool {
+        if self.trace_sample_rate == 1 {
+            return true;
+        }
+        let mut hasher = DefaultHasher::new();
+        record.target().hash(&mut hasher);
+        record.line().hash(&mut hasher);
+        let counter = &self.trace_counters[hasher.finish() as usize % SAMPLING_COUNTERS];
+        counter.fetch_add(1, Ordering::Relaxed) % self.trace_sample_rate == 0
+    }
+}
+
+impl Log for NodeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_of(metadata.target()) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata())
+            && (record.level() != Level::Trace || self.sample(record))
+        {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) { self.inner.flush() }
+}
+
+/// Check whether `target` is `module` or one of its submodules.
+fn is_in_module(target: &str, module: &str) -> bool {
+    target.strip_prefix(module).map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// The context of the log messages emitted by the current thread.
+#[derive(Debug, Clone, Default)]
+struct LogContext {
+    peer:          Option<String>,
+    block_hash:    Option<String>,
+    genesis_index: Option<u32>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+}
+
+/// Restores the previous log context of the thread when dropped.
+#[must_use]
+pub struct ContextGuard {
+    previous: Option<LogContext>,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous.take() {
+            CONTEXT.with(|context| *context.borrow_mut() = previous);
+        }
+    }
+}
+
+fn update_context(update: impl FnOnce(&mut LogContext)) -> ContextGuard {
+    if !STRUCTURED.load(Ordering::Relaxed) {
+        return ContextGuard {
+            previous: None,
+        };
+    }
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        let previous = context.clone();
+        update(&mut context);
+        ContextGuard {
+            previous: Some(previous),
+        }
+    })
+}
+
+/// Attach the given peer to the messages logged by the current thread until
+/// the returned guard is dropped.
+pub fn with_peer(peer: impl Display) -> ContextGuard {
+    update_context(|context| context.peer = Some(peer.to_string()))
+}
+
+/// Attach the given block hash to the messages logged by the current thread
+/// until the returned guard is dropped.
+pub fn with_block_hash(hash: impl Display) -> ContextGuard {
+    update_context(|context| context.block_hash = Some(hash.to_string()))
+}
+
+/// Attach the given genesis index to the messages logged by the current
+/// thread until the returned guard is dropped.
+pub fn with_genesis_index(genesis_index: u32) -> ContextGuard {
+    update_context(|context| context.genesis_index = Some(genesis_index))
+}
+
+/// Whether messages are logged with their context, i.e., whether setting the
+/// context is worthwhile.
+pub fn is_structured() -> bool { STRUCTURED.load(Ordering::Relaxed) }
+
+/// Find the hash of a block in a free-form log message from consensus, i.e.,
+/// 64 hexadecimal digits following the word "block".
+pub fn find_block_hash(message: &str) -> Option<&str> {
+    let mut words = message.split_whitespace();
+    let mut previous = words.next()?;
+    for word in words {
+        let candidate = word.trim_end_matches(|c: char| !c.is_ascii_hexdigit());
+        if previous.eq_ignore_ascii_case("block")
+            && candidate.len() == 64
+            && candidate.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Some(candidate);
+        }
+        previous = word;
+    }
+    None
+}
+
+fn json_line(record: &Record) -> serde_json::Value {
+    let mut line = serde_json::Map::new();
+    line.insert(
+        "timestamp".into(),
+        chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Nanos, true).into(),
+    );
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("module".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    CONTEXT.with(|context| {
+        let context = context.borrow();
+        if let Some(ref peer) = context.peer {
+            line.insert("peer".into(), peer.as_str().into());
+        }
+        if let Some(ref hash) = context.block_hash {
+            line.insert("block_hash".into(), hash.as_str().into());
+        }
+        if let Some(genesis_index) = context.genesis_index {
+            line.insert("genesis_index".into(), genesis_index.into());
+        }
+    });
+    line.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_level_settings() {
+        let setting: LogLevelSetting = "network=debug".parse().unwrap();
+        assert_eq!(setting.level, LevelFilter::Debug);
+        assert!(setting.modules().contains(&"concordium_node::p2p".to_string()));
+        let setting: LogLevelSetting = "concordium_node::rpc=warn".parse().unwrap();
+        assert_eq!(setting.modules(), vec!["concordium_node::rpc".to_string()]);
+        assert!("network".parse::<LogLevelSetting>().is_err());
+        assert!("network=loud".parse::<LogLevelSetting>().is_err());
+
+        assert!(is_in_module("concordium_node::p2p::maintenance", "concordium_node::p2p"));
+        assert!(is_in_module("concordium_node::p2p", "concordium_node::p2p"));
+        assert!(!is_in_module("concordium_node::p2pool", "concordium_node::p2p"));
+    }
+
+    #[test]
+    fn test_find_block_hash() {
+        let hash = "a".repeat(64);
+        assert_eq!(find_block_hash(&format!("Block {} arrived", hash)), Some(hash.as_str()));
+        assert_eq!(find_block_hash(&format!("Finalized block {}.", hash)), Some(hash.as_str()));
+        assert_eq!(find_block_hash(&format!("Transaction {} received", hash)), None);
+        assert_eq!(find_block_hash("block abc"), None);
+        assert_eq!(find_block_hash(""), None);
+    }
+}
@@ -0,0 +1,492 @@
+//! Management of the addresses of potential peers.
+//!
+//! Addresses learned from other peers are kept in two tables, in the spirit of
+//! Bitcoin's address manager. The "new" table holds addresses we have heard
+//! about but never successfully connected to, and the "tried" table holds
+//! addresses we have successfully connected to at least once.
+//!
+//! Both tables consist of fixed-size buckets. The bucket of an address in the
+//! new table is determined by the address group of the peer that told us about
+//! it, together with the group of the address itself, so that a single source
+//! can only ever fill a small fraction of the table. The bucket of an address
+//! in the tried table is determined by the group of the address only. Bucket
+//! placement is keyed by a random secret so that an attacker cannot predict
+//! which addresses collide.
+
+use rand::{seq::SliceRandom, Rng};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+};
+use twox_hash::XxHash64;
+
+use crate::common::{get_current_stamp, P2PPeer};
+
+/// Number of buckets in the new table.
+const NEW_BUCKET_COUNT: usize = 256;
+/// Number of buckets in the tried table.
+const TRIED_BUCKET_COUNT: usize = 64;
+/// Maximum number of addresses in a single bucket.
+const BUCKET_SIZE: usize = 64;
+/// Number of buckets of the new table that the addresses learned from a single
+/// source group are spread over.
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 32;
+/// Number of buckets of the tried table that the addresses of a single group
+/// are spread over.
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+/// Number of connection attempts after which an address that was never
+/// successfully connected to is forgotten.
+const MAX_FAILED_ATTEMPTS: u32 = 3;
+
+/// The group an IP address belongs to. Addresses in the same group are assumed
+/// to be controlled by the same party, so the address manager avoids relying
+/// on many peers from a single group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressGroup {
+    /// The /16 prefix of an IPv4 address.
+    V4([u8; 2]),
+    /// The /32 prefix of an IPv6 address.
+    V6([u8; 4]),
+    /// Addresses that are not globally routable, e.g., loopback or private
+    /// addresses. Peers on such addresses only exist in local networks, so
+    /// the number of such addresses that are kept and selected is limited
+    /// separately, see [`AddressManager::new`].
+    Local,
+}
+
+impl From<IpAddr> for AddressGroup {
+    fn from(ip: IpAddr) -> Self {
+        let ip = match ip {
+            IpAddr::V6(ip6) => ip6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip4 => ip4,
+        };
+        match ip {
+            IpAddr::V4(ip4) => {
+                if ip4.is_loopback()
+                    || ip4.is_private()
+                    || ip4.is_link_local()
+                    || ip4.is_unspecified()
+                {
+                    AddressGroup::Local
+                } else {
+                    let octets = ip4.octets();
+                    AddressGroup::V4([octets[0], octets[1]])
+                }
+            }
+            IpAddr::V6(ip6) => {
+                let segments = ip6.segments();
+                let is_unique_local = segments[0] & 0xfe00 == 0xfc00;
+                let is_link_local = segments[0] & 0xffc0 == 0xfe80;
+                if ip6.is_loopback() || ip6.is_unspecified() || is_unique_local || is_link_local {
+                    AddressGroup::Local
+                } else {
+                    let octets = ip6.octets();
+                    AddressGroup::V6([octets[0], octets[1], octets[2], octets[3]])
+                }
+            }
+        }
+    }
+}
+
+/// What the address manager knows about a single address.
+#[derive(Debug, Clone)]
+struct AddressInfo {
+    peer:         P2PPeer,
+    /// The group of the peer that told us about the address.
+    source_group: AddressGroup,
+    /// Whether the address is in the tried table, as opposed to the new table.
+    in_tried:     bool,
+    /// Timestamp (in ms) of when the address was last added or refreshed.
+    last_seen:    u64,
+    /// Number of connection attempts since the last successful connection.
+    attempts:     u32,
+}
+
+/// The address manager, see the module documentation.
+pub struct AddressManager {
+    /// The secret used to place addresses in buckets.
+    key: u64,
+    /// Maximum number of addresses in the new table learned from a single
+    /// source group.
+    max_addresses_per_source: usize,
+    /// Maximum number of addresses in the [`AddressGroup::Local`] group in the
+    /// new table.
+    max_local_addresses: usize,
+    /// Maximum number of outbound connections to addresses in the
+    /// [`AddressGroup::Local`] group, see [`AddressManager::select`].
+    max_local_outbound: usize,
+    addresses: HashMap<SocketAddr, AddressInfo>,
+    new_buckets: Vec<Vec<SocketAddr>>,
+    tried_buckets: Vec<Vec<SocketAddr>>,
+    /// The number of addresses in the new table per source group.
+    source_counts: HashMap<AddressGroup, usize>,
+    /// The number of addresses in the [`AddressGroup::Local`] group in the new
+    /// table.
+    new_local_count: usize,
+}
+
+impl AddressManager {
+    /// Create an empty address manager with a fresh random secret. Addresses
+    /// in the [`AddressGroup::Local`] group count like any other group, except
+    /// that at most `max_local_addresses` of them are kept in the new table and
+    /// up to `max_local_outbound` of them are selected for outbound
+    /// connections, rather than one per group, so that local test networks
+    /// can be configured to connect to each other.
+    pub fn new(
+        max_addresses_per_source: usize,
+        max_local_addresses: usize,
+        max_local_outbound: usize,
+    ) -> Self {
+        Self {
+            key: rand::thread_rng().gen(),
+            max_addresses_per_source,
+            max_local_addresses,
+            max_local_outbound,
+            addresses: HashMap::new(),
+            new_buckets: vec![Vec::new(); NEW_BUCKET_COUNT],
+            tried_buckets: vec![Vec::new(); TRIED_BUCKET_COUNT],
+            source_counts: HashMap::new(),
+            new_local_count: 0,
+        }
+    }
+
+    /// The number of addresses in the new table.
+    pub fn new_len(&self) -> usize { self.new_buckets.iter().map(Vec::len).sum() }
+
+    /// The number of addresses in the tried table.
+    pub fn tried_len(&self) -> usize { self.tried_buckets.iter().map(Vec::len).sum() }
+
+    /// Check whether the address is known.
+    pub fn contains(&self, addr: SocketAddr) -> bool { self.addresses.contains_key(&addr) }
+
+    fn hash(&self, value: impl Hash) -> u64 {
+        let mut hasher = XxHash64::with_seed(self.key);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn new_bucket(&self, source_group: AddressGroup, addr: SocketAddr) -> usize {
+        // local addresses all share a group, so they are spread by the address
+        // itself to not end up in a single bucket
+        let slot = match AddressGroup::from(addr.ip()) {
+            AddressGroup::Local => self.hash((source_group, addr.ip())),
+            group => self.hash((source_group, group)),
+        } % NEW_BUCKETS_PER_SOURCE_GROUP;
+        (self.hash((source_group, slot)) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    fn tried_bucket(&self, addr: SocketAddr) -> usize {
+        let group = AddressGroup::from(addr.ip());
+        let slot = self.hash(addr) % TRIED_BUCKETS_PER_GROUP;
+        (self.hash((group, slot)) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    /// Add the addresses of peers that were advertised to us by the peer at
+    /// `source`. Addresses that are already known are only refreshed.
+    /// Returns the number of addresses that were added.
+    pub fn add(&mut self, peers: &[P2PPeer], source: IpAddr) -> usize {
+        let source_group = AddressGroup::from(source);
+        let now = get_current_stamp();
+        let mut added = 0;
+        for peer in peers {
+            if let Some(info) = self.addresses.get_mut(&peer.addr) {
+                info.last_seen = now;
+                continue;
+            }
+            if self.source_counts.get(&source_group).copied().unwrap_or(0)
+                >= self.max_addresses_per_source
+            {
+                trace!(
+                    "Ignoring address {} from {}: too many from the same source",
+                    peer.addr,
+                    source
+                );
+                continue;
+            }
+            let is_local = AddressGroup::from(peer.addr.ip()) == AddressGroup::Local;
+            if is_local && self.new_local_count >= self.max_local_addresses {
+                trace!("Ignoring address {} from {}: too many local addresses", peer.addr, source);
+                continue;
+            }
+            let bucket = self.new_bucket(source_group, peer.addr);
+            if self.new_buckets[bucket].len() >= BUCKET_SIZE {
+                self.evict_from_new(bucket);
+            }
+            self.new_buckets[bucket].push(peer.addr);
+            *self.source_counts.entry(source_group).or_insert(0) += 1;
+            if is_local {
+                self.new_local_count += 1;
+            }
+            self.addresses.insert(peer.addr, AddressInfo {
+                peer: *peer,
+                source_group,
+                in_tried: false,
+                last_seen: now,
+                attempts: 0,
+            });
+            added += 1;
+        }
+        added
+    }
+
+    /// Remove the address from the new bucket with the given index that was
+    /// seen the longest time ago.
+    fn evict_from_new(&mut self, bucket: usize) {
+        let oldest = self.new_buckets[bucket]
+            .iter()
+            .copied()
+            .min_by_key(|addr| self.addresses.get(addr).map_or(0, |info| info.last_seen));
+        if let Some(addr) = oldest {
+            self.remove(addr);
+        }
+    }
+
+    /// Record that a connection to the address was attempted. Addresses that
+    /// are not in the tried table are forgotten after a few failed attempts.
+    pub fn mark_attempt(&mut self, addr: SocketAddr) {
+        let forget = match self.addresses.get_mut(&addr) {
+            Some(info) => {
+                info.attempts += 1;
+                !info.in_tried && info.attempts > MAX_FAILED_ATTEMPTS
+            }
+            None => false,
+        };
+        if forget {
+            self.remove(addr);
+        }
+    }
+
+    /// Record that we successfully connected to the given peer, moving it to
+    /// the tried table. If the peer is not known yet it is added directly to
+    /// the tried table. If the target tried bucket is full, its oldest entry is
+    /// moved back to the new table.
+    pub fn mark_good(&mut self, peer: P2PPeer) {
+        let now = get_current_stamp();
+        let info = match self.addresses.get_mut(&peer.addr) {
+            Some(info) if info.in_tried => {
+                info.peer = peer;
+                info.last_seen = now;
+                info.attempts = 0;
+                return;
+            }
+            Some(_) => {
+                let mut info = self.remove(peer.addr).expect("The address is known.");
+                info.peer = peer;
+                info
+            }
+            None => AddressInfo {
+                peer,
+                source_group: AddressGroup::from(peer.addr.ip()),
+                in_tried: false,
+                last_seen: now,
+                attempts: 0,
+            },
+        };
+
+        let bucket = self.tried_bucket(peer.addr);
+        if self.tried_buckets[bucket].len() >= BUCKET_SIZE {
+            let oldest = self.tried_buckets[bucket]
+                .iter()
+                .copied()
+                .min_by_key(|addr| self.addresses.get(addr).map_or(0, |info| info.last_seen));
+            if let Some(evicted) = oldest.and_then(|addr| self.remove(addr)) {
+                // The evicted peer was reachable, so keep it around as a new address,
+                // attributed to itself as the source.
+                self.add(&[evicted.peer], evicted.peer.addr.ip());
+            }
+        }
+        self.tried_buckets[bucket].push(peer.addr);
+        self.addresses.insert(peer.addr, AddressInfo {
+            in_tried: true,
+            last_seen: now,
+            attempts: 0,
+            ..info
+        });
+    }
+
+    /// Forget the address, returning what was known about it.
+    fn remove(&mut self, addr: SocketAddr) -> Option<AddressInfo> {
+        let info = self.addresses.remove(&addr)?;
+        if info.in_tried {
+            let bucket = self.tried_bucket(addr);
+            self.tried_buckets[bucket].retain(|a| *a != addr);
+        } else {
+            let bucket = self.new_bucket(info.source_group, addr);
+            self.new_buckets[bucket].retain(|a| *a != addr);
+            if let Some(count) = self.source_counts.get_mut(&info.source_group) {
+                *count -= 1;
+                if *count == 0 {
+                    self.source_counts.remove(&info.source_group);
+                }
+            }
+            if AddressGroup::from(addr.ip()) == AddressGroup::Local {
+                self.new_local_count -= 1;
+            }
+        }
+        Some(info)
+    }
+
+    /// Forget the address, e.g., because the peer misbehaved.
+    pub fn forget(&mut self, addr: SocketAddr) { self.remove(addr); }
+
+    /// Select up to `count` addresses to connect to. Addresses are drawn
+    /// randomly, alternating between the tried and new tables, such that no
+    /// two selected addresses share a group and no selected address shares a
+    /// group with `used_groups` (the groups of our existing outbound
+    /// connections). Addresses in the [`AddressGroup::Local`] group are
+    /// instead selected as long as there are fewer than the configured maximum
+    /// of them, counting the `local_outbound` existing outbound connections to
+    /// such addresses.
+    pub fn select(
+        &self,
+        count: usize,
+        used_groups: &HashSet<AddressGroup>,
+        local_outbound: usize,
+        exclude: &HashSet<SocketAddr>,
+    ) -> Vec<P2PPeer> {
+        let mut rng = rand::thread_rng();
+        let mut tried = Vec::new();
+        let mut new = Vec::new();
+        for info in self.addresses.values().filter(|info| !exclude.contains(&info.peer.addr)) {
+            if info.in_tried {
+                tried.push(info.peer);
+            } else {
+                new.push(info.peer);
+            }
+        }
+        tried.shuffle(&mut rng);
+        new.shuffle(&mut rng);
+
+        let mut used_groups = used_groups.clone();
+        let mut local_outbound = local_outbound;
+        let mut selected = Vec::new();
+        while selected.len() < count && !(tried.is_empty() && new.is_empty()) {
+            let from_tried = new.is_empty() || (!tried.is_empty() && rng.gen_bool(0.5));
+            let candidate = if from_tried {
+                tried.pop()
+            } else {
+                new.pop()
+            };
+            if let Some(peer) = candidate {
+                let group = AddressGroup::from(peer.addr.ip());
+                if group == AddressGroup::Local {
+                    if local_outbound < self.max_local_outbound {
+                        local_outbound += 1;
+                        selected.push(peer);
+                    }
+                } else if used_groups.insert(group) {
+                    selected.push(peer);
+                }
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{P2PNodeId, PeerType};
+    use std::net::Ipv4Addr;
+
+    fn peer(a: u8, b: u8, c: u8, d: u8) -> P2PPeer {
+        P2PPeer {
+            id:        rand::thread_rng().gen::<P2PNodeId>(),
+            addr:      SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), 8888),
+            peer_type: PeerType::Node,
+        }
+    }
+
+    #[test]
+    fn test_address_groups() {
+        assert_eq!(
+            AddressGroup::from("8.8.4.4".parse::<IpAddr>().unwrap()),
+            AddressGroup::from("8.8.8.8".parse::<IpAddr>().unwrap())
+        );
+        assert_ne!(
+            AddressGroup::from("8.8.4.4".parse::<IpAddr>().unwrap()),
+            AddressGroup::from("8.9.4.4".parse::<IpAddr>().unwrap())
+        );
+        assert_eq!(
+            AddressGroup::from("::ffff:8.8.4.4".parse::<IpAddr>().unwrap()),
+            AddressGroup::V4([8, 8])
+        );
+        assert_eq!(
+            AddressGroup::from("2001:db8:1::1".parse::<IpAddr>().unwrap()),
+            AddressGroup::V6([0x20, 0x01, 0x0d, 0xb8])
+        );
+        assert_eq!(AddressGroup::from("127.0.0.1".parse::<IpAddr>().unwrap()), AddressGroup::Local);
+        assert_eq!(AddressGroup::from("10.1.2.3".parse::<IpAddr>().unwrap()), AddressGroup::Local);
+        assert_eq!(AddressGroup::from("fd00::1".parse::<IpAddr>().unwrap()), AddressGroup::Local);
+    }
+
+    #[test]
+    fn test_source_limit() {
+        let mut manager = AddressManager::new(10, 15, 1);
+        let source = "1.2.3.4".parse().unwrap();
+        let peers = (0..50).map(|i| peer(20 + i, 1, 1, 1)).collect::<Vec<_>>();
+        assert_eq!(manager.add(&peers, source), 10);
+        // Another host in the same /16 is the same source.
+        assert_eq!(manager.add(&peers[10..], "1.2.200.200".parse().unwrap()), 0);
+        // A different source can still add addresses.
+        assert_eq!(manager.add(&peers[10..], "5.6.7.8".parse().unwrap()), 10);
+        assert_eq!(manager.new_len(), 20);
+        assert_eq!(manager.tried_len(), 0);
+        // Peers in a local network count as a single source.
+        let local_peers = (0..200).map(|i| peer(10, 0, 0, i)).collect::<Vec<_>>();
+        assert_eq!(manager.add(&local_peers, "10.0.0.200".parse().unwrap()), 10);
+        assert_eq!(manager.add(&local_peers, "192.168.1.1".parse().unwrap()), 0);
+        // Local addresses are limited in total, whatever their source.
+        assert_eq!(manager.add(&local_peers[10..], "9.9.9.9".parse().unwrap()), 5);
+        assert_eq!(manager.new_len(), 35);
+        manager.forget(local_peers[0].addr);
+        assert_eq!(manager.add(&local_peers[20..], "9.9.9.9".parse().unwrap()), 1);
+    }
+
+    #[test]
+    fn test_mark_good_and_attempts() {
+        let mut manager = AddressManager::new(100, 100, 1);
+        let good = peer(30, 1, 1, 1);
+        let bad = peer(40, 1, 1, 1);
+        manager.add(&[good, bad], "1.2.3.4".parse().unwrap());
+        manager.mark_good(good);
+        assert_eq!(manager.new_len(), 1);
+        assert_eq!(manager.tried_len(), 1);
+        for _ in 0..=MAX_FAILED_ATTEMPTS {
+            manager.mark_attempt(good.addr);
+            manager.mark_attempt(bad.addr);
+        }
+        // Only addresses that were never connected to are forgotten.
+        assert!(manager.contains(good.addr));
+        assert!(!manager.contains(bad.addr));
+        assert_eq!(manager.new_len(), 0);
+    }
+
+    #[test]
+    fn test_select_diverse_groups() {
+        let mut manager = AddressManager::new(100, 100, 2);
+        // Ten addresses in the same /16 and ten in distinct /16s.
+        let same_group = (0..10).map(|i| peer(50, 50, i, 1)).collect::<Vec<_>>();
+        let distinct = (0..10).map(|i| peer(60 + i, 1, 1, 1)).collect::<Vec<_>>();
+        manager.add(&same_group, "1.2.3.4".parse().unwrap());
+        manager.add(&distinct, "5.6.7.8".parse().unwrap());
+
+        let used = [AddressGroup::V4([60, 1])].into_iter().collect();
+        let selected = manager.select(20, &used, 0, &HashSet::new());
+        let groups =
+            selected.iter().map(|p| AddressGroup::from(p.addr.ip())).collect::<HashSet<_>>();
+        assert_eq!(groups.len(), selected.len(), "Selected addresses must be in distinct groups.");
+        assert_eq!(selected.len(), 10, "One from the shared group and nine distinct ones.");
+        assert!(!groups.contains(&AddressGroup::V4([60, 1])));
+    }
+    #[test]
+    fn test_select_local_limit() {
+        let mut manager = AddressManager::new(100, 100, 2);
+        let local_peers = (0..10).map(|i| peer(10, 0, 0, i)).collect::<Vec<_>>();
+        manager.add(&local_peers, "1.2.3.4".parse().unwrap());
+        assert_eq!(manager.select(10, &HashSet::new(), 0, &HashSet::new()).len(), 2);
+        assert_eq!(manager.select(10, &HashSet::new(), 1, &HashSet::new()).len(), 1);
+        assert!(manager.select(10, &HashSet::new(), 2, &HashSet::new()).is_empty());
+    }
+}
@@ -1,15 +1,21 @@
 //! Network bucket handling.
 
+use concordium_base::hashes::BlockHash;
 use prometheus::IntGaugeVec;
-use rand::seq::IteratorRandom;
+use rand::seq::SliceRandom;
+use semver::Version;
 use std::{
-    collections::HashSet,
+    borrow::Borrow,
+    cmp,
+    collections::{HashMap, HashSet},
     hash::{Hash, Hasher},
+    net::SocketAddr,
 };
 
 use crate::{
     common::{get_current_stamp, p2p_peer::RemotePeerId, PeerType, RemotePeer},
-    network::Networks,
+    configuration::MAX_BOOTSTRAPPER_KEEP_ALIVE,
+    network::{address_manager::AddressGroup, Networks},
 };
 
 const BUCKET_COUNT: usize = 1;
@@ -17,18 +23,68 @@ const BUCKET_COUNT: usize = 1;
 /// A representation of a node in a bucket.
 #[derive(Eq, Clone)]
 struct Node {
-    pub peer:      RemotePeer,
-    pub networks:  Networks,
+    pub peer:           RemotePeer,
+    /// The external address of the peer, which identifies the node in the
+    /// bucket.
+    pub external_addr:  SocketAddr,
+    pub networks:       Networks,
     /// The timestamp pointing to when the node was seen last.
-    pub last_seen: u64,
+    pub last_seen:      u64,
+    /// The version the node advertised in its handshake.
+    pub node_version:   Version,
+    /// The genesis block hashes the node advertised in its handshake.
+    pub genesis_blocks: Vec<BlockHash>,
+    /// Whether the last probe of the node's external address succeeded, if
+    /// the node was probed.
+    pub dialable:       Option<bool>,
+    /// The timestamp of when the node is due to be probed.
+    pub next_probe:     u64,
+}
+
+impl Node {
+    /// Check whether the node can be handed out to peers under the policy.
+    fn is_compatible(&self, policy: &PeerListPolicy) -> bool {
+        if let Some(min_version) = policy.min_peer_version {
+            if self.node_version < *min_version {
+                return false;
+            }
+        }
+        // The node must agree with us on the genesis block hashes they both know,
+        // and know at least the initial genesis block.
+        policy.genesis_blocks.is_empty()
+            || (!self.genesis_blocks.is_empty()
+                && self.genesis_blocks.iter().zip(policy.genesis_blocks).all(|(a, b)| a == b))
+    }
 }
 
 impl PartialEq for Node {
-    fn eq(&self, other: &Node) -> bool { self.peer.external_addr() == other.peer.external_addr() }
+    fn eq(&self, other: &Node) -> bool { self.external_addr == other.external_addr }
 }
 
 impl Hash for Node {
-    fn hash<H: Hasher>(&self, state: &mut H) { self.peer.external_addr().hash(state) }
+    fn hash<H: Hasher>(&self, state: &mut H) { self.external_addr.hash(state) }
+}
+
+impl Borrow<SocketAddr> for Node {
+    fn borrow(&self) -> &SocketAddr { &self.external_addr }
+}
+
+/// The policy a bootstrapper applies when selecting the peers it hands out.
+pub struct PeerListPolicy<'a> {
+    /// Nodes seen within this period (in ms) are preferred over the others.
+    pub recently_seen_period: u64,
+    /// The maximum number of nodes from the same [AddressGroup]. The
+    /// [AddressGroup::Local] group is exempt from this, and 0 means no limit.
+    pub max_peers_per_group:  usize,
+    /// Nodes advertising an older version are not handed out.
+    pub min_peer_version:     Option<&'a Version>,
+    /// Our genesis block hashes. Nodes advertising conflicting ones are not
+    /// handed out.
+    pub genesis_blocks:       &'a [BlockHash],
+    /// Whether nodes that failed their last probe are handed out.
+    pub share_undialable:     bool,
+    /// Whether only nodes that passed their last probe are handed out.
+    pub require_dialable:     bool,
 }
 
 /// A bucket of nodes.
@@ -53,45 +109,119 @@ impl Buckets {
         &mut self,
         peer: RemotePeer,
         networks: Networks,
+        node_version: Version,
+        genesis_blocks: Vec<BlockHash>,
         bucket_size_gauge: &IntGaugeVec,
     ) {
         let bucket = &mut self.buckets[0];
-        if bucket
-            .replace(Node {
+        let now = get_current_stamp();
+        let external_addr = peer.external_addr();
+        // The result of probing the node is kept, and new nodes are first probed
+        // once the bootstrapper closed its connection to them.
+        let (dialable, next_probe) = match bucket.take(&external_addr) {
+            Some(node) => (node.dialable, node.next_probe),
+            None => {
+                // If the peer is new in the bucket reflect that in the
+                // metric.
+                bucket_size_gauge.with_label_values(&["0"]).inc();
+                (None, now + MAX_BOOTSTRAPPER_KEEP_ALIVE)
+            }
+        };
+        bucket.insert(Node {
+            peer,
+            external_addr,
+            networks,
+            last_seen: now,
+            node_version,
+            genesis_blocks,
+            dialable,
+            next_probe,
+        });
+    }
+
+    /// Update the networks of a node in the bucket. Nodes that are no longer
+    /// in the bucket are added again on their next handshake.
+    pub fn update_network_ids(&mut self, peer: RemotePeer, networks: Networks) {
+        let bucket = &mut self.buckets[0];
+        if let Some(node) = bucket.take(&peer.external_addr()) {
+            bucket.insert(Node {
                 peer,
                 networks,
                 last_seen: get_current_stamp(),
-            })
-            .is_none()
-        {
-            // If the peer is new in the bucket reflect that in the
-            // metric.
-            bucket_size_gauge.with_label_values(&["0"]).inc();
+                ..node
+            });
         }
     }
 
-    /// Update the networks of a node in the bucket.
-    pub fn update_network_ids(&mut self, peer: RemotePeer, networks: Networks) {
+    /// Returns the external addresses of up to `number` nodes that are due to
+    /// be probed, and schedules their next probe `interval` ms from now.
+    pub fn take_probe_candidates(&mut self, number: usize, interval: u64) -> Vec<SocketAddr> {
+        let now = get_current_stamp();
         let bucket = &mut self.buckets[0];
-        bucket.replace(Node {
-            peer,
-            networks,
-            last_seen: get_current_stamp(),
-        });
+        let due = bucket
+            .iter()
+            .filter(|node| node.next_probe <= now)
+            .map(|node| node.external_addr)
+            .take(number)
+            .collect::<Vec<_>>();
+        for addr in &due {
+            if let Some(mut node) = bucket.take(addr) {
+                node.next_probe = now + interval;
+                bucket.insert(node);
+            }
+        }
+        due
     }
 
-    /// Returns all the nodes in buckets with the possible exception of the
-    /// sender, if it is supplied.
-    fn get_all_nodes(&self, sender: Option<RemotePeerId>, networks: &Networks) -> Vec<RemotePeer> {
+    /// Record the result of probing the node with the given external address.
+    /// A node that accepted the probe counts as seen.
+    pub fn record_probe(&mut self, addr: SocketAddr, dialable: bool) {
+        let bucket = &mut self.buckets[0];
+        if let Some(mut node) = bucket.take(&addr) {
+            node.dialable = Some(dialable);
+            if dialable {
+                node.last_seen = get_current_stamp();
+            }
+            bucket.insert(node);
+        }
+    }
+
+    /// Update the gauge of the number of nodes by the result of their last
+    /// probe.
+    pub fn export_probe_results(&self, probe_results_gauge: &IntGaugeVec) {
+        let mut counts = [0i64; 3];
+        for node in self.buckets.iter().flat_map(HashSet::iter) {
+            counts[match node.dialable {
+                Some(true) => 0,
+                Some(false) => 1,
+                None => 2,
+            }] += 1;
+        }
+        for (status, count) in ["dialable", "undialable", "unprobed"].iter().zip(counts) {
+            probe_results_gauge.with_label_values(&[status]).set(count);
+        }
+    }
+
+    /// Returns all the nodes in buckets that are compatible with the policy,
+    /// with the possible exception of the sender, if it is supplied.
+    fn get_all_nodes(
+        &self,
+        sender: Option<RemotePeerId>,
+        networks: &Networks,
+        policy: &PeerListPolicy,
+    ) -> Vec<&Node> {
         let mut nodes = Vec::new();
         let filter_criteria = |node: &&Node| {
             node.peer.peer_type == PeerType::Node
                 && Some(node.peer.local_id) != sender
                 && (networks.is_empty() || !node.networks.is_disjoint(networks))
+                && node.is_compatible(policy)
+                && (policy.share_undialable || node.dialable != Some(false))
+                && (!policy.require_dialable || node.dialable == Some(true))
         };
 
         for bucket in &self.buckets {
-            nodes.extend(bucket.iter().filter(filter_criteria).map(|node| node.peer.to_owned()))
+            nodes.extend(bucket.iter().filter(filter_criteria))
         }
 
         nodes
@@ -105,29 +235,66 @@ impl Buckets {
     /// Checks whether the buckets are empty.
     pub fn is_empty(&self) -> bool { self.len() == 0 }
 
-    /// Returns the desired number of nodes from the buckets.
+    /// Returns up to the desired number of random nodes from the buckets,
+    /// selected according to the policy. Nodes seen recently are picked before
+    /// the others, nodes that failed their last probe are picked last (if at
+    /// all), and nodes exceeding the limit of their address group are skipped.
     /// This is only used if the node is running as a bootstrapper.
     pub fn get_random_nodes(
         &self,
-        sender: RemotePeerId,
+        sender: Option<RemotePeerId>,
         number: usize,
         networks: &Networks,
+        policy: &PeerListPolicy,
     ) -> Vec<RemotePeer> {
         let mut rng = rand::thread_rng();
-        self.get_all_nodes(Some(sender), networks).into_iter().choose_multiple(&mut rng, number)
+        let seen_after = get_current_stamp().saturating_sub(policy.recently_seen_period);
+        let mut nodes = self.get_all_nodes(sender, networks, policy);
+        nodes.shuffle(&mut rng);
+        // the sort is stable, so the order within each tier remains random
+        nodes.sort_by_key(|node| {
+            if node.dialable == Some(false) {
+                2
+            } else if node.last_seen >= seen_after {
+                0
+            } else {
+                1
+            }
+        });
+
+        let mut group_counts: HashMap<AddressGroup, usize> = HashMap::new();
+        let mut selected = Vec::with_capacity(cmp::min(number, nodes.len()));
+        for node in nodes {
+            if selected.len() >= number {
+                break;
+            }
+            let group = AddressGroup::from(node.peer.external_addr().ip());
+            if group != AddressGroup::Local && policy.max_peers_per_group > 0 {
+                let count = group_counts.entry(group).or_insert(0);
+                if *count >= policy.max_peers_per_group {
+                    continue;
+                }
+                *count += 1;
+            }
+            selected.push(node.peer);
+        }
+        selected
     }
 
-    /// Removes the bucket nodes older than then specified amount of time.
+    /// Removes the bucket nodes older than then specified amount of time, and
+    /// updates the gauges of the bucket size and the probe results.
     pub fn clean_buckets(
         &mut self,
         timeout_bucket_entry_period: u64,
         bucket_size_gauge: &IntGaugeVec,
+        probe_results_gauge: &IntGaugeVec,
     ) {
         let clean_before = get_current_stamp() - timeout_bucket_entry_period;
         let bucket = &mut self.buckets[0];
         bucket.retain(|entry| entry.last_seen >= clean_before);
         let new_bucket_size = bucket.len();
         bucket_size_gauge.with_label_values(&["0"]).set(new_bucket_size as i64);
+        self.export_probe_results(probe_results_gauge);
     }
 }
 
@@ -135,7 +302,7 @@ impl Buckets {
 mod tests {
     use super::*;
     use crate::common::P2PNodeId;
-    use prometheus::register_int_gauge_vec;
+    use prometheus::{register_int_gauge_vec, Opts};
     use rand::Rng;
     use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
@@ -167,8 +334,154 @@ mod tests {
             .expect("Unable to create dummy gauge.");
 
         // and check that only one peer is inserted
-        buckets.insert_into_bucket(p2p_peer, Default::default(), &dummy_gauge);
-        buckets.insert_into_bucket(p2p_duplicate_peer, Default::default(), &dummy_gauge);
+        let version = Version::new(6, 3, 0);
+        buckets.insert_into_bucket(
+            p2p_peer,
+            Default::default(),
+            version.clone(),
+            Vec::new(),
+            &dummy_gauge,
+        );
+        buckets.insert_into_bucket(
+            p2p_duplicate_peer,
+            Default::default(),
+            version,
+            Vec::new(),
+            &dummy_gauge,
+        );
         assert_eq!(buckets.buckets.len(), 1);
     }
+
+    #[test]
+    pub fn test_peer_list_policy() {
+        let mut buckets = Buckets::default();
+        let gauge = IntGaugeVec::new(Opts::new("bucket_policy_gauge", "help"), &["bucket"])
+            .expect("Unable to create dummy gauge.");
+        let genesis = vec![BlockHash::new([1u8; 32]), BlockHash::new([2u8; 32])];
+
+        let mut add = |ip: [u8; 4], version: Version, genesis_blocks: Vec<BlockHash>| {
+            let peer = RemotePeer {
+                self_id:       Some(rand::thread_rng().gen::<P2PNodeId>()),
+                addr:          SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), 1000),
+                local_id:      rand::thread_rng().gen(),
+                external_port: 8888,
+                peer_type:     PeerType::Node,
+            };
+            buckets.insert_into_bucket(peer, Default::default(), version, genesis_blocks, &gauge);
+            peer.external_addr()
+        };
+        let v6 = Version::new(6, 3, 0);
+        // Three peers in the same /16, two in distinct ones.
+        let same_group = [
+            add([50, 50, 1, 1], v6.clone(), genesis.clone()),
+            add([50, 50, 2, 1], v6.clone(), genesis.clone()),
+            add([50, 50, 3, 1], v6.clone(), genesis[..1].to_vec()),
+        ];
+        let stale = add([60, 60, 1, 1], v6.clone(), genesis.clone());
+        let other = add([70, 70, 1, 1], v6.clone(), genesis.clone());
+        // Excluded for their version or genesis.
+        let old = add([80, 80, 1, 1], Version::new(5, 4, 2), genesis.clone());
+        let forked = add([90, 90, 1, 1], v6.clone(), vec![BlockHash::new([3u8; 32])]);
+        let no_genesis = add([100, 100, 1, 1], v6.clone(), Vec::new());
+        let min_version = Version::new(6, 0, 0);
+        let policy = PeerListPolicy {
+            recently_seen_period: 60_000,
+            max_peers_per_group:  1,
+            min_peer_version:     Some(&min_version),
+            genesis_blocks:       &genesis,
+            share_undialable:     false,
+            require_dialable:     false,
+        };
+
+        let bucket = &mut buckets.buckets[0];
+        let mut node = bucket.iter().find(|n| n.peer.external_addr() == stale).cloned().unwrap();
+        bucket.remove(&node);
+        node.last_seen -= 120_000;
+        bucket.insert(node);
+
+        let sender = Some(rand::thread_rng().gen());
+        let selected = buckets
+            .get_random_nodes(sender, 10, &Default::default(), &policy)
+            .iter()
+            .map(RemotePeer::external_addr)
+            .collect::<HashSet<_>>();
+        assert_eq!(selected.len(), 3, "One peer per group and no incompatible peers.");
+        assert_eq!(selected.iter().filter(|addr| same_group.contains(addr)).count(), 1);
+        assert!(selected.contains(&stale) && selected.contains(&other));
+        for excluded in [old, forked, no_genesis] {
+            assert!(!selected.contains(&excluded));
+        }
+
+        // Recently seen peers are preferred.
+        for _ in 0..10 {
+            let selected = buckets.get_random_nodes(sender, 2, &Default::default(), &policy);
+            assert!(selected.iter().all(|peer| peer.external_addr() != stale));
+        }
+    }
+
+    #[test]
+    pub fn test_probe_results() {
+        let mut buckets = Buckets::default();
+        let gauge = IntGaugeVec::new(Opts::new("bucket_probe_gauge", "help"), &["bucket"])
+            .expect("Unable to create dummy gauge.");
+        let addrs = [[60, 60, 1, 1], [70, 70, 1, 1]].map(|ip| {
+            let peer = RemotePeer {
+                self_id:       Some(rand::thread_rng().gen::<P2PNodeId>()),
+                addr:          SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), 1000),
+                local_id:      rand::thread_rng().gen(),
+                external_port: 8888,
+                peer_type:     PeerType::Node,
+            };
+            let version = Version::new(6, 3, 0);
+            buckets.insert_into_bucket(peer, Default::default(), version, Vec::new(), &gauge);
+            peer.external_addr()
+        });
+
+        // New nodes are only probed once the bootstrapper closed its connection to
+        // them.
+        assert!(buckets.take_probe_candidates(10, 1000).is_empty());
+        let nodes = buckets.buckets[0].drain().collect::<Vec<_>>();
+        for node in nodes {
+            buckets.buckets[0].insert(Node {
+                next_probe: 0,
+                ..node
+            });
+        }
+        let mut candidates = buckets.take_probe_candidates(10, 1000);
+        candidates.sort();
+        assert_eq!(candidates, addrs);
+        assert!(buckets.take_probe_candidates(10, 1000).is_empty(), "The probes are scheduled.");
+
+        buckets.record_probe(addrs[0], false);
+        buckets.record_probe(addrs[1], true);
+        let mut policy = PeerListPolicy {
+            recently_seen_period: 60_000,
+            max_peers_per_group:  0,
+            min_peer_version:     None,
+            genesis_blocks:       &[],
+            share_undialable:     false,
+            require_dialable:     false,
+        };
+        let sender = Some(rand::thread_rng().gen());
+        let selected = |buckets: &Buckets, number, policy: &PeerListPolicy| {
+            buckets
+                .get_random_nodes(sender, number, &Default::default(), policy)
+                .iter()
+                .map(RemotePeer::external_addr)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(selected(&buckets, 10, &policy), vec![addrs[1]]);
+        policy.share_undialable = true;
+        assert_eq!(selected(&buckets, 10, &policy).len(), 2);
+        assert_eq!(selected(&buckets, 1, &policy), vec![addrs[1]], "Dialable nodes come first.");
+        policy.require_dialable = true;
+        assert_eq!(selected(&buckets, 10, &policy), vec![addrs[1]]);
+
+        // Cleaning the buckets refreshes the gauge of the probe results.
+        let probe_gauge = IntGaugeVec::new(Opts::new("probe_results_gauge", "help"), &["status"])
+            .expect("Unable to create dummy gauge.");
+        buckets.clean_buckets(60_000, &gauge, &probe_gauge);
+        let count = |status| probe_gauge.with_label_values(&[status]).get();
+        assert_eq!((count("dialable"), count("undialable"), count("unprobed")), (1, 1, 0));
+    }
 }
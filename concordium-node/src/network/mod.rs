@@ -1,9 +1,10 @@
 //! Network-related objects.
 
+pub mod address_manager;
 pub mod buckets;
 pub mod serialization;
 
-pub use self::buckets::Buckets;
+pub use self::{address_manager::AddressManager, buckets::Buckets};
 use crate::common::{
     p2p_peer::{P2PPeer, RemotePeerId},
     P2PNodeId,
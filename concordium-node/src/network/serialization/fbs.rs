@@ -38,6 +38,20 @@ impl NetworkMessage {
         }
     }
 
+    /// Obtain the packet type tag of a serialized message without fully
+    /// deserializing it. Returns `None` if the message is not a packet.
+    pub fn peek_packet_type(buffer: &[u8]) -> anyhow::Result<Option<u8>> {
+        let root = network::size_prefixed_root_as_network_message(buffer)?;
+        if root.payload_type() != network::NetworkPayload::NetworkPacket {
+            return Ok(None);
+        }
+        let packet = root
+            .payload_as_network_packet()
+            .context("missing network message payload (expected a packet)")?;
+        let payload = packet.payload().context("missing packet payload")?;
+        payload.bytes().first().copied().context("empty packet payload").map(Some)
+    }
+
     pub fn serialize<T: Write>(&self, target: &mut T) -> anyhow::Result<()> {
         let capacity = if let NetworkPayload::NetworkPacket(ref packet) = self.payload {
             packet.message.len() + 64 // FIXME: fine-tune the overhead
@@ -0,0 +1,401 @@
+//! Exposition of the node's metrics in the formats supported by Prometheus.
+//!
+//! Besides the classic Prometheus text format, the metrics can be exposed in
+//! the [OpenMetrics](https://openmetrics.io) text format and the Prometheus
+//! protobuf format. The OpenMetrics format is the only one of these that
+//! carries exemplars, which link histogram observations to traces.
+
+use crate::lock_or_die;
+use prometheus::{
+    proto::{Metric, MetricFamily, MetricType},
+    Encoder, ProtobufEncoder, TextEncoder,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Content type of the OpenMetrics text format.
+pub const OPENMETRICS_FORMAT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A format in which the metrics can be exposed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpositionFormat {
+    /// The Prometheus text format (version 0.0.4).
+    Text,
+    /// The OpenMetrics text format (version 1.0.0).
+    OpenMetrics,
+    /// The Prometheus protobuf format, consisting of length delimited
+    /// `MetricFamily` messages.
+    Protobuf,
+}
+
+impl ExpositionFormat {
+    /// Choose the format to respond with based on the `Accept` header of a
+    /// scrape request. The supported media type with the highest quality
+    /// value is chosen, preferring the earliest listed one on ties. The
+    /// Prometheus text format is used if none of the listed media types are
+    /// supported.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut best = (Self::Text, 0.0);
+        for media_range in accept.unwrap_or_default().split(',') {
+            let mut params = media_range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+            let mut quality = 1.0;
+            let mut protobuf_message = None;
+            for param in params {
+                if let Some((name, value)) = param.split_once('=') {
+                    match name.trim().to_ascii_lowercase().as_str() {
+                        "q" => quality = value.trim().parse().unwrap_or(0.0),
+                        "proto" => protobuf_message = Some(value.trim()),
+                        _ => {}
+                    }
+                }
+            }
+            let format = match media_type.as_str() {
+                "application/openmetrics-text" => Self::OpenMetrics,
+                "application/vnd.google.protobuf"
+                    if protobuf_message == Some("io.prometheus.client.MetricFamily") =>
+                {
+                    Self::Protobuf
+                }
+                "text/plain" => Self::Text,
+                _ => continue,
+            };
+            if quality > best.1 {
+                best = (format, quality);
+            }
+        }
+        best.0
+    }
+
+    /// The value of the `Content-Type` header of a response in this format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => prometheus::TEXT_FORMAT,
+            Self::OpenMetrics => OPENMETRICS_FORMAT,
+            Self::Protobuf => prometheus::PROTOBUF_FORMAT,
+        }
+    }
+
+    /// Encode the metric families in this format.
+    pub fn encode(
+        self,
+        metric_families: &[MetricFamily],
+        exemplars: &Exemplars,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        match self {
+            Self::Text => TextEncoder::new().encode(metric_families, &mut buffer)?,
+            Self::OpenMetrics => {
+                buffer = encode_openmetrics(metric_families, exemplars).into_bytes()
+            }
+            Self::Protobuf => ProtobufEncoder::new().encode(metric_families, &mut buffer)?,
+        }
+        Ok(buffer)
+    }
+}
+
+/// An exemplar of a histogram bucket, i.e., an observation that fell into the
+/// bucket together with the trace it was made in.
+#[derive(Debug, Clone, PartialEq)]
+struct Exemplar {
+    /// The trace id, hex encoded.
+    trace_id:  String,
+    /// The observed value.
+    value:     f64,
+    /// The time of the observation (seconds since the Unix epoch).
+    timestamp: f64,
+}
+
+/// Key identifying a histogram bucket: the name of the histogram, its sorted
+/// label pairs and the index of the bucket.
+type BucketKey = (String, Vec<(String, String)>, usize);
+
+/// The latest exemplar of each bucket of the histograms that record them.
+#[derive(Default, Clone)]
+pub struct Exemplars {
+    buckets: Arc<Mutex<HashMap<BucketKey, Exemplar>>>,
+}
+
+impl Exemplars {
+    /// Record an observation of `value` into the histogram `name` with the
+    /// given label pairs and bucket upper bounds (excluding +Inf), as the
+    /// exemplar of the bucket the value falls into.
+    pub fn record(
+        &self,
+        name: &str,
+        labels: &[(&str, &str)],
+        upper_bounds: &[f64],
+        value: f64,
+        trace_id: [u8; 16],
+    ) {
+        let index =
+            upper_bounds.iter().position(|bound| value <= *bound).unwrap_or(upper_bounds.len());
+        let mut labels: Vec<(String, String)> =
+            labels.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        labels.sort();
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0.0, |duration| duration.as_secs_f64());
+        lock_or_die!(self.buckets).insert((name.to_owned(), labels, index), Exemplar {
+            trace_id: hex::encode(trace_id),
+            value,
+            timestamp,
+        });
+    }
+
+    /// Look up the exemplar of a bucket of a gathered histogram.
+    fn get(&self, name: &str, metric: &Metric, index: usize) -> Option<Exemplar> {
+        let mut labels: Vec<(String, String)> = metric
+            .get_label()
+            .iter()
+            .map(|pair| (pair.get_name().to_owned(), pair.get_value().to_owned()))
+            .collect();
+        labels.sort();
+        lock_or_die!(self.buckets).get(&(name.to_owned(), labels, index)).cloned()
+    }
+}
+
+/// Encode the metric families in the OpenMetrics text format, attaching the
+/// recorded exemplars to histogram buckets.
+fn encode_openmetrics(metric_families: &[MetricFamily], exemplars: &Exemplars) -> String {
+    let mut out = String::new();
+    for family in metric_families {
+        let name = family.get_name();
+        let metric_type = family.get_field_type();
+        // Counters are named without the `_total` suffix, which is instead added to
+        // their samples.
+        let family_name = match metric_type {
+            MetricType::COUNTER => name.strip_suffix("_total").unwrap_or(name),
+            _ => name,
+        };
+        let type_name = match metric_type {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        let _ = writeln!(out, "# TYPE {} {}", family_name, type_name);
+        if !family.get_help().is_empty() {
+            let _ = writeln!(out, "# HELP {} {}", family_name, escape(family.get_help()));
+        }
+        for metric in family.get_metric() {
+            match metric_type {
+                MetricType::COUNTER => {
+                    let sample = format!("{}_total", family_name);
+                    write_sample(&mut out, &sample, metric, None, metric.get_counter().get_value());
+                }
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, metric, None, metric.get_gauge().get_value());
+                }
+                MetricType::UNTYPED => {
+                    write_sample(&mut out, name, metric, None, metric.get_untyped().get_value());
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_name = format!("{}_bucket", name);
+                    let mut buckets: Vec<(f64, f64)> = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| {
+                            (bucket.get_upper_bound(), bucket.get_cumulative_count() as f64)
+                        })
+                        .collect();
+                    if !buckets.last().map_or(false, |(bound, _)| *bound == f64::INFINITY) {
+                        buckets.push((f64::INFINITY, histogram.get_sample_count() as f64));
+                    }
+                    for (index, (upper_bound, count)) in buckets.into_iter().enumerate() {
+                        let le = format_value(upper_bound);
+                        write_sample(&mut out, &bucket_name, metric, Some(("le", &le)), count);
+                        if let Some(exemplar) = exemplars.get(name, metric, index) {
+                            // Replace the newline of the sample by the exemplar.
+                            out.pop();
+                            let _ = writeln!(
+                                out,
+                                " # {{trace_id=\"{}\"}} {} {}",
+                                exemplar.trace_id,
+                                format_value(exemplar.value),
+                                format_value(exemplar.timestamp)
+                            );
+                        }
+                    }
+                    write_sample(
+                        &mut out,
+                        &format!("{}_count", name),
+                        metric,
+                        None,
+                        histogram.get_sample_count() as f64,
+                    );
+                    write_sample(
+                        &mut out,
+                        &format!("{}_sum", name),
+                        metric,
+                        None,
+                        histogram.get_sample_sum(),
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = format_value(quantile.get_quantile());
+                        write_sample(
+                            &mut out,
+                            name,
+                            metric,
+                            Some(("quantile", &q)),
+                            quantile.get_value(),
+                        );
+                    }
+                    write_sample(
+                        &mut out,
+                        &format!("{}_count", name),
+                        metric,
+                        None,
+                        summary.get_sample_count() as f64,
+                    );
+                    write_sample(
+                        &mut out,
+                        &format!("{}_sum", name),
+                        metric,
+                        None,
+                        summary.get_sample_sum(),
+                    );
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Write a single sample line, including the labels of the metric and an
+/// optional additional label.
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    metric: &Metric,
+    additional_label: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    let labels = metric
+        .get_label()
+        .iter()
+        .map(|pair| (pair.get_name(), pair.get_value()))
+        .chain(additional_label)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+        .collect::<Vec<_>>();
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = write!(out, " {}", format_value(value));
+    if metric.get_timestamp_ms() != 0 {
+        let _ = write!(out, " {}", format_value(metric.get_timestamp_ms() as f64 / 1000.0));
+    }
+    out.push('\n');
+}
+
+/// Format a number as required by OpenMetrics, in particular with infinities
+/// written as `+Inf` and `-Inf`, and with integral bucket bounds written as
+/// floats (e.g., `1.0`).
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 {
+            "+Inf"
+        } else {
+            "-Inf"
+        }
+        .to_owned()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Escape a label value or help text.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{HistogramOpts, HistogramVec, IntCounter, IntGauge, Opts, Registry};
+
+    #[test]
+    fn negotiate_format() {
+        assert_eq!(ExpositionFormat::negotiate(None), ExpositionFormat::Text);
+        assert_eq!(ExpositionFormat::negotiate(Some("*/*")), ExpositionFormat::Text);
+        assert_eq!(
+            ExpositionFormat::negotiate(Some(
+                "application/openmetrics-text;version=1.0.0;q=0.5,application/openmetrics-text;\
+                 version=0.0.1;q=0.4,text/plain;version=0.0.4;q=0.3,*/*;q=0.2"
+            )),
+            ExpositionFormat::OpenMetrics
+        );
+        assert_eq!(
+            ExpositionFormat::negotiate(Some(
+                "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;\
+                 encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3"
+            )),
+            ExpositionFormat::Protobuf
+        );
+        assert_eq!(
+            ExpositionFormat::negotiate(Some(
+                "text/plain;q=0.9,application/openmetrics-text;q=0.5"
+            )),
+            ExpositionFormat::Text
+        );
+    }
+
+    #[test]
+    fn encode_openmetrics_with_exemplars() {
+        let registry = Registry::new();
+        let counter =
+            IntCounter::with_opts(Opts::new("packets_total", "Number of \"packets\"")).unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        let gauge = IntGauge::with_opts(Opts::new("peers", "Number of peers")).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("response_time_seconds", "Response time").buckets(vec![0.1, 1.0]),
+            &["method"],
+        )
+        .unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.inc_by(3);
+        gauge.set(2);
+        histogram.with_label_values(&["get"]).observe(0.5);
+        let exemplars = Exemplars::default();
+        exemplars.record(
+            "response_time_seconds",
+            &[("method", "get")],
+            &[0.1, 1.0],
+            0.5,
+            [0xab; 16],
+        );
+
+        let encoded = encode_openmetrics(&registry.gather(), &exemplars);
+        let lines: Vec<&str> = encoded.lines().collect();
+        assert_eq!(lines[0], "# TYPE packets counter");
+        assert_eq!(lines[1], "# HELP packets Number of \\\"packets\\\"");
+        assert_eq!(lines[2], "packets_total 3.0");
+        assert_eq!(lines[3], "# TYPE peers gauge");
+        assert_eq!(lines[5], "peers 2.0");
+        assert_eq!(lines[6], "# TYPE response_time_seconds histogram");
+        assert_eq!(lines[8], "response_time_seconds_bucket{method=\"get\",le=\"0.1\"} 0.0");
+        assert!(lines[9].starts_with(
+            "response_time_seconds_bucket{method=\"get\",le=\"1.0\"} 1.0 # \
+             {trace_id=\"abababababababababababababababab\"} 0.5 "
+        ));
+        assert_eq!(lines[10], "response_time_seconds_bucket{method=\"get\",le=\"+Inf\"} 1.0");
+        assert_eq!(lines[11], "response_time_seconds_count{method=\"get\"} 1.0");
+        assert_eq!(lines[12], "response_time_seconds_sum{method=\"get\"} 0.5");
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+}
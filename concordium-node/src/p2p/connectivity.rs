@@ -3,7 +3,8 @@
 use crate::{
     common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerType, RemotePeer},
     configuration as config,
-    connection::{ConnChange, Connection, MessageSendingPriority},
+    connection::{traffic::TrafficCategory, ConnChange, Connection, MessageSendingPriority},
+    consensus_ffi::helpers::PacketType,
     lock_or_die, netmsg,
     network::{
         Handshake, NetworkId, NetworkPacket, NetworkRequest, PacketDestination,
@@ -54,7 +55,12 @@ impl P2PNode {
             error!("Could not serialize a network request message: {}", e)
         } else {
             let filter = |_: &Connection| true;
-            self.send_over_all_connections(&serialized, &filter);
+            self.send_over_connections(
+                &serialized,
+                TrafficCategory::Network,
+                &filter,
+                MessageSendingPriority::Normal,
+            );
         }
     }
 
@@ -64,13 +70,27 @@ impl P2PNode {
         &self,
         data: &[u8],
         conn_filter: &dyn Fn(&Connection) -> bool,
+    ) -> usize {
+        let category = TrafficCategory::of_serialized(data);
+        self.send_over_connections(data, category, conn_filter, MessageSendingPriority::Normal)
+    }
+
+    /// Send a `data` message of the given category with the given priority to
+    /// all connections adhering to the specified filter. Returns the number of
+    /// sent messages.
+    fn send_over_connections(
+        &self,
+        data: &[u8],
+        category: TrafficCategory,
+        conn_filter: &dyn Fn(&Connection) -> bool,
+        priority: MessageSendingPriority,
     ) -> usize {
         let mut sent_messages = 0usize;
         let data = Arc::from(data);
 
         for conn in write_or_die!(self.connections()).values_mut().filter(|conn| conn_filter(conn))
         {
-            conn.async_send(Arc::clone(&data), MessageSendingPriority::Normal);
+            conn.async_send(Arc::clone(&data), category, priority);
             sent_messages += 1;
         }
 
@@ -198,14 +218,14 @@ impl P2PNode {
         let peers_to_skip = match inner_pkt.destination {
             PacketDestination::Direct(..) => vec![],
             PacketDestination::Broadcast(ref dont_relay_to) => {
-                if self.config.relay_broadcast_percentage < 1.0 {
+                let relay_broadcast_percentage = self.config.dynamic().relay_broadcast_percentage;
+                if relay_broadcast_percentage < 1.0 {
                     use rand::seq::SliceRandom;
                     let mut rng = rand::thread_rng();
                     let mut peers = self.get_node_peer_tokens();
                     peers.retain(|token| !dont_relay_to.contains(token));
-                    let peers_to_take = f64::floor(
-                        f64::from(peers.len() as u32) * self.config.relay_broadcast_percentage,
-                    );
+                    let peers_to_take =
+                        f64::floor(f64::from(peers.len() as u32) * relay_broadcast_percentage);
                     peers
                         .choose_multiple(&mut rng, peers_to_take as usize)
                         .copied()
@@ -223,7 +243,23 @@ impl P2PNode {
         };
         let network_id = inner_pkt.network_id;
 
+        // broadcast blocks and finalization messages are what peers need to make
+        // progress, so with an upload limit they are sent before other messages and
+        // get a reserved share of it; direct messages, e.g., catch-up responses, do
+        // not. Without a limit the messages are sent in order as before.
+        let priority = match inner_pkt.message.first().copied().map(PacketType::try_from) {
+            Some(Ok(
+                PacketType::Block
+                | PacketType::FinalizationMessage
+                | PacketType::FinalizationRecord,
+            )) if target.is_none() && self.connection_handler.bandwidth.limits_upload() => {
+                MessageSendingPriority::High
+            }
+            _ => MessageSendingPriority::Normal,
+        };
+
         let message = netmsg!(NetworkPacket, inner_pkt);
+        let category = TrafficCategory::of_message(&message);
         let mut serialized = Vec::with_capacity(256);
         message.serialize(&mut serialized)?;
 
@@ -231,12 +267,12 @@ impl P2PNode {
         if let Some(target_token) = target {
             // direct messages
             let filter = |conn: &Connection| conn.remote_peer.local_id == target_token;
-            sent += self.send_over_all_connections(&serialized, &filter);
+            sent += self.send_over_connections(&serialized, category, &filter, priority);
         } else {
             // broadcast messages
             let filter =
                 |conn: &Connection| is_valid_broadcast_target(conn, &peers_to_skip, network_id);
-            sent += self.send_over_all_connections(&serialized, &filter);
+            sent += self.send_over_connections(&serialized, category, &filter, priority);
         }
 
         Ok(sent)
@@ -270,6 +306,12 @@ impl P2PNode {
                 }
 
                 if events.iter().any(|event| event.token() == conn.token() && event.is_readable()) {
+                    conn.low_level.notify_readable();
+                }
+
+                // the socket is also read without a new event if the previous read was
+                // interrupted by the download limit
+                if conn.low_level.is_readable() {
                     match conn.read_stream(&conn_stats) {
                         Err(e) => {
                             error!("[receiving from {}] {}", conn, e);
@@ -285,25 +327,29 @@ impl P2PNode {
                         Ok(false) => {
                             // The connection was closed by the peer.
                             debug!("Connection to {} closed by peer", conn);
-                            self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                            self.register_conn_change(ConnChange::ClosedByPeer(conn.token()));
                             return;
                         }
                         Ok(true) => {}
                     }
                 }
 
-                let closed_or_error = |event: &Event| {
-                    event.token() == conn.token()
-                        && (event.is_read_closed() || event.is_write_closed() || event.is_error())
-                };
+                let is_conn_event = |event: &&Event| event.token() == conn.token();
 
-                if events.iter().any(closed_or_error) {
-                    // Generally, connections will be closed as a result of a read or write failing
-                    // or returning 0 bytes, rather than reaching here. This is more of a back stop,
-                    // and might catch a failure sooner in the case where we do not currently have
-                    // anything to write.
+                // Generally, connections will be closed as a result of a read or write failing
+                // or returning 0 bytes, rather than reaching here. This is more of a back stop,
+                // and might catch a failure sooner in the case where we do not currently have
+                // anything to write.
+                if events.iter().filter(is_conn_event).any(|event| event.is_error()) {
                     debug!("Closing connection to {}", conn);
                     self.register_conn_change(ConnChange::RemovalByToken(conn.token()));
+                } else if events
+                    .iter()
+                    .filter(is_conn_event)
+                    .any(|event| event.is_read_closed() || event.is_write_closed())
+                {
+                    debug!("Connection to {} closed by peer", conn);
+                    self.register_conn_change(ConnChange::ClosedByPeer(conn.token()));
                 }
             })
     }
@@ -465,13 +511,24 @@ pub fn connect(
         }
     );
 
+    // Besides the total limit, only outbound connections count towards the limit
+    // on outbound connections, so that inbound connections beyond their own limit
+    // cannot prevent us from connecting to peers of our own choosing.
     if respect_max_peers && peer_type == PeerType::Node {
-        let current_peer_count = node.get_peer_stats(Some(PeerType::Node)).len() as u16;
-        if current_peer_count >= node.config.max_allowed_nodes {
+        let counts = node.get_connection_counts();
+        let dynamic_config = node.config.dynamic();
+        if counts.outbound >= usize::from(dynamic_config.max_outbound_nodes) {
+            bail!(
+                "Maximum number of outbound peers reached {}/{}",
+                counts.outbound,
+                dynamic_config.max_outbound_nodes
+            );
+        }
+        if counts.outbound + counts.inbound >= usize::from(dynamic_config.max_allowed_nodes) {
             bail!(
                 "Maximum number of peers reached {}/{}",
-                current_peer_count,
-                node.config.max_allowed_nodes
+                counts.outbound + counts.inbound,
+                dynamic_config.max_allowed_nodes
             );
         }
     }
@@ -551,9 +608,10 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
 
     let curr_stamp = get_current_stamp();
     let peer_type = node.peer_type();
+    let dynamic_config = node.config.dynamic();
 
     let is_conn_faulty = |conn: &Connection| -> bool {
-        if let Some(max_latency) = node.config.max_latency {
+        if let Some(max_latency) = dynamic_config.max_latency {
             conn.get_latency() >= max_latency
         } else {
             false
@@ -590,25 +648,32 @@ pub fn connection_housekeeping(node: &Arc<P2PNode>) -> bool {
         }
     }
 
-    // if the number of peers exceeds the desired value, close a random selection of
-    // post-handshake non-given connections to lower it
+    // if the number of inbound peers exceeds its limit, or the number of all peers
+    // exceeds the maximum allowed, close some post-handshake non-given inbound
+    // connections to lower it. Outbound connections are never evicted, so that
+    // peers connecting to us cannot push out the peers we chose.
     if peer_type == PeerType::Node {
-        let max_allowed_nodes = node.config.max_allowed_nodes;
-        let peer_count = node.get_peer_stats(Some(PeerType::Node)).len() as u16;
-        if peer_count > max_allowed_nodes {
-            // drop connections to any non-given peers.
-            let mut rng = rand::thread_rng();
-            let to_drop = read_or_die!(node.connections())
+        let counts = node.get_connection_counts();
+        let excess =
+            counts.inbound.saturating_sub(usize::from(dynamic_config.max_inbound_nodes)).max(
+                (counts.inbound + counts.outbound)
+                    .saturating_sub(usize::from(dynamic_config.max_allowed_nodes)),
+            );
+        if excess > 0 {
+            let candidates = read_or_die!(node.connections())
                 .iter()
-                .filter_map(|(&token, conn)| {
-                    // only consider non-given connections for removal
-                    if node.is_given_connection(conn) {
-                        None
-                    } else {
-                        Some(token)
-                    }
+                .filter(|(_, conn)| {
+                    conn.remote_peer_type() == PeerType::Node
+                        && !conn.is_outbound()
+                        && !node.is_given_connection(conn)
+                })
+                .map(|(&token, conn)| EvictionCandidate {
+                    token,
+                    created: conn.stats.created,
+                    latency: conn.get_latency(),
                 })
-                .choose_multiple(&mut rng, (peer_count - max_allowed_nodes) as usize);
+                .collect();
+            let to_drop = select_inbound_for_eviction(candidates, excess);
 
             node.remove_connections(&to_drop);
         }
@@ -724,3 +789,89 @@ fn send_message_over_network(
         0
     }
 }
+
+/// The number of inbound connections with the lowest latency, and
+/// independently the number of longest-lived inbound connections, that are
+/// protected from eviction.
+const PROTECTED_INBOUND_PER_CATEGORY: usize = 4;
+
+/// An inbound connection that may be evicted to make room for others.
+struct EvictionCandidate {
+    token:   Token,
+    created: u64,
+    latency: u64,
+}
+
+/// Select `count` inbound connections to drop. The lowest-latency and the
+/// longest-lived connections are protected, and a random selection of the
+/// remaining ones is chosen. If that is not enough, the most recently created
+/// of the protected connections are chosen as well.
+fn select_inbound_for_eviction(mut candidates: Vec<EvictionCandidate>, count: usize) -> Vec<Token> {
+    let mut protected = Vec::new();
+
+    // a latency of 0 means that it has not been measured yet
+    candidates.sort_unstable_by_key(|candidate| (candidate.latency == 0, candidate.latency));
+    let rest = candidates.split_off(PROTECTED_INBOUND_PER_CATEGORY.min(candidates.len()));
+    protected.append(&mut candidates);
+    candidates = rest;
+
+    candidates.sort_unstable_by_key(|candidate| candidate.created);
+    let rest = candidates.split_off(PROTECTED_INBOUND_PER_CATEGORY.min(candidates.len()));
+    protected.append(&mut candidates);
+    candidates = rest;
+
+    let mut to_drop = candidates
+        .iter()
+        .map(|candidate| candidate.token)
+        .choose_multiple(&mut rand::thread_rng(), count);
+    if to_drop.len() < count {
+        protected.sort_unstable_by_key(|candidate| std::cmp::Reverse(candidate.created));
+        to_drop
+            .extend(protected.iter().map(|candidate| candidate.token).take(count - to_drop.len()));
+    }
+    to_drop
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_inbound_for_eviction() {
+        // connection i was created at time i, and the latency decreases with i
+        // except for the last two, whose latency is not measured yet
+        let candidates = || {
+            (0..12)
+                .map(|i| EvictionCandidate {
+                    token:   Token(i),
+                    created: i as u64,
+                    latency: if i < 10 {
+                        100 - i as u64
+                    } else {
+                        0
+                    },
+                })
+                .collect::<Vec<_>>()
+        };
+        let lowest_latency = [6, 7, 8, 9];
+        let longest_lived = [0, 1, 2, 3];
+
+        // only unprotected connections are dropped while there are enough of them
+        let mut to_drop = select_inbound_for_eviction(candidates(), 4);
+        to_drop.sort_unstable();
+        assert_eq!(to_drop, vec![Token(4), Token(5), Token(10), Token(11)]);
+        let to_drop = select_inbound_for_eviction(candidates(), 2);
+        assert_eq!(to_drop.len(), 2);
+        assert!(to_drop
+            .iter()
+            .all(|token| !lowest_latency.contains(&token.0) && !longest_lived.contains(&token.0)));
+
+        // then the most recently created protected connections
+        let mut to_drop = select_inbound_for_eviction(candidates(), 6);
+        to_drop.sort_unstable();
+        assert_eq!(to_drop, vec![Token(4), Token(5), Token(8), Token(9), Token(10), Token(11)]);
+
+        assert_eq!(select_inbound_for_eviction(candidates(), 20).len(), 12);
+        assert!(select_inbound_for_eviction(Vec::new(), 1).is_empty());
+    }
+}
@@ -0,0 +1,621 @@
+//! A DNS seed server run by a bootstrapper.
+//!
+//! The seed is a small authoritative DNS responder for a single name. It
+//! answers `A` and `AAAA` queries for the name with the IP addresses of the
+//! healthy peers in the bootstrapper's buckets, and `TXT` queries with their
+//! addresses including the port, so that nodes can bootstrap from the name
+//! without the records being maintained by hand. The peers are selected at
+//! most once every [`PEERS_CACHE_DURATION`]. The name is the apex of a zone
+//! with a minimal `SOA` and `NS` record, and the `SOA` record is included in
+//! negative answers so that resolvers can cache them. Names below the seed
+//! name do not exist, and queries for any other name are refused. Only UDP is
+//! supported, and answers are limited to what fits into a single 512 byte
+//! message. Since answers are larger than queries, the number of queries
+//! answered per source address is limited, so that the seed cannot be used to
+//! flood others with spoofed queries.
+
+use anyhow::{ensure, Context};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    network::{buckets::PeerListPolicy, Networks},
+    p2p::P2PNode,
+    read_or_die, spawn_or_die,
+};
+
+/// The maximum size of a DNS message sent over UDP without EDNS.
+const MAX_UDP_MESSAGE_SIZE: usize = 512;
+/// The size of the header of a DNS message.
+const HEADER_SIZE: usize = 12;
+/// The pointer to the name in the question, which directly follows the header.
+const QUESTION_NAME_POINTER: [u8; 2] = [0xc0, HEADER_SIZE as u8];
+/// How long the server waits for a query before checking whether the node is
+/// shutting down.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+/// The maximum number of queries answered per source address in every
+/// [`RATE_LIMIT_WINDOW`]. Further queries are ignored.
+const MAX_QUERIES_PER_SOURCE: u32 = 10;
+/// The window in which the queries of a source address are counted.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+/// How long the selected peers are served before they are selected again.
+const PEERS_CACHE_DURATION: Duration = Duration::from_secs(1);
+
+/// The serial number of the `SOA` record. The records of the zone are not
+/// transferred to secondary servers, so it never changes.
+const SOA_SERIAL: u32 = 1;
+/// The refresh, retry and expire intervals (in seconds) of the `SOA` record.
+const SOA_INTERVALS: [u32; 3] = [3600, 600, 86400];
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+
+const RCODE_NO_ERROR: u16 = 0;
+const RCODE_FORMAT_ERROR: u16 = 1;
+const RCODE_NAME_ERROR: u16 = 3;
+const RCODE_NOT_IMPLEMENTED: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+const TYPE_A: u16 = 1;
+const TYPE_NS: u16 = 2;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+/// The question of a query.
+struct Question {
+    /// The queried name in lower case, without the trailing dot.
+    name:   String,
+    qtype:  u16,
+    qclass: u16,
+    /// The offset of the end of the question in the query.
+    end:    usize,
+}
+
+/// Parse the question of a query, which starts directly after the header.
+fn parse_question(query: &[u8]) -> Option<Question> {
+    let mut offset = HEADER_SIZE;
+    let mut labels = Vec::new();
+    loop {
+        let len = usize::from(*query.get(offset)?);
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // compression pointers are not used in the question of a query
+        if len > 63 {
+            return None;
+        }
+        let label = query.get(offset..offset + len)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+    let fields = query.get(offset..offset + 4)?;
+    Some(Question {
+        name:   labels.join("."),
+        qtype:  u16::from_be_bytes([fields[0], fields[1]]),
+        qclass: u16::from_be_bytes([fields[2], fields[3]]),
+        end:    offset + 4,
+    })
+}
+
+/// Normalize a domain name to lower case without the trailing dot, and encode
+/// it as a sequence of labels. `what` describes the name in errors.
+fn encode_name(name: &str, what: &str) -> anyhow::Result<(String, Vec<u8>)> {
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    ensure!(
+        !name.is_empty() && name.len() <= 253,
+        "The {} must have between 1 and 253 characters",
+        what
+    );
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.') {
+        ensure!(
+            !label.is_empty() && label.len() <= 63,
+            "The labels of the {} must have between 1 and 63 characters",
+            what
+        );
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    Ok((name, encoded))
+}
+
+/// Append a resource record to the response, unless the response would exceed
+/// the maximum message size. Returns whether the record was appended.
+fn push_record(response: &mut Vec<u8>, owner: &[u8], rtype: u16, ttl: u32, rdata: &[u8]) -> bool {
+    if response.len() + owner.len() + 10 + rdata.len() > MAX_UDP_MESSAGE_SIZE {
+        return false;
+    }
+    response.extend_from_slice(owner);
+    response.extend_from_slice(&rtype.to_be_bytes());
+    response.extend_from_slice(&CLASS_IN.to_be_bytes());
+    response.extend_from_slice(&ttl.to_be_bytes());
+    response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    response.extend_from_slice(rdata);
+    true
+}
+
+/// The records served for a seed name.
+#[derive(Debug, Clone)]
+pub struct SeedZone {
+    /// The seed name in lower case, without the trailing dot.
+    name:        String,
+    /// The encoded seed name.
+    apex:        Vec<u8>,
+    /// The data of the `NS` record, i.e., the encoded name of the name server.
+    ns_rdata:    Vec<u8>,
+    /// The data of the `SOA` record.
+    soa_rdata:   Vec<u8>,
+    /// The time to live (in seconds) of the records.
+    ttl:         u32,
+    /// The maximum number of records in an answer.
+    max_records: usize,
+}
+
+impl SeedZone {
+    /// The zone of the seed name `name` served by the name server with the
+    /// name `nameserver`. The `SOA` record names `hostmaster` at the seed name
+    /// as the responsible mailbox, and uses the TTL for negative answers.
+    pub fn new(name: &str, nameserver: &str, ttl: u32, max_records: usize) -> anyhow::Result<Self> {
+        let (name, apex) = encode_name(name, "DNS seed name")?;
+        let (_, ns_rdata) = encode_name(nameserver, "DNS seed name server")?;
+        let (_, mailbox) = encode_name(&format!("hostmaster.{}", name), "DNS seed name")?;
+        ensure!(max_records > 0, "The maximum number of DNS seed records must be positive");
+        let mut soa_rdata = ns_rdata.clone();
+        soa_rdata.extend_from_slice(&mailbox);
+        for field in std::iter::once(SOA_SERIAL).chain(SOA_INTERVALS).chain(std::iter::once(ttl)) {
+            soa_rdata.extend_from_slice(&field.to_be_bytes());
+        }
+        Ok(Self {
+            name,
+            apex,
+            ns_rdata,
+            soa_rdata,
+            ttl,
+            max_records,
+        })
+    }
+
+    /// The response to a query. The addresses of the peers to serve are only
+    /// obtained if the query asks for records of the seed name. Returns `None`
+    /// if the message should be ignored, e.g., because it is not a query.
+    pub fn answer(&self, query: &[u8], peers: impl FnOnce() -> Vec<SocketAddr>) -> Option<Vec<u8>> {
+        let header = query.get(..HEADER_SIZE)?;
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        if flags & FLAG_RESPONSE != 0 {
+            return None;
+        }
+        let opcode = flags & 0x7800;
+        let question_count = u16::from_be_bytes([header[4], header[5]]);
+
+        let mut response = Vec::with_capacity(MAX_UDP_MESSAGE_SIZE);
+        let response_header = |response: &mut Vec<u8>, rcode: u16, questions: u16| {
+            response.extend_from_slice(&header[..2]);
+            let flags =
+                FLAG_RESPONSE | opcode | FLAG_AUTHORITATIVE | (flags & FLAG_RECURSION_DESIRED);
+            response.extend_from_slice(&(flags | rcode).to_be_bytes());
+            response.extend_from_slice(&questions.to_be_bytes());
+            // the answer, authority and additional counts
+            response.extend_from_slice(&[0; 6]);
+        };
+        if opcode != 0 {
+            response_header(&mut response, RCODE_NOT_IMPLEMENTED, 0);
+            return Some(response);
+        }
+        let question = match parse_question(query) {
+            Some(question) if question_count == 1 => question,
+            _ => {
+                response_header(&mut response, RCODE_FORMAT_ERROR, 0);
+                return Some(response);
+            }
+        };
+
+        let rcode = if question.qclass != CLASS_IN && question.qclass != CLASS_ANY {
+            RCODE_REFUSED
+        } else if question.name == self.name {
+            RCODE_NO_ERROR
+        } else if question.name.ends_with(&format!(".{}", self.name)) {
+            RCODE_NAME_ERROR
+        } else {
+            RCODE_REFUSED
+        };
+        response_header(&mut response, rcode, 1);
+        response.extend_from_slice(&query[HEADER_SIZE..question.end]);
+        if rcode == RCODE_REFUSED {
+            return Some(response);
+        }
+
+        let mut answer_count: u16 = 0;
+        if rcode == RCODE_NO_ERROR {
+            match question.qtype {
+                TYPE_A | TYPE_AAAA | TYPE_TXT => {
+                    for rdata in self.address_records(question.qtype, &peers()) {
+                        if !push_record(
+                            &mut response,
+                            &QUESTION_NAME_POINTER,
+                            question.qtype,
+                            self.ttl,
+                            &rdata,
+                        ) {
+                            break;
+                        }
+                        answer_count += 1;
+                    }
+                }
+                TYPE_NS | TYPE_SOA => {
+                    let rdata = if question.qtype == TYPE_NS {
+                        &self.ns_rdata
+                    } else {
+                        &self.soa_rdata
+                    };
+                    if push_record(
+                        &mut response,
+                        &QUESTION_NAME_POINTER,
+                        question.qtype,
+                        self.ttl,
+                        rdata,
+                    ) {
+                        answer_count = 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        response[6..8].copy_from_slice(&answer_count.to_be_bytes());
+        // A name that does not exist or has no records of the queried type.
+        if answer_count == 0
+            && push_record(&mut response, &self.apex, TYPE_SOA, self.ttl, &self.soa_rdata)
+        {
+            response[8..10].copy_from_slice(&1u16.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// The data of at most `max_records` distinct records of the type for the
+    /// addresses of the peers.
+    fn address_records(&self, qtype: u16, peers: &[SocketAddr]) -> Vec<Vec<u8>> {
+        let mut seen = HashSet::new();
+        peers
+            .iter()
+            .filter_map(|addr| {
+                let ip = match addr.ip() {
+                    IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+                    ip => ip,
+                };
+                let rdata = match (qtype, ip) {
+                    (TYPE_A, IpAddr::V4(ip)) => ip.octets().to_vec(),
+                    (TYPE_AAAA, IpAddr::V6(ip)) => ip.octets().to_vec(),
+                    (TYPE_TXT, ip) => {
+                        let text = SocketAddr::new(ip, addr.port()).to_string();
+                        let mut rdata = vec![text.len() as u8];
+                        rdata.extend_from_slice(text.as_bytes());
+                        rdata
+                    }
+                    _ => return None,
+                };
+                // several peers can share an IP address
+                if seen.insert(rdata.clone()) {
+                    Some(rdata)
+                } else {
+                    None
+                }
+            })
+            .take(self.max_records)
+            .collect()
+    }
+}
+
+/// The peers served by the seed, which are selected at most once every
+/// [`PEERS_CACHE_DURATION`], since selecting them requires locking the buckets.
+#[derive(Default)]
+struct PeersCache {
+    peers:    Vec<SocketAddr>,
+    /// The time the peers were selected, if they were.
+    selected: Option<Instant>,
+}
+
+impl PeersCache {
+    /// The peers to serve at time `now`, selecting them with `select` if the
+    /// cached peers are too old.
+    fn get(&mut self, now: Instant, select: impl FnOnce() -> Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let fresh = match self.selected {
+            Some(selected) => now.duration_since(selected) < PEERS_CACHE_DURATION,
+            None => false,
+        };
+        if !fresh {
+            self.peers = select();
+            self.selected = Some(now);
+        }
+        self.peers.clone()
+    }
+}
+
+/// A DNS seed server listening on a UDP socket.
+pub struct DnsSeed {
+    socket:       UdpSocket,
+    zone:         SeedZone,
+    /// The start of the current rate limiting window.
+    window_start: Instant,
+    /// The number of queries received from each source address in the
+    /// current rate limiting window.
+    query_counts: HashMap<IpAddr, u32>,
+    peers:        PeersCache,
+}
+
+impl DnsSeed {
+    pub fn bind(addr: SocketAddr, zone: SeedZone) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(addr)
+            .with_context(|| format!("Could not bind the DNS seed server to {}", addr))?;
+        socket.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(Self {
+            socket,
+            zone,
+            window_start: Instant::now(),
+            query_counts: HashMap::new(),
+            peers: PeersCache::default(),
+        })
+    }
+
+    /// The address the server listens on.
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> { Ok(self.socket.local_addr()?) }
+
+    /// Check whether a query from the source address received at time `now` is
+    /// within the rate limit, and count it.
+    fn within_rate_limit(&mut self, source: IpAddr, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= RATE_LIMIT_WINDOW {
+            self.window_start = now;
+            self.query_counts.clear();
+        }
+        let count = self.query_counts.entry(source).or_insert(0);
+        *count += 1;
+        *count <= MAX_QUERIES_PER_SOURCE
+    }
+
+    /// Wait for a single query and answer it with the peers selected by
+    /// `select_peers`, or with the cached peers if they were selected recently.
+    /// Returns `false` if no query arrived in time.
+    pub fn serve_once(
+        &mut self,
+        select_peers: impl FnOnce() -> Vec<SocketAddr>,
+    ) -> anyhow::Result<bool> {
+        let mut buf = [0u8; MAX_UDP_MESSAGE_SIZE];
+        let (len, source) = match self.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let now = Instant::now();
+        if !self.within_rate_limit(source.ip(), now) {
+            trace!("Ignoring a DNS seed query from {}: too many queries", source);
+            return Ok(true);
+        }
+        let peers = &mut self.peers;
+        if let Some(response) = self.zone.answer(&buf[..len], || peers.get(now, select_peers)) {
+            trace!("Answering a DNS seed query from {}", source);
+            self.socket.send_to(&response, source)?;
+        }
+        Ok(true)
+    }
+}
+
+/// Spawn the thread serving the DNS seed queries with the healthy peers in the
+/// node's buckets. These are selected like the peers it shares in a peer list,
+/// except that undialable peers are never served and, if peers are probed,
+/// only those that passed their last probe are.
+pub fn spawn(node_ref: &Arc<P2PNode>, mut seed: DnsSeed) -> JoinHandle<()> {
+    let node = Arc::clone(node_ref);
+    spawn_or_die!("dns seed", {
+        let networks = Networks::default();
+        let peers = || {
+            let genesis_blocks = read_or_die!(node.config.regenesis_arc.blocks).clone();
+            let policy = PeerListPolicy {
+                share_undialable: false,
+                require_dialable: node.config.bootstrapper_probe_interval > 0,
+                ..node.peer_list_policy(&genesis_blocks)
+            };
+            read_or_die!(node.buckets())
+                .get_random_nodes(None, usize::MAX, &networks, &policy)
+                .iter()
+                .map(|peer| peer.external_addr())
+                .collect()
+        };
+        while !node.is_network_stopped() {
+            if let Err(e) = seed.serve_once(&peers) {
+                error!("Could not answer a DNS seed query: {}", e);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a query for the name with the given type.
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut query = id.to_be_bytes().to_vec();
+        query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+        query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.push(0);
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    /// The response code, the data of the answers and the types of the
+    /// authority records in a response.
+    fn parse_response(query: &[u8], response: &[u8]) -> (u16, Vec<Vec<u8>>, Vec<u16>) {
+        assert_eq!(response[..2], query[..2], "The response must have the id of the query.");
+        let flags = u16::from_be_bytes([response[2], response[3]]);
+        assert_ne!(flags & FLAG_RESPONSE, 0);
+        assert_ne!(flags & FLAG_AUTHORITATIVE, 0);
+        let answer_count = u16::from_be_bytes([response[6], response[7]]);
+        let authority_count = u16::from_be_bytes([response[8], response[9]]);
+        let mut offset = parse_question(response).map_or(HEADER_SIZE, |q| q.end);
+        let mut records = Vec::new();
+        for _ in 0..answer_count + authority_count {
+            // skip the owner name, which ends with a pointer or the root label
+            loop {
+                match response[offset] {
+                    0 => {
+                        offset += 1;
+                        break;
+                    }
+                    len if len >= 0xc0 => {
+                        offset += 2;
+                        break;
+                    }
+                    len => offset += 1 + usize::from(len),
+                }
+            }
+            let rtype = u16::from_be_bytes([response[offset], response[offset + 1]]);
+            let len = usize::from(u16::from_be_bytes([response[offset + 8], response[offset + 9]]));
+            offset += 10;
+            records.push((rtype, response[offset..offset + len].to_vec()));
+            offset += len;
+        }
+        assert_eq!(offset, response.len());
+        let authority = records.split_off(usize::from(answer_count));
+        (
+            flags & 0xf,
+            records.into_iter().map(|(_, rdata)| rdata).collect(),
+            authority.into_iter().map(|(rtype, _)| rtype).collect(),
+        )
+    }
+
+    #[test]
+    fn test_seed_answers() -> anyhow::Result<()> {
+        let zone = SeedZone::new("Seed.Example.com.", "ns.example.com", 60, 3)?;
+        let peers: Vec<SocketAddr> = ["1.2.3.4:8888", "1.2.3.4:9999", "[2001:db8::1]:8888"]
+            .iter()
+            .map(|addr| addr.parse())
+            .collect::<Result<_, _>>()?;
+        let answer = |q: &[u8]| parse_response(q, &zone.answer(q, || peers.clone()).unwrap());
+
+        let (rcode, answers, authority) = answer(&query(1, "seed.EXAMPLE.com", TYPE_A));
+        assert_eq!(rcode, RCODE_NO_ERROR);
+        assert_eq!(answers, vec![vec![1, 2, 3, 4]], "Addresses are only listed once.");
+        assert!(authority.is_empty());
+        let (_, answers, _) = answer(&query(2, "seed.example.com", TYPE_AAAA));
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].len(), 16);
+        let (_, answers, _) = answer(&query(3, "seed.example.com", TYPE_TXT));
+        let texts = answers.iter().map(|a| String::from_utf8_lossy(&a[1..])).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["1.2.3.4:8888", "1.2.3.4:9999", "[2001:db8::1]:8888"]);
+
+        // The apex has an SOA and NS record.
+        let ns = b"\x02ns\x07example\x03com\x00".to_vec();
+        assert_eq!(
+            answer(&query(4, "seed.example.com", TYPE_NS)),
+            (RCODE_NO_ERROR, vec![ns.clone()], vec![])
+        );
+        let (_, answers, _) = answer(&query(5, "seed.example.com", TYPE_SOA));
+        assert_eq!(answers.len(), 1);
+        assert!(answers[0].starts_with(&ns));
+        assert!(answers[0][ns.len()..].starts_with(b"\x0ahostmaster\x04seed\x07example\x03com\x00"));
+        assert!(answers[0].ends_with(&60u32.to_be_bytes()), "The negative TTL is the TTL.");
+
+        // Negative answers include the SOA record, and refused queries are not
+        // answered.
+        assert_eq!(
+            answer(&query(6, "a.seed.example.com", TYPE_A)),
+            (RCODE_NAME_ERROR, vec![], vec![TYPE_SOA])
+        );
+        assert_eq!(
+            answer(&query(7, "seed.example.com", 15)),
+            (RCODE_NO_ERROR, vec![], vec![TYPE_SOA])
+        );
+        assert_eq!(answer(&query(8, "example.com", TYPE_A)), (RCODE_REFUSED, vec![], vec![]));
+        let q = query(9, "seed.example.com", TYPE_A);
+        assert_eq!(
+            parse_response(&q, &zone.answer(&q, Vec::new).unwrap()),
+            (RCODE_NO_ERROR, vec![], vec![TYPE_SOA])
+        );
+        // The peers are only obtained for the queries they are served for.
+        for q in [
+            query(6, "a.seed.example.com", TYPE_A),
+            query(7, "seed.example.com", 15),
+            query(8, "example.com", TYPE_A),
+            query(10, "seed.example.com", TYPE_SOA),
+        ] {
+            assert!(zone.answer(&q, || panic!("The peers must not be obtained.")).is_some());
+        }
+
+        // At most `max_records` records, and never more than fits in a message.
+        let many: Vec<SocketAddr> =
+            (0..100).map(|i| SocketAddr::from(([10, 0, 0, i], 8888))).collect();
+        let q = query(11, "seed.example.com", TYPE_A);
+        assert_eq!(parse_response(&q, &zone.answer(&q, || many.clone()).unwrap()).1.len(), 3);
+        let zone = SeedZone::new("seed.example.com", "ns.example.com", 60, 100)?;
+        let q = query(12, "seed.example.com", TYPE_TXT);
+        let response = zone.answer(&q, || many.clone()).unwrap();
+        assert!(response.len() <= MAX_UDP_MESSAGE_SIZE);
+        assert!(!parse_response(&q, &response).1.is_empty());
+
+        // Responses are ignored.
+        let mut q = query(13, "seed.example.com", TYPE_A);
+        q[2] |= 0x80;
+        assert!(zone.answer(&q, || many.clone()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_seed_server() -> anyhow::Result<()> {
+        let zone = SeedZone::new("seed.test", "ns.seed.test", 60, 10)?;
+        let mut seed = DnsSeed::bind("127.0.0.1:0".parse()?, zone)?;
+        let server_addr = seed.local_addr()?;
+        let client = UdpSocket::bind("127.0.0.1:0")?;
+        client.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let q = query(42, "seed.test", TYPE_A);
+        client.send_to(&q, server_addr)?;
+
+        assert!(seed.serve_once(|| vec![SocketAddr::from(([192, 0, 2, 1], 8888))])?);
+        let mut buf = [0u8; MAX_UDP_MESSAGE_SIZE];
+        let len = client.recv(&mut buf)?;
+        assert_eq!(
+            parse_response(&q, &buf[..len]),
+            (RCODE_NO_ERROR, vec![vec![192, 0, 2, 1]], vec![])
+        );
+
+        // Queries beyond the rate limit of a source in a window are ignored.
+        let source = IpAddr::from([192, 0, 2, 1]);
+        let start = Instant::now();
+        for _ in 0..MAX_QUERIES_PER_SOURCE {
+            assert!(seed.within_rate_limit(source, start));
+        }
+        assert!(!seed.within_rate_limit(source, start + RATE_LIMIT_WINDOW / 2));
+        assert!(seed.within_rate_limit(IpAddr::from([192, 0, 2, 2]), start));
+        assert!(seed.within_rate_limit(source, start + RATE_LIMIT_WINDOW));
+        Ok(())
+    }
+
+    #[test]
+    fn test_peers_cache() {
+        let mut cache = PeersCache::default();
+        let peers = vec![SocketAddr::from(([192, 0, 2, 1], 8888))];
+        let start = Instant::now();
+        assert_eq!(cache.get(start, || peers.clone()), peers);
+        assert_eq!(
+            cache.get(start + PEERS_CACHE_DURATION / 2, || panic!("The peers must be cached.")),
+            peers
+        );
+        assert!(cache.get(start + PEERS_CACHE_DURATION, Vec::new).is_empty());
+    }
+}
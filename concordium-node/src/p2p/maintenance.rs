@@ -1,32 +1,40 @@
 //! Node maintenance methods.
 
-use anyhow::Context;
+use anyhow::{ensure, Context};
 use chrono::prelude::*;
+use concordium_base::hashes::BlockHash;
 use crossbeam_channel::{self, Receiver, Sender};
 use mio::{net::TcpListener, Events, Interest, Poll, Registry, Token};
 use nohash_hasher::BuildNoHashHasher;
-use rand::{prelude::SliceRandom, thread_rng, Rng};
+use rand::Rng;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use rkv::{
     backend::{Lmdb, LmdbEnvironment},
     Manager, Rkv,
 };
 
 #[cfg(feature = "network_dump")]
-use crate::dumper::{create_dump_thread, DumpItem};
+use crate::dumper::{create_dump_thread, DumpItem, DumpOptions, DumpRequest};
 use crate::{
     common::{get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, P2PPeer, PeerType},
     configuration::{self as config, Config},
-    connection::{ConnChange, Connection, DeduplicationHashAlgorithm, DeduplicationQueues},
+    connection::{
+        bandwidth::GlobalBandwidth, low_level::probe_noise_handshake, queues::QueuePolicy,
+        ConnChange, Connection, DeduplicationHashAlgorithm, DeduplicationQueues,
+    },
     consensus_ffi::{
         catch_up::PeerList,
         consensus::{ConsensusContainer, Regenesis, CALLBACK_QUEUE},
     },
-    lock_or_die,
-    network::{Buckets, NetworkId, Networks},
+    lock_or_die, logging,
+    network::{
+        address_manager::AddressGroup, buckets::PeerListPolicy, AddressManager, Buckets, NetworkId,
+        Networks,
+    },
     p2p::{
         bans::BanId,
         connectivity::{accept, connect, connection_housekeeping, AcceptFailureReason, SELF_TOKEN},
-        peers::check_peers,
+        peers::{check_peers, StoredPeer},
     },
     plugins::consensus::{check_peer_states, update_peer_list},
     read_or_die, spawn_or_die,
@@ -48,14 +56,126 @@ use std::{
         atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex, RwLock,
     },
-    thread::JoinHandle,
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
+/// The number of peers a bootstrapper probes concurrently.
+const PROBE_CONCURRENCY: usize = 16;
+
+/// Configuration bits of a node that can be changed while the node is
+/// running. Changes replace the whole object so that they are applied
+/// atomically.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DynamicNodeConfig {
+    pub desired_nodes_count:        u16,
+    pub max_allowed_nodes:          u16,
+    /// Maximum number of post-handshake connections to nodes that we initiated.
+    pub max_outbound_nodes:         u16,
+    /// Maximum number of post-handshake connections to nodes that we accepted.
+    pub max_inbound_nodes:          u16,
+    pub relay_broadcast_percentage: f64,
+    pub max_latency:                Option<u64>,
+}
+
+impl DynamicNodeConfig {
+    /// Derive the configuration from the settings. Limits that are not set
+    /// are derived from the desired number of nodes, as on startup.
+    fn from_settings(settings: &DynamicSettings) -> Self {
+        let max_allowed_nodes = settings.max_allowed_nodes.unwrap_or_else(|| {
+            f64::floor(
+                f64::from(settings.desired_nodes)
+                    * (f64::from(settings.max_allowed_nodes_percentage) / 100f64),
+            ) as u16
+        });
+        DynamicNodeConfig {
+            desired_nodes_count: settings.desired_nodes,
+            max_allowed_nodes,
+            max_outbound_nodes: settings.max_outbound_nodes.unwrap_or(settings.desired_nodes),
+            max_inbound_nodes: settings.max_inbound_nodes.unwrap_or(max_allowed_nodes),
+            relay_broadcast_percentage: settings.relay_broadcast_percentage,
+            max_latency: settings.max_latency,
+        }
+    }
+
+    /// Check the constraints between the values, mirroring the validation of
+    /// the configuration on startup.
+    fn validate(&self, hard_connection_limit: u16) -> anyhow::Result<()> {
+        ensure!(
+            self.max_allowed_nodes >= self.desired_nodes_count,
+            "Max allowed nodes ({}) must be greater or equal to desired nodes ({})",
+            self.max_allowed_nodes,
+            self.desired_nodes_count
+        );
+        ensure!(
+            hard_connection_limit >= self.desired_nodes_count,
+            "Hard connection limit ({}) can't be less than desired nodes ({})",
+            hard_connection_limit,
+            self.desired_nodes_count
+        );
+        ensure!(self.max_outbound_nodes > 0, "Max outbound nodes must be positive");
+        ensure!(
+            (0.0..=1.0).contains(&self.relay_broadcast_percentage),
+            "Percentage of peers to relay broadcasted packets to, must be between 0.0 and 1.0"
+        );
+        Ok(())
+    }
+}
+
+/// The configured values the [`DynamicNodeConfig`] is derived from. Unlike
+/// in the derived configuration, the limits that are derived from the desired
+/// number of nodes are `None`, so that they follow changes of it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DynamicSettings {
+    desired_nodes:                u16,
+    max_allowed_nodes:            Option<u16>,
+    max_allowed_nodes_percentage: u16,
+    max_outbound_nodes:           Option<u16>,
+    max_inbound_nodes:            Option<u16>,
+    relay_broadcast_percentage:   f64,
+    max_latency:                  Option<u64>,
+}
+
+impl DynamicSettings {
+    fn from_config(conf: &config::ConnectionConfig) -> Self {
+        DynamicSettings {
+            desired_nodes:                conf.desired_nodes,
+            max_allowed_nodes:            conf.max_allowed_nodes,
+            max_allowed_nodes_percentage: conf.max_allowed_nodes_percentage,
+            max_outbound_nodes:           conf.max_outbound_nodes,
+            max_inbound_nodes:            conf.max_inbound_nodes,
+            relay_broadcast_percentage:   conf.relay_broadcast_percentage,
+            // a maximum latency of 0 disables the limit
+            max_latency:                  conf.max_latency.filter(|&latency| latency > 0),
+        }
+    }
+
+    /// Apply an update, leaving the settings that are not in it unchanged.
+    fn update(&self, update: &config::DynamicConfigUpdate) -> Self {
+        DynamicSettings {
+            desired_nodes:                update.desired_nodes.unwrap_or(self.desired_nodes),
+            max_allowed_nodes:            update.max_allowed_nodes.or(self.max_allowed_nodes),
+            max_allowed_nodes_percentage: self.max_allowed_nodes_percentage,
+            max_outbound_nodes:           update.max_outbound_nodes.or(self.max_outbound_nodes),
+            max_inbound_nodes:            update.max_inbound_nodes.or(self.max_inbound_nodes),
+            relay_broadcast_percentage:   update
+                .relay_broadcast_percentage
+                .unwrap_or(self.relay_broadcast_percentage),
+            max_latency:                  match update.max_latency {
+                Some(latency) => Some(latency).filter(|&latency| latency > 0),
+                None => self.max_latency,
+            },
+        }
+    }
+}
+
 /// Configuration bits applicable to a node.
 pub struct NodeConfig {
     pub no_net: bool,
-    pub desired_nodes_count: u16,
+    /// The configuration bits that can be changed at runtime, see
+    /// [`P2PNode::apply_config_update`], and the settings they are derived
+    /// from.
+    dynamic: RwLock<(DynamicSettings, DynamicNodeConfig)>,
     pub no_bootstrap_dns: bool,
     /// Clear persistent bans on startup.
     pub clear_bans: bool,
@@ -67,15 +187,12 @@ pub struct NodeConfig {
     /// are resolved on startup or when they are added and during execution
     /// we only keep them instead of the domain name.
     pub given_addresses: RwLock<HashSet<SocketAddr>>,
-    pub max_allowed_nodes: u16,
-    pub relay_broadcast_percentage: f64,
     pub poll_interval: u64,
     pub housekeeping_interval: u64,
     pub bootstrapping_interval: u64,
     pub print_peers: bool,
     pub bootstrapper_wait_minimum_peers: u16,
     pub data_dir_path: PathBuf,
-    pub max_latency: Option<u64>,
     pub hard_connection_limit: u16,
     pub conn_requests_batch_limit: u16,
     pub catch_up_batch_limit: i64,
@@ -88,6 +205,28 @@ pub struct NodeConfig {
     pub socket_write_size: usize,
     pub drop_rebroadcast_probability: Option<f64>,
     pub bootstrapper_peer_list_size: usize,
+    /// Peers seen by a bootstrapper within this period (in ms) are preferred
+    /// when it hands out peers.
+    pub bootstrapper_recently_seen_period: u64,
+    /// Maximum number of peers from the same address group a bootstrapper
+    /// hands out in a single peer list.
+    pub bootstrapper_max_peers_per_group: usize,
+    /// Peers advertising an older version are not handed out by a
+    /// bootstrapper.
+    pub bootstrapper_min_peer_version: Option<semver::Version>,
+    /// The interval (in ms) in which a bootstrapper sends at most
+    /// `bootstrapper_peer_lists_per_ip` peer lists to the same IP address.
+    pub bootstrapper_peer_list_interval: u64,
+    /// The number of peer lists a bootstrapper sends to the same IP address
+    /// per `bootstrapper_peer_list_interval`.
+    pub bootstrapper_peer_lists_per_ip: u16,
+    /// The interval (in ms) at which a bootstrapper probes the external
+    /// address of each peer, 0 if it does not probe peers.
+    pub bootstrapper_probe_interval: u64,
+    /// The time (in ms) a bootstrapper waits for a probe to complete.
+    pub bootstrapper_probe_timeout: u64,
+    /// Whether a bootstrapper hands out peers that failed their last probe.
+    pub bootstrapper_share_undialable: bool,
     pub default_network: NetworkId,
     pub socket_so_linger: Option<u16>,
     pub events_queue_size: usize,
@@ -95,6 +234,17 @@ pub struct NodeConfig {
     pub regenesis_arc: Arc<Regenesis>,
     pub max_normal_keep_alive_ms: u64,
     pub clear_persisted_peers: bool,
+    /// Maximum time (in ms) since a persisted peer was last connected before
+    /// it is removed from the persisted peers.
+    pub persisted_peers_max_age_ms: u64,
+    /// Number of peers whose traffic is exported individually.
+    pub traffic_metrics_peers: usize,
+}
+
+impl NodeConfig {
+    /// Obtain the current values of the configuration bits that can be changed
+    /// at runtime.
+    pub fn dynamic(&self) -> DynamicNodeConfig { read_or_die!(self.dynamic).1 }
 }
 
 /// The collection of connections to peer nodes.
@@ -108,20 +258,37 @@ pub struct ConnChanges {
 
 /// The set of objects related to node's connections.
 pub struct ConnectionHandler {
-    pub next_token:           AtomicUsize,
-    pub buckets:              RwLock<Buckets>,
+    pub next_token:            AtomicUsize,
+    pub buckets:               RwLock<Buckets>,
+    /// Addresses of potential peers learned from other peers.
+    pub address_manager:       Mutex<AddressManager>,
     #[cfg(feature = "network_dump")]
-    pub log_dumper:           RwLock<Option<Sender<DumpItem>>>,
-    pub conn_candidates:      Mutex<Connections>,
-    pub connections:          RwLock<Connections>,
-    pub conn_changes:         ConnChanges,
-    pub soft_bans:            RwLock<HashMap<BanId, Instant>>, // (id, expiry)
-    pub networks:             RwLock<Networks>,
-    pub deduplication_queues: DeduplicationQueues,
-    pub last_bootstrap:       AtomicU64,
-    pub last_peer_update:     AtomicU64,
-    pub total_received:       AtomicU64,
-    pub total_sent:           AtomicU64,
+    pub log_dumper:            RwLock<Option<Sender<DumpItem>>>,
+    pub conn_candidates:       Mutex<Connections>,
+    pub connections:           RwLock<Connections>,
+    pub conn_changes:          ConnChanges,
+    pub soft_bans:             RwLock<HashMap<BanId, Instant>>, // (id, expiry)
+    pub networks:              RwLock<Networks>,
+    pub deduplication_queues:  DeduplicationQueues,
+    pub last_bootstrap:        AtomicU64,
+    pub last_peer_update:      AtomicU64,
+    pub total_received:        AtomicU64,
+    pub total_sent:            AtomicU64,
+    /// The bandwidth limits shared by all the connections.
+    pub bandwidth:             Arc<GlobalBandwidth>,
+    /// The configuration of the outbound message queues of the connections.
+    pub queue_policy:          Arc<QueuePolicy>,
+    /// The start (in ms) of the current peer list interval of each requesting
+    /// IP address and the number of peer lists sent to it since, see
+    /// [`P2PNode::register_peer_list_served`]. Only used by bootstrappers.
+    pub peer_lists_served:     Mutex<HashMap<IpAddr, (u64, u16)>>,
+    /// The external addresses of the peers last written to the persisted peers
+    /// and the time (in ms) they were written, see
+    /// [`P2PNode::persist_connected_peers`].
+    pub persisted_connected:   Mutex<(HashSet<SocketAddr>, u64)>,
+    /// The label sets (peer, direction, category) of the per-peer traffic
+    /// metrics exported last, see [`P2PNode::export_peer_traffic`].
+    pub exported_peer_traffic: Mutex<HashSet<(String, &'static str, &'static str)>>,
 }
 
 impl ConnectionHandler {
@@ -143,6 +310,11 @@ impl ConnectionHandler {
         ConnectionHandler {
             next_token: AtomicUsize::new(1),
             buckets: Default::default(),
+            address_manager: Mutex::new(AddressManager::new(
+                conf.connection.max_addresses_per_source,
+                conf.connection.max_local_addresses,
+                conf.connection.max_local_outbound_nodes,
+            )),
             #[cfg(feature = "network_dump")]
             log_dumper: Default::default(),
             conn_candidates: Default::default(),
@@ -155,6 +327,11 @@ impl ConnectionHandler {
             last_peer_update: Default::default(),
             total_received: Default::default(),
             total_sent: Default::default(),
+            bandwidth: Arc::new(GlobalBandwidth::new(&conf.connection)),
+            queue_policy: Arc::new(QueuePolicy::new(&conf.connection)),
+            peer_lists_served: Default::default(),
+            persisted_connected: Default::default(),
+            exported_peer_traffic: Default::default(),
         }
     }
 
@@ -170,8 +347,10 @@ impl ConnectionHandler {
 /// Facilitates the `network_dump` feature.
 #[cfg(feature = "network_dump")]
 pub struct NetworkDumper {
-    switch: Sender<(std::path::PathBuf, bool)>,
-    sender: Sender<crate::dumper::DumpItem>,
+    switch:          Sender<Option<DumpRequest>>,
+    sender:          Sender<crate::dumper::DumpItem>,
+    /// The filters and limits of dumps started without explicit options.
+    default_options: DumpOptions,
 }
 
 #[cfg(feature = "network_dump")]
@@ -179,11 +358,13 @@ impl NetworkDumper {
     fn new(ip: IpAddr, id: P2PNodeId, config: &Config) -> Self {
         let (dump_tx, dump_rx) = crossbeam_channel::bounded(config::DUMP_QUEUE_DEPTH);
         let (act_tx, act_rx) = crossbeam_channel::bounded(config::DUMP_SWITCH_QUEUE_DEPTH);
-        create_dump_thread(ip, id, dump_rx, act_rx, config.common.data_dir.clone());
+        let local_addr = SocketAddr::new(ip, config.common.listen_port);
+        create_dump_thread(local_addr, id, dump_rx, act_rx, config.common.data_dir.clone());
 
         Self {
-            switch: act_tx,
-            sender: dump_tx,
+            switch:          act_tx,
+            sender:          dump_tx,
+            default_options: DumpOptions::from(&config.dump),
         }
     }
 }
@@ -319,23 +500,19 @@ impl P2PNode {
 
         let given_addresses = RwLock::new(parse_config_nodes(&conf.connection)?);
 
+        let dynamic_settings = DynamicSettings::from_config(&conf.connection);
+
         let config = NodeConfig {
             no_net: conf.cli.no_network,
-            desired_nodes_count: conf.connection.desired_nodes,
+            dynamic: RwLock::new((
+                dynamic_settings,
+                DynamicNodeConfig::from_settings(&dynamic_settings),
+            )),
             no_bootstrap_dns: conf.connection.no_bootstrap_dns,
             clear_bans: conf.connection.clear_bans,
             disallow_multiple_peers_on_ip: conf.connection.disallow_multiple_peers_on_ip,
             bootstrap_nodes: conf.connection.bootstrap_nodes.clone(),
             given_addresses,
-            max_allowed_nodes: if let Some(max) = conf.connection.max_allowed_nodes {
-                max
-            } else {
-                f64::floor(
-                    f64::from(conf.connection.desired_nodes)
-                        * (f64::from(conf.connection.max_allowed_nodes_percentage) / 100f64),
-                ) as u16
-            },
-            relay_broadcast_percentage: conf.connection.relay_broadcast_percentage,
             poll_interval: conf.cli.poll_interval,
             housekeeping_interval: conf.connection.housekeeping_interval,
             bootstrapping_interval: conf.connection.bootstrapping_interval,
@@ -345,7 +522,6 @@ impl P2PNode {
                 PeerType::Node => 0,
             },
             data_dir_path: conf.common.data_dir.clone(),
-            max_latency: conf.connection.max_latency,
             conn_requests_batch_limit: conf.connection.conn_requests_batch_limit,
             hard_connection_limit: conf.connection.hard_connection_limit,
             catch_up_batch_limit: conf.connection.catch_up_batch_limit,
@@ -365,6 +541,14 @@ impl P2PNode {
                 _ => None,
             },
             bootstrapper_peer_list_size: conf.bootstrapper.peer_list_size,
+            bootstrapper_recently_seen_period: conf.bootstrapper.recently_seen_period,
+            bootstrapper_max_peers_per_group: conf.bootstrapper.max_peers_per_group,
+            bootstrapper_min_peer_version: conf.bootstrapper.min_peer_version.clone(),
+            bootstrapper_peer_list_interval: conf.bootstrapper.peer_list_interval,
+            bootstrapper_peer_lists_per_ip: conf.bootstrapper.peer_lists_per_ip,
+            bootstrapper_probe_interval: conf.bootstrapper.probe_interval,
+            bootstrapper_probe_timeout: conf.bootstrapper.probe_timeout,
+            bootstrapper_share_undialable: conf.bootstrapper.share_undialable,
             default_network: NetworkId::from(conf.common.network_ids[0]), // always present
             socket_so_linger: conf.connection.socket_so_linger,
             events_queue_size: conf.connection.events_queue_size,
@@ -372,6 +556,8 @@ impl P2PNode {
             regenesis_arc,
             max_normal_keep_alive_ms: conf.connection.max_normal_keep_alive * 1000,
             clear_persisted_peers: conf.connection.clear_persisted_peers,
+            persisted_peers_max_age_ms: conf.connection.persisted_peers_max_age * 1000,
+            traffic_metrics_peers: conf.prometheus.prometheus_traffic_peers,
         };
 
         let connection_handler = ConnectionHandler::new(conf);
@@ -477,6 +663,53 @@ impl P2PNode {
     #[inline]
     pub fn buckets(&self) -> &RwLock<Buckets> { &self.connection_handler.buckets }
 
+    /// The policy with which a bootstrapper selects the peers it shares, given
+    /// its current genesis block hashes.
+    pub fn peer_list_policy<'a>(&'a self, genesis_blocks: &'a [BlockHash]) -> PeerListPolicy<'a> {
+        PeerListPolicy {
+            recently_seen_period: self.config.bootstrapper_recently_seen_period,
+            max_peers_per_group: self.config.bootstrapper_max_peers_per_group,
+            min_peer_version: self.config.bootstrapper_min_peer_version.as_ref(),
+            genesis_blocks,
+            share_undialable: self.config.bootstrapper_share_undialable,
+            require_dialable: false,
+        }
+    }
+
+    /// Record that a peer list is sent to a requester connecting from the given
+    /// IP address, unless the configured number of peer lists was already sent
+    /// to that address within the configured interval, in which case `false`
+    /// is returned. Requesters are identified by the address they connect from
+    /// rather than by anything they announce about themselves, so that they
+    /// cannot get around the limit by reconnecting.
+    pub fn register_peer_list_served(&self, requester: IpAddr) -> bool {
+        let interval = self.config.bootstrapper_peer_list_interval;
+        if interval == 0 {
+            return true;
+        }
+        let now = get_current_stamp();
+        let mut served = lock_or_die!(self.connection_handler.peer_lists_served);
+        let (window_start, count) = served.entry(requester).or_insert((now, 0));
+        if now >= *window_start + interval {
+            *window_start = now;
+            *count = 0;
+        }
+        if *count >= self.config.bootstrapper_peer_lists_per_ip {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Forget the requesters whose peer list limit has expired, see
+    /// [`P2PNode::register_peer_list_served`].
+    pub fn prune_peer_lists_served(&self) {
+        let served_before =
+            get_current_stamp().saturating_sub(self.config.bootstrapper_peer_list_interval);
+        lock_or_die!(self.connection_handler.peer_lists_served)
+            .retain(|_, &mut (window_start, _)| window_start > served_before);
+    }
+
     /// Notify the node handler that a connection needs to undergo a major
     /// change.
     #[inline]
@@ -486,11 +719,28 @@ impl P2PNode {
         }
     }
 
-    /// Activate the network dump feature.
+    /// Activate the network dump feature, using the filters and limits from
+    /// the node configuration.
     #[cfg(feature = "network_dump")]
     pub fn activate_dump(&self, path: &str, raw: bool) -> anyhow::Result<()> {
+        self.activate_dump_with_options(path, raw, self.network_dumper.default_options.clone())
+    }
+
+    /// Activate the network dump feature with the given filters and limits.
+    /// A dump that is already running is stopped first.
+    #[cfg(feature = "network_dump")]
+    pub fn activate_dump_with_options(
+        &self,
+        path: &str,
+        raw: bool,
+        options: DumpOptions,
+    ) -> anyhow::Result<()> {
         let path = std::path::PathBuf::from(path);
-        self.network_dumper.switch.send((path, raw))?;
+        self.network_dumper.switch.send(Some(DumpRequest {
+            path,
+            raw,
+            options,
+        }))?;
         self.dump_start(self.network_dumper.sender.clone());
         Ok(())
     }
@@ -498,8 +748,7 @@ impl P2PNode {
     /// Deactivate the network dump feature.
     #[cfg(feature = "network_dump")]
     pub fn stop_dump(&self) -> anyhow::Result<()> {
-        let path = std::path::PathBuf::new();
-        self.network_dumper.switch.send((path, false))?;
+        self.network_dumper.switch.send(None)?;
         self.dump_stop();
         Ok(())
     }
@@ -514,6 +763,59 @@ impl P2PNode {
     #[cfg(feature = "network_dump")]
     pub fn dump_stop(&self) { *write_or_die!(self.connection_handler.log_dumper) = None; }
 
+    /// Apply an update of the runtime configuration. The update is validated
+    /// and then applied atomically, and each changed value is logged. Limits
+    /// that were not configured explicitly are derived again from the desired
+    /// number of nodes. If the update is invalid, nothing is changed.
+    pub fn apply_config_update(&self, update: config::DynamicConfigUpdate) -> anyhow::Result<()> {
+        {
+            let mut dynamic = write_or_die!(self.config.dynamic);
+            let (old_settings, old) = *dynamic;
+            let new_settings = old_settings.update(&update);
+            let new = DynamicNodeConfig::from_settings(&new_settings);
+            new.validate(self.config.hard_connection_limit)
+                .context("The configuration update was rejected")?;
+
+            macro_rules! log_change {
+                ($($field:ident),*) => {
+                    $(if old.$field != new.$field {
+                        info!(
+                            "Changing {} from {:?} to {:?}",
+                            stringify!($field),
+                            old.$field,
+                            new.$field
+                        );
+                    })*
+                };
+            }
+            log_change!(
+                desired_nodes_count,
+                max_allowed_nodes,
+                max_outbound_nodes,
+                max_inbound_nodes,
+                relay_broadcast_percentage,
+                max_latency
+            );
+            *dynamic = (new_settings, new);
+        }
+
+        if let Some(level) = update.log_level {
+            if level != logging::level() {
+                if logging::level_is_dynamic() {
+                    info!("Changing the log level from {} to {}", logging::level(), level);
+                    logging::set_level(level);
+                } else {
+                    warn!(
+                        "The log level is not changed to {}, since it is given by the LOG_LEVEL \
+                         environment variable or a log configuration.",
+                        level
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
     /// Get the node's client version.
     pub fn get_version(&self) -> String { crate::VERSION.to_string() }
 
@@ -728,6 +1030,9 @@ pub fn spawn(
                 {
                     let attempted_bootstrap = connection_housekeeping(&node);
                     if node.peer_type() != PeerType::Bootstrapper {
+                        if let Err(e) = node.persist_connected_peers() {
+                            error!("Could not persist the connected peers: {}", e);
+                        }
                         node.measure_connection_latencies()
                     }
 
@@ -736,6 +1041,8 @@ pub fn spawn(
                     if let Err(e) = node.measure_throughput() {
                         error!("Could not measure throughput: {}", e);
                     }
+                    node.export_peer_traffic(&peer_stat_list);
+                    node.prune_peer_lists_served();
 
                     log_time = Instant::now();
                     iterations_since_housekeeping = 0;
@@ -751,6 +1058,7 @@ pub fn spawn(
                 write_or_die!(node.buckets()).clean_buckets(
                     node.config.timeout_bucket_entry_period,
                     &node.stats.peer_bucket_size,
+                    &node.stats.peer_bucket_probe_results,
                 );
                 last_buckets_cleaned = Instant::now();
             }
@@ -777,6 +1085,64 @@ pub fn spawn(
 
     // Register info about thread into P2PNode.
     write_or_die!(node_ref.threads).push(poll_thread);
+
+    if node_ref.peer_type() == PeerType::Bootstrapper
+        && node_ref.config.bootstrapper_probe_interval > 0
+    {
+        let probe_thread = spawn_peer_probes(node_ref);
+        write_or_die!(node_ref.threads).push(probe_thread);
+    }
+}
+
+/// Spawn the thread with which a bootstrapper probes the external addresses of
+/// the peers in its buckets, so that it can avoid handing out peers that
+/// cannot be connected to, e.g., because they are behind a NAT.
+fn spawn_peer_probes(node_ref: &Arc<P2PNode>) -> JoinHandle<()> {
+    let node = Arc::clone(node_ref);
+    spawn_or_die!("peer probes", {
+        let mut pool = None;
+        let timeout = Duration::from_millis(node.config.bootstrapper_probe_timeout);
+
+        while !node.is_network_stopped() {
+            let pool = match pool {
+                Some(ref pool) => pool,
+                None => {
+                    match rayon::ThreadPoolBuilder::new().num_threads(PROBE_CONCURRENCY).build() {
+                        Ok(new_pool) => pool.insert(new_pool),
+                        Err(e) => {
+                            error!("Could not create the threads for probing peers: {}", e);
+                            thread::sleep(Duration::from_secs(1));
+                            continue;
+                        }
+                    }
+                }
+            };
+            let candidates = write_or_die!(node.buckets())
+                .take_probe_candidates(PROBE_CONCURRENCY, node.config.bootstrapper_probe_interval);
+            if candidates.is_empty() {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+
+            let results = pool.install(|| {
+                candidates
+                    .into_par_iter()
+                    .map(|addr| match probe_noise_handshake(addr, timeout) {
+                        Ok(()) => (addr, true),
+                        Err(e) => {
+                            debug!("Peer at {} failed the probe: {}", addr, e);
+                            (addr, false)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            });
+            let mut buckets = write_or_die!(node.buckets());
+            for (addr, dialable) in results {
+                buckets.record_probe(addr, dialable);
+            }
+            buckets.export_probe_results(&node.stats.peer_bucket_probe_results);
+        }
+    })
 }
 
 /// Process a change to the set of connections.
@@ -808,11 +1174,22 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                     existing.remote_addr() == addr || existing.remote_peer.external_addr() == addr
                 });
                 if !is_connected {
+                    let stored_peer = StoredPeer::connected(
+                        &conn.remote_peer,
+                        conn.get_latency(),
+                        get_current_stamp(),
+                    );
+                    // a successful outbound connection shows that the address is reachable
+                    if conn.is_outbound() && conn.remote_peer_type() == PeerType::Node {
+                        if let Some(peer) = conn.remote_peer.peer() {
+                            lock_or_die!(node.connection_handler.address_manager).mark_good(peer);
+                        }
+                    }
                     conns.insert(conn.token(), conn);
                     node.bump_last_peer_update();
                     // insert the peer in the lmdb store so the node can
                     // reconnect to the peer if the node restarts.
-                    if let Err(err) = node.persist_peer(addr) {
+                    if let Err(err) = node.persist_peer(addr, stored_peer) {
                         error!("Could not persist peer to database {}", err);
                     }
                 } else {
@@ -820,34 +1197,55 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                 }
             }
         }
-        ConnChange::NewPeers(mut peers) => {
-            let mut new_peers = 0;
-            let current_peers = node.get_peer_stats(Some(PeerType::Node));
-
-            let curr_peer_count = current_peers.len();
-
-            // Shuffle the peers we received try to discover more useful peers over time
-            // and not get stuck continuously connecting to useless ones, and then dropping
-            // connections.
-            peers.shuffle(&mut thread_rng());
+        ConnChange::ConnectToKnownAddresses => {
+            let curr_peer_count = node.get_peer_stats(Some(PeerType::Node)).len();
+            let outbound_count = node.get_connection_counts().outbound;
+            let dynamic_config = node.config.dynamic();
+            let wanted = (dynamic_config.desired_nodes_count as usize)
+                .saturating_sub(curr_peer_count)
+                .min(usize::from(dynamic_config.max_outbound_nodes).saturating_sub(outbound_count));
+            if wanted == 0 {
+                return;
+            }
 
-            // Try to connect to each peer in turn.
-            // If we are already connected to a peer, this will fail.
-            for peer in peers {
-                if new_peers + curr_peer_count >= node.config.desired_nodes_count as usize {
-                    break;
+            // Avoid the address groups of our existing outbound connections, so that
+            // a single party cannot easily control all our outbound peers.
+            let mut used_groups = HashSet::new();
+            let mut local_outbound = 0;
+            let mut exclude = HashSet::new();
+            for conn in lock_or_die!(node.conn_candidates())
+                .values()
+                .chain(read_or_die!(node.connections()).values())
+            {
+                if conn.is_outbound() {
+                    let group = AddressGroup::from(conn.remote_addr().ip());
+                    if group == AddressGroup::Local {
+                        local_outbound += 1;
+                    }
+                    used_groups.insert(group);
                 }
+                exclude.insert(conn.remote_addr());
+                exclude.insert(conn.remote_peer.external_addr());
+            }
 
-                trace!("Got info for peer {} ({})", peer.id, peer.addr);
+            let selected = lock_or_die!(node.connection_handler.address_manager).select(
+                wanted,
+                &used_groups,
+                local_outbound,
+                &exclude,
+            );
+            for peer in selected {
+                trace!("Selected known address {} ({})", peer.addr, peer.id);
+                lock_or_die!(node.connection_handler.address_manager).mark_attempt(peer.addr);
                 if let Err(e) = connect(node, PeerType::Node, peer.addr, Some(peer.id), true) {
-                    debug!("Could not connect to discovered peer {}", e);
-                } else {
-                    new_peers += 1;
+                    debug!("Could not connect to known address {}", e);
                 }
             }
         }
         ConnChange::ExpulsionByToken(token) => {
             if let Some((is_conn, remote_peer)) = node.remove_connection(token) {
+                lock_or_die!(node.connection_handler.address_manager)
+                    .forget(remote_peer.external_addr());
                 let ip = remote_peer.addr.ip();
                 warn!("Soft-banning {} due to a breach of protocol", ip);
                 write_or_die!(node.connection_handler.soft_bans).insert(
@@ -877,6 +1275,12 @@ fn process_conn_change(node: &Arc<P2PNode>, conn_change: ConnChange) {
                 }
             }
         }
+        ConnChange::ClosedByPeer(token) => {
+            trace!("Removing connection with token {:?} closed by the peer", token);
+            // The persisted entry of the peer, if any, is kept. It ages and is eventually
+            // pruned if we do not connect to the peer again.
+            node.remove_connection(token);
+        }
         ConnChange::RemoveAllByTokens(tokens) => {
             trace!("Removing connections with tokens {:?}", tokens);
             let (_, removed_connected_peers) = node.remove_connections(&tokens);
@@ -936,3 +1340,87 @@ fn parse_config_nodes(conf: &config::ConnectionConfig) -> anyhow::Result<HashSet
     }
     Ok(out)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> DynamicSettings {
+        DynamicSettings {
+            desired_nodes:                7,
+            max_allowed_nodes:            None,
+            max_allowed_nodes_percentage: 150,
+            max_outbound_nodes:           None,
+            max_inbound_nodes:            None,
+            relay_broadcast_percentage:   1.0,
+            max_latency:                  Some(500),
+        }
+    }
+
+    fn limits(settings: &DynamicSettings) -> (u16, u16, u16) {
+        let config = DynamicNodeConfig::from_settings(settings);
+        (config.max_allowed_nodes, config.max_outbound_nodes, config.max_inbound_nodes)
+    }
+
+    #[test]
+    fn test_dynamic_settings_update() {
+        let initial = settings();
+        assert_eq!(limits(&initial), (10, 7, 10));
+
+        // the limits that are not configured follow the desired nodes
+        let updated = initial.update(&config::DynamicConfigUpdate {
+            desired_nodes: Some(20),
+            ..Default::default()
+        });
+        assert_eq!(limits(&updated), (30, 20, 30));
+        assert!(DynamicNodeConfig::from_settings(&updated).validate(50).is_ok());
+
+        // configured limits are kept
+        let updated = updated.update(&config::DynamicConfigUpdate {
+            max_allowed_nodes: Some(25),
+            max_outbound_nodes: Some(5),
+            ..Default::default()
+        });
+        assert_eq!(limits(&updated), (25, 5, 25));
+        let updated = updated.update(&config::DynamicConfigUpdate {
+            desired_nodes: Some(22),
+            ..Default::default()
+        });
+        assert_eq!(limits(&updated), (25, 5, 25));
+        assert_eq!(updated.max_latency, Some(500));
+
+        // a maximum latency of 0 removes the limit
+        let updated = updated.update(&config::DynamicConfigUpdate {
+            max_latency: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(DynamicNodeConfig::from_settings(&updated).max_latency, None);
+    }
+
+    #[test]
+    fn test_dynamic_config_validation() {
+        let valid = |update: config::DynamicConfigUpdate| {
+            DynamicNodeConfig::from_settings(&settings().update(&update)).validate(50).is_ok()
+        };
+        assert!(valid(Default::default()));
+        // more desired nodes than the configured maximum
+        assert!(!valid(config::DynamicConfigUpdate {
+            desired_nodes: Some(12),
+            max_allowed_nodes: Some(10),
+            ..Default::default()
+        }));
+        // more desired nodes than the hard connection limit
+        assert!(!valid(config::DynamicConfigUpdate {
+            desired_nodes: Some(51),
+            ..Default::default()
+        }));
+        assert!(!valid(config::DynamicConfigUpdate {
+            max_outbound_nodes: Some(0),
+            ..Default::default()
+        }));
+        assert!(!valid(config::DynamicConfigUpdate {
+            relay_broadcast_percentage: Some(1.5),
+            ..Default::default()
+        }));
+    }
+}
@@ -2,6 +2,7 @@
 
 pub mod bans;
 pub mod connectivity;
+pub mod dns_seed;
 pub mod maintenance;
 pub mod peers;
 
@@ -11,6 +12,7 @@ pub use self::maintenance::{Connections, P2PNode};
 mod tests {
     use crate::{
         common::{p2p_peer::RemotePeerId, PeerType},
+        configuration::DynamicConfigUpdate,
         p2p::bans::PersistedBanId,
         test_utils::*,
     };
@@ -63,6 +65,36 @@ mod tests {
 
         stop_node_delete_dirs(dp, node);
 
+        Ok(())
+    }
+    #[test]
+    fn test_apply_config_update() -> anyhow::Result<()> {
+        let port = next_available_port();
+        let (node, dp) = make_node_and_sync(port, vec![100], PeerType::Node, vec![])?;
+        let initial = node.config.dynamic();
+
+        // an invalid update changes nothing
+        let update = DynamicConfigUpdate {
+            desired_nodes: Some(20),
+            relay_broadcast_percentage: Some(2.0),
+            ..Default::default()
+        };
+        assert!(node.apply_config_update(update).is_err());
+        assert_eq!(node.config.dynamic(), initial);
+
+        let update = DynamicConfigUpdate {
+            desired_nodes: Some(20),
+            ..Default::default()
+        };
+        node.apply_config_update(update)?;
+        let updated = node.config.dynamic();
+        assert_eq!(updated.desired_nodes_count, 20);
+        assert_eq!(updated.max_allowed_nodes, 30);
+        assert_eq!(updated.max_outbound_nodes, 20);
+        assert_eq!(updated.relay_broadcast_percentage, initial.relay_broadcast_percentage);
+
+        stop_node_delete_dirs(dp, node);
+
         Ok(())
     }
 }
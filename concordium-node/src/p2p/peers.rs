@@ -1,23 +1,55 @@
 //! Peer handling.
 
 use crate::{
-    common::{get_current_stamp, p2p_peer::RemotePeerId, PeerStats, PeerType},
-    connection::Connection,
-    netmsg,
+    common::{
+        get_current_stamp, p2p_peer::RemotePeerId, P2PNodeId, PeerStats, PeerType, RemotePeer,
+    },
+    connection::{traffic::TrafficCounts, Connection},
+    lock_or_die, netmsg,
     network::NetworkRequest,
     p2p::{connectivity::connect, maintenance::attempt_bootstrap, P2PNode},
     read_or_die,
 };
-use anyhow::ensure;
+use anyhow::{bail, ensure};
+use byteorder::{ReadBytesExt, WriteBytesExt};
 use chrono::Utc;
+use concordium_base::common::{Buffer, Deserial, Serial};
 use prometheus::core::Atomic;
 use rkv::{StoreOptions, Value};
 use std::{
+    cmp::Reverse,
+    collections::HashSet,
     net::SocketAddr,
     sync::{atomic::Ordering, Arc},
 };
 
+/// The number of post-handshake connections to nodes, split by the side that
+/// initiated the connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    /// Connections that we initiated.
+    pub outbound: usize,
+    /// Connections that the peer initiated.
+    pub inbound:  usize,
+}
+
 impl P2PNode {
+    /// Obtain the number of inbound and outbound connections to nodes (as
+    /// opposed to bootstrappers).
+    pub fn get_connection_counts(&self) -> ConnectionCounts {
+        let mut counts = ConnectionCounts::default();
+        for conn in read_or_die!(self.connections()).values() {
+            if conn.remote_peer_type() == PeerType::Node {
+                if conn.is_outbound() {
+                    counts.outbound += 1;
+                } else {
+                    counts.inbound += 1;
+                }
+            }
+        }
+        counts
+    }
+
     /// Obtain the list of statistics from all the peers, optionally of a
     /// specific peer type.
     pub fn get_peer_stats(&self, peer_type: Option<PeerType>) -> Vec<PeerStats> {
@@ -83,6 +115,35 @@ impl P2PNode {
         Ok(())
     }
 
+    /// Exports the traffic of the connected peers to the prometheus metrics.
+    /// Only the peers with the most traffic are exported individually, which
+    /// bounds the number of exported series. The series of peers that are no
+    /// longer exported are removed, while the others are updated in place so
+    /// that a scrape never sees them missing.
+    pub fn export_peer_traffic(&self, peer_stats: &[PeerStats]) {
+        let mut exported = HashSet::new();
+        for (peer, traffic) in group_peer_traffic(peer_stats, self.config.traffic_metrics_peers) {
+            for (direction, category, count) in traffic.iter() {
+                let labels = [peer.as_str(), direction.label(), category.label()];
+                self.stats
+                    .peer_traffic_messages
+                    .with_label_values(&labels)
+                    .set(count.messages as i64);
+                self.stats.peer_traffic_bytes.with_label_values(&labels).set(count.bytes as i64);
+                exported.insert((peer.clone(), direction.label(), category.label()));
+            }
+        }
+
+        let mut previously_exported = lock_or_die!(self.connection_handler.exported_peer_traffic);
+        for (peer, direction, category) in previously_exported.difference(&exported) {
+            let labels = [peer.as_str(), *direction, *category];
+            // removing only fails if the series does not exist
+            let _ = self.stats.peer_traffic_messages.remove_label_values(&labels);
+            let _ = self.stats.peer_traffic_bytes.remove_label_values(&labels);
+        }
+        *previously_exported = exported;
+    }
+
     fn send_get_peers(&self) {
         let request =
             NetworkRequest::GetPeers(read_or_die!(self.networks()).iter().copied().collect());
@@ -120,7 +181,18 @@ impl P2PNode {
 /// work and the strange looking messages "already connected to ..." in the
 /// logs.
 pub fn check_peers(node: &Arc<P2PNode>, peer_stats: &[PeerStats], attempted_bootstrap: bool) {
-    debug!("I currently have {}/{} peers", peer_stats.len(), node.config.max_allowed_nodes);
+    let dynamic_config = node.config.dynamic();
+    debug!("I currently have {}/{} peers", peer_stats.len(), dynamic_config.max_allowed_nodes);
+    if node.self_peer.peer_type == PeerType::Node {
+        let counts = node.get_connection_counts();
+        debug!(
+            "Outbound nodes: {}/{}, inbound nodes: {}/{}",
+            counts.outbound,
+            dynamic_config.max_outbound_nodes,
+            counts.inbound,
+            dynamic_config.max_inbound_nodes
+        );
+    }
 
     if node.config.print_peers {
         node.print_stats(peer_stats);
@@ -129,7 +201,7 @@ pub fn check_peers(node: &Arc<P2PNode>, peer_stats: &[PeerStats], attempted_boot
     if node.self_peer.peer_type == PeerType::Node {
         let node_count = peer_stats.iter().filter(|peer| peer.peer_type == PeerType::Node).count();
 
-        if !node.config.no_net && node_count < node.config.desired_nodes_count as usize {
+        if !node.config.no_net && node_count < dynamic_config.desired_nodes_count as usize {
             if peer_stats.is_empty() {
                 if !attempted_bootstrap {
                     if !node.config.no_bootstrap_dns {
@@ -150,6 +222,27 @@ pub fn check_peers(node: &Arc<P2PNode>, peer_stats: &[PeerStats], attempted_boot
     }
 }
 
+/// Groups the traffic of the peers by their node id, keeping only the `top`
+/// peers with the most bytes exchanged separate and summing up the traffic of
+/// the rest under `"other"`.
+fn group_peer_traffic(peer_stats: &[PeerStats], top: usize) -> Vec<(String, TrafficCounts)> {
+    let mut peers = peer_stats.iter().collect::<Vec<_>>();
+    peers.sort_by_key(|peer| Reverse(peer.traffic.total_bytes()));
+    let mut grouped = peers
+        .iter()
+        .take(top)
+        .map(|peer| (peer.self_id.to_string(), peer.traffic))
+        .collect::<Vec<_>>();
+    if peers.len() > top {
+        let mut other = TrafficCounts::default();
+        for peer in &peers[top..] {
+            other.add(&peer.traffic);
+        }
+        grouped.push(("other".to_owned(), other));
+    }
+    grouped
+}
+
 /// Calculate the average bytes bps (Bytes per second) received and sent during
 /// the time `delta` (specified in milliseconds).
 fn calculate_average_throughput(
@@ -176,40 +269,274 @@ fn calculate_average_throughput(
 /// The lmdb store name of the persisted peers.
 const PEERS_STORE_NAME: &str = "peers";
 
-/// Try connect to previously connected peers if any.
+/// Maximum time (in ms) between two refreshes of the persisted entries of the
+/// connected peers, see [`P2PNode::persist_connected_peers`].
+const PERSISTED_PEERS_REFRESH_INTERVAL: u64 = 3_600_000;
+
+/// Version of the serialization format of [`StoredPeer`].
+const STORED_PEER_VERSION: u8 = 0;
+
+/// An entry in the persisted peer database. Entries are keyed by the external
+/// address of the peer, i.e., the address where it accepts connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoredPeer {
+    /// The id the peer advertised in its last handshake with us, if known.
+    pub id:           Option<P2PNodeId>,
+    pub peer_type:    PeerType,
+    /// Timestamp (in ms) at which we were last connected to the peer.
+    pub last_success: u64,
+    /// Number of times the peer was dialed since the last successful
+    /// handshake with it.
+    pub failures:     u32,
+    /// The latency (in ms) last measured on the connection to the peer. 0 if it
+    /// was never measured.
+    pub latency:      u64,
+}
+
+impl StoredPeer {
+    /// Construct an entry for a peer we are connected to at time `now`.
+    pub fn connected(peer: &RemotePeer, latency: u64, now: u64) -> Self {
+        Self {
+            id: peer.self_id,
+            peer_type: peer.peer_type,
+            last_success: now,
+            failures: 0,
+            latency,
+        }
+    }
+
+    /// Construct an entry for a peer that was persisted before entries carried
+    /// any metadata, migrated at time `now`. Such peers were connected when
+    /// they were persisted, so they are treated as seen at the time of the
+    /// migration and age from then on.
+    fn legacy(now: u64) -> Self {
+        Self {
+            id:           None,
+            peer_type:    PeerType::Node,
+            last_success: now,
+            failures:     0,
+            latency:      0,
+        }
+    }
+
+    /// The score of the entry at time `now`, in the range (0, 1]. Peers that
+    /// were seen recently, that did not fail to connect, and that have low
+    /// latency score higher. The score halves with each failure and decays
+    /// hyperbolically with the time since the last success (in hours) and the
+    /// latency (in seconds).
+    pub fn score(&self, now: u64) -> f64 {
+        let age_hours = now.saturating_sub(self.last_success) as f64 / 3_600_000.0;
+        let latency_secs = self.latency as f64 / 1000.0;
+        0.5f64.powi(self.failures.min(64) as i32) / ((1.0 + age_hours) * (1.0 + latency_secs))
+    }
+}
+
+impl Serial for StoredPeer {
+    fn serial<W: Buffer + WriteBytesExt>(&self, target: &mut W) {
+        STORED_PEER_VERSION.serial(target);
+        match self.id {
+            None => 0u8.serial(target),
+            Some(id) => {
+                1u8.serial(target);
+                id.serial(target);
+            }
+        }
+        match self.peer_type {
+            PeerType::Node => 0u8.serial(target),
+            PeerType::Bootstrapper => 1u8.serial(target),
+        }
+        self.last_success.serial(target);
+        self.failures.serial(target);
+        self.latency.serial(target);
+    }
+}
+
+impl Deserial for StoredPeer {
+    fn deserial<R: ReadBytesExt>(source: &mut R) -> anyhow::Result<Self> {
+        let version = u8::deserial(source)?;
+        ensure!(version == STORED_PEER_VERSION, "Unsupported stored peer version {}.", version);
+        let id = match u8::deserial(source)? {
+            0 => None,
+            1 => Some(P2PNodeId::deserial(source)?),
+            tag => bail!("Invalid tag for the stored peer id: {}.", tag),
+        };
+        let peer_type = match u8::deserial(source)? {
+            0 => PeerType::Node,
+            1 => PeerType::Bootstrapper,
+            tag => bail!("Invalid tag for the stored peer type: {}.", tag),
+        };
+        Ok(Self {
+            id,
+            peer_type,
+            last_success: u64::deserial(source)?,
+            failures: u32::deserial(source)?,
+            latency: u64::deserial(source)?,
+        })
+    }
+}
+
+/// Try connect to previously connected peers if any. Entries that are older
+/// than the configured maximum age are pruned first, and then at most
+/// `desired_nodes_count` of the remaining peers are dialed, best scoring
+/// first.
 /// Note that as opposed to [`connect_to_config_nodes`] this function respects
 /// the maximum peers configured for the node.
 pub fn connect_to_stored_nodes(node: &Arc<P2PNode>) -> anyhow::Result<()> {
-    let Ok(kvs_env) = node.kvs.read() else {
-        anyhow::bail!("could not read previously connected peers: cannot obtain lock for the kvs");
-    };
-    let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
-    let peers_reader = kvs_env.read()?;
-    let peers_iter = peers_store.iter_start(&peers_reader)?;
-    for entry in peers_iter {
-        let (peer_bytes, _expiry) = entry?;
-        let stored_peer: SocketAddr = serde_json::from_slice(peer_bytes)?;
-        if let Err(e) = connect(node, PeerType::Node, stored_peer, None, true) {
-            warn!("could not connect to previously connected peer {}", e);
+    let pruned = node.prune_persisted_peers(node.config.persisted_peers_max_age_ms)?;
+    if pruned > 0 {
+        debug!("Pruned {} stale entries from the persisted peers", pruned);
+    }
+
+    let now = get_current_stamp();
+    let mut stored_peers = node
+        .get_persisted_peers()?
+        .into_iter()
+        .filter(|(_, stored_peer)| stored_peer.peer_type == PeerType::Node)
+        .collect::<Vec<_>>();
+    stored_peers.sort_by(|(_, a), (_, b)| b.score(now).total_cmp(&a.score(now)));
+
+    // Each dial counts as a failure until the handshake with the peer concludes,
+    // at which point the entry is overwritten.
+    let desired_nodes_count = usize::from(node.config.dynamic().desired_nodes_count);
+    let mut dialed = Vec::new();
+    for (addr, mut stored_peer) in stored_peers {
+        if dialed.len() >= desired_nodes_count {
+            break;
+        }
+        match connect(node, PeerType::Node, addr, stored_peer.id, true) {
+            Ok(()) => {
+                stored_peer.failures = stored_peer.failures.saturating_add(1);
+                dialed.push((addr, stored_peer));
+            }
+            Err(e) => warn!("could not connect to previously connected peer {}", e),
         }
     }
-    Ok(())
+    node.persist_peers(&dialed)
 }
 
 impl P2PNode {
-    /// Persist the [`SocketAddr`] of a peer.
-    pub fn persist_peer(&self, peer_addr: SocketAddr) -> anyhow::Result<()> {
+    /// Persist the entry of a peer with the given external address, replacing
+    /// any existing entry.
+    pub fn persist_peer(
+        &self,
+        peer_addr: SocketAddr,
+        stored_peer: StoredPeer,
+    ) -> anyhow::Result<()> {
+        self.persist_peers(&[(peer_addr, stored_peer)])
+    }
+
+    /// Persist the entries of the given peers in a single transaction.
+    pub fn persist_peers(&self, stored_peers: &[(SocketAddr, StoredPeer)]) -> anyhow::Result<()> {
+        if stored_peers.is_empty() {
+            return Ok(());
+        }
         let Ok(kv) = self.kvs.read() else {
             anyhow::bail!("Could not acquire lock over lmdb");
         };
         let peers_store = kv.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
-        let buf = serde_json::to_vec::<SocketAddr>(&peer_addr)?;
         let mut writer = kv.write()?;
-        peers_store.put(&mut writer, buf, &Value::U64(0))?;
+        for (peer_addr, stored_peer) in stored_peers {
+            let key = serde_json::to_vec::<SocketAddr>(peer_addr)?;
+            let mut value = Vec::new();
+            stored_peer.serial(&mut value);
+            peers_store.put(&mut writer, key, &Value::Blob(&value))?;
+        }
         writer.commit()?;
         Ok(())
     }
 
+    /// Refresh the persisted entries of all the peers we are currently
+    /// connected to, recording them as seen now with their latest latency.
+    /// The entries are only written if the set of connected peers changed
+    /// since they were last written, or if they were last written more than
+    /// [`PERSISTED_PEERS_REFRESH_INTERVAL`] ago, so that the entries of peers
+    /// we stay connected to do not expire.
+    pub fn persist_connected_peers(&self) -> anyhow::Result<()> {
+        let now = get_current_stamp();
+        let stored_peers = read_or_die!(self.connections())
+            .values()
+            .map(|conn| {
+                let stored_peer = StoredPeer::connected(&conn.remote_peer, conn.get_latency(), now);
+                (conn.remote_peer.external_addr(), stored_peer)
+            })
+            .collect::<Vec<_>>();
+        let connected = stored_peers.iter().map(|(addr, _)| *addr).collect::<HashSet<_>>();
+        let mut persisted = lock_or_die!(self.connection_handler.persisted_connected);
+        if persisted.0 == connected
+            && now.saturating_sub(persisted.1) < PERSISTED_PEERS_REFRESH_INTERVAL
+        {
+            return Ok(());
+        }
+        self.persist_peers(&stored_peers)?;
+        *persisted = (connected, now);
+        Ok(())
+    }
+
+    /// Obtain all the entries of the persisted peer database. Entries that
+    /// cannot be decoded are skipped with a warning.
+    pub fn get_persisted_peers(&self) -> anyhow::Result<Vec<(SocketAddr, StoredPeer)>> {
+        let Ok(kvs_env) = self.kvs.read() else {
+            anyhow::bail!(
+                "could not read previously connected peers: cannot obtain lock for the kvs"
+            );
+        };
+        let peers_store = kvs_env.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let peers_reader = kvs_env.read()?;
+        let mut stored_peers = Vec::new();
+        for entry in peers_store.iter_start(&peers_reader)? {
+            let (peer_bytes, value) = entry?;
+            match decode_stored_peer(peer_bytes, value, get_current_stamp()) {
+                Ok(stored_peer) => stored_peers.push(stored_peer),
+                Err(e) => warn!("Skipping an invalid entry in the persisted peers: {}", e),
+            }
+        }
+        Ok(stored_peers)
+    }
+
+    /// Remove all the entries of peers we have not been connected to in the
+    /// last `max_age` milliseconds, as well as entries that cannot be decoded.
+    /// Entries persisted by earlier versions are rewritten with the current
+    /// time as their last success, see [`StoredPeer::legacy`]. Returns the
+    /// number of removed entries.
+    pub fn prune_persisted_peers(&self, max_age: u64) -> anyhow::Result<usize> {
+        let Ok(kv) = self.kvs.read() else {
+            anyhow::bail!("Could not acquire lock over lmdb");
+        };
+        let now = get_current_stamp();
+        let peers_store = kv.open_single(PEERS_STORE_NAME, StoreOptions::create())?;
+        let (to_remove, to_migrate) = {
+            let reader = kv.read()?;
+            let mut to_remove = Vec::new();
+            let mut to_migrate = Vec::new();
+            for entry in peers_store.iter_start(&reader)? {
+                let (peer_bytes, value) = entry?;
+                let is_legacy = matches!(value, Value::U64(_));
+                match decode_stored_peer(peer_bytes, value, now) {
+                    Ok((_, stored_peer)) if is_legacy => {
+                        to_migrate.push((peer_bytes.to_vec(), stored_peer))
+                    }
+                    Ok((_, stored_peer))
+                        if now.saturating_sub(stored_peer.last_success) <= max_age => {}
+                    _ => to_remove.push(peer_bytes.to_vec()),
+                }
+            }
+            (to_remove, to_migrate)
+        };
+        if !to_remove.is_empty() || !to_migrate.is_empty() {
+            let mut writer = kv.write()?;
+            for key in &to_remove {
+                peers_store.delete(&mut writer, key)?;
+            }
+            for (key, stored_peer) in &to_migrate {
+                let mut value = Vec::new();
+                stored_peer.serial(&mut value);
+                peers_store.put(&mut writer, key, &Value::Blob(&value))?;
+            }
+            writer.commit()?;
+        }
+        Ok(to_remove.len())
+    }
+
     /// Remove a peer from the persisted peer database.
     pub fn remove_persisted_peer(&self, peer_addr: SocketAddr) -> anyhow::Result<()> {
         let Ok(kv) = self.kvs.read() else {
@@ -235,6 +562,23 @@ impl P2PNode {
     }
 }
 
+/// Decode an entry of the persisted peer database at time `now`. Entries
+/// written before entries carried metadata have a `U64` value, see
+/// [`StoredPeer::legacy`].
+fn decode_stored_peer(
+    peer_bytes: &[u8],
+    value: Value,
+    now: u64,
+) -> anyhow::Result<(SocketAddr, StoredPeer)> {
+    let peer_addr: SocketAddr = serde_json::from_slice(peer_bytes)?;
+    let stored_peer = match value {
+        Value::Blob(mut bytes) => StoredPeer::deserial(&mut bytes)?,
+        Value::U64(_) => StoredPeer::legacy(now),
+        _ => bail!("Unexpected value type for the persisted peer {}.", peer_addr),
+    };
+    Ok((peer_addr, stored_peer))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -259,4 +603,93 @@ mod tests {
             "Calculation should fail since time difference is negative."
         );
     }
+
+    #[test]
+    fn test_group_peer_traffic() {
+        use crate::connection::{
+            traffic::{TrafficCategory, TrafficDirection},
+            ConnectionStats,
+        };
+
+        let peer_stats = (1..=4u64)
+            .map(|i| {
+                let conn_stats = ConnectionStats::new(0);
+                conn_stats.traffic.record(
+                    TrafficDirection::Received,
+                    TrafficCategory::Network,
+                    100 * i as usize,
+                );
+                PeerStats::new(
+                    (i as usize).into(),
+                    P2PNodeId(i),
+                    "127.0.0.1:8888".parse().unwrap(),
+                    8888,
+                    PeerType::Node,
+                    &conn_stats,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let grouped = group_peer_traffic(&peer_stats, 2);
+        let labels = grouped.iter().map(|(peer, _)| peer.as_str()).collect::<Vec<_>>();
+        assert_eq!(labels, [P2PNodeId(4).to_string(), P2PNodeId(3).to_string(), "other".into()]);
+        assert_eq!(grouped[2].1.total_bytes(), 300);
+
+        assert_eq!(group_peer_traffic(&peer_stats, 4).len(), 4);
+        assert_eq!(group_peer_traffic(&peer_stats, 0).len(), 1);
+    }
+
+    #[test]
+    fn test_stored_peer_serialization() {
+        let stored_peer = StoredPeer {
+            id:           Some(P2PNodeId(0x1234)),
+            peer_type:    PeerType::Node,
+            last_success: 1_700_000_000_000,
+            failures:     3,
+            latency:      250,
+        };
+        let mut bytes = Vec::new();
+        stored_peer.serial(&mut bytes);
+        let key = serde_json::to_vec(&"127.0.0.1:8888".parse::<SocketAddr>().unwrap()).unwrap();
+        let (addr, decoded) = decode_stored_peer(&key, Value::Blob(&bytes), 0)
+            .expect("Stored peer should deserialize.");
+        assert_eq!(addr, "127.0.0.1:8888".parse().unwrap());
+        assert_eq!(decoded, stored_peer);
+
+        // Entries persisted before entries carried metadata are still accepted, and
+        // are treated as seen at the time they are decoded.
+        let (_, legacy) = decode_stored_peer(&key, Value::U64(0), 1_700_000_000_000)
+            .expect("Legacy stored peer should be accepted.");
+        assert_eq!(legacy, StoredPeer::legacy(1_700_000_000_000));
+        assert_eq!(legacy.last_success, 1_700_000_000_000);
+    }
+
+    #[test]
+    fn test_stored_peer_score() {
+        let now = 10 * 3_600_000;
+        let good = StoredPeer {
+            id:           None,
+            peer_type:    PeerType::Node,
+            last_success: now,
+            failures:     0,
+            latency:      50,
+        };
+        assert!(good.score(now) > 0.9 && good.score(now) <= 1.0);
+
+        let failing = StoredPeer {
+            failures: 2,
+            ..good
+        };
+        let stale = StoredPeer {
+            last_success: now - 5 * 3_600_000,
+            ..good
+        };
+        let slow = StoredPeer {
+            latency: 2_000,
+            ..good
+        };
+        for worse in [failing, stale, slow] {
+            assert!(worse.score(now) < good.score(now), "{:?} should score lower.", worse);
+        }
+    }
 }
@@ -17,11 +17,14 @@ use crate::{
         },
         messaging::{ConsensusMessage, DistributionMode, MessageType},
     },
+    logging,
     p2p::{
         connectivity::{send_broadcast_message, send_direct_message},
         P2PNode,
     },
-    read_or_die, write_or_die,
+    read_or_die,
+    telemetry::{Span, SpanKind},
+    write_or_die,
 };
 use concordium_base::common::Deserial;
 
@@ -226,6 +229,7 @@ pub fn handle_consensus_inbound_msg(
     };
 
     let source = request.source_peer();
+    let _log_context = logging::with_peer(source);
     // relay external messages to Consensus
     let (consensus_result, finalizer) = send_msg_to_consensus(node, source, consensus, &request)?;
     // adjust the peer state(s) based on the feedback from Consensus
@@ -271,18 +275,22 @@ fn send_msg_to_consensus(
     let consensus_response = match message.variant {
         Block => {
             let genesis_index = u32::deserial(&mut Cursor::new(&payload[..4]))?;
+            let _log_context = logging::with_genesis_index(genesis_index);
             consensus.receive_block(genesis_index, &payload[4..])
         }
         FinalizationMessage => {
             let genesis_index = u32::deserial(&mut Cursor::new(&payload[..4]))?;
+            let _log_context = logging::with_genesis_index(genesis_index);
             (consensus.send_finalization(genesis_index, &payload[4..]), Option::None)
         }
         FinalizationRecord => {
             let genesis_index = u32::deserial(&mut Cursor::new(&payload[..4]))?;
+            let _log_context = logging::with_genesis_index(genesis_index);
             (consensus.send_finalization_record(genesis_index, &payload[4..]), Option::None)
         }
         CatchUpStatus => {
             let genesis_index = u32::deserial(&mut Cursor::new(&payload[..4]))?;
+            let _log_context = logging::with_genesis_index(genesis_index);
             (
                 consensus.receive_catch_up_status(
                     genesis_index,
@@ -349,7 +357,7 @@ pub fn update_peer_list(node: &P2PNode) {
     peers.pending_queue.retain(|id| peer_ids.contains(id));
     if let Some(in_progress) = peers.catch_up_peer {
         if !peers.peer_states.contains_key(&in_progress) {
-            peers.catch_up_peer = None;
+            peers.end_catch_up("peer removed");
         }
     }
 
@@ -370,6 +378,9 @@ fn try_catch_up(node: &P2PNode, consensus: &ConsensusContainer, peers: &mut Peer
     if let Some(id) = peers.next_pending() {
         debug!("Attempting to catch up with peer {}", id);
         peers.catch_up_stamp = get_current_stamp();
+        let mut span = Span::start("catch-up", SpanKind::Internal, None);
+        span.set_attribute("peer", id);
+        peers.catch_up_span = Some(span);
         let sent = send_direct_message(
             node,
             id,
@@ -386,7 +397,10 @@ fn try_catch_up(node: &P2PNode, consensus: &ConsensusContainer, peers: &mut Peer
             // If no packets were sent, then this must not be a valid peer,
             // so remove it from the peers.
             debug!("Could not send catch-up message to peer {}", id);
-            peers.catch_up_peer = None;
+            if let Some(ref mut span) = peers.catch_up_span {
+                span.set_error("could not send the catch-up status");
+            }
+            peers.end_catch_up("send failed");
             peers.peer_states.remove(&id);
         }
     }
@@ -417,6 +431,9 @@ pub fn check_peer_states(node: &P2PNode, consensus: &ConsensusContainer) {
             if now > catch_up_stamp + MAX_CATCH_UP_TIME {
                 // Try to remove the peer since it timed-out.
                 debug!("Peer {} took too long to catch up; dropping", peer_id);
+                if let Some(ref mut span) = write_or_die!(node.peers).catch_up_span {
+                    span.set_error("timed out");
+                }
                 // This function may not actually remove the peer, so we do not assume
                 // that it will be removed.
                 node.register_conn_change(ConnChange::RemovalByToken(peer_id.to_token()));
@@ -425,7 +442,7 @@ pub fn check_peer_states(node: &P2PNode, consensus: &ConsensusContainer) {
             // Connection no longer exists
             debug!("Connection to catch-up-in-progress peer {} no longer exists", peer_id);
             let peers = &mut write_or_die!(node.peers);
-            peers.catch_up_peer = None;
+            peers.end_catch_up("connection closed");
             peers.peer_states.remove(&peer_id);
             try_catch_up(node, consensus, peers);
         }
@@ -450,7 +467,7 @@ fn update_peer_states(
                 // We are up-to-date with the peer.
                 peers.peer_states.insert(source_peer, UpToDate);
                 if peers.catch_up_peer == Some(source_peer) {
-                    peers.catch_up_peer = None;
+                    peers.end_catch_up("up to date");
                 }
             }
             PendingBlock | PendingFinalization => {
@@ -460,7 +477,7 @@ fn update_peer_states(
                     _ => peers.pending_queue.push_back(source_peer),
                 }
                 if peers.catch_up_peer == Some(source_peer) {
-                    peers.catch_up_peer = None;
+                    peers.end_catch_up("behind");
                 }
             }
             ContinueCatchUp => {
@@ -1,7 +1,10 @@
 //! Node's statistics and their exposure.
 
 use crate::{
-    common::p2p_node_id::P2PNodeId, configuration, consensus_ffi::consensus::ConsensusContainer,
+    common::p2p_node_id::P2PNodeId,
+    configuration,
+    consensus_ffi::consensus::ConsensusContainer,
+    openmetrics::{Exemplars, ExpositionFormat},
     read_or_die, spawn_or_die,
 };
 use anyhow::Context;
@@ -13,13 +16,13 @@ use gotham::{
     router::{builder::*, Router},
     state::{FromState, State},
 };
-use http::{status::StatusCode, Response};
+use http::{header::ACCEPT, status::StatusCode, HeaderMap, Response};
 use hyper::Body;
 use prometheus::{
     self,
     core::{Atomic, AtomicI64, AtomicU64, GenericGauge},
-    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
-    Opts, Registry, TextEncoder,
+    Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry,
 };
 use std::{
     net::SocketAddr,
@@ -38,13 +41,15 @@ impl IntoResponse for HTMLStringResponse {
 
 #[derive(Clone, gotham_derive::StateData)]
 struct PrometheusStateData {
-    registry: Arc<RwLock<Registry>>,
+    registry:  Arc<RwLock<Registry>>,
+    exemplars: Exemplars,
 }
 
 impl PrometheusStateData {
-    fn new(registry: Registry) -> Self {
+    fn new(registry: Registry, exemplars: Exemplars) -> Self {
         Self {
             registry: Arc::new(RwLock::new(registry)),
+            exemplars,
         }
     }
 }
@@ -168,6 +173,9 @@ pub struct StatsExportService {
     pub packets_sent: IntCounter,
     /// Current number of connected peers.
     pub connected_peers: IntGauge,
+    /// Current number of connected nodes (excluding bootstrappers) labelled by
+    /// the direction of the connection (`direction=inbound|outbound`).
+    pub connected_peers_by_direction: IntGaugeVec,
     /// Total number of connections received.
     pub connections_received: IntCounter,
     /// Current number of inbound high priority messages in queue.
@@ -182,6 +190,36 @@ pub struct StatsExportService {
     pub received_bytes: IntCounter,
     /// Total number of bytes sent.
     pub sent_bytes: IntCounter,
+    /// Total number of network messages labelled by the direction
+    /// (`direction=received|sent`) and the category of the message
+    /// (`message=<category>`).
+    ///
+    /// Possible values of `message` are:
+    /// - `"block"`
+    /// - `"transaction"`
+    /// - `"finalization record"`
+    /// - `"finalization message"`
+    /// - `"catch-up status message"`
+    /// - `"network"` Messages of the network layer, e.g., pings and handshakes.
+    /// - `"invalid"` Messages that could not be decoded.
+    pub traffic_messages: IntCounterVec,
+    /// Total number of bytes of network messages, labelled like
+    /// `traffic_messages`.
+    pub traffic_bytes: IntCounterVec,
+    /// Total number of messages that were queued for sending to a peer but
+    /// dropped, labelled by the category of the message like
+    /// `traffic_messages` (`message=<category>`) and the reason
+    /// (`reason=full|expired`).
+    pub outbound_dropped_messages: IntCounterVec,
+    /// Number of network messages exchanged with the currently connected peers
+    /// since the connection was established, labelled like `traffic_messages`
+    /// and by the peer (`peer=<node id>`). Only the peers with the most
+    /// traffic are labelled individually; the rest are aggregated under
+    /// `peer="other"`.
+    pub peer_traffic_messages: IntGaugeVec,
+    /// Number of bytes exchanged with the currently connected peers, labelled
+    /// like `peer_traffic_messages`.
+    pub peer_traffic_bytes: IntGaugeVec,
     /// The block height of the last finalized block.
     pub last_finalized_block_height: GenericGauge<AtomicU64>,
     /// Timestamp of receiving last finalized block (Unix time in milliseconds).
@@ -190,6 +228,14 @@ pub struct StatsExportService {
     pub last_arrived_block_timestamp: IntGauge,
     /// The block height of the last finalized block.
     pub last_arrived_block_height: GenericGauge<AtomicU64>,
+    /// Delay between the timestamp (slot time) of a block and the time it
+    /// arrived at the node, in seconds.
+    pub block_arrive_latency: Histogram,
+    /// Delay between the arrival of a block and its finalization, in seconds.
+    pub block_finalization_latency: Histogram,
+    /// Difference between the timestamps of consecutive finalized blocks, in
+    /// seconds.
+    pub block_interval: Histogram,
     /// Total number of blocks baked by the node since startup.
     pub baked_blocks: IntCounter,
     /// Total number of finalized blocks baked by the node since startup.
@@ -235,6 +281,11 @@ pub struct StatsExportService {
     /// gRPC method name (`method=<name>`) and the gRPC response status
     /// (`status=<status>`).
     pub grpc_request_response_time: HistogramVec,
+    /// The upper bounds of the buckets of `grpc_request_response_time`.
+    grpc_request_response_time_buckets: Vec<f64>,
+    /// Exemplars of the histograms, linking observations to the traces of the
+    /// requests they were made for. Only exposed in the OpenMetrics format.
+    exemplars: Exemplars,
     /// Counter for tracking inflight requests.
     /// This is passed to the Tower layer `InFlightRequestLayer` provided by
     /// `tower_http::metrics` and then synced with the prometheus gauge on each
@@ -275,13 +326,19 @@ pub struct StatsExportService {
     /// The number of peers that recently connected to the node labelled by the
     /// bucket in which they are contained.
     pub peer_bucket_size: IntGaugeVec,
+    /// The number of peers in the buckets labelled by the result of their
+    /// last probe.
+    pub peer_bucket_probe_results: IntGaugeVec,
     /// The number of connections maintained by the GRPC V2 server.
     pub grpc_connected_clients: GenericGauge<AtomicU64>,
 }
 
 impl StatsExportService {
     /// Creates a new instance of the stats export service object.
-    pub fn new(grpc_duration_buckets: Vec<f64>) -> anyhow::Result<Self> {
+    pub fn new(
+        grpc_duration_buckets: Vec<f64>,
+        block_latency_buckets: Vec<f64>,
+    ) -> anyhow::Result<Self> {
         let registry = Registry::new();
 
         let packets_received = IntCounter::with_opts(Opts::new(
@@ -302,6 +359,15 @@ impl StatsExportService {
         ))?;
         registry.register(Box::new(connected_peers.clone()))?;
 
+        let connected_peers_by_direction = IntGaugeVec::new(
+            Opts::new(
+                "network_connected_peers_by_direction",
+                "Current number of connected nodes labelled by the direction of the connection",
+            ),
+            &["direction"],
+        )?;
+        registry.register(Box::new(connected_peers_by_direction.clone()))?;
+
         let connections_received = IntCounter::with_opts(Opts::new(
             "network_connections_received_total",
             "Total number of connections received",
@@ -342,6 +408,68 @@ impl StatsExportService {
             IntCounter::with_opts(Opts::new("network_sent_bytes", "Total number of bytes sent"))?;
         registry.register(Box::new(sent_bytes.clone()))?;
 
+        let traffic_messages = IntCounterVec::new(
+            Opts::new(
+                "network_traffic_messages_total",
+                "Total number of network messages labelled by the direction and the category of \
+                 the message",
+            )
+            .variable_label("direction")
+            .variable_label("message"),
+            &["direction", "message"],
+        )?;
+        registry.register(Box::new(traffic_messages.clone()))?;
+
+        let traffic_bytes = IntCounterVec::new(
+            Opts::new(
+                "network_traffic_bytes_total",
+                "Total number of bytes of network messages labelled by the direction and the \
+                 category of the message",
+            )
+            .variable_label("direction")
+            .variable_label("message"),
+            &["direction", "message"],
+        )?;
+        registry.register(Box::new(traffic_bytes.clone()))?;
+
+        let outbound_dropped_messages = IntCounterVec::new(
+            Opts::new(
+                "network_outbound_dropped_messages_total",
+                "Total number of messages dropped from the outbound queues labelled by the \
+                 category of the message and the reason",
+            )
+            .variable_label("message")
+            .variable_label("reason"),
+            &["message", "reason"],
+        )?;
+        registry.register(Box::new(outbound_dropped_messages.clone()))?;
+
+        let peer_traffic_messages = IntGaugeVec::new(
+            Opts::new(
+                "network_peer_traffic_messages",
+                "Number of network messages exchanged with the connected peers labelled by the \
+                 peer, the direction and the category of the message",
+            )
+            .variable_label("peer")
+            .variable_label("direction")
+            .variable_label("message"),
+            &["peer", "direction", "message"],
+        )?;
+        registry.register(Box::new(peer_traffic_messages.clone()))?;
+
+        let peer_traffic_bytes = IntGaugeVec::new(
+            Opts::new(
+                "network_peer_traffic_bytes",
+                "Number of bytes exchanged with the connected peers labelled by the peer, the \
+                 direction and the category of the message",
+            )
+            .variable_label("peer")
+            .variable_label("direction")
+            .variable_label("message"),
+            &["peer", "direction", "message"],
+        )?;
+        registry.register(Box::new(peer_traffic_bytes.clone()))?;
+
         let last_finalized_block_height = GenericGauge::with_opts(Opts::new(
             "consensus_last_finalized_block_height",
             "The block height of the last finalized block",
@@ -366,6 +494,33 @@ impl StatsExportService {
         ))?;
         registry.register(Box::new(last_arrived_block_timestamp.clone()))?;
 
+        let block_arrive_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "consensus_block_arrive_latency_seconds",
+                "Delay between the timestamp of a block and its arrival at the node in seconds",
+            )
+            .buckets(block_latency_buckets.clone()),
+        )?;
+        registry.register(Box::new(block_arrive_latency.clone()))?;
+
+        let block_finalization_latency = Histogram::with_opts(
+            HistogramOpts::new(
+                "consensus_block_finalization_latency_seconds",
+                "Delay between the arrival of a block at the node and its finalization in seconds",
+            )
+            .buckets(block_latency_buckets.clone()),
+        )?;
+        registry.register(Box::new(block_finalization_latency.clone()))?;
+
+        let block_interval = Histogram::with_opts(
+            HistogramOpts::new(
+                "consensus_block_interval_seconds",
+                "Time between the timestamps of consecutive finalized blocks in seconds",
+            )
+            .buckets(block_latency_buckets),
+        )?;
+        registry.register(Box::new(block_interval.clone()))?;
+
         let baked_blocks = IntCounter::with_opts(Opts::new(
             "consensus_baked_blocks_total",
             "Total number of blocks baked by the node since startup",
@@ -437,6 +592,12 @@ impl StatsExportService {
         ))?;
         registry.register(Box::new(node_startup_timestamp.clone()))?;
 
+        // An empty list of buckets means that the default buckets are used.
+        let grpc_request_response_time_buckets = if grpc_duration_buckets.is_empty() {
+            prometheus::DEFAULT_BUCKETS.to_vec()
+        } else {
+            grpc_duration_buckets
+        };
         let grpc_request_response_time = HistogramVec::new(
             HistogramOpts::new(
                 "grpc_request_response_time_seconds",
@@ -444,7 +605,7 @@ impl StatsExportService {
             )
             .variable_label("method")
             .variable_label("status")
-            .buckets(grpc_duration_buckets),
+            .buckets(grpc_request_response_time_buckets.clone()),
             &["method", "status"],
         )?;
         registry.register(Box::new(grpc_request_response_time.clone()))?;
@@ -483,6 +644,16 @@ impl StatsExportService {
         )?;
         registry.register(Box::new(peer_bucket_size.clone()))?;
 
+        let peer_bucket_probe_results = IntGaugeVec::new(
+            Opts::new(
+                "peer_bucket_probe_results",
+                "The number of peers in the buckets labelled by the result of their last probe \
+                 (dialable, undialable or unprobed)",
+            ),
+            &["result"],
+        )?;
+        registry.register(Box::new(peer_bucket_probe_results.clone()))?;
+
         let grpc_connected_clients = GenericGauge::with_opts(Opts::new(
             "grpc_connected_clients",
             "Current number of clients connected to the gRPC V2 interface",
@@ -494,6 +665,7 @@ impl StatsExportService {
             packets_received,
             packets_sent,
             connected_peers,
+            connected_peers_by_direction,
             connections_received,
             inbound_high_priority_message_queue_size,
             inbound_low_priority_message_queue_size,
@@ -501,10 +673,18 @@ impl StatsExportService {
             outbound_low_priority_message_queue_size,
             received_bytes,
             sent_bytes,
+            traffic_messages,
+            traffic_bytes,
+            outbound_dropped_messages,
+            peer_traffic_messages,
+            peer_traffic_bytes,
             last_finalized_block_height,
             last_finalized_block_timestamp,
             last_arrived_block_height,
             last_arrived_block_timestamp,
+            block_arrive_latency,
+            block_finalization_latency,
+            block_interval,
             baked_blocks,
             finalized_baked_blocks,
             received_consensus_messages,
@@ -515,6 +695,8 @@ impl StatsExportService {
             node_info,
             node_startup_timestamp,
             grpc_request_response_time,
+            grpc_request_response_time_buckets,
+            exemplars: Exemplars::default(),
             grpc_in_flight_requests_counter,
             unsupported_pending_protocol_version,
             last_throughput_measurement_timestamp,
@@ -523,20 +705,59 @@ impl StatsExportService {
             avg_bps_in,
             avg_bps_out,
             peer_bucket_size,
+            peer_bucket_probe_results,
             grpc_connected_clients,
         })
     }
 
-    fn metrics(state: State) -> (State, String) {
+    /// Observe the response time of a gRPC request. If the request is part of
+    /// a sampled trace, the observation is recorded as an exemplar of its
+    /// bucket.
+    pub fn observe_grpc_request(
+        &self,
+        method: &str,
+        status: &str,
+        duration: f64,
+        trace_id: Option<[u8; 16]>,
+    ) {
+        self.grpc_request_response_time.with_label_values(&[method, status]).observe(duration);
+        if let Some(trace_id) = trace_id {
+            self.exemplars.record(
+                "grpc_request_response_time_seconds",
+                &[("method", method), ("status", status)],
+                &self.grpc_request_response_time_buckets,
+                duration,
+                trace_id,
+            );
+        }
+    }
+
+    /// Serve the metrics in the format requested by the `Accept` header of the
+    /// request, see [ExpositionFormat::negotiate].
+    fn metrics(state: State) -> (State, Response<Body>) {
         let state_data = PrometheusStateData::borrow_from(&state);
-        let encoder = TextEncoder::new();
+        let format = ExpositionFormat::negotiate(
+            HeaderMap::borrow_from(&state).get(ACCEPT).and_then(|value| value.to_str().ok()),
+        );
         let metric_families = read_or_die!(state_data.registry).gather();
-        let mut buffer = vec![];
-        assert!(encoder.encode(&metric_families, &mut buffer).is_ok());
-        match String::from_utf8(buffer) {
-            Ok(buf) => (state, buf),
-            Err(_) => (state, "".to_string()),
-        }
+        let response = match format.encode(&metric_families, &state_data.exemplars) {
+            Ok(body) => create_response(
+                &state,
+                StatusCode::OK,
+                format.content_type().parse().unwrap_or(mime::TEXT_PLAIN),
+                body,
+            ),
+            Err(e) => {
+                error!("Could not encode the metrics: {}", e);
+                create_response(
+                    &state,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    mime::TEXT_PLAIN,
+                    e.to_string(),
+                )
+            }
+        };
+        (state, response)
     }
 
     fn index(state: State) -> (State, HTMLStringResponse) {
@@ -549,7 +770,7 @@ impl StatsExportService {
     }
 
     fn router(&self) -> Router {
-        let state_data = PrometheusStateData::new(self.registry.clone());
+        let state_data = PrometheusStateData::new(self.registry.clone(), self.exemplars.clone());
         let middleware = StateMiddleware::new(state_data);
         let pipeline = single_middleware(middleware);
         let (chain, pipelines) = single_pipeline(pipeline);
@@ -617,9 +838,11 @@ impl StatsExportService {
 pub fn instantiate_stats_export_engine(
     conf: &configuration::PrometheusConfig,
 ) -> anyhow::Result<Arc<StatsExportService>> {
-    let prom =
-        StatsExportService::new(conf.prometheus_metric_grpc_response_time_buckets.to_owned())
-            .context("Could not start statistics collection engine.")?;
+    let prom = StatsExportService::new(
+        conf.prometheus_metric_grpc_response_time_buckets.to_owned(),
+        conf.prometheus_metric_block_latency_buckets.to_owned(),
+    )
+    .context("Could not start statistics collection engine.")?;
     Ok(Arc::new(prom))
 }
 
@@ -642,7 +865,44 @@ pub fn start_push_gateway(
             conf.prometheus_job_name.clone(),
             instance_name,
             conf.prometheus_push_username.clone(),
-            conf.prometheus_push_password.clone(),
+            conf.prometheus_push_password.as_ref().map(|password| password.0.clone()),
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_latency_histograms() -> anyhow::Result<()> {
+        let stats = StatsExportService::new(vec![0.1], vec![1.0, 10.0])?;
+        stats.block_arrive_latency.observe(0.5);
+        stats.block_finalization_latency.observe(5.0);
+        stats.block_interval.observe(20.0);
+
+        let families = stats.registry.gather();
+        let bucket_counts = |name: &str| -> Vec<(f64, u64)> {
+            let family = families
+                .iter()
+                .find(|family| family.get_name() == name)
+                .unwrap_or_else(|| panic!("{} is not registered", name));
+            family.get_metric()[0]
+                .get_histogram()
+                .get_bucket()
+                .iter()
+                .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                .collect()
+        };
+        assert_eq!(bucket_counts("consensus_block_arrive_latency_seconds"), vec![
+            (1.0, 1),
+            (10.0, 1)
+        ]);
+        assert_eq!(bucket_counts("consensus_block_finalization_latency_seconds"), vec![
+            (1.0, 0),
+            (10.0, 1)
+        ]);
+        assert_eq!(bucket_counts("consensus_block_interval_seconds"), vec![(1.0, 0), (10.0, 0)]);
+        Ok(())
+    }
+}
@@ -0,0 +1,531 @@
+//! Export of traces to an OpenTelemetry collector, using OTLP over HTTP with
+//! the JSON encoding.
+//!
+//! Spans are recorded for each GRPC2 request, for each query into consensus
+//! that is made while handling a traced request, and for each catch-up round.
+//! The trace context of a GRPC2 request is taken from its `traceparent` header
+//! (W3C Trace Context) if it is present. While a span is entered its context is
+//! the current context of the thread, so it becomes the parent of the spans
+//! started on that thread in the meantime.
+
+use crate::{common::P2PNodeId, configuration::TracingConfig, read_or_die, write_or_die};
+use anyhow::Context as _;
+use rand::Rng;
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The maximum number of finished spans waiting to be exported. Spans that
+/// finish while the queue is full are dropped.
+const MAX_PENDING_SPANS: usize = 8192;
+/// The maximum number of spans exported in a single request.
+const MAX_BATCH_SIZE: usize = 512;
+/// How often the finished spans are exported.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Whether traces are exported at all.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TRACER: RwLock<Option<Tracer>> = RwLock::new(None);
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceContext>> = Cell::new(None);
+}
+
+struct Tracer {
+    sender:       tokio::sync::mpsc::Sender<FinishedSpan>,
+    /// The fraction of the traces started by the node that are recorded.
+    sample_ratio: f64,
+}
+
+/// Start exporting traces if an OTLP endpoint is configured. The spans are
+/// sent from a task on the current tokio runtime.
+pub fn start_exporter(conf: &TracingConfig, id: P2PNodeId) {
+    if let Some(ref endpoint) = conf.otlp_endpoint {
+        info!("Exporting traces to {}", endpoint);
+        let (sender, receiver) = tokio::sync::mpsc::channel(MAX_PENDING_SPANS);
+        *write_or_die!(TRACER) = Some(Tracer {
+            sender,
+            sample_ratio: conf.otlp_sample_ratio,
+        });
+        ENABLED.store(true, Ordering::Relaxed);
+        let exporter = Exporter::new(endpoint.as_str(), &conf.otlp_service_name, id);
+        tokio::spawn(exporter.run(receiver));
+    }
+}
+
+/// The identity of a span, as propagated between services.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id:  [u8; 8],
+    /// Whether the trace is recorded.
+    pub sampled:  bool,
+}
+
+impl TraceContext {
+    /// Parse the value of a W3C `traceparent` header.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let parts = header.trim().split('-').collect::<Vec<_>>();
+        let (version, trace_id, span_id, flags) = match parts[..] {
+            [version, trace_id, span_id, flags, ..] => (version, trace_id, span_id, flags),
+            _ => return None,
+        };
+        // version 00 has exactly four fields, and ff is invalid
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.len() != 4) {
+            return None;
+        }
+        let mut context = TraceContext {
+            trace_id: [0; 16],
+            span_id:  [0; 8],
+            sampled:  false,
+        };
+        hex::decode_to_slice(trace_id, &mut context.trace_id).ok()?;
+        hex::decode_to_slice(span_id, &mut context.span_id).ok()?;
+        let mut flags_byte = [0u8];
+        hex::decode_to_slice(flags, &mut flags_byte).ok()?;
+        context.sampled = flags_byte[0] & 1 == 1;
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return None;
+        }
+        Some(context)
+    }
+
+    /// Format the context as the value of a W3C `traceparent` header.
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            hex::encode(self.trace_id),
+            hex::encode(self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+/// The context of the span that is currently entered on this thread, if any.
+pub fn current() -> Option<TraceContext> { CURRENT.with(Cell::get) }
+
+/// The kind of a span, as defined by OpenTelemetry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    /// An operation within the node.
+    Internal = 1,
+    /// The handling of a request from a client.
+    Server   = 2,
+}
+
+/// A span that is exported when it is dropped. A span that is not recorded,
+/// because tracing is disabled or the trace is not sampled, does nothing.
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+struct SpanData {
+    name:           String,
+    context:        TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    kind:           SpanKind,
+    start:          SystemTime,
+    attributes:     Vec<(&'static str, String)>,
+    error:          Option<String>,
+}
+
+struct FinishedSpan {
+    data: SpanData,
+    end:  SystemTime,
+}
+
+impl Span {
+    /// Start a span. Without a parent the span starts a new trace, which is
+    /// recorded with the configured sample ratio. With a parent the span is
+    /// recorded if the parent is.
+    pub fn start(name: impl Into<String>, kind: SpanKind, parent: Option<TraceContext>) -> Self {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return Self::not_recorded();
+        }
+        let mut rng = rand::thread_rng();
+        let (trace_id, parent_span_id) = match parent {
+            Some(parent) if parent.sampled => (parent.trace_id, Some(parent.span_id)),
+            Some(_) => return Self::not_recorded(),
+            None => {
+                let sample_ratio = read_or_die!(TRACER).as_ref().map_or(0.0, |t| t.sample_ratio);
+                if rng.gen::<f64>() >= sample_ratio {
+                    return Self::not_recorded();
+                }
+                (rng.gen(), None)
+            }
+        };
+        Span {
+            data: Some(SpanData {
+                name: name.into(),
+                context: TraceContext {
+                    trace_id,
+                    span_id: rng.gen(),
+                    sampled: true,
+                },
+                parent_span_id,
+                kind,
+                start: SystemTime::now(),
+                attributes: Vec::new(),
+                error: None,
+            }),
+        }
+    }
+
+    /// A span that is not recorded.
+    pub fn not_recorded() -> Self {
+        Span {
+            data: None,
+        }
+    }
+
+    /// The context of the span, if it is recorded.
+    pub fn context(&self) -> Option<TraceContext> { self.data.as_ref().map(|data| data.context) }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(ref mut data) = self.data {
+            data.attributes.push((key, value.to_string()));
+        }
+    }
+
+    /// Mark the operation of the span as failed.
+    pub fn set_error(&mut self, message: impl ToString) {
+        if let Some(ref mut data) = self.data {
+            data.error = Some(message.to_string());
+        }
+    }
+
+    /// Make the span the current span of the thread until the returned guard
+    /// is dropped.
+    pub fn enter(&self) -> EnteredContext { enter_context(self.context()) }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(data) = self.data.take() {
+            if let Some(ref tracer) = *read_or_die!(TRACER) {
+                let span = FinishedSpan {
+                    data,
+                    end: SystemTime::now(),
+                };
+                // drop the span if the exporter can't keep up
+                let _ = tracer.sender.try_send(span);
+            }
+        }
+    }
+}
+
+/// Start a span for a query into consensus, if a traced request is being
+/// handled on this thread.
+pub fn ffi_span(name: &'static str) -> Span {
+    match current() {
+        Some(parent) => Span::start(name, SpanKind::Internal, Some(parent)),
+        None => Span::not_recorded(),
+    }
+}
+
+/// Restores the previous current span of the thread when dropped.
+#[must_use]
+pub struct EnteredContext {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for EnteredContext {
+    fn drop(&mut self) { CURRENT.with(|current| current.set(self.previous)) }
+}
+
+fn enter_context(context: Option<TraceContext>) -> EnteredContext {
+    EnteredContext {
+        previous: CURRENT.with(|current| current.replace(context)),
+    }
+}
+
+/// A future that makes the given span context current whenever it is polled.
+pub struct Instrumented<F> {
+    inner:   Pin<Box<F>>,
+    context: Option<TraceContext>,
+}
+
+impl<F: Future> Instrumented<F> {
+    pub fn new(inner: F, context: Option<TraceContext>) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            context,
+        }
+    }
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _entered = enter_context(self.context);
+        self.inner.as_mut().poll(cx)
+    }
+}
+
+/// Spawn a task on the current tokio runtime that keeps the current context of
+/// the thread, so the queries into consensus made by the task are recorded in
+/// the span of the request that spawned it.
+pub fn spawn_in_current_context<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static, {
+    tokio::spawn(Instrumented::new(future, current()))
+}
+
+/// Sends batches of finished spans to the collector.
+struct Exporter {
+    client:   reqwest::Client,
+    url:      String,
+    resource: serde_json::Value,
+}
+
+impl Exporter {
+    fn new(endpoint: &str, service_name: &str, id: P2PNodeId) -> Self {
+        Self {
+            client:   reqwest::Client::new(),
+            url:      format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            resource: serde_json::json!({
+                "attributes": [
+                    string_attribute("service.name", service_name),
+                    string_attribute("service.version", crate::VERSION),
+                    string_attribute("service.instance.id", &id.to_string()),
+                ]
+            }),
+        }
+    }
+
+    async fn run(self, mut receiver: tokio::sync::mpsc::Receiver<FinishedSpan>) {
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+        loop {
+            let closed = tokio::select! {
+                span = receiver.recv() => match span {
+                    Some(span) => {
+                        batch.push(span);
+                        if batch.len() < MAX_BATCH_SIZE {
+                            continue;
+                        }
+                        false
+                    }
+                    None => true,
+                },
+                _ = interval.tick() => false,
+            };
+            if !batch.is_empty() {
+                if let Err(e) = self.export(&batch).await {
+                    warn!("Could not export {} spans: {:#}", batch.len(), e);
+                }
+                batch.clear();
+            }
+            if closed {
+                break;
+            }
+        }
+    }
+
+    async fn export(&self, spans: &[FinishedSpan]) -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "resourceSpans": [{
+                "resource": self.resource,
+                "scopeSpans": [{
+                    "scope": { "name": crate::APPNAME, "version": crate::VERSION },
+                    "spans": spans.iter().map(encode_span).collect::<Vec<_>>(),
+                }],
+            }],
+        });
+        self.client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .context("Could not reach the collector.")?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+fn string_attribute(key: &str, value: &str) -> serde_json::Value {
+    serde_json::json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn encode_span(span: &FinishedSpan) -> serde_json::Value {
+    let data = &span.data;
+    let mut encoded = serde_json::json!({
+        "traceId": hex::encode(data.context.trace_id),
+        "spanId": hex::encode(data.context.span_id),
+        "name": data.name,
+        "kind": data.kind as u8,
+        "startTimeUnixNano": unix_nanos(data.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": data
+            .attributes
+            .iter()
+            .map(|(key, value)| string_attribute(key, value))
+            .collect::<Vec<_>>(),
+        "status": match data.error {
+            Some(ref message) => serde_json::json!({ "code": 2, "message": message }),
+            None => serde_json::json!({ "code": 0 }),
+        },
+    });
+    if let Some(parent_span_id) = data.parent_span_id {
+        encoded["parentSpanId"] = hex::encode(parent_span_id).into();
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{self, BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    /// Stand-in for the trace collector. Accept a single request on the
+    /// listener, answer it with an empty `200 OK` response and return its body.
+    fn accept_request(listener: TcpListener) -> io::Result<Vec<u8>> {
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end().to_ascii_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(length) = line.strip_prefix("content-length:") {
+                content_length = length
+                    .trim()
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        reader.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")?;
+        Ok(body)
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::from_traceparent(header).unwrap();
+        assert!(context.sampled);
+        assert_eq!(context.span_id, [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]);
+        assert_eq!(context.to_traceparent(), header);
+
+        let not_sampled = TraceContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+        )
+        .unwrap();
+        assert!(!not_sampled.sampled);
+        // later versions may add fields
+        assert!(TraceContext::from_traceparent(
+            "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra"
+        )
+        .is_some());
+
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+        ] {
+            assert_eq!(TraceContext::from_traceparent(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_current_context() {
+        let context = TraceContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        );
+        assert_eq!(current(), None);
+        {
+            let _entered = enter_context(context);
+            assert_eq!(current(), context);
+            {
+                let _nested = enter_context(None);
+                assert_eq!(current(), None);
+            }
+            assert_eq!(current(), context);
+        }
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn test_export_to_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let collector = std::thread::spawn(move || accept_request(listener));
+
+        let start = UNIX_EPOCH + Duration::from_secs(1);
+        let span = |name: &str, span_id: u8, parent_span_id: Option<u8>| FinishedSpan {
+            data: SpanData {
+                name: name.to_owned(),
+                context: TraceContext {
+                    trace_id: [1; 16],
+                    span_id:  [span_id; 8],
+                    sampled:  true,
+                },
+                parent_span_id: parent_span_id.map(|id| [id; 8]),
+                kind: SpanKind::Internal,
+                start,
+                attributes: vec![("peer", "0000000000000001".to_owned())],
+                error: None,
+            },
+            end:  start + Duration::from_millis(5),
+        };
+        let mut request = span("/concordium.v2.Queries/GetAccountInfo", 2, None);
+        request.data.kind = SpanKind::Server;
+        request.data.error = Some("not found".to_owned());
+        let query = span("get_account_info_v2", 3, Some(2));
+
+        let exporter = Exporter::new(&endpoint, "test-node", P2PNodeId(42));
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(exporter.export(&[request, query]))
+            .unwrap();
+
+        let body: serde_json::Value =
+            serde_json::from_slice(&collector.join().unwrap().unwrap()).unwrap();
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0],
+            string_attribute("service.name", "test-node")
+        );
+        let spans = &resource_spans["scopeSpans"][0]["spans"];
+        assert_eq!(spans[0]["kind"], 2);
+        assert_eq!(spans[0]["status"]["code"], 2);
+        assert_eq!(spans[0]["startTimeUnixNano"], "1000000000");
+        assert_eq!(spans[0]["endTimeUnixNano"], "1005000000");
+        assert!(spans[0].get("parentSpanId").is_none());
+        assert_eq!(spans[1]["name"], "get_account_info_v2");
+        assert_eq!(spans[1]["traceId"], "01".repeat(16));
+        assert_eq!(spans[1]["parentSpanId"], spans[0]["spanId"]);
+        assert_eq!(spans[1]["attributes"][0], string_attribute("peer", "0000000000000001"));
+    }
+}
@@ -110,7 +110,7 @@ pub fn make_node_and_sync(
     config.connection.housekeeping_interval = 10;
     let regenesis_arc = Arc::new(Regenesis::from_blocks(regenesis_blocks));
 
-    let stats = Arc::new(StatsExportService::new(Vec::new()).unwrap());
+    let stats = Arc::new(StatsExportService::new(Vec::new(), Vec::new()).unwrap());
     let (node, server, poll) = P2PNode::new(None, &config, node_type, stats, regenesis_arc)?;
 
     spawn(&node, server, poll, None);
@@ -121,7 +121,11 @@ pub fn make_node_and_sync(
 
 /// Connects `source` and `target` nodes
 pub fn connect(source: &Arc<P2PNode>, target: &P2PNode) {
-    source.register_conn_change(ConnChange::NewPeers(vec![target.self_peer]));
+    source.register_conn_change(ConnChange::NewConn {
+        addr:      target.self_peer.addr,
+        peer_type: PeerType::Node,
+        given:     false,
+    });
 }
 
 /// Waits until all handshakes with other nodes have concluded.
@@ -1,10 +1,8 @@
 //! Miscellaneous utilities.
 
-use crate::configuration as config;
-use env_logger::{Builder, Env};
+use crate::{configuration as config, logging};
 use log::LevelFilter;
 use std::{
-    io::Write,
     net::{SocketAddr, ToSocketAddrs},
     path::Path,
 };
@@ -18,30 +16,22 @@ pub fn setup_logger_config(config_file: &Path) {
     log4rs::init_file(config_file, Default::default()).unwrap();
 }
 
-/// Sets up a logger that logs to stderr.
-pub fn setup_logger(trace: bool, debug: bool, no_log_timestamp: bool) {
-    let env = if trace {
-        Env::default().filter_or("LOG_LEVEL", "trace")
-    } else if debug {
-        Env::default().filter_or("LOG_LEVEL", "debug")
+/// Sets up a logger that logs to stderr, see [`logging::setup`].
+pub fn setup_logger(conf: &config::CommonConfig) {
+    let level = if conf.trace {
+        LevelFilter::Trace
+    } else if conf.debug {
+        LevelFilter::Debug
     } else {
-        Env::default().filter_or("LOG_LEVEL", "info")
+        LevelFilter::Info
     };
-
-    let mut log_builder = Builder::from_env(env);
-    if no_log_timestamp {
-        log_builder.format_timestamp(None);
-    } else {
-        log_builder.format(|buf, record| {
-            writeln!(buf, "{}: {}: {}", buf.timestamp_nanos(), record.level(), record.args())
-        });
-    }
-    log_builder.filter(Some("tokio_reactor"), LevelFilter::Error);
-    log_builder.filter(Some("hyper"), LevelFilter::Error);
-    log_builder.filter(Some("reqwest"), LevelFilter::Error);
-    log_builder.filter(Some("gotham"), LevelFilter::Error);
-    log_builder.filter(Some("h2"), LevelFilter::Error);
-    log_builder.init();
+    logging::setup(&logging::LoggerOptions {
+        level,
+        format: conf.log_format,
+        no_timestamp: conf.no_log_timestamp,
+        module_levels: conf.log_levels.clone(),
+        trace_sample_rate: conf.log_trace_sample_rate,
+    });
 }
 
 /// Sets up a logger for the macOS syslog which logs with the provided
@@ -90,6 +80,11 @@ pub fn get_bootstrap_nodes(bootstrap_nodes: &[String]) -> Result<Vec<SocketAddr>
 pub fn get_config_and_logging_setup() -> anyhow::Result<(config::Config, config::AppPreferences)> {
     // Get config and app preferences
     let conf = config::parse_config()?;
+    if conf.common.check_config {
+        // The configuration was validated when parsing it.
+        println!("The configuration is valid. The effective configuration is:\n{:#?}", conf);
+        std::process::exit(0);
+    }
     let app_prefs = config::AppPreferences::new(
         conf.common.config_dir.to_owned(),
         conf.common.data_dir.to_owned(),
@@ -108,16 +103,38 @@ pub fn get_config_and_logging_setup() -> anyhow::Result<(config::Config, config:
     };
 
     #[cfg(target_os = "macos")]
-    match conf.macos.use_mac_log {
-        Some(ref subsystem) => setup_macos_logger(conf.common.trace, conf.common.debug, subsystem),
-        None => setup_logger(conf.common.trace, conf.common.debug, conf.common.no_log_timestamp),
+    let default_logger = match conf.macos.use_mac_log {
+        Some(ref subsystem) => {
+            setup_macos_logger(conf.common.trace, conf.common.debug, subsystem);
+            false
+        }
+        None => {
+            setup_logger(&conf.common);
+            true
+        }
     };
 
     #[cfg(not(target_os = "macos"))]
-    if let Some(ref log_config) = conf.common.log_config {
+    let default_logger = if let Some(ref log_config) = conf.common.log_config {
         setup_logger_config(log_config);
+        false
     } else {
-        setup_logger(conf.common.trace, conf.common.debug, conf.common.no_log_timestamp);
+        setup_logger(&conf.common);
+        true
+    };
+
+    if !default_logger {
+        // keep the level that is changed when reloading the configuration in sync
+        logging::set_level(log::max_level());
+        if conf.common.log_format != logging::LogFormat::Text
+            || !conf.common.log_levels.is_empty()
+            || conf.common.log_trace_sample_rate != 1
+        {
+            warn!(
+                "The log format, log levels and trace sampling options only apply to the default \
+                 logger and are ignored."
+            );
+        }
     }
 
     info!("Starting up {} version {}!", crate::APPNAME, crate::VERSION);
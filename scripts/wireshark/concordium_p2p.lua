@@ -0,0 +1,572 @@
+-- Wireshark dissector for the Concordium P2P protocol.
+--
+-- Generated by generate-dissector.py from schema.fbs.
+-- Do not edit by hand; regenerate it when the schema changes.
+
+local p2p = Proto("ccd_p2p", "Concordium P2P")
+
+local FILE_IDENTIFIER = "CP2P"
+local ROOT_TYPE = "NetworkMessage"
+
+local SCALAR_SIZES = {
+    bool = 1,
+    byte = 1,
+    double = 8,
+    float = 4,
+    float32 = 4,
+    float64 = 8,
+    int = 4,
+    int16 = 2,
+    int32 = 4,
+    int64 = 8,
+    int8 = 1,
+    long = 8,
+    short = 2,
+    ubyte = 1,
+    uint = 4,
+    uint16 = 2,
+    uint32 = 4,
+    uint64 = 8,
+    uint8 = 1,
+    ulong = 8,
+    ushort = 2,
+}
+
+local PACKET_TYPES = {
+    [0] = "Block",
+    [1] = "Transaction",
+    [2] = "FinalizationRecord",
+    [3] = "FinalizationMessage",
+    [4] = "CatchUpStatus",
+}
+
+local enums = {
+    Direction = {
+        type = "uint8",
+        values = {
+            [0] = "Direct",
+            [1] = "Broadcast",
+        },
+    },
+    RequestVariant = {
+        type = "uint8",
+        values = {
+            [0] = "Ping",
+            [1] = "GetPeers",
+            [2] = "Handshake",
+            [5] = "JoinNetwork",
+            [6] = "LeaveNetwork",
+        },
+    },
+    ResponseVariant = {
+        type = "uint8",
+        values = {
+            [0] = "Pong",
+            [1] = "PeerList",
+        },
+    },
+    IpVariant = {
+        type = "uint8",
+        values = {
+            [0] = "V4",
+            [1] = "V6",
+        },
+    },
+    PeerVariant = {
+        type = "uint8",
+        values = {
+            [0] = "Node",
+            [1] = "Bootstrapper",
+        },
+    },
+}
+
+-- Union members are numbered from 1; 0 means that no value is present.
+local unions = {
+    RequestPayload = {
+        [1] = "NetworkIds",
+        [2] = "Handshake",
+        [3] = "NetworkId",
+    },
+    ResponsePayload = {
+        [1] = "PeerList",
+    },
+    NetworkPayload = {
+        [1] = "NetworkPacket",
+        [2] = "NetworkRequest",
+        [3] = "NetworkResponse",
+    },
+}
+
+-- The fields of each table with their vtable slot. A union field takes
+-- two slots: its type and its value.
+local tables = {}
+local fields = {}
+
+tables.NetworkId = {
+    {
+        name = "id",
+        kind = "scalar",
+        type = "uint16",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint16("ccd_p2p.network_id.id", "id", nil, nil, nil, nil),
+    },
+}
+for _, f in ipairs(tables.NetworkId) do
+    table.insert(fields, f.field)
+end
+
+tables.Destination = {
+    {
+        name = "variant",
+        kind = "enum",
+        type = "Direction",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint8("ccd_p2p.destination.variant", "variant", base.DEC, enums.Direction.values, nil, nil),
+    },
+    {
+        name = "target",
+        kind = "scalar",
+        type = "uint64",
+        vector = false,
+        slot = 1,
+        field = ProtoField.uint64("ccd_p2p.destination.target", "target", nil, nil, nil, "should only be set if Direction is Direct."),
+    },
+}
+for _, f in ipairs(tables.Destination) do
+    table.insert(fields, f.field)
+end
+
+tables.NetworkPacket = {
+    {
+        name = "destination",
+        kind = "table",
+        type = "Destination",
+        vector = false,
+        slot = 0,
+        field = ProtoField.none("ccd_p2p.network_packet.destination", "destination", "whether this message has to be broadcasted or is only for our consensus instance."),
+    },
+    {
+        name = "network_id",
+        kind = "scalar",
+        type = "uint16",
+        vector = false,
+        slot = 1,
+        field = ProtoField.uint16("ccd_p2p.network_packet.network_id", "network_id", nil, nil, nil, "the network Id in which the packet has to be considered."),
+    },
+    {
+        name = "payload",
+        kind = "scalar",
+        type = "uint8",
+        vector = true,
+        slot = 2,
+        field = ProtoField.bytes("ccd_p2p.network_packet.payload", "payload", base.NONE, "the payload of the packet. First byte will be read by the code that connects the network and the consensus layers. Will be interpreted following this table: - 0: Block - 1: Transaction - 2: FinalizationRecord - 3: FinalizationMessage - 4: CatchUpStatus These payloads are generated by the consensus layer and MUST NOT be modified by the network layer."),
+    },
+}
+for _, f in ipairs(tables.NetworkPacket) do
+    table.insert(fields, f.field)
+end
+
+tables.Version = {
+    {
+        name = "version",
+        kind = "scalar",
+        type = "uint8",
+        vector = true,
+        slot = 0,
+        field = ProtoField.bytes("ccd_p2p.version.version", "version", base.NONE, nil),
+    },
+}
+for _, f in ipairs(tables.Version) do
+    table.insert(fields, f.field)
+end
+
+tables.BlockHash = {
+    {
+        name = "genesis_block",
+        kind = "scalar",
+        type = "uint8",
+        vector = true,
+        slot = 0,
+        field = ProtoField.bytes("ccd_p2p.block_hash.genesis_block", "genesis_block", base.NONE, nil),
+    },
+}
+for _, f in ipairs(tables.BlockHash) do
+    table.insert(fields, f.field)
+end
+
+tables.Handshake = {
+    {
+        name = "version",
+        kind = "scalar",
+        type = "uint8",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint8("ccd_p2p.handshake.version", "version", nil, nil, nil, "the version of this message. Later versions are expected to append new fields at the end so messages should still be understood to some extent."),
+    },
+    {
+        name = "node_id",
+        kind = "scalar",
+        type = "uint64",
+        vector = false,
+        slot = 1,
+        field = ProtoField.uint64("ccd_p2p.handshake.node_id", "node_id", nil, nil, nil, "the node id of the sender."),
+    },
+    {
+        name = "port",
+        kind = "scalar",
+        type = "uint16",
+        vector = false,
+        slot = 2,
+        field = ProtoField.uint16("ccd_p2p.handshake.port", "port", nil, nil, nil, "the port of the sender."),
+    },
+    {
+        name = "network_ids",
+        kind = "scalar",
+        type = "uint16",
+        vector = true,
+        slot = 3,
+        field = ProtoField.uint16("ccd_p2p.handshake.network_ids", "network_ids", nil, nil, nil, "the network ids in which the sender node participates."),
+    },
+    {
+        name = "node_version",
+        kind = "table",
+        type = "Version",
+        vector = false,
+        slot = 4,
+        field = ProtoField.none("ccd_p2p.handshake.node_version", "node_version", "the version of the node that the sender is running. Deprecated and incompatible versions will be rejected on handshake."),
+    },
+    {
+        name = "wire_versions",
+        kind = "scalar",
+        type = "uint8",
+        vector = true,
+        slot = 5,
+        field = ProtoField.bytes("ccd_p2p.handshake.wire_versions", "wire_versions", base.NONE, "the version of the messaging protocol that the sender is running. Incompatible versions will be rejected on handshake as they are assumed to use new messsages or expect a different behavior from the other party."),
+    },
+    {
+        name = "genesis_blocks",
+        kind = "table",
+        type = "BlockHash",
+        vector = true,
+        slot = 6,
+        field = ProtoField.none("ccd_p2p.handshake.genesis_blocks", "genesis_blocks", "the list of genesis block hash and later regenesis blocks hashes that are known by the sender's consensus layer. If running a bootstrapper node, this list will be provided by a CLI flag as there is no consensus container running. Only when the sender's list of a prefix of the receiver's list or viceversa, handshake will succeed as both nodes belong to the same network."),
+    },
+    {
+        name = "zk",
+        kind = "scalar",
+        type = "uint8",
+        vector = true,
+        slot = 7,
+        field = ProtoField.bytes("ccd_p2p.handshake.zk", "zk", base.NONE, "a zero knowledge proof provided by the sender. Currently unused."),
+    },
+}
+for _, f in ipairs(tables.Handshake) do
+    table.insert(fields, f.field)
+end
+
+tables.NetworkIds = {
+    {
+        name = "ids",
+        kind = "scalar",
+        type = "uint16",
+        vector = true,
+        slot = 0,
+        field = ProtoField.uint16("ccd_p2p.network_ids.ids", "ids", nil, nil, nil, nil),
+    },
+}
+for _, f in ipairs(tables.NetworkIds) do
+    table.insert(fields, f.field)
+end
+
+tables.NetworkRequest = {
+    {
+        name = "variant",
+        kind = "enum",
+        type = "RequestVariant",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint8("ccd_p2p.network_request.variant", "variant", base.DEC, enums.RequestVariant.values, nil, nil),
+    },
+    {
+        name = "payload",
+        kind = "union",
+        type = "RequestPayload",
+        vector = false,
+        slot = 2,
+        field = ProtoField.none("ccd_p2p.network_request.payload", "payload", nil),
+    },
+}
+for _, f in ipairs(tables.NetworkRequest) do
+    table.insert(fields, f.field)
+end
+
+tables.IpAddr = {
+    {
+        name = "variant",
+        kind = "enum",
+        type = "IpVariant",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint8("ccd_p2p.ip_addr.variant", "variant", base.DEC, enums.IpVariant.values, nil, nil),
+    },
+    {
+        name = "octets",
+        kind = "scalar",
+        type = "uint8",
+        vector = true,
+        slot = 1,
+        field = ProtoField.bytes("ccd_p2p.ip_addr.octets", "octets", base.NONE, nil),
+    },
+}
+for _, f in ipairs(tables.IpAddr) do
+    table.insert(fields, f.field)
+end
+
+tables.P2PPeer = {
+    {
+        name = "id",
+        kind = "scalar",
+        type = "uint64",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint64("ccd_p2p.p2p_peer.id", "id", nil, nil, nil, nil),
+    },
+    {
+        name = "addr",
+        kind = "table",
+        type = "IpAddr",
+        vector = false,
+        slot = 1,
+        field = ProtoField.none("ccd_p2p.p2p_peer.addr", "addr", nil),
+    },
+    {
+        name = "port",
+        kind = "scalar",
+        type = "uint16",
+        vector = false,
+        slot = 2,
+        field = ProtoField.uint16("ccd_p2p.p2p_peer.port", "port", nil, nil, nil, nil),
+    },
+    {
+        name = "variant",
+        kind = "enum",
+        type = "PeerVariant",
+        vector = false,
+        slot = 3,
+        field = ProtoField.uint8("ccd_p2p.p2p_peer.variant", "variant", base.DEC, enums.PeerVariant.values, nil, nil),
+    },
+}
+for _, f in ipairs(tables.P2PPeer) do
+    table.insert(fields, f.field)
+end
+
+tables.PeerList = {
+    {
+        name = "peers",
+        kind = "table",
+        type = "P2PPeer",
+        vector = true,
+        slot = 0,
+        field = ProtoField.none("ccd_p2p.peer_list.peers", "peers", nil),
+    },
+}
+for _, f in ipairs(tables.PeerList) do
+    table.insert(fields, f.field)
+end
+
+tables.NetworkResponse = {
+    {
+        name = "variant",
+        kind = "enum",
+        type = "ResponseVariant",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint8("ccd_p2p.network_response.variant", "variant", base.DEC, enums.ResponseVariant.values, nil, nil),
+    },
+    {
+        name = "payload",
+        kind = "union",
+        type = "ResponsePayload",
+        vector = false,
+        slot = 2,
+        field = ProtoField.none("ccd_p2p.network_response.payload", "payload", nil),
+    },
+}
+for _, f in ipairs(tables.NetworkResponse) do
+    table.insert(fields, f.field)
+end
+
+tables.NetworkMessage = {
+    {
+        name = "timestamp",
+        kind = "scalar",
+        type = "uint64",
+        vector = false,
+        slot = 0,
+        field = ProtoField.uint64("ccd_p2p.network_message.timestamp", "timestamp", nil, nil, nil, nil),
+    },
+    {
+        name = "payload",
+        kind = "union",
+        type = "NetworkPayload",
+        vector = false,
+        slot = 2,
+        field = ProtoField.none("ccd_p2p.network_message.payload", "payload", nil),
+    },
+}
+for _, f in ipairs(tables.NetworkMessage) do
+    table.insert(fields, f.field)
+end
+
+local packet_type_field = ProtoField.uint8("ccd_p2p.network_packet.packet_type", "packet type", base.DEC, PACKET_TYPES)
+table.insert(fields, packet_type_field)
+p2p.fields = fields
+
+-- Flatbuffers decoding. All offsets are relative to the start of the tvb.
+
+local function uoffset(tvb, pos)
+    return pos + tvb(pos, 4):le_uint()
+end
+
+-- The position of the field in the given slot of a table, or nil if the field
+-- is absent.
+local function field_pos(tvb, table_pos, slot)
+    local vtable_pos = table_pos - tvb(table_pos, 4):le_int()
+    local vtable_len = tvb(vtable_pos, 2):le_uint()
+    local entry = 4 + 2 * slot
+    if entry + 2 > vtable_len then
+        return nil
+    end
+    local offset = tvb(vtable_pos + entry, 2):le_uint()
+    if offset == 0 then
+        return nil
+    end
+    return table_pos + offset
+end
+
+local function table_len(tvb, table_pos)
+    local vtable_pos = table_pos - tvb(table_pos, 4):le_int()
+    return tvb(vtable_pos + 2, 2):le_uint()
+end
+
+local function read_scalar(tvb, pos, type)
+    local size = SCALAR_SIZES[type]
+    if size == 8 then
+        return tvb(pos, size):le_uint64()
+    end
+    return tvb(pos, size):le_uint()
+end
+
+local dissect_table
+
+-- Dissect a single value of the given kind and type, located at `pos`.
+-- Returns a short summary of the value, if any.
+local function dissect_value(tvb, tree, f, kind, type, pos)
+    if kind == "scalar" or kind == "enum" then
+        local scalar = kind == "enum" and enums[type].type or type
+        tree:add_le(f.field, tvb(pos, SCALAR_SIZES[scalar]))
+        if kind == "enum" then
+            return enums[type].values[read_scalar(tvb, pos, scalar)]
+        end
+    elseif kind == "string" then
+        local str_pos = uoffset(tvb, pos)
+        local len = tvb(str_pos, 4):le_uint()
+        tree:add(f.field, tvb(str_pos + 4, len))
+    elseif kind == "table" then
+        local target = uoffset(tvb, pos)
+        local subtree = tree:add(f.field, tvb(target, table_len(tvb, target)))
+        subtree:append_text(" (" .. type .. ")")
+        return dissect_table(tvb, subtree, target, type)
+    end
+    return nil
+end
+
+-- Dissect the table of the given type located at `table_pos`. Returns a short
+-- summary of the table, made from its union members and variants.
+dissect_table = function(tvb, tree, table_pos, type_name)
+    local summary = {}
+    for _, f in ipairs(tables[type_name]) do
+        local pos = field_pos(tvb, table_pos, f.slot)
+        if f.kind == "union" then
+            local type_pos = field_pos(tvb, table_pos, f.slot - 1)
+            local member = type_pos and unions[f.type][tvb(type_pos, 1):le_uint()]
+            if pos and member then
+                local target = uoffset(tvb, pos)
+                local subtree = tree:add(f.field, tvb(target, table_len(tvb, target)))
+                subtree:append_text(" (" .. member .. ")")
+                table.insert(summary, member)
+                local inner = dissect_table(tvb, subtree, target, member)
+                if inner ~= "" then
+                    table.insert(summary, inner)
+                end
+            end
+        elseif pos and f.vector then
+            local vec_pos = uoffset(tvb, pos)
+            local len = tvb(vec_pos, 4):le_uint()
+            if len == 0 then
+                tree:add(tvb(vec_pos, 4), f.name .. ": (empty)")
+            elseif f.type == "uint8" or f.type == "ubyte" then
+                tree:add(f.field, tvb(vec_pos + 4, len))
+                if type_name == "NetworkPacket" and f.name == "payload" then
+                    tree:add(packet_type_field, tvb(vec_pos + 4, 1))
+                    local packet_type = PACKET_TYPES[tvb(vec_pos + 4, 1):le_uint()]
+                    table.insert(summary, packet_type or "UnknownPacket")
+                end
+            else
+                local size = (f.kind == "scalar" or f.kind == "enum")
+                    and SCALAR_SIZES[f.kind == "enum" and enums[f.type].type or f.type] or 4
+                local subtree = tree:add(f.field, tvb(vec_pos, 4 + len * size))
+                subtree:append_text(" (" .. len .. " elements)")
+                for i = 0, len - 1 do
+                    dissect_value(tvb, subtree, f, f.kind, f.type, vec_pos + 4 + i * size)
+                end
+            end
+        elseif pos then
+            local value = dissect_value(tvb, tree, f, f.kind, f.type, pos)
+            if value and f.name == "variant" then
+                table.insert(summary, value)
+            end
+        elseif f.kind == "enum" and f.name == "variant" then
+            -- fields with the default value are not serialized
+            table.insert(summary, enums[f.type].values[0])
+        end
+    end
+    return table.concat(summary, " ")
+end
+
+-- Messages are size-prefixed flatbuffers.
+
+local function get_message_len(tvb, pinfo, offset)
+    return tvb(offset, 4):le_uint() + 4
+end
+
+local function dissect_message(tvb, pinfo, tree)
+    pinfo.cols.protocol = "CCD-P2P"
+    local subtree = tree:add(p2p, tvb(), "Concordium P2P message")
+    local summary = dissect_table(tvb, subtree, uoffset(tvb, 4), ROOT_TYPE)
+    subtree:append_text(": " .. summary)
+    pinfo.cols.info = summary
+    return tvb:len()
+end
+
+function p2p.dissector(tvb, pinfo, tree)
+    dissect_tcp_pdus(tvb, tree, 4, get_message_len, dissect_message)
+    return tvb:len()
+end
+
+-- Every message carries the file identifier after the size prefix and the
+-- offset of the root table.
+local function heuristic_dissector(tvb, pinfo, tree)
+    if tvb:len() < 12 or tvb(8, 4):string() ~= FILE_IDENTIFIER then
+        return false
+    end
+    pinfo.conversation = p2p
+    p2p.dissector(tvb, pinfo, tree)
+    return true
+end
+
+p2p:register_heuristic("tcp", heuristic_dissector)
+DissectorTable.get("tcp.port"):add_for_decode_as(p2p)
@@ -0,0 +1,416 @@
+#!/usr/bin/env python3
+"""Generate a Wireshark dissector for the Concordium P2P protocol.
+
+The dissector is generated from the flatbuffers schema of the network messages
+and decodes the size-prefixed messages in the pcapng files exported by the
+node's network dump (see the `dump-replay export-pcap` command and the
+`CONCORDIUM_NODE_DUMP_PCAP` option).
+
+Usage: generate-dissector.py [SCHEMA] [OUTPUT]
+"""
+
+import os
+import re
+import sys
+
+SCRIPT_DIR = os.path.dirname(os.path.abspath(__file__))
+DEFAULT_SCHEMA = os.path.join(
+    SCRIPT_DIR, "..", "..", "concordium-node", "src", "network", "serialization", "schema.fbs"
+)
+DEFAULT_OUTPUT = os.path.join(SCRIPT_DIR, "concordium_p2p.lua")
+
+PROTO_NAME = "ccd_p2p"
+
+# The size and the ProtoField constructor of the flatbuffers scalar types.
+SCALARS = {
+    "bool": (1, "bool"),
+    "byte": (1, "int8"),
+    "ubyte": (1, "uint8"),
+    "int8": (1, "int8"),
+    "uint8": (1, "uint8"),
+    "short": (2, "int16"),
+    "ushort": (2, "uint16"),
+    "int16": (2, "int16"),
+    "uint16": (2, "uint16"),
+    "int": (4, "int32"),
+    "uint": (4, "uint32"),
+    "int32": (4, "int32"),
+    "uint32": (4, "uint32"),
+    "long": (8, "int64"),
+    "ulong": (8, "uint64"),
+    "int64": (8, "int64"),
+    "uint64": (8, "uint64"),
+    "float": (4, "float"),
+    "float32": (4, "float"),
+    "double": (8, "double"),
+    "float64": (8, "double"),
+}
+
+# The first byte of the payload of a packet is interpreted by the consensus
+# layer; the schema only documents it, so the names are listed here.
+PACKET_TYPES = {
+    0: "Block",
+    1: "Transaction",
+    2: "FinalizationRecord",
+    3: "FinalizationMessage",
+    4: "CatchUpStatus",
+}
+
+DOC_START = "\x01"
+DOC_END = "\x02"
+
+
+def strip_comments(source):
+    """Remove comments, keeping the documentation comments as markers."""
+    lines = []
+    for line in source.splitlines():
+        doc = re.match(r"\s*///\s?(.*)$", line)
+        if doc:
+            lines.append(DOC_START + doc.group(1).strip() + DOC_END)
+        else:
+            lines.append(re.sub(r"//.*$", "", line))
+    return "\n".join(lines)
+
+
+def split_items(body, separator):
+    """Split the body of a declaration into items with their documentation."""
+    items = []
+    for raw in body.split(separator):
+        docs = re.findall(DOC_START + "(.*?)" + DOC_END, raw)
+        text = re.sub(DOC_START + ".*?" + DOC_END, "", raw).strip()
+        if text:
+            items.append((text, " ".join(docs)))
+    return items
+
+
+def parse_schema(source):
+    text = strip_comments(source)
+    schema = {"enums": {}, "unions": {}, "tables": {}, "root": None, "identifier": None}
+    for kind, name, base, body in re.findall(
+        r"\b(enum|union|table)\s+(\w+)\s*(?::\s*(\w+))?\s*\{(.*?)\}", text, re.S
+    ):
+        if kind == "enum":
+            values = []
+            next_value = 0
+            for item, _ in split_items(body, ","):
+                member = re.match(r"(\w+)(?:\s*=\s*(\d+))?$", item)
+                if not member:
+                    sys.exit("Cannot parse the enum member '%s' of %s." % (item, name))
+                if member.group(2) is not None:
+                    next_value = int(member.group(2))
+                values.append((next_value, member.group(1)))
+                next_value += 1
+            schema["enums"][name] = {"type": base, "values": values}
+        elif kind == "union":
+            schema["unions"][name] = [item for item, _ in split_items(body, ",")]
+        else:
+            fields = []
+            for item, doc in split_items(body, ";"):
+                field = re.match(r"(\w+)\s*:\s*(\[?\s*\w+\s*\]?)$", item)
+                if not field:
+                    sys.exit("Cannot parse the field '%s' of %s." % (item, name))
+                fields.append((field.group(1), field.group(2).replace(" ", ""), doc))
+            schema["tables"][name] = fields
+    root = re.search(r"\broot_type\s+(\w+)\s*;", text)
+    identifier = re.search(r'\bfile_identifier\s+"(\w{4})"\s*;', text)
+    if not root or not identifier:
+        sys.exit("The schema must declare a root type and a file identifier.")
+    schema["root"] = root.group(1)
+    schema["identifier"] = identifier.group(1)
+    return schema
+
+
+def snake_case(name):
+    return re.sub(r"(?<=[a-z])(?=[A-Z])|(?<=[A-Z0-9])(?=[A-Z][a-z])", "_", name).lower()
+
+
+def lua_string(value):
+    return '"' + value.replace("\\", "\\\\").replace('"', '\\"') + '"'
+
+
+def classify(schema, type_name):
+    """The kind of a (non-vector) type."""
+    if type_name in SCALARS:
+        return "scalar"
+    if type_name == "string":
+        return "string"
+    if type_name in schema["enums"]:
+        return "enum"
+    if type_name in schema["unions"]:
+        return "union"
+    if type_name in schema["tables"]:
+        return "table"
+    sys.exit("Unknown type '%s'." % type_name)
+
+
+def generate(schema, schema_path):
+    out = []
+    emit = out.append
+    emit("-- Wireshark dissector for the Concordium P2P protocol.")
+    emit("--")
+    emit("-- Generated by generate-dissector.py from %s." % os.path.basename(schema_path))
+    emit("-- Do not edit by hand; regenerate it when the schema changes.")
+    emit("")
+    emit('local p2p = Proto("%s", "Concordium P2P")' % PROTO_NAME)
+    emit("")
+    emit("local FILE_IDENTIFIER = %s" % lua_string(schema["identifier"]))
+    emit("local ROOT_TYPE = %s" % lua_string(schema["root"]))
+    emit("")
+
+    emit("local SCALAR_SIZES = {")
+    for name, (size, _) in sorted(SCALARS.items()):
+        emit("    %s = %d," % (name, size))
+    emit("}")
+    emit("")
+
+    emit("local PACKET_TYPES = {")
+    for value, name in sorted(PACKET_TYPES.items()):
+        emit("    [%d] = %s," % (value, lua_string(name)))
+    emit("}")
+    emit("")
+
+    emit("local enums = {")
+    for name, enum in schema["enums"].items():
+        emit("    %s = {" % name)
+        emit("        type = %s," % lua_string(enum["type"]))
+        emit("        values = {")
+        for value, member in enum["values"]:
+            emit("            [%d] = %s," % (value, lua_string(member)))
+        emit("        },")
+        emit("    },")
+    emit("}")
+    emit("")
+
+    emit("-- Union members are numbered from 1; 0 means that no value is present.")
+    emit("local unions = {")
+    for name, members in schema["unions"].items():
+        emit("    %s = {" % name)
+        for index, member in enumerate(members, start=1):
+            emit("        [%d] = %s," % (index, lua_string(member)))
+        emit("    },")
+    emit("}")
+    emit("")
+
+    emit("-- The fields of each table with their vtable slot. A union field takes")
+    emit("-- two slots: its type and its value.")
+    emit("local tables = {}")
+    emit("local fields = {}")
+    for table, table_fields in schema["tables"].items():
+        emit("")
+        emit("tables.%s = {" % table)
+        slot = 0
+        for name, type_name, doc in table_fields:
+            vector = type_name.startswith("[")
+            element = type_name.strip("[]")
+            kind = classify(schema, element)
+            if kind == "union":
+                if vector:
+                    sys.exit("Vectors of unions are not supported (%s.%s)." % (table, name))
+                slot += 1
+            abbr = "%s.%s.%s" % (PROTO_NAME, snake_case(table), name)
+            label = name
+            desc = lua_string(doc) if doc else "nil"
+            if vector and element in ("uint8", "ubyte"):
+                field = "ProtoField.bytes(%s, %s, base.NONE, %s)" % (
+                    lua_string(abbr), lua_string(label), desc)
+            elif kind == "scalar" and SCALARS[element][1] in ("float", "double"):
+                field = "ProtoField.%s(%s, %s, nil, %s)" % (
+                    SCALARS[element][1], lua_string(abbr), lua_string(label), desc)
+            elif kind == "scalar":
+                field = "ProtoField.%s(%s, %s, nil, nil, nil, %s)" % (
+                    SCALARS[element][1], lua_string(abbr), lua_string(label), desc)
+            elif kind == "enum":
+                base_type = schema["enums"][element]["type"]
+                field = "ProtoField.%s(%s, %s, base.DEC, enums.%s.values, nil, %s)" % (
+                    SCALARS[base_type][1], lua_string(abbr), lua_string(label), element, desc)
+            elif kind == "string":
+                field = "ProtoField.string(%s, %s, base.UNICODE, %s)" % (
+                    lua_string(abbr), lua_string(label), desc)
+            else:
+                field = "ProtoField.none(%s, %s, %s)" % (lua_string(abbr), lua_string(label), desc)
+            emit("    {")
+            emit("        name = %s," % lua_string(name))
+            emit("        kind = %s," % lua_string(kind))
+            emit("        type = %s," % lua_string(element))
+            emit("        vector = %s," % ("true" if vector else "false"))
+            emit("        slot = %d," % slot)
+            emit("        field = %s," % field)
+            emit("    },")
+            slot += 1
+        emit("}")
+        emit("for _, f in ipairs(tables.%s) do" % table)
+        emit("    table.insert(fields, f.field)")
+        emit("end")
+    emit("")
+    emit("local packet_type_field = ProtoField.uint8(%s, \"packet type\", base.DEC, PACKET_TYPES)"
+         % lua_string(PROTO_NAME + ".network_packet.packet_type"))
+    emit("table.insert(fields, packet_type_field)")
+    emit("p2p.fields = fields")
+    emit("")
+    emit(RUNTIME.strip("\n"))
+    emit("")
+    return "\n".join(out)
+
+
+# The part of the dissector that does not depend on the schema.
+RUNTIME = r"""
+-- Flatbuffers decoding. All offsets are relative to the start of the tvb.
+
+local function uoffset(tvb, pos)
+    return pos + tvb(pos, 4):le_uint()
+end
+
+-- The position of the field in the given slot of a table, or nil if the field
+-- is absent.
+local function field_pos(tvb, table_pos, slot)
+    local vtable_pos = table_pos - tvb(table_pos, 4):le_int()
+    local vtable_len = tvb(vtable_pos, 2):le_uint()
+    local entry = 4 + 2 * slot
+    if entry + 2 > vtable_len then
+        return nil
+    end
+    local offset = tvb(vtable_pos + entry, 2):le_uint()
+    if offset == 0 then
+        return nil
+    end
+    return table_pos + offset
+end
+
+local function table_len(tvb, table_pos)
+    local vtable_pos = table_pos - tvb(table_pos, 4):le_int()
+    return tvb(vtable_pos + 2, 2):le_uint()
+end
+
+local function read_scalar(tvb, pos, type)
+    local size = SCALAR_SIZES[type]
+    if size == 8 then
+        return tvb(pos, size):le_uint64()
+    end
+    return tvb(pos, size):le_uint()
+end
+
+local dissect_table
+
+-- Dissect a single value of the given kind and type, located at `pos`.
+-- Returns a short summary of the value, if any.
+local function dissect_value(tvb, tree, f, kind, type, pos)
+    if kind == "scalar" or kind == "enum" then
+        local scalar = kind == "enum" and enums[type].type or type
+        tree:add_le(f.field, tvb(pos, SCALAR_SIZES[scalar]))
+        if kind == "enum" then
+            return enums[type].values[read_scalar(tvb, pos, scalar)]
+        end
+    elseif kind == "string" then
+        local str_pos = uoffset(tvb, pos)
+        local len = tvb(str_pos, 4):le_uint()
+        tree:add(f.field, tvb(str_pos + 4, len))
+    elseif kind == "table" then
+        local target = uoffset(tvb, pos)
+        local subtree = tree:add(f.field, tvb(target, table_len(tvb, target)))
+        subtree:append_text(" (" .. type .. ")")
+        return dissect_table(tvb, subtree, target, type)
+    end
+    return nil
+end
+
+-- Dissect the table of the given type located at `table_pos`. Returns a short
+-- summary of the table, made from its union members and variants.
+dissect_table = function(tvb, tree, table_pos, type_name)
+    local summary = {}
+    for _, f in ipairs(tables[type_name]) do
+        local pos = field_pos(tvb, table_pos, f.slot)
+        if f.kind == "union" then
+            local type_pos = field_pos(tvb, table_pos, f.slot - 1)
+            local member = type_pos and unions[f.type][tvb(type_pos, 1):le_uint()]
+            if pos and member then
+                local target = uoffset(tvb, pos)
+                local subtree = tree:add(f.field, tvb(target, table_len(tvb, target)))
+                subtree:append_text(" (" .. member .. ")")
+                table.insert(summary, member)
+                local inner = dissect_table(tvb, subtree, target, member)
+                if inner ~= "" then
+                    table.insert(summary, inner)
+                end
+            end
+        elseif pos and f.vector then
+            local vec_pos = uoffset(tvb, pos)
+            local len = tvb(vec_pos, 4):le_uint()
+            if len == 0 then
+                tree:add(tvb(vec_pos, 4), f.name .. ": (empty)")
+            elseif f.type == "uint8" or f.type == "ubyte" then
+                tree:add(f.field, tvb(vec_pos + 4, len))
+                if type_name == "NetworkPacket" and f.name == "payload" then
+                    tree:add(packet_type_field, tvb(vec_pos + 4, 1))
+                    local packet_type = PACKET_TYPES[tvb(vec_pos + 4, 1):le_uint()]
+                    table.insert(summary, packet_type or "UnknownPacket")
+                end
+            else
+                local size = (f.kind == "scalar" or f.kind == "enum")
+                    and SCALAR_SIZES[f.kind == "enum" and enums[f.type].type or f.type] or 4
+                local subtree = tree:add(f.field, tvb(vec_pos, 4 + len * size))
+                subtree:append_text(" (" .. len .. " elements)")
+                for i = 0, len - 1 do
+                    dissect_value(tvb, subtree, f, f.kind, f.type, vec_pos + 4 + i * size)
+                end
+            end
+        elseif pos then
+            local value = dissect_value(tvb, tree, f, f.kind, f.type, pos)
+            if value and f.name == "variant" then
+                table.insert(summary, value)
+            end
+        elseif f.kind == "enum" and f.name == "variant" then
+            -- fields with the default value are not serialized
+            table.insert(summary, enums[f.type].values[0])
+        end
+    end
+    return table.concat(summary, " ")
+end
+
+-- Messages are size-prefixed flatbuffers.
+
+local function get_message_len(tvb, pinfo, offset)
+    return tvb(offset, 4):le_uint() + 4
+end
+
+local function dissect_message(tvb, pinfo, tree)
+    pinfo.cols.protocol = "CCD-P2P"
+    local subtree = tree:add(p2p, tvb(), "Concordium P2P message")
+    local summary = dissect_table(tvb, subtree, uoffset(tvb, 4), ROOT_TYPE)
+    subtree:append_text(": " .. summary)
+    pinfo.cols.info = summary
+    return tvb:len()
+end
+
+function p2p.dissector(tvb, pinfo, tree)
+    dissect_tcp_pdus(tvb, tree, 4, get_message_len, dissect_message)
+    return tvb:len()
+end
+
+-- Every message carries the file identifier after the size prefix and the
+-- offset of the root table.
+local function heuristic_dissector(tvb, pinfo, tree)
+    if tvb:len() < 12 or tvb(8, 4):string() ~= FILE_IDENTIFIER then
+        return false
+    end
+    pinfo.conversation = p2p
+    p2p.dissector(tvb, pinfo, tree)
+    return true
+end
+
+p2p:register_heuristic("tcp", heuristic_dissector)
+DissectorTable.get("tcp.port"):add_for_decode_as(p2p)
+"""
+
+
+def main():
+    schema_path = sys.argv[1] if len(sys.argv) > 1 else DEFAULT_SCHEMA
+    output_path = sys.argv[2] if len(sys.argv) > 2 else DEFAULT_OUTPUT
+    with open(schema_path) as f:
+        schema = parse_schema(f.read())
+    with open(output_path, "w") as f:
+        f.write(generate(schema, schema_path))
+    print("Wrote %s" % output_path)
+
+
+if __name__ == "__main__":
+    main()